@@ -14,7 +14,10 @@ CREATE TABLE IF NOT EXISTS media (
     rating REAL,
     added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     last_watched TIMESTAMP,
-    watch_count INTEGER DEFAULT 0
+    watch_count INTEGER DEFAULT 0,
+    updated_at TIMESTAMP, -- bumped whenever the item or its details change, used for ETags
+    missing_since TIMESTAMP, -- when a scan first found its file gone, it is removed after a grace period
+    content_hash TEXT -- partial hash of the file, to recognize it after a move or rename
 );
 
 -- TV shows seasons
@@ -40,6 +43,8 @@ CREATE TABLE IF NOT EXISTS episodes (
     still_path TEXT,
     air_date TEXT,
     runtime INTEGER,
+    missing_since TIMESTAMP, -- see media
+    content_hash TEXT,
     FOREIGN KEY (media_id) REFERENCES media(id) ON DELETE CASCADE,
     FOREIGN KEY (season_id) REFERENCES seasons(id) ON DELETE CASCADE
 );
@@ -111,6 +116,142 @@ CREATE TABLE IF NOT EXISTS libraries (
     scan_automatically BOOLEAN DEFAULT 1
 );
 
+-- Technical details of playable files, read from the file headers during scans
+CREATE TABLE IF NOT EXISTS media_files (
+    id TEXT PRIMARY KEY,
+    media_id TEXT NOT NULL,
+    episode_id TEXT, -- NULL for movies and music
+    path TEXT NOT NULL UNIQUE,
+    container TEXT NOT NULL, -- mp4, matroska
+    duration REAL, -- seconds
+    bitrate INTEGER, -- overall, in bits per second
+    size INTEGER NOT NULL,
+    modified_at TIMESTAMP, -- file modification time when probed
+    probed_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (media_id) REFERENCES media(id) ON DELETE CASCADE,
+    FOREIGN KEY (episode_id) REFERENCES episodes(id) ON DELETE CASCADE
+);
+
+-- Video, audio and subtitle tracks of a media file
+CREATE TABLE IF NOT EXISTS media_streams (
+    id TEXT PRIMARY KEY,
+    file_id TEXT NOT NULL,
+    stream_index INTEGER NOT NULL, -- track number in the container
+    kind TEXT NOT NULL, -- video, audio, subtitle
+    codec TEXT NOT NULL, -- h264, hevc, aac, dts, subrip, ...
+    codec_string TEXT, -- RFC 6381 codecs parameter, e.g. avc1.640028
+    profile TEXT,
+    language TEXT,
+    title TEXT,
+    is_default BOOLEAN DEFAULT 0,
+    is_forced BOOLEAN DEFAULT 0,
+    width INTEGER,
+    height INTEGER,
+    frame_rate REAL,
+    bit_depth INTEGER,
+    hdr TEXT, -- hdr10, hlg, dolby_vision; NULL for SDR
+    channels INTEGER,
+    channel_layout TEXT, -- mono, stereo, 5.1, 7.1
+    sample_rate INTEGER,
+    FOREIGN KEY (file_id) REFERENCES media_files(id) ON DELETE CASCADE
+);
+
+-- Subtitle files found next to videos, e.g. Movie.en.forced.srt, and text subtitle
+-- tracks embedded in the videos themselves
+CREATE TABLE IF NOT EXISTS subtitles (
+    id TEXT PRIMARY KEY,
+    media_id TEXT NOT NULL,
+    episode_id TEXT, -- NULL for movies
+    path TEXT NOT NULL, -- the subtitle file, or the video for embedded tracks
+    stream_index INTEGER, -- track number of embedded tracks, NULL for files
+    format TEXT NOT NULL, -- subrip, ass, webvtt, microdvd, mov_text
+    language TEXT, -- ISO 639-1, e.g. en
+    title TEXT,
+    is_forced BOOLEAN DEFAULT 0,
+    is_sdh BOOLEAN DEFAULT 0,
+    is_default BOOLEAN DEFAULT 0,
+    is_uploaded BOOLEAN DEFAULT 0, -- uploaded through the API rather than found in the library
+    time_offset REAL DEFAULT 0, -- seconds added to every cue
+    added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (media_id) REFERENCES media(id) ON DELETE CASCADE,
+    FOREIGN KEY (episode_id) REFERENCES episodes(id) ON DELETE CASCADE
+);
+
+-- Timing corrections users made for themselves, on top of the subtitle's own offset
+CREATE TABLE IF NOT EXISTS subtitle_offsets (
+    user_id TEXT NOT NULL,
+    subtitle_id TEXT NOT NULL,
+    time_offset REAL NOT NULL, -- seconds
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, subtitle_id),
+    FOREIGN KEY (subtitle_id) REFERENCES subtitles(id) ON DELETE CASCADE
+);
+
+-- Chapters and skip markers of movies and episodes
+CREATE TABLE IF NOT EXISTS markers (
+    id TEXT PRIMARY KEY,
+    media_id TEXT NOT NULL,
+    episode_id TEXT, -- NULL for movies
+    marker_type TEXT NOT NULL, -- chapter, intro, credits, recap
+    start_time REAL NOT NULL, -- seconds
+    end_time REAL NOT NULL,
+    title TEXT,
+    source TEXT NOT NULL, -- file (read from the container), manual or detected
+    confidence REAL, -- 0 to 1, for detected markers
+    added_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (media_id) REFERENCES media(id) ON DELETE CASCADE,
+    FOREIGN KEY (episode_id) REFERENCES episodes(id) ON DELETE CASCADE
+);
+
+-- Seek preview thumbnails of movies and episodes, packed into sprite sheets
+CREATE TABLE IF NOT EXISTS trickplay (
+    id TEXT PRIMARY KEY,
+    media_id TEXT NOT NULL,
+    episode_id TEXT, -- NULL for movies
+    status TEXT NOT NULL, -- queued, running, ready, failed
+    file_etag TEXT NOT NULL, -- version of the video the thumbnails are made from
+    interval REAL NOT NULL, -- seconds between thumbnails
+    width INTEGER NOT NULL, -- of one thumbnail
+    height INTEGER NOT NULL,
+    columns INTEGER NOT NULL, -- thumbnails per row of a sprite sheet
+    rows INTEGER NOT NULL,
+    thumbnails INTEGER DEFAULT 0,
+    sprites INTEGER DEFAULT 0,
+    has_bif BOOLEAN DEFAULT 0, -- Roku BIF file next to the sprites
+    error TEXT,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (media_id) REFERENCES media(id) ON DELETE CASCADE,
+    FOREIGN KEY (episode_id) REFERENCES episodes(id) ON DELETE CASCADE
+);
+
+-- Users allowed or not allowed to download, overriding DOWNLOADS_REQUIRE_PERMISSION
+CREATE TABLE IF NOT EXISTS download_permissions (
+    user_id TEXT PRIMARY KEY,
+    can_download BOOLEAN NOT NULL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Background jobs such as library scans, with their progress
+CREATE TABLE IF NOT EXISTS jobs (
+    id TEXT PRIMARY KEY,
+    kind TEXT NOT NULL, -- scan
+    library_id TEXT,
+    paths TEXT, -- folders of a partial scan, one per line; NULL scans the whole library
+    status TEXT NOT NULL, -- queued, running, completed, failed, cancelled
+    total INTEGER DEFAULT 0, -- files found
+    processed INTEGER DEFAULT 0, -- files looked at so far
+    added INTEGER DEFAULT 0, -- new movies or episodes
+    existing INTEGER DEFAULT 0, -- files already in the library
+    missing INTEGER DEFAULT 0, -- items whose file is gone, kept for the grace period
+    removed INTEGER DEFAULT 0, -- items whose file stayed gone past the grace period
+    errors INTEGER DEFAULT 0, -- files that couldn't be probed or searched for subtitles
+    error TEXT, -- why the job failed
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    started_at TIMESTAMP,
+    finished_at TIMESTAMP,
+    FOREIGN KEY (library_id) REFERENCES libraries(id) ON DELETE CASCADE
+);
+
 -- Create indexes for common queries
 CREATE INDEX IF NOT EXISTS idx_media_type ON media(type);
 CREATE INDEX IF NOT EXISTS idx_seasons_media_id ON seasons(media_id);
@@ -120,4 +261,12 @@ CREATE INDEX IF NOT EXISTS idx_media_people_media_id ON media_people(media_id);
 CREATE INDEX IF NOT EXISTS idx_media_people_person_id ON media_people(person_id);
 CREATE INDEX IF NOT EXISTS idx_media_genres_media_id ON media_genres(media_id);
 CREATE INDEX IF NOT EXISTS idx_watch_progress_user_media ON watch_progress(user_id, media_id);
-CREATE INDEX IF NOT EXISTS idx_watch_progress_media_id ON watch_progress(media_id);
\ No newline at end of file
+CREATE INDEX IF NOT EXISTS idx_watch_progress_media_id ON watch_progress(media_id);
+CREATE INDEX IF NOT EXISTS idx_media_files_media_id ON media_files(media_id);
+CREATE INDEX IF NOT EXISTS idx_media_streams_file_id ON media_streams(file_id);
+CREATE INDEX IF NOT EXISTS idx_markers_media_id ON markers(media_id, episode_id);
+CREATE INDEX IF NOT EXISTS idx_subtitles_media_id ON subtitles(media_id, episode_id);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_trickplay_item ON trickplay(media_id, IFNULL(episode_id, ''));
+CREATE UNIQUE INDEX IF NOT EXISTS idx_subtitles_path ON subtitles(path, IFNULL(stream_index, -1));
+-- At most one scan of a library is queued or running
+CREATE UNIQUE INDEX IF NOT EXISTS idx_jobs_active ON jobs(kind, library_id) WHERE status IN ('queued', 'running');
\ No newline at end of file
@@ -0,0 +1,365 @@
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::{Pool, Sqlite};
+use std::env;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::api::episodes;
+use crate::db::models::{DownloadPermission, DownloadPermissionDto, Episode, Media, Season};
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::media::sandbox::PathSandbox;
+use crate::media::scanner;
+use crate::streaming::file::RangeFile;
+use crate::streaming::signing::{StreamToken, UrlSigner};
+use crate::streaming::zip::ZipArchive;
+
+// Who may download. Users without a permission of their own may download unless
+// DOWNLOADS_REQUIRE_PERMISSION is set, in which case they have to be allowed first.
+pub struct DownloadPolicy {
+    require_permission: bool,
+}
+
+impl DownloadPolicy {
+    pub fn from_env() -> Self {
+        let require_permission = env::var("DOWNLOADS_REQUIRE_PERMISSION")
+            .map(|value| matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        Self { require_permission }
+    }
+
+    async fn check(&self, db: &Pool<Sqlite>, user_id: &str) -> Result<()> {
+        let allowed = match queries::get_download_permission(db, user_id).await? {
+            Some(permission) => permission.can_download,
+            None => !self.require_permission,
+        };
+        if !allowed {
+            return Err(AppError::Forbidden(format!("{} is not allowed to download", user_id)));
+        }
+        Ok(())
+    }
+}
+
+// A single file, or several packed into a ZIP archive
+enum Download {
+    File { path: PathBuf, filename: String },
+    Archive { filename: String, files: Vec<(String, PathBuf)> },
+}
+
+#[derive(Responder)]
+pub enum DownloadResponse {
+    File(Box<RangeFile>),
+    Archive(ZipArchive),
+}
+
+impl Download {
+    fn filename(&self) -> &str {
+        match self {
+            Download::File { filename, .. } | Download::Archive { filename, .. } => filename,
+        }
+    }
+
+    async fn respond(self) -> Result<DownloadResponse> {
+        match self {
+            Download::File { path, filename } => {
+                Ok(DownloadResponse::File(Box::new(RangeFile::open(path).await?.download_as(filename))))
+            }
+            Download::Archive { filename, files } => Ok(DownloadResponse::Archive(ZipArchive::new(filename, files).await?)),
+        }
+    }
+
+    // Size of the download in bytes, archives included
+    async fn size(self) -> Result<u64> {
+        match self.respond().await? {
+            DownloadResponse::File(file) => Ok(file.len()),
+            DownloadResponse::Archive(archive) => Ok(archive.len()),
+        }
+    }
+}
+
+// Make a name safe to save on any system: no path separators or characters Windows
+// refuses, and no leading or trailing dots and spaces
+fn clean_filename(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { ' ' } else { c })
+        .collect();
+    let cleaned = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+    let cleaned = cleaned.trim_matches(|c| c == '.' || c == ' ');
+    if cleaned.is_empty() { "download".to_string() } else { cleaned.to_string() }
+}
+
+// The extension of a file with its dot, or nothing
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map_or_else(String::new, |extension| format!(".{}", extension))
+}
+
+// e.g. "Title (2001).mkv"
+fn media_filename(media: &Media, path: &Path) -> String {
+    let name = match media.year {
+        Some(year) => format!("{} ({})", media.title, year),
+        None => media.title.clone(),
+    };
+    format!("{}{}", clean_filename(&name), extension(path))
+}
+
+// e.g. "Show - S01E02 - Title.mkv"
+fn episode_filename(show: &Media, season: &Season, episode: &Episode) -> String {
+    let mut name = format!("{} - S{:02}E{:02}", show.title, season.season_number, episode.episode_number);
+    if !episode.title.trim().is_empty() {
+        name.push_str(&format!(" - {}", episode.title));
+    }
+    format!("{}{}", clean_filename(&name), extension(Path::new(&episode.path)))
+}
+
+fn season_folder(season: &Season) -> String {
+    format!("Season {:02}", season.season_number)
+}
+
+async fn checked_path(db: &Pool<Sqlite>, sandbox: &PathSandbox, path: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(path);
+    sandbox.check_file(db, &path).await?;
+    Ok(path)
+}
+
+async fn episode_download(db: &Pool<Sqlite>, sandbox: &PathSandbox, id: &str) -> Result<Download> {
+    let episode = episodes::find_episode(db, id).await?;
+    let season = queries::get_season_by_id(db, &episode.season_id).await?;
+    let show = queries::get_media_by_id(db, &episode.media_id).await?;
+
+    Ok(Download::File {
+        path: checked_path(db, sandbox, &episode.path).await?,
+        filename: episode_filename(&show, &season, &episode),
+    })
+}
+
+// The episodes of a season, in a folder per season when `folders` is set
+async fn season_files(
+    db: &Pool<Sqlite>,
+    sandbox: &PathSandbox,
+    show: &Media,
+    season: &Season,
+    folders: bool,
+) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for episode in queries::get_episodes_by_season_id(db, &season.id).await? {
+        let mut name = episode_filename(show, season, &episode);
+        if folders {
+            name = format!("{}/{}", season_folder(season), name);
+        }
+        files.push((name, checked_path(db, sandbox, &episode.path).await?));
+    }
+    Ok(files)
+}
+
+async fn season_download(db: &Pool<Sqlite>, sandbox: &PathSandbox, id: &str) -> Result<Download> {
+    let season = queries::get_season_by_id(db, id).await?;
+    let show = queries::get_media_by_id(db, &season.media_id).await?;
+    let files = season_files(db, sandbox, &show, &season, false).await?;
+    if files.is_empty() {
+        return Err(AppError::NotFound(format!("Season {} has no episodes", id)));
+    }
+
+    Ok(Download::Archive {
+        filename: format!("{}.zip", clean_filename(&format!("{} - {}", show.title, season_folder(&season)))),
+        files,
+    })
+}
+
+// A movie or a music file, or a whole show or album as an archive
+async fn media_download(db: &Pool<Sqlite>, sandbox: &PathSandbox, id: &str) -> Result<Download> {
+    let media = queries::get_media_by_id(db, id).await?;
+    let archive_name = format!("{}.zip", clean_filename(&media.title));
+
+    if media.media_type == "tvshow" {
+        let mut files = Vec::new();
+        for season in queries::get_seasons_by_media_id(db, id).await? {
+            files.extend(season_files(db, sandbox, &media, &season, true).await?);
+        }
+        if files.is_empty() {
+            return Err(AppError::NotFound(format!("{} has no episodes", media.title)));
+        }
+        return Ok(Download::Archive { filename: archive_name, files });
+    }
+
+    let path = checked_path(db, sandbox, &media.path).await?;
+    if !path.is_dir() {
+        return Ok(Download::File { filename: media_filename(&media, &path), path });
+    }
+
+    // An album: its audio files, by their paths in the album's folder
+    let mut files = Vec::new();
+    for entry in WalkDir::new(&path).sort_by_file_name().into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() || !scanner::is_audio_file(entry.path()) {
+            continue;
+        }
+        let Ok(relative) = entry.path().strip_prefix(&path) else {
+            continue;
+        };
+        let name = relative
+            .components()
+            .map(|component| clean_filename(&component.as_os_str().to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join("/");
+        files.push((name, checked_path(db, sandbox, &entry.path().to_string_lossy()).await?));
+    }
+    if files.is_empty() {
+        return Err(AppError::NotFound(format!("{} has no audio files", media.title)));
+    }
+    Ok(Download::Archive { filename: archive_name, files })
+}
+
+// Download tokens are signed for this, so a stream URL can't be used to download
+fn download_item(id: &str) -> String {
+    format!("download/{}", id)
+}
+
+// A short-lived download URL for a user who may download
+async fn sign_download(
+    download: Download,
+    url: String,
+    id: &str,
+    user_id: &str,
+    db: &Pool<Sqlite>,
+    policy: &DownloadPolicy,
+    signer: &UrlSigner,
+) -> Result<Json<serde_json::Value>> {
+    policy.check(db, user_id).await?;
+
+    let token = signer.sign(&download_item(id), user_id);
+    let filename = download.filename().to_string();
+    let size = download.size().await?;
+
+    Ok(Json(serde_json::json!({
+        "url": format!("{}?{}", url, token.query()),
+        "filename": filename,
+        "size": size,
+        "userId": token.user,
+        "expiresAt": token.expires_at(),
+    })))
+}
+
+// Check a download URL's signature, and that its user may still download
+async fn verify_download(
+    id: &str,
+    token: Option<StreamToken>,
+    db: &Pool<Sqlite>,
+    policy: &DownloadPolicy,
+    signer: &UrlSigner,
+) -> Result<()> {
+    let token = signer.verify(&download_item(id), token)?;
+    policy.check(db, &token.user).await
+}
+
+// Download URL of a movie, a music file, or a whole show or album as a ZIP archive
+#[post("/media/<id>?<user_id>")]
+pub async fn sign_media(
+    id: String,
+    user_id: String,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    policy: &State<DownloadPolicy>,
+    signer: &State<UrlSigner>,
+) -> Result<Json<serde_json::Value>> {
+    let download = media_download(db, sandbox, &id).await?;
+    let url = format!("/api/downloads/media/{}", id);
+    sign_download(download, url, &id, &user_id, db, policy, signer).await
+}
+
+#[get("/media/<id>?<token..>")]
+pub async fn download_media(
+    id: String,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    policy: &State<DownloadPolicy>,
+    signer: &State<UrlSigner>,
+) -> Result<DownloadResponse> {
+    verify_download(&id, token, db, policy, signer).await?;
+    media_download(db, sandbox, &id).await?.respond().await
+}
+
+// Download URL of an episode, saved as e.g. "Show - S01E02 - Title.mkv"
+#[post("/episodes/<id>?<user_id>")]
+pub async fn sign_episode(
+    id: String,
+    user_id: String,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    policy: &State<DownloadPolicy>,
+    signer: &State<UrlSigner>,
+) -> Result<Json<serde_json::Value>> {
+    let download = episode_download(db, sandbox, &id).await?;
+    let url = format!("/api/downloads/episodes/{}", id);
+    sign_download(download, url, &id, &user_id, db, policy, signer).await
+}
+
+#[get("/episodes/<id>?<token..>")]
+pub async fn download_episode(
+    id: String,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    policy: &State<DownloadPolicy>,
+    signer: &State<UrlSigner>,
+) -> Result<DownloadResponse> {
+    verify_download(&id, token, db, policy, signer).await?;
+    episode_download(db, sandbox, &id).await?.respond().await
+}
+
+// Download URL of a season's episodes as a ZIP archive
+#[post("/seasons/<id>?<user_id>")]
+pub async fn sign_season(
+    id: String,
+    user_id: String,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    policy: &State<DownloadPolicy>,
+    signer: &State<UrlSigner>,
+) -> Result<Json<serde_json::Value>> {
+    let download = season_download(db, sandbox, &id).await?;
+    let url = format!("/api/downloads/seasons/{}", id);
+    sign_download(download, url, &id, &user_id, db, policy, signer).await
+}
+
+#[get("/seasons/<id>?<token..>")]
+pub async fn download_season(
+    id: String,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    policy: &State<DownloadPolicy>,
+    signer: &State<UrlSigner>,
+) -> Result<DownloadResponse> {
+    verify_download(&id, token, db, policy, signer).await?;
+    season_download(db, sandbox, &id).await?.respond().await
+}
+
+// Whether a user may download, falling back to the policy for users without a
+// permission of their own
+#[get("/permissions/<user_id>")]
+pub async fn get_permission(
+    user_id: String,
+    db: &State<Pool<Sqlite>>,
+    policy: &State<DownloadPolicy>,
+) -> Result<Json<serde_json::Value>> {
+    let permission = queries::get_download_permission(db, &user_id).await?;
+    Ok(Json(serde_json::json!({
+        "userId": user_id,
+        "canDownload": permission.as_ref().map_or(!policy.require_permission, |permission| permission.can_download),
+        "isDefault": permission.is_none(),
+    })))
+}
+
+#[put("/permissions/<user_id>", data = "<permission>")]
+pub async fn set_permission(
+    user_id: String,
+    permission: Json<DownloadPermissionDto>,
+    db: &State<Pool<Sqlite>>,
+) -> Result<Json<DownloadPermission>> {
+    let permission = queries::set_download_permission(db, &user_id, permission.can_download).await?;
+    Ok(Json(permission))
+}
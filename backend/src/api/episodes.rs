@@ -1,29 +1,37 @@
 use std::path::PathBuf;
-use std::io::{Read, Seek, SeekFrom};
-use std::fs::File;
-use std::ops::Range;
 
-use rocket::fs::NamedFile;
-use rocket::http::{ContentType, Header, Status};
+use rocket::http::ContentType;
 use rocket::serde::json::Json;
 use rocket::State;
-use rocket::response::Responder;
-use rocket::{Request, Response};
 use sqlx::{Pool, Sqlite};
 
-use crate::db::models::Episode;
+use crate::api::transcode::{StreamOptions, StreamResponse};
+use crate::container::index::IndexCache;
+use crate::db::models::{DeviceProfileDto, Episode, Trickplay};
+use crate::db::queries;
 use crate::error::{AppError, Result};
+use crate::media::sandbox::PathSandbox;
+use crate::streaming::conditional::Cached;
+use crate::streaming::dash::{self, Manifest, DASH_SEGMENT_BASE};
+use crate::streaming::file::RangeFile;
+use crate::streaming::hls::{self, Playlist, SegmentName};
+use crate::streaming::playback::{self, PlayMethod, PlaybackUrls};
+use crate::streaming::segment::Fragment;
+use crate::streaming::sessions::{self, Client, PlaybackSessions, Stream};
+use crate::streaming::signing::{StreamToken, UrlSigner};
+use crate::subtitles::cache::SubtitleCache;
+use crate::transcode::session::TranscodeManager;
+use crate::trickplay::generator::{self, SpriteFile, TrickplayGenerator};
 
+// An episode with its chapters and skip markers
 #[get("/<id>")]
-pub async fn get_episode(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<Episode>> {
-    let episode = sqlx::query_as::<_, Episode>("SELECT * FROM episodes WHERE id = ?")
-        .bind(&id)
-        .fetch_optional(db.inner())
-        .await
-        .map_err(AppError::Database)?
-        .ok_or_else(|| AppError::NotFound(format!("Episode with id {} not found", id)))?;
-    
-    Ok(Json(episode))
+pub async fn get_episode(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<serde_json::Value>> {
+    let episode = find_episode(db, &id).await?;
+    let markers = queries::get_markers(db, &episode.media_id, Some(&episode.id)).await?;
+
+    let mut details = serde_json::json!(episode);
+    details["markers"] = serde_json::json!(markers);
+    Ok(Json(details))
 }
 
 #[get("/media/<media_id>")]
@@ -48,211 +56,280 @@ pub async fn get_episodes_by_season(season_id: String, db: &State<Pool<Sqlite>>)
     Ok(Json(episodes))
 }
 
-// Custom struct for handling range requests with support for seeking
-pub struct RangeFile {
-    file_path: PathBuf,
-    content_type: ContentType,
-    file_size: u64,
+pub async fn find_episode(db: &Pool<Sqlite>, id: &str) -> Result<Episode> {
+    sqlx::query_as::<_, Episode>("SELECT * FROM episodes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+        .map_err(AppError::Database)?
+        .ok_or_else(|| AppError::NotFound(format!("Episode with id {} not found", id)))
 }
 
-impl RangeFile {
-    pub fn new(file_path: PathBuf, content_type: ContentType) -> Result<Self> {
-        let metadata = std::fs::metadata(&file_path)
-            .map_err(|e| AppError::Io(e))?;
-        
-        Ok(Self {
-            file_path,
-            content_type,
-            file_size: metadata.len(),
-        })
-    }
-    
-    // Parse range header value like "bytes=0-1023"
-    fn parse_range_header(range_header: &str, file_size: u64) -> Option<Range<u64>> {
-        let bytes_prefix = "bytes=";
-        if !range_header.starts_with(bytes_prefix) {
-            return None;
-        }
-        
-        let range_str = &range_header[bytes_prefix.len()..];
-        let split: Vec<&str> = range_str.split('-').collect();
-        if split.len() != 2 {
-            return None;
-        }
-        
-        let start = match split[0].parse::<u64>() {
-            Ok(s) => s,
-            Err(_) => return None,
-        };
-        
-        // Handle ranges like "bytes=1000-" (from 1000 to end)
-        let end = if split[1].is_empty() {
-            file_size - 1
-        } else {
-            match split[1].parse::<u64>() {
-                Ok(e) => e,
-                Err(_) => return None,
-            }
-        };
-        
-        // Validate range
-        if start > end || start >= file_size {
-            return None;
-        }
-        
-        // Ensure end doesn't exceed file size
-        let end = std::cmp::min(end, file_size - 1);
-        
-        Some(start..end + 1) // +1 because Range is exclusive on the end
-    }
+// Look up the file of an episode, which has to be in a library
+pub async fn episode_path(db: &Pool<Sqlite>, sandbox: &PathSandbox, id: &str) -> Result<PathBuf> {
+    let episode = find_episode(db, id).await?;
+    let path = PathBuf::from(&episode.path);
+    sandbox.check_file(db, &path).await?;
+    Ok(path)
 }
 
-impl<'r> Responder<'r, 'static> for RangeFile {
-    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
-        // Check if we have a range header
-        let range_header = req.headers().get_one("Range");
+// Query string for the URIs in playlists and manifests, carrying over the signature
+fn stream_query(token: &StreamToken) -> String {
+    format!("?{}", token.query())
+}
+
+// The episode file, see api::media::stream_media for `?profile=` and `?format=` and
+// sign_stream for signed URLs
+#[allow(clippy::too_many_arguments)]
+#[get("/stream/<id>?<profile>&<format>&<start>&<token..>")]
+pub async fn stream_episode(
+    id: String,
+    profile: Option<String>,
+    format: Option<String>,
+    start: Option<f64>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    transcoder: &State<TranscodeManager>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<StreamResponse> {
+    let token = signer.verify(&id, token)?;
+    let path = episode_path(db, sandbox, &id).await?;
+    let options = StreamOptions {
+        profile: profile.as_deref(),
+        format: format.as_deref(),
+        start,
+        token: &token,
+    };
+    sessions.track(db, &token, &client, Stream::episode(&id, &path, options.method())).await?;
+    StreamResponse::new(path, options, transcoder, indexes, signer, sessions).await
+}
+
+// HLS master playlist of an episode, see api::media::hls_master
+#[allow(clippy::too_many_arguments)]
+#[get("/stream/<id>/hls/master.m3u8?<token..>")]
+pub async fn hls_master(
+    id: String,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Playlist>> {
+    let token = signer.verify(&id, token)?;
+    let path = episode_path(db, sandbox, &id).await?;
+    sessions.track(db, &token, &client, Stream::episode(&id, &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    hls::master_response(&indexed, &stream_query(&token))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/stream/<id>/hls/<track>/index.m3u8?<token..>")]
+pub async fn hls_playlist(
+    id: String,
+    track: u32,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Playlist>> {
+    let token = signer.verify(&id, token)?;
+    let path = episode_path(db, sandbox, &id).await?;
+    sessions.track(db, &token, &client, Stream::episode(&id, &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    hls::media_response(&indexed, track, &stream_query(&token))
+}
 
-        match range_header {
-            Some(range_value) => {
-                // Parse the range
-                if let Some(byte_range) = Self::parse_range_header(range_value, self.file_size) {
-                    // Open the file
-                    let mut file = match File::open(&self.file_path) {
-                        Ok(f) => f,
-                        Err(_) => return Err(Status::InternalServerError),
-                    };
+#[allow(clippy::too_many_arguments)]
+#[get("/stream/<id>/hls/<track>/init.mp4?<token..>")]
+pub async fn hls_init(
+    id: String,
+    track: u32,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Fragment>> {
+    let token = signer.verify(&id, token)?;
+    let path = episode_path(db, sandbox, &id).await?;
+    sessions.track(db, &token, &client, Stream::episode(&id, &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    Fragment::init(&indexed, track)
+}
 
-                    // The length of content we'll return
-                    let range_length = byte_range.end - byte_range.start;
+#[allow(clippy::too_many_arguments)]
+#[get("/stream/<id>/hls/<track>/subtitles.vtt?<token..>")]
+pub async fn hls_subtitles(
+    id: String,
+    track: u32,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    subtitles: &State<SubtitleCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<(ContentType, String)>> {
+    let token = signer.verify(&id, token)?;
+    let path = episode_path(db, sandbox, &id).await?;
+    sessions.track(db, &token, &client, Stream::episode(&id, &path, PlayMethod::Remux)).await?;
+    subtitles.webvtt(indexes, &path, track).await
+}
 
-                    // Seek to the start position
-                    if let Err(_) = file.seek(SeekFrom::Start(byte_range.start)) {
-                        return Err(Status::InternalServerError);
-                    }
+#[allow(clippy::too_many_arguments)]
+#[get("/stream/<id>/hls/<track>/<segment>?<token..>", rank = 2)]
+pub async fn hls_segment(
+    id: String,
+    track: u32,
+    segment: SegmentName,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Fragment>> {
+    let token = signer.verify(&id, token)?;
+    let path = episode_path(db, sandbox, &id).await?;
+    sessions.track(db, &token, &client, Stream::episode(&id, &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    Fragment::media(&indexed, track, segment.0)
+}
 
-                    // Read the requested bytes
-                    let mut buffer = vec![0; range_length as usize];
-                    match file.read_exact(&mut buffer) {
-                        Ok(_) => (),
-                        // If we can't read the exact amount (e.g., EOF), adjust the buffer
-                        Err(_) => {
-                            // Alternative: read what we can
-                            let mut partial_buffer = Vec::new();
-                            if let Err(_) = file.read_to_end(&mut partial_buffer) {
-                                return Err(Status::InternalServerError);
-                            }
-                            buffer = partial_buffer;
-                        }
-                    }
+// MPEG-DASH manifest of an episode, see api::media::dash_manifest
+#[allow(clippy::too_many_arguments)]
+#[get("/stream/<id>/dash/manifest.mpd?<token..>")]
+pub async fn dash_manifest(
+    id: String,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Manifest>> {
+    let token = signer.verify(&id, token)?;
+    let path = episode_path(db, sandbox, &id).await?;
+    sessions.track(db, &token, &client, Stream::episode(&id, &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    dash::manifest_response(&indexed, DASH_SEGMENT_BASE, &stream_query(&token))
+}
 
-                    // Create the response
-                    let actual_length = buffer.len() as u64;
-                    let response = Response::build()
-                        .status(Status::PartialContent)
-                        .header(self.content_type.clone())
-                        .header(Header::new("Accept-Ranges", "bytes"))
-                        .header(Header::new(
-                            "Content-Range",
-                            format!(
-                                "bytes {}-{}/{}",
-                                byte_range.start,
-                                byte_range.start + actual_length - 1,
-                                self.file_size
-                            ),
-                        ))
-                        .header(Header::new("Content-Length", actual_length.to_string()))
-                        .sized_body(actual_length as usize, std::io::Cursor::new(buffer))
-                        .finalize();
+// Stream URLs of an episode, signed for a user
+fn signed_urls(id: &str, user_id: &str, signer: &UrlSigner) -> (PlaybackUrls, StreamToken) {
+    let token = signer.sign(id, user_id);
+    let query = stream_query(&token);
+    let urls = PlaybackUrls {
+        direct: format!("/api/episodes/stream/{}{}", id, query),
+        hls: format!("/api/episodes/stream/{}/hls/master.m3u8{}", id, query),
+        dash: format!("/api/episodes/stream/{}/dash/manifest.mpd{}", id, query),
+    };
+    (urls, token)
+}
 
-                    Ok(response)
-                } else {
-                    // Invalid range, return 416 Range Not Satisfiable
-                    let response = Response::build()
-                        .status(Status::RangeNotSatisfiable)
-                        .header(Header::new("Content-Range", format!("bytes */{}", self.file_size)))
-                        .finalize();
+// Signed stream URLs of an episode, see api::media::sign_stream
+#[post("/stream/<id>/sign?<user_id>")]
+pub async fn sign_stream(
+    id: String,
+    user_id: String,
+    db: &State<Pool<Sqlite>>,
+    signer: &State<UrlSigner>,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Json<serde_json::Value>> {
+    find_episode(db, &id).await?;
+    sessions.check_item_stopped(&user_id, None, Some(&id))?;
+    let (urls, token) = signed_urls(&id, &user_id, signer);
+    Ok(Json(urls.signed_json(&token)))
+}
 
-                    Ok(response)
-                }
-            }
-            None => {
-                // No range header, return the full file with proper headers
-                let file = File::open(&self.file_path);
-                match file {
-                    Ok(mut file) => {
-                        let mut buffer = Vec::with_capacity(self.file_size as usize);
-                        if let Err(_) = file.read_to_end(&mut buffer) {
-                            return Err(Status::InternalServerError);
-                        }
-                        let response = Response::build()
-                            .status(Status::Ok)
-                            .header(self.content_type)
-                            .header(Header::new("Accept-Ranges", "bytes"))
-                            .header(Header::new("Content-Length", self.file_size.to_string()))
-                            .sized_body(buffer.len(), std::io::Cursor::new(buffer))
-                            .finalize();
+// Playback plan of an episode, see api::media::get_playback_plan
+#[post("/stream/<id>/playback?<user_id>", data = "<device>")]
+pub async fn get_playback_plan(
+    id: String,
+    user_id: String,
+    device: Json<DeviceProfileDto>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    signer: &State<UrlSigner>,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Json<serde_json::Value>> {
+    sessions.check_limit(&user_id, None, Some(&id))?;
+    let episode = find_episode(db, &id).await?;
+    let path = episode_path(db, sandbox, &id).await?;
+    let (urls, token) = signed_urls(&id, &user_id, signer);
 
-                        Ok(response)
-                    }
-                    Err(_) => Err(Status::InternalServerError),
-                }
-            }
-        }
-    }
+    let mut plan = playback::plan(db, &episode.media_id, Some(&id), &path, &device, &urls).await?;
+    plan["sessionId"] = serde_json::json!(sessions::session_id(&token));
+    Ok(Json(plan))
 }
 
-#[get("/stream/<id>")]
-pub async fn stream_episode(id: String, db: &State<Pool<Sqlite>>) -> Result<RangeFile> {
-    // Get the episode from the database
-    let episode = sqlx::query_as::<_, Episode>("SELECT * FROM episodes WHERE id = ?")
-        .bind(&id)
-        .fetch_optional(db.inner())
-        .await
-        .map_err(AppError::Database)?
-        .ok_or_else(|| AppError::NotFound(format!("Episode with id {} not found", id)))?;
+// Seek preview thumbnails of an episode, see api::media::get_trickplay and the routes
+// after it
+#[get("/stream/<id>/trickplay")]
+pub async fn get_trickplay(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<Trickplay>> {
+    let episode = find_episode(db, &id).await?;
+    queries::get_trickplay(db, &episode.media_id, Some(&id))
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("No seek previews for {}", id)))
+}
 
-    let path = PathBuf::from(&episode.path);
+#[post("/stream/<id>/trickplay?<force>")]
+pub async fn generate_trickplay(
+    id: String,
+    force: Option<bool>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    trickplay: &State<TrickplayGenerator>,
+) -> Result<Json<Trickplay>> {
+    let episode = find_episode(db, &id).await?;
+    let path = episode_path(db, sandbox, &id).await?;
+    let queued = trickplay
+        .enqueue(db, &episode.media_id, Some(&id), path, force.unwrap_or(false))
+        .await?;
+    Ok(Json(queued))
+}
 
-    // Check file existence and permissions before opening
-    match tokio::fs::metadata(&path).await {
-        Ok(metadata) => {
-            if !metadata.is_file() {
-                return Err(AppError::Io(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    format!("Path is not a regular file: {}", path.display()),
-                )));
-            }
-        }
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                return Err(AppError::Io(std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    format!("File not found: {}", path.display()),
-                )));
-            } else if e.kind() == std::io::ErrorKind::PermissionDenied {
-                return Err(AppError::Io(std::io::Error::new(
-                    std::io::ErrorKind::PermissionDenied,
-                    format!("Access is denied to file: {}", path.display()),
-                )));
-            } else {
-                return Err(AppError::Io(e));
-            }
-        }
-    }
+#[get("/stream/<id>/trickplay/thumbnails.vtt")]
+pub async fn trickplay_vtt(
+    id: String,
+    db: &State<Pool<Sqlite>>,
+    trickplay: &State<TrickplayGenerator>,
+) -> Result<Cached<(ContentType, String)>> {
+    let episode = find_episode(db, &id).await?;
+    let ready = trickplay.ready(db, &episode.media_id, Some(&id)).await?;
+    Ok(generator::thumbnails_vtt(&ready, ""))
+}
 
-    // Determine content type based on file extension
-    let content_type = match path.extension().and_then(|ext| ext.to_str()) {
-        None => ContentType::Binary,
-        Some("mp4") => ContentType::MP4,
-        Some("mkv") => ContentType::new("video", "x-matroska"),
-        Some("avi") => ContentType::new("video", "x-msvideo"),
-        Some("mov") => ContentType::new("video", "quicktime"),
-        Some("mp3") => ContentType::MP3,
-        Some("flac") => ContentType::new("audio", "flac"),
-        Some("m4a") => ContentType::new("audio", "mp4"),
-        _ => ContentType::Binary,
-    };
+#[get("/stream/<id>/trickplay/thumbnails.bif")]
+pub async fn trickplay_bif(id: String, db: &State<Pool<Sqlite>>, trickplay: &State<TrickplayGenerator>) -> Result<RangeFile> {
+    let episode = find_episode(db, &id).await?;
+    let ready = trickplay.ready(db, &episode.media_id, Some(&id)).await?;
+    RangeFile::open(trickplay.bif_path(&ready)?).await
+}
 
-    // Return the file with support for range requests
-    RangeFile::new(path, content_type)
-}
\ No newline at end of file
+#[get("/stream/<id>/trickplay/<sprite>", rank = 2)]
+pub async fn trickplay_sprite(
+    id: String,
+    sprite: SpriteFile,
+    db: &State<Pool<Sqlite>>,
+    trickplay: &State<TrickplayGenerator>,
+) -> Result<RangeFile> {
+    let episode = find_episode(db, &id).await?;
+    let ready = trickplay.ready(db, &episode.media_id, Some(&id)).await?;
+    RangeFile::open(trickplay.sprite_path(&ready, sprite)?).await
+}
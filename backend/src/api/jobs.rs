@@ -0,0 +1,33 @@
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::{Pool, Sqlite};
+
+use crate::db::models::Job;
+use crate::db::queries;
+use crate::error::Result;
+use crate::jobs::queue::JobQueue;
+
+// Most recent jobs, optionally of one library or in one status
+#[get("/?<library_id>&<status>&<limit>")]
+pub async fn get_jobs(
+    library_id: Option<String>,
+    status: Option<String>,
+    limit: Option<i64>,
+    db: &State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Job>>> {
+    let limit = limit.unwrap_or(50).clamp(1, 500);
+    let jobs = queries::get_jobs(db, library_id.as_deref(), status.as_deref(), limit).await?;
+    Ok(Json(jobs))
+}
+
+// A job with its progress so far
+#[get("/<id>")]
+pub async fn get_job(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<Job>> {
+    Ok(Json(queries::get_job(db, &id).await?))
+}
+
+// Cancel a queued or running job. A running scan keeps what it found so far.
+#[delete("/<id>")]
+pub async fn cancel_job(id: String, db: &State<Pool<Sqlite>>, jobs: &State<JobQueue>) -> Result<Json<Job>> {
+    Ok(Json(jobs.cancel(db, &id).await?))
+}
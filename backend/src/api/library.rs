@@ -10,7 +10,9 @@ use crate::db::models::{Library, CreateLibraryDto};
 use crate::db::queries;
 use crate::db::generate_id;
 use crate::error::{AppError, Result};
-use crate::media::scanner;
+use crate::jobs::queue::JobQueue;
+use crate::jobs::watcher::LibraryWatcher;
+use crate::media::sandbox::PathSandbox;
 
 #[get("/")]
 pub async fn get_libraries(db: &State<Pool<Sqlite>>) -> Result<Json<Vec<Library>>> {
@@ -20,8 +22,10 @@ pub async fn get_libraries(db: &State<Pool<Sqlite>>) -> Result<Json<Vec<Library>
 
 #[post("/", data = "<library>")]
 pub async fn create_library(
-    library: Json<CreateLibraryDto>, 
-    db: &State<Pool<Sqlite>>
+    mut library: Json<CreateLibraryDto>, 
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    watcher: &State<LibraryWatcher>,
 ) -> Result<Json<Library>> {
     // Validate the path
     let path = Path::new(&library.path);
@@ -32,6 +36,9 @@ pub async fn create_library(
     if !path.is_dir() {
         return Err(AppError::InvalidInput(format!("Path is not a directory: {}", library.path)));
     }
+
+    // Libraries are stored by their resolved path, which later checks compare with
+    library.path = sandbox.check_new_library(db, &library.path).await?.to_string_lossy().to_string();
     
     // Validate the media type
     match library.media_type.as_str() {
@@ -43,42 +50,50 @@ pub async fn create_library(
     
     // Create the library
     let created_library = queries::create_library(db, library.0).await?;
+    watcher.reload();
     
     Ok(Json(created_library))
 }
 
+// Scan a library in the background. The returned job is followed with
+// GET /api/jobs/<id>; while a scan of the library is queued or running, that one is
+// returned instead of starting another.
 #[post("/<id>/scan")]
-pub async fn scan_library(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<serde_json::Value>> {
-    // Get the library
+pub async fn scan_library(
+    id: String,
+    db: &State<Pool<Sqlite>>,
+    jobs: &State<JobQueue>,
+) -> Result<Json<serde_json::Value>> {
     let library = queries::get_library_by_id(db, &id).await?;
-    
-    // Begin scanning
-    tracing::info!("Scanning library: {} at path {}", library.name, library.path);
-    
-    // Scan based on media type
-    let result = match library.media_type.as_str() {
-        "movie" => scanner::scan_movies(db, &library).await,
-        "tvshow" => scanner::scan_tv_shows(db, &library).await,
-        "music" => scanner::scan_music(db, &library).await,
-        _ => return Err(AppError::InvalidInput(
-            format!("Invalid media type: {}. Must be 'movie', 'tvshow', or 'music'", library.media_type)
-        )),
+    let (job, queued) = jobs.enqueue_scan(db, &library.id).await?;
+
+    let message = if queued {
+        format!("Library scan queued for {}", library.name)
+    } else {
+        format!("Library {} is already being scanned", library.name)
     };
-    
-    match result {
-        Ok(scan_results) => Ok(Json(serde_json::json!({
-            "success": true,
-            "message": format!("Library scan completed for {}", library.name),
-            "results": scan_results
-        }))),
-        Err(e) => Err(e),
-    }
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "message": message,
+        "queued": queued,
+        "job": job
+    })))
 }
 
 #[delete("/<id>")]
-pub async fn delete_library(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<serde_json::Value>> {
+pub async fn delete_library(
+    id: String,
+    db: &State<Pool<Sqlite>>,
+    jobs: &State<JobQueue>,
+    watcher: &State<LibraryWatcher>,
+) -> Result<Json<serde_json::Value>> {
     // Check if the library exists
     let _ = queries::get_library_by_id(db, &id).await?;
+
+    // A scan in progress would go on adding to the deleted library
+    if let Some(scan) = jobs.active_scan(db, &id).await? {
+        jobs.cancel(db, &scan.id).await?;
+    }
     
     // Delete the library
     sqlx::query("DELETE FROM libraries WHERE id = ?")
@@ -86,6 +101,7 @@ pub async fn delete_library(id: String, db: &State<Pool<Sqlite>>) -> Result<Json
         .execute(db.inner())
         .await
         .map_err(AppError::Database)?;
+    watcher.reload();
     
     Ok(Json(serde_json::json!({
         "success": true,
@@ -0,0 +1,177 @@
+use rocket::serde::json::{json, Json, Value};
+use rocket::State;
+use sqlx::{Pool, Sqlite};
+
+use crate::api::media::check_episode;
+use crate::db::generate_id;
+use crate::db::models::{Marker, SetMarkerDto};
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::media::intro::{DetectionJob, IntroDetector};
+use crate::media::markers::{self, SKIP_MARKER_TYPES, SOURCE_DETECTED, SOURCE_MANUAL};
+use crate::media::sandbox::PathSandbox;
+
+// Chapters and skip markers of a movie, or of an episode of a show with `?episode=`
+#[get("/media/<media_id>?<episode>")]
+pub async fn get_markers(
+    media_id: String,
+    episode: Option<String>,
+    db: &State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Marker>>> {
+    let markers = queries::get_markers(db, &media_id, episode.as_deref()).await?;
+    Ok(Json(markers))
+}
+
+// Set the intro, credits or recap of a movie or episode by hand. It replaces any marker
+// of that type, including one read from the file's chapters.
+#[put("/media/<media_id>/<marker_type>?<episode>", data = "<marker>")]
+pub async fn set_marker(
+    media_id: String,
+    marker_type: String,
+    episode: Option<String>,
+    marker: Json<SetMarkerDto>,
+    db: &State<Pool<Sqlite>>,
+) -> Result<Json<Marker>> {
+    if !SKIP_MARKER_TYPES.contains(&marker_type.as_str()) {
+        return Err(AppError::InvalidInput(format!(
+            "marker type must be one of {}",
+            SKIP_MARKER_TYPES.join(", ")
+        )));
+    }
+    if !marker.start.is_finite() || !marker.end.is_finite() || marker.start < 0.0 || marker.end <= marker.start {
+        return Err(AppError::InvalidInput("start must be non-negative and before end".to_string()));
+    }
+
+    check_episode(db, &media_id, episode.as_deref()).await?;
+
+    let id = generate_id();
+    let mut tx = db.begin().await.map_err(AppError::Database)?;
+
+    sqlx::query("DELETE FROM markers WHERE media_id = ? AND episode_id IS ? AND marker_type = ?")
+        .bind(&media_id)
+        .bind(&episode)
+        .bind(&marker_type)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+
+    sqlx::query(
+        "INSERT INTO markers (id, media_id, episode_id, marker_type, start_time, end_time, title, source)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(&media_id)
+    .bind(&episode)
+    .bind(&marker_type)
+    .bind(marker.start)
+    .bind(marker.end)
+    .bind(&marker.title)
+    .bind(SOURCE_MANUAL)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::Database)?;
+
+    tx.commit().await.map_err(AppError::Database)?;
+
+    // The item's details list its markers
+    queries::touch_media(db, &media_id).await?;
+
+    Ok(Json(find_marker(db, &id).await?))
+}
+
+async fn find_marker(db: &Pool<Sqlite>, id: &str) -> Result<Marker> {
+    sqlx::query_as::<_, Marker>("SELECT * FROM markers WHERE id = ?")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+        .map_err(AppError::Database)?
+        .ok_or_else(|| AppError::NotFound(format!("Marker with id {} not found", id)))
+}
+
+// Remove a skip marker, which is also how a detected one is rejected. Chapters belong
+// to the file and come back when it is probed.
+#[delete("/<id>")]
+pub async fn delete_marker(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<Value>> {
+    let marker = find_marker(db, &id).await?;
+
+    if marker.marker_type == markers::CHAPTER {
+        return Err(AppError::InvalidInput("Chapters can't be deleted".to_string()));
+    }
+
+    sqlx::query("DELETE FROM markers WHERE id = ?")
+        .bind(&id)
+        .execute(db.inner())
+        .await
+        .map_err(AppError::Database)?;
+    queries::touch_media(db, &marker.media_id).await?;
+
+    Ok(Json(json!({
+        "success": true,
+        "message": "Marker deleted successfully"
+    })))
+}
+
+fn detection_json(job: &DetectionJob) -> Value {
+    json!({
+        "seasonId": job.season_id,
+        "status": job.status.as_str(),
+        "episodes": job.episodes,
+        "analyzed": job.analyzed,
+        "detected": job.detected,
+        "error": job.error,
+        "startedAt": job.started_at,
+        "finishedAt": job.finished_at,
+    })
+}
+
+// Find the intros of a season's episodes in the background, by comparing their audio.
+// Detected intros replace earlier detected ones, but not intros set by hand or read
+// from chapters.
+#[post("/seasons/<season_id>/detect")]
+pub async fn detect_intros(
+    season_id: String,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    detector: &State<IntroDetector>,
+) -> Result<Json<Value>> {
+    let job = detector.start(db, sandbox, &season_id).await?;
+    Ok(Json(detection_json(&job)))
+}
+
+// Progress of the last intro detection of a season
+#[get("/seasons/<season_id>/detect")]
+pub async fn get_detection(season_id: String, detector: &State<IntroDetector>) -> Result<Json<Value>> {
+    Ok(Json(detection_json(&detector.job(&season_id)?)))
+}
+
+// Detected markers to review, least confident first. They are accepted with
+// POST /<id>/accept and rejected by deleting them.
+#[get("/detected?<season>&<max_confidence>")]
+pub async fn get_detected_markers(
+    season: Option<String>,
+    max_confidence: Option<f64>,
+    db: &State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Marker>>> {
+    let markers = queries::get_detected_markers(db, season.as_deref(), max_confidence).await?;
+    Ok(Json(markers))
+}
+
+// Accept a detected marker as if it had been set by hand, so that later detections
+// leave it alone
+#[post("/<id>/accept")]
+pub async fn accept_marker(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<Marker>> {
+    let marker = find_marker(db, &id).await?;
+    if marker.source != SOURCE_DETECTED {
+        return Err(AppError::InvalidInput("Only detected markers need to be accepted".to_string()));
+    }
+
+    sqlx::query("UPDATE markers SET source = ? WHERE id = ?")
+        .bind(SOURCE_MANUAL)
+        .bind(&id)
+        .execute(db.inner())
+        .await
+        .map_err(AppError::Database)?;
+    queries::touch_media(db, &marker.media_id).await?;
+
+    Ok(Json(find_marker(db, &id).await?))
+}
@@ -1,240 +1,443 @@
-use rocket::http::{ContentType, Header, Status};
+use rocket::http::ContentType;
 use rocket::serde::json::Json;
-use rocket::{State, Response};
-use rocket::response::Responder;
-use rocket::fs::NamedFile;
-use rocket::request::Request;
+use rocket::State;
 use sqlx::{Pool, Sqlite, Row};
 use std::path::PathBuf;
-use std::io::{Read, Seek, SeekFrom};
-use std::fs::File;
-use std::ops::Range;
+use std::time::SystemTime;
 
-use crate::db::models::Media;
+use crate::api::transcode::{StreamOptions, StreamResponse};
+use crate::container::index::IndexCache;
+use crate::db::models::{DeviceProfileDto, Media, Trickplay};
 use crate::db::queries;
 use crate::error::{AppError, Result};
+use crate::media::sandbox::PathSandbox;
+use crate::streaming::conditional::{self, Cached};
+use crate::streaming::dash::{self, Manifest, DASH_SEGMENT_BASE};
+use crate::streaming::file::RangeFile;
+use crate::streaming::hls::{self, Playlist, SegmentName};
+use crate::streaming::playback::{self, PlayMethod, PlaybackUrls};
+use crate::streaming::segment::Fragment;
+use crate::streaming::sessions::{self, Client, PlaybackSessions, Stream};
+use crate::streaming::signing::{StreamToken, UrlSigner};
+use crate::subtitles::cache::SubtitleCache;
+use crate::transcode::session::TranscodeManager;
+use crate::trickplay::generator::{self, SpriteFile, TrickplayGenerator};
+
+// When a media row last changed, falling back to when it was added for rows that
+// predate the updated_at column
+fn media_version(media: &Media) -> chrono::DateTime<chrono::Utc> {
+    media.updated_at.unwrap_or(media.added_at)
+}
+
+// ETag and Last-Modified for a list of media rows. Any insert, delete or update
+// changes either the count or the newest version.
+fn list_validators(media: &[Media]) -> (String, Option<SystemTime>) {
+    let newest = media.iter().map(media_version).max();
+    let etag = conditional::weak_etag(&[
+        media.len() as u64,
+        newest.map_or(0, |version| version.timestamp_micros() as u64),
+    ]);
+
+    (etag, newest.map(SystemTime::from))
+}
+
+// ETag and Last-Modified for a single media row
+fn item_validators(media: &Media) -> (String, Option<SystemTime>) {
+    let version = media_version(media);
+    let etag = conditional::weak_etag(&[version.timestamp_micros() as u64]);
+
+    (etag, Some(SystemTime::from(version)))
+}
 
 #[get("/")]
-pub async fn get_all_media(db: &State<Pool<Sqlite>>) -> Result<Json<Vec<Media>>> {
+pub async fn get_all_media(db: &State<Pool<Sqlite>>) -> Result<Cached<Json<Vec<Media>>>> {
     let media = queries::get_all_media(db).await?;
-    Ok(Json(media))
+    let (etag, last_modified) = list_validators(&media);
+    Ok(Cached::new(Json(media), etag, last_modified))
 }
 
 #[get("/info/<id>")]
-pub async fn get_media(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<Media>> {
+pub async fn get_media(id: String, db: &State<Pool<Sqlite>>) -> Result<Cached<Json<Media>>> {
     let media = queries::get_media_by_id(db, &id).await?;
-    Ok(Json(media))
+    let (etag, last_modified) = item_validators(&media);
+    Ok(Cached::new(Json(media), etag, last_modified))
 }
 
 #[get("/type/<media_type>")]
-pub async fn get_media_by_type(media_type: String, db: &State<Pool<Sqlite>>) -> Result<Json<Vec<Media>>> {
+pub async fn get_media_by_type(media_type: String, db: &State<Pool<Sqlite>>) -> Result<Cached<Json<Vec<Media>>>> {
     let media = queries::get_media_by_type(db, &media_type).await?;
-    Ok(Json(media))
-}
-
-// Custom struct for handling range requests with support for seeking
-pub struct RangeFile {
-    file_path: PathBuf,
-    content_type: ContentType,
-    file_size: u64,
+    let (etag, last_modified) = list_validators(&media);
+    Ok(Cached::new(Json(media), etag, last_modified))
 }
 
-impl RangeFile {
-    pub fn new(file_path: PathBuf, content_type: ContentType) -> Result<Self> {
-        let metadata = std::fs::metadata(&file_path)
-            .map_err(|e| AppError::Io(e))?;
-        
-        Ok(Self {
-            file_path,
-            content_type,
-            file_size: metadata.len(),
-        })
-    }
-    
-    // Parse range header value like "bytes=0-1023"
-    fn parse_range_header(range_header: &str, file_size: u64) -> Option<Range<u64>> {
-        let bytes_prefix = "bytes=";
-        if !range_header.starts_with(bytes_prefix) {
-            return None;
-        }
-        
-        let range_str = &range_header[bytes_prefix.len()..];
-        let split: Vec<&str> = range_str.split('-').collect();
-        if split.len() != 2 {
-            return None;
-        }
-        
-        let start = match split[0].parse::<u64>() {
-            Ok(s) => s,
-            Err(_) => return None,
-        };
-        
-        // Handle ranges like "bytes=1000-" (from 1000 to end)
-        let end = if split[1].is_empty() {
-            file_size - 1
-        } else {
-            match split[1].parse::<u64>() {
-                Ok(e) => e,
-                Err(_) => return None,
-            }
-        };
-        
-        // Validate range
-        if start > end || start >= file_size {
-            return None;
+// Check that an episode given for a media item belongs to it. TV shows need one,
+// movies can't have one.
+pub async fn check_episode(db: &Pool<Sqlite>, media_id: &str, episode: Option<&str>) -> Result<()> {
+    let media = queries::get_media_by_id(db, media_id).await?;
+    match (media.media_type.as_str(), episode) {
+        ("tvshow", None) => Err(AppError::InvalidInput("Episode ID is required for TV shows".to_string())),
+        ("tvshow", Some(episode_id)) => {
+            sqlx::query("SELECT 1 FROM episodes WHERE id = ? AND media_id = ?")
+                .bind(episode_id)
+                .bind(media_id)
+                .fetch_optional(db)
+                .await
+                .map_err(AppError::Database)?
+                .ok_or_else(|| AppError::NotFound(format!("Episode not found: {}", episode_id)))?;
+            Ok(())
         }
-        
-        // Ensure end doesn't exceed file size
-        let end = std::cmp::min(end, file_size - 1);
-        
-        Some(start..end + 1) // +1 because Range is exclusive on the end
+        (_, Some(_)) => Err(AppError::InvalidInput("Only TV shows have episodes".to_string())),
+        _ => Ok(()),
     }
 }
 
-impl<'r> Responder<'r, 'static> for RangeFile {
-    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
-        // Check if we have a range header
-        let range_header = req.headers().get_one("Range");
-        
-        match range_header {
-            Some(range_value) => {
-                // Parse the range
-                if let Some(byte_range) = Self::parse_range_header(range_value, self.file_size) {
-                    // Open the file
-                    let mut file = match File::open(&self.file_path) {
-                        Ok(f) => f,
-                        Err(_) => return Err(Status::InternalServerError),
-                    };
-                    
-                    // The length of content we'll return
-                    let range_length = byte_range.end - byte_range.start;
-                    
-                    // Seek to the start position
-                    if let Err(_) = file.seek(SeekFrom::Start(byte_range.start)) {
-                        return Err(Status::InternalServerError);
-                    }
-                    
-                    // Read the requested bytes
-                    let mut buffer = vec![0; range_length as usize];
-                    match file.read_exact(&mut buffer) {
-                        Ok(_) => (),
-                        // If we can't read the exact amount (e.g., EOF), adjust the buffer
-                        Err(_) => {
-                            // Alternative: read what we can
-                            let mut partial_buffer = Vec::new();
-                            if let Err(_) = file.read_to_end(&mut partial_buffer) {
-                                return Err(Status::InternalServerError);
-                            }
-                            buffer = partial_buffer;
-                        }
-                    }
-                    
-                    // Create the response
-                    let actual_length = buffer.len() as u64;
-                    let mut response = Response::build()
-                        .status(Status::PartialContent)
-                        .header(self.content_type.clone())
-                        .header(Header::new("Accept-Ranges", "bytes"))
-                        .header(Header::new(
-                            "Content-Range", 
-                            format!("bytes {}-{}/{}", 
-                                byte_range.start, 
-                                byte_range.start + actual_length - 1, 
-                                self.file_size)
-                        ))
-                        .header(Header::new("Content-Length", actual_length.to_string()))
-                        .sized_body(actual_length as usize, std::io::Cursor::new(buffer))
-                        .finalize();
-                    
-                    Ok(response)
-                } else {
-                    // Invalid range, return 416 Range Not Satisfiable
-                    let mut response = Response::build()
-                        .status(Status::RangeNotSatisfiable)
-                        .header(Header::new("Content-Range", format!("bytes */{}", self.file_size)))
-                        .finalize();
-                    
-                    Ok(response)
-                }
-            },
-            None => {
-                // No range header, return the full file with proper headers
-                // NOTE: Cannot use async .await here, so fallback to sync file read
-                let mut file = match File::open(&self.file_path) {
-                    Ok(f) => f,
-                    Err(_) => return Err(Status::InternalServerError),
-                };
-                let mut buffer = Vec::with_capacity(self.file_size as usize);
-                if let Err(_) = file.read_to_end(&mut buffer) {
-                    return Err(Status::InternalServerError);
-                }
-
-                let mut response = Response::build()
-                    .status(Status::Ok)
-                    .header(self.content_type.clone())
-                    .header(Header::new("Accept-Ranges", "bytes"))
-                    .header(Header::new("Content-Length", self.file_size.to_string()))
-                    .sized_body(buffer.len(), std::io::Cursor::new(buffer))
-                    .finalize();
-
-                Ok(response)
-            }
-        }
-    }
+// Resolve the file to play for a media item, which has to be in a library. TV shows
+// are directories, so the episode to play has to be given. Stream URLs are signed for
+// the episode when there is one, so it has to belong to the item.
+pub async fn resolve_media_path(
+    db: &Pool<Sqlite>,
+    sandbox: &PathSandbox,
+    id: &str,
+    episode: Option<&str>,
+) -> Result<PathBuf> {
+    check_episode(db, id, episode).await?;
+    let path = media_path(db, id, episode).await?;
+    sandbox.check_file(db, &path).await?;
+    Ok(path)
 }
 
-#[get("/info/<id>/stream?<episode>")]
-pub async fn stream_media(id: String, episode: Option<String>, db: &State<Pool<Sqlite>>) -> Result<RangeFile> {
+async fn media_path(db: &Pool<Sqlite>, id: &str, episode: Option<&str>) -> Result<PathBuf> {
     // Get the media file from the database
-    let media = queries::get_media_by_id(db, &id).await?;
+    let media = queries::get_media_by_id(db, id).await?;
     
     // Determine the path based on media type and if episode is provided
-    let path = if media.media_type == "tvshow" {
+    if media.media_type == "tvshow" {
         if let Some(episode_id) = episode {
             // Get the episode path
-            let row = sqlx::query("SELECT path FROM episodes WHERE id = ?")
-                .bind(&episode_id)
-                .fetch_optional(db.inner())
+            let row = sqlx::query("SELECT path FROM episodes WHERE id = ? AND media_id = ?")
+                .bind(episode_id)
+                .bind(id)
+                .fetch_optional(db)
                 .await
                 .map_err(AppError::Database)?
                 .ok_or_else(|| AppError::NotFound(format!("Episode not found: {}", episode_id)))?;
                 
-            PathBuf::from(row.get::<String, _>("path"))
+            Ok(PathBuf::from(row.get::<String, _>("path")))
         } else {
             // No episode specified for a TV show
-            return Err(AppError::InvalidInput("Episode ID is required for TV shows".to_string()));
+            Err(AppError::InvalidInput("Episode ID is required for TV shows".to_string()))
         }
     } else {
         // Regular media file (movie or music)
-        PathBuf::from(&media.path)
-    };
-    
-    // Verify the file exists
-    if !path.exists() {
-        return Err(AppError::NotFound(format!("Media file not found: {:?}", path)));
+        Ok(PathBuf::from(&media.path))
     }
-    
-    // Determine content type based on file extension
-    let content_type = match path.extension().and_then(|ext| ext.to_str()) {
-        Some("mp4") => ContentType::MP4,
-        Some("mkv") => ContentType::new("video", "x-matroska"),
-        Some("avi") => ContentType::new("video", "x-msvideo"),
-        Some("mov") => ContentType::new("video", "quicktime"),
-        Some("mp3") => ContentType::MP3,
-        Some("flac") => ContentType::new("audio", "flac"),
-        Some("m4a") => ContentType::new("audio", "mp4"),
-        _ => ContentType::Binary,
+}
+
+// Query string to append to HLS URIs so the episode selection carries over
+fn episode_query(episode: Option<&str>) -> String {
+    episode.map_or_else(String::new, |episode| format!("?episode={}", episode))
+}
+
+// Query string for the URIs in playlists and manifests, carrying over the episode
+// selection and the signature
+fn stream_query(episode: Option<&str>, token: &StreamToken) -> String {
+    match episode {
+        Some(episode) => format!("?episode={}&{}", episode, token.query()),
+        None => format!("?{}", token.query()),
+    }
+}
+
+// Stream URLs of an episode are signed for the episode, so they work under either route
+fn stream_item<'a>(id: &'a str, episode: Option<&'a str>) -> &'a str {
+    episode.unwrap_or(id)
+}
+
+// The file with support for range requests. With `?format=mp4` the file is remuxed to
+// fragmented MP4 (optionally from `&start=` seconds), with `?profile=` the response is
+// an HLS playlist of a new transcoding session. Like every stream route it needs a
+// signed URL, see sign_stream.
+#[allow(clippy::too_many_arguments)]
+#[get("/info/<id>/stream?<episode>&<profile>&<format>&<start>&<token..>")]
+pub async fn stream_media(
+    id: String,
+    episode: Option<String>,
+    profile: Option<String>,
+    format: Option<String>,
+    start: Option<f64>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    transcoder: &State<TranscodeManager>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<StreamResponse> {
+    let token = signer.verify(stream_item(&id, episode.as_deref()), token)?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    let options = StreamOptions {
+        profile: profile.as_deref(),
+        format: format.as_deref(),
+        start,
+        token: &token,
     };
-    
-    // Return the file with support for range requests
-    RangeFile::new(path, content_type)
+    sessions.track(db, &token, &client, Stream::media(&id, episode.as_deref(), &path, options.method())).await?;
+    StreamResponse::new(path, options, transcoder, indexes, signer, sessions).await
+}
+
+// HLS master playlist, remuxing the file into fMP4 segments without transcoding
+#[allow(clippy::too_many_arguments)]
+#[get("/info/<id>/hls/master.m3u8?<episode>&<token..>")]
+pub async fn hls_master(
+    id: String,
+    episode: Option<String>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Playlist>> {
+    let token = signer.verify(stream_item(&id, episode.as_deref()), token)?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    sessions.track(db, &token, &client, Stream::media(&id, episode.as_deref(), &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    hls::master_response(&indexed, &stream_query(episode.as_deref(), &token))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/info/<id>/hls/<track>/index.m3u8?<episode>&<token..>")]
+pub async fn hls_playlist(
+    id: String,
+    track: u32,
+    episode: Option<String>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Playlist>> {
+    let token = signer.verify(stream_item(&id, episode.as_deref()), token)?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    sessions.track(db, &token, &client, Stream::media(&id, episode.as_deref(), &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    hls::media_response(&indexed, track, &stream_query(episode.as_deref(), &token))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/info/<id>/hls/<track>/init.mp4?<episode>&<token..>")]
+pub async fn hls_init(
+    id: String,
+    track: u32,
+    episode: Option<String>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Fragment>> {
+    let token = signer.verify(stream_item(&id, episode.as_deref()), token)?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    sessions.track(db, &token, &client, Stream::media(&id, episode.as_deref(), &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    Fragment::init(&indexed, track)
+}
+
+// Text subtitle track as WebVTT, referenced from the DASH manifest
+#[allow(clippy::too_many_arguments)]
+#[get("/info/<id>/hls/<track>/subtitles.vtt?<episode>&<token..>")]
+pub async fn hls_subtitles(
+    id: String,
+    track: u32,
+    episode: Option<String>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    subtitles: &State<SubtitleCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<(ContentType, String)>> {
+    let token = signer.verify(stream_item(&id, episode.as_deref()), token)?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    sessions.track(db, &token, &client, Stream::media(&id, episode.as_deref(), &path, PlayMethod::Remux)).await?;
+    subtitles.webvtt(indexes, &path, track).await
+}
+
+// Ranked below the playlist and init routes, which would otherwise collide with it
+#[allow(clippy::too_many_arguments)]
+#[get("/info/<id>/hls/<track>/<segment>?<episode>&<token..>", rank = 2)]
+pub async fn hls_segment(
+    id: String,
+    track: u32,
+    segment: SegmentName,
+    episode: Option<String>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Fragment>> {
+    let token = signer.verify(stream_item(&id, episode.as_deref()), token)?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    sessions.track(db, &token, &client, Stream::media(&id, episode.as_deref(), &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    Fragment::media(&indexed, track, segment.0)
+}
+
+// MPEG-DASH manifest for the same remuxed segments as the HLS routes
+#[allow(clippy::too_many_arguments)]
+#[get("/info/<id>/dash/manifest.mpd?<episode>&<token..>")]
+pub async fn dash_manifest(
+    id: String,
+    episode: Option<String>,
+    token: Option<StreamToken>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    indexes: &State<IndexCache>,
+    signer: &State<UrlSigner>,
+    client: Client,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Cached<Manifest>> {
+    let token = signer.verify(stream_item(&id, episode.as_deref()), token)?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    sessions.track(db, &token, &client, Stream::media(&id, episode.as_deref(), &path, PlayMethod::Remux)).await?;
+    let indexed = indexes.get(&path).await?;
+    dash::manifest_response(&indexed, DASH_SEGMENT_BASE, &stream_query(episode.as_deref(), &token))
+}
+
+// Stream URLs of an item, signed for a user
+fn signed_urls(id: &str, episode: Option<&str>, user_id: &str, signer: &UrlSigner) -> (PlaybackUrls, StreamToken) {
+    let token = signer.sign(stream_item(id, episode), user_id);
+    let query = stream_query(episode, &token);
+    let urls = PlaybackUrls {
+        direct: format!("/api/media/info/{}/stream{}", id, query),
+        hls: format!("/api/media/info/{}/hls/master.m3u8{}", id, query),
+        dash: format!("/api/media/info/{}/dash/manifest.mpd{}", id, query),
+    };
+    (urls, token)
+}
+
+// Short-lived URLs letting a user stream an item, e.g. in an external player. They
+// stop working at `expiresAt`.
+#[post("/info/<id>/sign?<episode>&<user_id>")]
+pub async fn sign_stream(
+    id: String,
+    episode: Option<String>,
+    user_id: String,
+    db: &State<Pool<Sqlite>>,
+    signer: &State<UrlSigner>,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Json<serde_json::Value>> {
+    check_episode(db, &id, episode.as_deref()).await?;
+    sessions.check_item_stopped(&user_id, Some(&id), episode.as_deref())?;
+    let (urls, token) = signed_urls(&id, episode.as_deref(), &user_id, signer);
+    Ok(Json(urls.signed_json(&token)))
+}
+
+// How a device should play an item, given what it can play. The URL in the plan is
+// signed for `?user_id=`.
+#[allow(clippy::too_many_arguments)]
+#[post("/info/<id>/playback?<episode>&<user_id>", data = "<device>")]
+pub async fn get_playback_plan(
+    id: String,
+    episode: Option<String>,
+    user_id: String,
+    device: Json<DeviceProfileDto>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    signer: &State<UrlSigner>,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Json<serde_json::Value>> {
+    sessions.check_limit(&user_id, Some(&id), episode.as_deref())?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    let (urls, token) = signed_urls(&id, episode.as_deref(), &user_id, signer);
+
+    let mut plan = playback::plan(db, &id, episode.as_deref(), &path, &device, &urls).await?;
+    // Players send heartbeats for the session the URL starts
+    plan["sessionId"] = serde_json::json!(sessions::session_id(&token));
+    Ok(Json(plan))
+}
+
+// Where the seek preview thumbnails of an item are at
+#[get("/info/<id>/trickplay?<episode>")]
+pub async fn get_trickplay(id: String, episode: Option<String>, db: &State<Pool<Sqlite>>) -> Result<Json<Trickplay>> {
+    queries::get_trickplay(db, &id, episode.as_deref())
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("No seek previews for {}", id)))
+}
+
+// Make the seek preview thumbnails of an item in the background. They are only redone
+// when the file changed, or with `?force=true`.
+#[post("/info/<id>/trickplay?<episode>&<force>")]
+pub async fn generate_trickplay(
+    id: String,
+    episode: Option<String>,
+    force: Option<bool>,
+    db: &State<Pool<Sqlite>>,
+    sandbox: &State<PathSandbox>,
+    trickplay: &State<TrickplayGenerator>,
+) -> Result<Json<Trickplay>> {
+    check_episode(db, &id, episode.as_deref()).await?;
+    let path = resolve_media_path(db, sandbox, &id, episode.as_deref()).await?;
+    let queued = trickplay
+        .enqueue(db, &id, episode.as_deref(), path, force.unwrap_or(false))
+        .await?;
+    Ok(Json(queued))
+}
+
+// WebVTT index of the thumbnails, for players' seek previews
+#[get("/info/<id>/trickplay/thumbnails.vtt?<episode>")]
+pub async fn trickplay_vtt(
+    id: String,
+    episode: Option<String>,
+    db: &State<Pool<Sqlite>>,
+    trickplay: &State<TrickplayGenerator>,
+) -> Result<Cached<(ContentType, String)>> {
+    let ready = trickplay.ready(db, &id, episode.as_deref()).await?;
+    Ok(generator::thumbnails_vtt(&ready, &episode_query(episode.as_deref())))
+}
+
+// The thumbnails as a Roku BIF file, when TRICKPLAY_BIF is set
+#[get("/info/<id>/trickplay/thumbnails.bif?<episode>")]
+pub async fn trickplay_bif(
+    id: String,
+    episode: Option<String>,
+    db: &State<Pool<Sqlite>>,
+    trickplay: &State<TrickplayGenerator>,
+) -> Result<RangeFile> {
+    let ready = trickplay.ready(db, &id, episode.as_deref()).await?;
+    RangeFile::open(trickplay.bif_path(&ready)?).await
+}
+
+// Ranked below the index routes, which would otherwise collide with it
+#[get("/info/<id>/trickplay/<sprite>?<episode>", rank = 2)]
+pub async fn trickplay_sprite(
+    id: String,
+    sprite: SpriteFile,
+    episode: Option<String>,
+    db: &State<Pool<Sqlite>>,
+    trickplay: &State<TrickplayGenerator>,
+) -> Result<RangeFile> {
+    let ready = trickplay.ready(db, &id, episode.as_deref()).await?;
+    RangeFile::open(trickplay.sprite_path(&ready, sprite)?).await
 }
 
 // Additional API to get detailed information about a media item
 #[get("/info/<id>/details")]
-pub async fn get_media_details(id: String, db: &State<Pool<Sqlite>>) -> Result<Json<serde_json::Value>> {
+pub async fn get_media_details(id: String, db: &State<Pool<Sqlite>>) -> Result<Cached<Json<serde_json::Value>>> {
     // Get the media item
     let media = queries::get_media_by_id(db, &id).await?;
+    let (etag, last_modified) = item_validators(&media);
     
     // If it's a TV show, get seasons and episodes
     let mut details = serde_json::json!(media);
@@ -264,5 +467,23 @@ pub async fn get_media_details(id: String, db: &State<Pool<Sqlite>>) -> Result<J
     let genres = queries::get_genres_by_media_id(db, &id).await?;
     details["genres"] = serde_json::json!(genres);
     
-    Ok(Json(details))
+    // Technical details of the files, with the episode they belong to for TV shows
+    let mut files = Vec::new();
+    for file in queries::get_media_files_by_media_id(db, &id).await? {
+        let streams = queries::get_streams_by_file_id(db, &file.id).await?;
+        let mut file_details = serde_json::json!(file);
+        file_details["streams"] = serde_json::json!(streams);
+        files.push(file_details);
+    }
+    details["files"] = serde_json::json!(files);
+
+    // Subtitle files, embedded tracks and uploads, with the episode they belong to
+    let subtitles = queries::get_subtitles_by_media_id(db, &id).await?;
+    details["subtitles"] = serde_json::json!(subtitles);
+
+    // Chapters and skip markers
+    let markers = queries::get_markers_by_media_id(db, &id).await?;
+    details["markers"] = serde_json::json!(markers);
+    
+    Ok(Cached::new(Json(details), etag, last_modified))
 }
\ No newline at end of file
@@ -229,6 +229,9 @@ async fn refresh_movie_metadata(
         }
     }
     
+    // Invalidate cached details now that everything has been written
+    queries::touch_media(db, &media.id).await?;
+    
     Ok(Json(serde_json::json!({
         "success": true,
         "message": "Movie metadata updated successfully",
@@ -438,6 +441,9 @@ async fn refresh_tvshow_metadata(
         }
     }
     
+    // Invalidate cached details now that everything has been written
+    queries::touch_media(db, &media.id).await?;
+    
     Ok(Json(serde_json::json!({
         "success": true,
         "message": "TV show metadata updated successfully",
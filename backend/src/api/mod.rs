@@ -2,4 +2,10 @@ pub mod media;
 pub mod library;
 pub mod metadata;
 pub mod progress;
-pub mod episodes;
\ No newline at end of file
+pub mod episodes;
+pub mod subtitles;
+pub mod markers;
+pub mod transcode;
+pub mod downloads;
+pub mod sessions;
+pub mod jobs;
\ No newline at end of file
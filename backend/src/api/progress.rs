@@ -5,6 +5,7 @@ use sqlx::{Pool, Sqlite, Row};
 use crate::db::models::{WatchProgress, UpdateProgressDto};
 use crate::db::queries;
 use crate::error::{AppError, Result};
+use crate::media::markers;
 
 #[get("/<media_id>?<user_id>")]
 pub async fn get_progress(
@@ -38,9 +39,16 @@ pub async fn update_progress(
         return Err(AppError::InvalidInput("duration must be positive".to_string()));
     }
     
-    // Calculate if it's completed (if not specified)
+    // Calculate if it's completed (if not specified): once the credits have started,
+    // or past 90% for items without a credits marker
     let progress_data = if progress.completed.is_none() {
-        let completed = progress.position as f32 / progress.duration as f32 > 0.9; // Mark as completed if watched more than 90%
+        let credits = queries::get_marker_by_type(db, &progress.media_id, progress.episode_id.as_deref(), markers::CREDITS)
+            .await?
+            .filter(|credits| credits.start_time > 0.0);
+        let completed = match credits {
+            Some(credits) => progress.position as f64 >= credits.start_time,
+            None => progress.position as f32 / progress.duration as f32 > 0.9,
+        };
         UpdateProgressDto {
             completed: Some(completed),
             ..progress.0
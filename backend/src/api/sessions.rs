@@ -0,0 +1,75 @@
+use rocket::serde::json::Json;
+use rocket::State;
+
+use crate::db::models::PlaybackHeartbeatDto;
+use crate::error::{AppError, Result};
+use crate::streaming::sessions::{PlaybackSession, PlaybackSessions};
+use crate::transcode::session::TranscodeManager;
+
+fn session_json(session: &PlaybackSession) -> serde_json::Value {
+    serde_json::json!({
+        "id": session.id,
+        "userId": session.user,
+        "device": session.user_agent,
+        "address": session.address,
+        "mediaId": session.media_id,
+        "episodeId": session.episode_id,
+        "title": session.title,
+        "episode": session.episode,
+        "method": session.method.as_str(),
+        "profile": session.profile.map(|profile| profile.name()),
+        "bitrate": session.bitrate,
+        "duration": session.duration,
+        "position": session.position,
+        "paused": session.paused,
+        "transcodeSession": session.transcode_session,
+        "startedAt": session.started_at,
+        "lastSeen": session.last_seen,
+    })
+}
+
+// Now playing: every stream that got a request or a heartbeat lately
+#[get("/")]
+pub async fn get_sessions(sessions: &State<PlaybackSessions>) -> Json<serde_json::Value> {
+    let sessions: Vec<serde_json::Value> = sessions.list().iter().map(session_json).collect();
+    Json(serde_json::json!(sessions))
+}
+
+#[get("/<id>")]
+pub async fn get_session(id: String, sessions: &State<PlaybackSessions>) -> Result<Json<serde_json::Value>> {
+    Ok(Json(session_json(&sessions.get(&id)?)))
+}
+
+// Sent by players every so often. Answered with 403 once the session was stopped.
+#[post("/<id>/heartbeat", data = "<heartbeat>")]
+pub async fn heartbeat(
+    id: String,
+    heartbeat: Json<PlaybackHeartbeatDto>,
+    sessions: &State<PlaybackSessions>,
+) -> Result<Json<serde_json::Value>> {
+    let session = sessions.heartbeat(&id, heartbeat.position, heartbeat.paused)?;
+    Ok(Json(session_json(&session)))
+}
+
+// Stop a stream: requests with its URL are refused from now on, and its transcoding
+// session, if any, is stopped
+#[delete("/<id>")]
+pub async fn stop_session(
+    id: String,
+    sessions: &State<PlaybackSessions>,
+    transcoder: &State<TranscodeManager>,
+) -> Result<Json<serde_json::Value>> {
+    let session = sessions.stop(&id)?;
+    if let Some(transcode_id) = &session.transcode_session {
+        match transcoder.stop(transcode_id).await {
+            // It may have been stopped for being idle already
+            Ok(()) | Err(AppError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "message": "Playback session stopped"
+    })))
+}
@@ -0,0 +1,256 @@
+use rocket::data::{Data, ToByteUnit};
+use rocket::http::ContentType;
+use rocket::serde::json::{json, Json, Value};
+use rocket::State;
+use sqlx::{Pool, Sqlite};
+use std::path::{Path, PathBuf};
+
+use crate::api::media::check_episode;
+use crate::container::index::IndexCache;
+use crate::db::generate_id;
+use crate::db::models::{Subtitle, SubtitleOffsetDto, SubtitleShiftDto, SubtitleUploadDto};
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::media::sandbox::PathSandbox;
+use crate::streaming::conditional::{self, Cached};
+use crate::streaming::file::validate_file;
+use crate::streaming::segment::derived_etag;
+use crate::subtitles::cache::SubtitleCache;
+use crate::subtitles::sidecar;
+use crate::subtitles::text::{self, SubtitleFormat};
+use crate::subtitles::upload::{SubtitleUploads, MAX_UPLOAD_SIZE};
+use crate::subtitles::webvtt;
+
+// Subtitles of a movie, or of an episode of a show with `?episode=`
+#[get("/media/<media_id>?<episode>")]
+pub async fn get_subtitles(
+    media_id: String,
+    episode: Option<String>,
+    db: &State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Subtitle>>> {
+    let subtitles = queries::get_subtitles(db, &media_id, episode.as_deref()).await?;
+    Ok(Json(subtitles))
+}
+
+// Frame rate of the video a subtitle belongs to, which MicroDVD cue times count in
+async fn video_frame_rate(db: &Pool<Sqlite>, subtitle: &Subtitle) -> Result<Option<f64>> {
+    let frame_rate: Option<Option<f64>> = sqlx::query_scalar(
+        "SELECT s.frame_rate FROM media_streams s JOIN media_files f ON s.file_id = f.id
+         WHERE f.media_id = ? AND f.episode_id IS ? AND s.kind = 'video'
+         ORDER BY s.is_default DESC, s.stream_index LIMIT 1"
+    )
+    .bind(&subtitle.media_id)
+    .bind(&subtitle.episode_id)
+    .fetch_optional(db)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(frame_rate.flatten())
+}
+
+// A subtitle file or embedded track converted to WebVTT for the browser's <track> element.
+// With `?user_id=` the user's own timing offset is applied too. Ranked below the
+// listing, whose /media/<media_id> path it would otherwise collide with.
+#[get("/<id>/subtitles.vtt?<user_id>", rank = 2)]
+pub async fn get_subtitle_file(
+    id: String,
+    user_id: Option<String>,
+    db: &State<Pool<Sqlite>>,
+    indexes: &State<IndexCache>,
+    cache: &State<SubtitleCache>,
+    sandbox: &State<PathSandbox>,
+) -> Result<Cached<(ContentType, String)>> {
+    let subtitle = queries::get_subtitle_by_id(db, &id).await?;
+
+    // Uploads are kept outside the libraries, everything else was found in one
+    let path = PathBuf::from(&subtitle.path);
+    if !subtitle.is_uploaded {
+        sandbox.check_file(db, &path).await?;
+    }
+    let metadata = validate_file(&path).await?;
+    let last_modified = metadata.modified().ok();
+    let file_etag = conditional::file_etag(metadata.len(), last_modified);
+
+    let document = match subtitle.stream_index {
+        Some(track) => cache.document(indexes, &path, track as u32, &file_etag).await?,
+        None => {
+            let format = SubtitleFormat::from_name(&subtitle.format)
+                .ok_or_else(|| AppError::Server(format!("Unknown subtitle format: {}", subtitle.format)))?;
+            let frame_rate = match format {
+                SubtitleFormat::MicroDvd => video_frame_rate(db, &subtitle).await?,
+                _ => None,
+            };
+
+            let bytes = tokio::fs::read(&path).await?;
+            text::to_webvtt(&bytes, format, subtitle.language.as_deref(), frame_rate)
+        }
+    };
+
+    let user_offset = match user_id.as_deref() {
+        Some(user_id) => queries::get_subtitle_offset(db, user_id, &id)
+            .await?
+            .map_or(0.0, |offset| offset.time_offset),
+        None => 0.0,
+    };
+    let offset = subtitle.time_offset + user_offset;
+
+    // Offsets change the document without changing the file
+    let mut resource = match subtitle.stream_index {
+        Some(track) => format!("{}-vtt", track),
+        None => "vtt".to_string(),
+    };
+    if offset != 0.0 {
+        resource.push_str(&format!("-{}ms", (offset * 1000.0).round() as i64));
+    }
+    let etag = derived_etag(&file_etag, &resource);
+
+    // Revalidated on every use, since offsets change under the same URL
+    let document = webvtt::shift(&document, offset);
+    Ok(Cached::new((ContentType::new("text", "vtt"), document), etag, last_modified)
+        .with_cache_control(conditional::CACHE_CONTROL_METADATA))
+}
+
+// Add a subtitle file to a movie, or to an episode of a show with `?episode=`. The file
+// is the request body; `?filename=` tells its format.
+#[post("/media/<media_id>?<upload..>", data = "<data>")]
+pub async fn upload_subtitle(
+    media_id: String,
+    upload: SubtitleUploadDto,
+    data: Data<'_>,
+    db: &State<Pool<Sqlite>>,
+    uploads: &State<SubtitleUploads>,
+) -> Result<Json<Subtitle>> {
+    check_episode(db, &media_id, upload.episode.as_deref()).await?;
+
+    let format = Path::new(&upload.filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(SubtitleFormat::from_extension)
+        .ok_or_else(|| AppError::InvalidInput(format!("Unsupported subtitle file: {}", upload.filename)))?;
+
+    let body = data.open(MAX_UPLOAD_SIZE.bytes()).into_bytes().await?;
+    if !body.is_complete() {
+        return Err(AppError::InvalidInput(format!(
+            "Subtitle files can be at most {} MB",
+            MAX_UPLOAD_SIZE / 1024 / 1024
+        )));
+    }
+
+    let language = upload
+        .language
+        .as_deref()
+        .map(|language| sidecar::language_code(language).unwrap_or(language).to_string());
+    SubtitleUploads::validate(&body, format, language.as_deref())?;
+
+    let id = generate_id();
+    let path = uploads.save(&id, format, &body).await?;
+
+    sqlx::query(
+        "INSERT INTO subtitles (id, media_id, episode_id, path, format, language, title, is_forced, is_sdh, is_uploaded)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1)"
+    )
+    .bind(&id)
+    .bind(&media_id)
+    .bind(&upload.episode)
+    .bind(path.to_string_lossy().to_string())
+    .bind(format.as_str())
+    .bind(&language)
+    .bind(&upload.title)
+    .bind(upload.forced)
+    .bind(upload.sdh)
+    .execute(db.inner())
+    .await
+    .map_err(AppError::Database)?;
+
+    // The item's details list its subtitles
+    queries::touch_media(db, &media_id).await?;
+
+    tracing::info!("Uploaded subtitles {} for {}", upload.filename, media_id);
+    Ok(Json(queries::get_subtitle_by_id(db, &id).await?))
+}
+
+// Delete an uploaded subtitle. Subtitles found in the library come back on the next
+// scan, so they can only be removed by deleting their files.
+#[delete("/<id>")]
+pub async fn delete_subtitle(
+    id: String,
+    db: &State<Pool<Sqlite>>,
+    uploads: &State<SubtitleUploads>,
+) -> Result<Json<Value>> {
+    let subtitle = queries::get_subtitle_by_id(db, &id).await?;
+    if !subtitle.is_uploaded {
+        return Err(AppError::InvalidInput("Only uploaded subtitles can be deleted".to_string()));
+    }
+
+    sqlx::query("DELETE FROM subtitles WHERE id = ?")
+        .bind(&id)
+        .execute(db.inner())
+        .await
+        .map_err(AppError::Database)?;
+    uploads.remove(Path::new(&subtitle.path)).await?;
+    queries::touch_media(db, &subtitle.media_id).await?;
+
+    Ok(Json(json!({
+        "success": true,
+        "message": "Subtitle deleted successfully"
+    })))
+}
+
+#[get("/<id>/offset?<user_id>", rank = 2)]
+pub async fn get_offset(id: String, user_id: String, db: &State<Pool<Sqlite>>) -> Result<Json<Value>> {
+    let subtitle = queries::get_subtitle_by_id(db, &id).await?;
+    let user_offset = queries::get_subtitle_offset(db, &user_id, &id)
+        .await?
+        .map_or(0.0, |offset| offset.time_offset);
+
+    Ok(Json(json!({
+        "subtitleId": id,
+        "userId": user_id,
+        "offset": user_offset,
+        "subtitleOffset": subtitle.time_offset,
+    })))
+}
+
+// Set a user's own timing offset for a subtitle, kept until changed; 0 removes it
+#[put("/<id>/offset", data = "<offset>")]
+pub async fn set_offset(id: String, offset: Json<SubtitleOffsetDto>, db: &State<Pool<Sqlite>>) -> Result<Json<Value>> {
+    if offset.user_id.is_empty() {
+        return Err(AppError::InvalidInput("user_id is required".to_string()));
+    }
+    validate_offset(offset.offset)?;
+
+    let subtitle = queries::get_subtitle_by_id(db, &id).await?;
+    queries::set_subtitle_offset(db, &offset.user_id, &id, offset.offset).await?;
+
+    Ok(Json(json!({
+        "subtitleId": id,
+        "userId": offset.user_id,
+        "offset": offset.offset,
+        "subtitleOffset": subtitle.time_offset,
+    })))
+}
+
+// Shift a subtitle for everyone. Shifts add up, and the file itself is left alone.
+#[post("/<id>/shift", data = "<shift>")]
+pub async fn shift_subtitle(id: String, shift: Json<SubtitleShiftDto>, db: &State<Pool<Sqlite>>) -> Result<Json<Subtitle>> {
+    validate_offset(shift.offset)?;
+    let subtitle = queries::get_subtitle_by_id(db, &id).await?;
+
+    sqlx::query("UPDATE subtitles SET time_offset = ? WHERE id = ?")
+        .bind(subtitle.time_offset + shift.offset)
+        .bind(&id)
+        .execute(db.inner())
+        .await
+        .map_err(AppError::Database)?;
+    queries::touch_media(db, &subtitle.media_id).await?;
+
+    Ok(Json(queries::get_subtitle_by_id(db, &id).await?))
+}
+
+// Offsets beyond an hour are typos rather than out-of-sync subtitles
+fn validate_offset(offset: f64) -> Result<()> {
+    if !offset.is_finite() || offset.abs() > 3600.0 {
+        return Err(AppError::InvalidInput("offset must be a number of seconds within an hour".to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use rocket::serde::json::Json;
+use rocket::State;
+use std::path::PathBuf;
+
+use crate::container::index::IndexCache;
+use crate::error::{AppError, Result};
+use crate::streaming::file::RangeFile;
+use crate::streaming::hls::Playlist;
+use crate::streaming::playback::PlayMethod;
+use crate::streaming::remux::{RemuxedFile, FORMAT_MP4};
+use crate::streaming::sessions::PlaybackSessions;
+use crate::streaming::signing::{StreamToken, UrlSigner};
+use crate::transcode::profile::Profile;
+use crate::transcode::session::{SegmentFile, TranscodeManager};
+
+// Where the session routes are mounted, segment URIs in session playlists start here
+pub const MOUNT_POINT: &str = "/api/transcode";
+
+// Options of the stream routes besides the item
+pub struct StreamOptions<'a> {
+    // Transcoding profile, answered with the playlist of a new session
+    pub profile: Option<&'a str>,
+    // `mp4` to remux the file into fragmented MP4
+    pub format: Option<&'a str>,
+    // Where the remuxed file starts, in seconds
+    pub start: Option<f64>,
+    // The verified signature of the request
+    pub token: &'a StreamToken,
+}
+
+impl StreamOptions<'_> {
+    // How the response plays the file, for the playback session
+    pub fn method(&self) -> PlayMethod {
+        if self.profile.is_some() {
+            PlayMethod::Transcode
+        } else if self.format.is_some() {
+            PlayMethod::Remux
+        } else {
+            PlayMethod::DirectPlay
+        }
+    }
+}
+
+// Response of the stream routes: the file itself, the file remuxed to MP4, or a
+// playlist of a transcoding session when a profile was asked for
+#[derive(Responder)]
+pub enum StreamResponse {
+    Direct(Box<RangeFile>),
+    Remuxed(Box<RemuxedFile>),
+    Transcoded(Playlist),
+}
+
+impl StreamResponse {
+    pub async fn new(
+        path: PathBuf,
+        options: StreamOptions<'_>,
+        transcoder: &TranscodeManager,
+        indexes: &IndexCache,
+        signer: &UrlSigner,
+        sessions: &PlaybackSessions,
+    ) -> Result<Self> {
+        if let Some(profile) = options.profile {
+            let profile = Profile::from_name(profile)?;
+            let session = transcoder.start(path, profile).await?;
+            sessions.transcoding(options.token, &session.id, profile);
+            // Segments are signed for the session, for as long as the request's URL is valid
+            let token = signer.sign_until(&session.id, &options.token.user, options.token.expires);
+            let query = format!("?{}", token.query());
+            return Ok(StreamResponse::Transcoded(Playlist(session.playlist(MOUNT_POINT, &query))));
+        }
+
+        match options.format {
+            Some(format) if format.eq_ignore_ascii_case(FORMAT_MP4) => {
+                let indexed = indexes.get(&path).await?;
+                Ok(StreamResponse::Remuxed(Box::new(RemuxedFile::new(&indexed, options.start)?)))
+            }
+            Some(format) => Err(AppError::InvalidInput(format!(
+                "Unknown stream format '{}', expected: {}",
+                format, FORMAT_MP4
+            ))),
+            None => Ok(StreamResponse::Direct(Box::new(RangeFile::open(path).await?))),
+        }
+    }
+}
+
+#[get("/")]
+pub async fn get_sessions(transcoder: &State<TranscodeManager>) -> Json<serde_json::Value> {
+    let sessions: Vec<serde_json::Value> = transcoder
+        .list()
+        .iter()
+        .map(|session| {
+            serde_json::json!({
+                "id": session.id,
+                "path": session.path,
+                "profile": session.profile.name(),
+                "duration": session.duration,
+                "segments": session.segment_count(),
+                "createdAt": session.created_at,
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!(sessions))
+}
+
+#[get("/<id>/<segment>?<token..>")]
+pub async fn get_segment(
+    id: String,
+    segment: SegmentFile,
+    token: Option<StreamToken>,
+    transcoder: &State<TranscodeManager>,
+    signer: &State<UrlSigner>,
+    sessions: &State<PlaybackSessions>,
+) -> Result<RangeFile> {
+    signer.verify(&id, token)?;
+    sessions.track_transcode(&id)?;
+    let session = transcoder.get(&id)?;
+    let path = session.segment(segment.0).await?;
+    RangeFile::open(path).await
+}
+
+#[delete("/<id>")]
+pub async fn stop_session(id: String, transcoder: &State<TranscodeManager>) -> Result<Json<serde_json::Value>> {
+    transcoder.stop(&id).await?;
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "message": "Transcoding session stopped"
+    })))
+}
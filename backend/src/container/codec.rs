@@ -0,0 +1,674 @@
+use crate::container::fmp4::{put_u16, put_u32, write_box, write_full_box};
+use crate::container::index::{Track, TrackKind};
+
+// Sampling frequencies indexed by the MPEG-4 samplingFrequencyIndex
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+// AC-3 sample rates indexed by fscod
+const AC3_SAMPLE_RATES: [u32; 3] = [48000, 44100, 32000];
+
+// Channel count for each AC-3 acmod, not counting LFE
+const AC3_CHANNELS: [u32; 8] = [2, 1, 2, 3, 3, 4, 4, 5];
+
+// MSB-first bit reader for codec headers
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.position / 8)?;
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, bits: usize) -> Option<()> {
+        self.read(bits).map(|_| ())
+    }
+}
+
+// Friendly codec name for a Matroska codec ID
+pub fn mkv_codec_name(codec_id: &str) -> String {
+    let name = match codec_id {
+        "V_MPEG4/ISO/AVC" => "h264",
+        "V_MPEGH/ISO/HEVC" => "hevc",
+        "V_AV1" => "av1",
+        "V_VP9" => "vp9",
+        "V_VP8" => "vp8",
+        "V_MPEG2" => "mpeg2video",
+        "V_MS/VFW/FOURCC" => "vfw",
+        id if id.starts_with("V_MPEG4/ISO/") => "mpeg4",
+        id if id.starts_with("A_AAC") => "aac",
+        "A_AC3" => "ac3",
+        "A_EAC3" => "eac3",
+        "A_DTS" | "A_DTS/EXPRESS" | "A_DTS/LOSSLESS" => "dts",
+        "A_TRUEHD" => "truehd",
+        "A_OPUS" => "opus",
+        "A_VORBIS" => "vorbis",
+        "A_FLAC" => "flac",
+        "A_MPEG/L3" => "mp3",
+        "A_MPEG/L2" => "mp2",
+        id if id.starts_with("A_PCM/") => "pcm",
+        "S_TEXT/UTF8" | "S_TEXT/ASCII" => "subrip",
+        "S_TEXT/ASS" | "S_TEXT/SSA" | "S_ASS" | "S_SSA" => "ass",
+        "S_TEXT/WEBVTT" => "webvtt",
+        "S_HDMV/PGS" => "pgs",
+        "S_VOBSUB" => "dvdsub",
+        other => return other.to_lowercase(),
+    };
+
+    name.to_string()
+}
+
+// Friendly codec name for an MP4 sample entry type
+pub fn mp4_codec_name(fourcc: &[u8]) -> String {
+    let name = match fourcc {
+        b"avc1" | b"avc3" | b"dva1" | b"dvav" => "h264",
+        b"hvc1" | b"hev1" | b"dvh1" | b"dvhe" => "hevc",
+        b"av01" | b"dav1" => "av1",
+        b"vp09" => "vp9",
+        b"vp08" => "vp8",
+        b"mp4v" => "mpeg4",
+        b"mp4a" => "aac",
+        b"ac-3" => "ac3",
+        b"ec-3" => "eac3",
+        b"dtsc" | b"dtsh" | b"dtsl" | b"dtse" | b"dtsx" => "dts",
+        b"mlpa" => "truehd",
+        b"Opus" => "opus",
+        b"fLaC" => "flac",
+        b".mp3" => "mp3",
+        b"alac" => "alac",
+        b"lpcm" | b"sowt" | b"twos" | b"ipcm" | b"in24" | b"fl32" => "pcm",
+        b"tx3g" => "mov_text",
+        b"wvtt" => "webvtt",
+        b"stpp" => "ttml",
+        b"c608" => "eia_608",
+        other => return String::from_utf8_lossy(other).trim().to_lowercase(),
+    };
+
+    name.to_string()
+}
+
+// "avc1.PPCCLL" from an AVCDecoderConfigurationRecord
+pub fn avc_codec_string(fourcc: &str, avcc: &[u8]) -> Option<String> {
+    let profile = avcc.get(1..4)?;
+    Some(format!("{}.{:02X}{:02X}{:02X}", fourcc, profile[0], profile[1], profile[2]))
+}
+
+// "hvc1.1.6.L93.B0" style string from an HEVCDecoderConfigurationRecord
+pub fn hevc_codec_string(fourcc: &str, hvcc: &[u8]) -> Option<String> {
+    if hvcc.len() < 13 {
+        return None;
+    }
+
+    let profile_space = hvcc[1] >> 6;
+    let tier = (hvcc[1] >> 5) & 1;
+    let profile_idc = hvcc[1] & 0x1F;
+    let compatibility = u32::from_be_bytes([hvcc[2], hvcc[3], hvcc[4], hvcc[5]]).reverse_bits();
+    let level = hvcc[12];
+
+    let space = match profile_space {
+        1 => "A",
+        2 => "B",
+        3 => "C",
+        _ => "",
+    };
+
+    let mut codec = format!(
+        "{}.{}{}.{:X}.{}{}",
+        fourcc,
+        space,
+        profile_idc,
+        compatibility,
+        if tier == 1 { 'H' } else { 'L' },
+        level
+    );
+
+    // Constraint flags, trailing zero bytes omitted
+    let constraints = &hvcc[6..12];
+    let used = constraints.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    for byte in &constraints[..used] {
+        codec.push_str(&format!(".{:X}", byte));
+    }
+
+    Some(codec)
+}
+
+// "av01.0.08M.08" from an AV1CodecConfigurationRecord
+pub fn av1_codec_string(av1c: &[u8]) -> Option<String> {
+    let profile = av1c.get(1)? >> 5;
+    let level = av1c.get(1)? & 0x1F;
+    let flags = *av1c.get(2)?;
+    let tier = if flags & 0x80 != 0 { 'H' } else { 'M' };
+    let depth = match (flags & 0x40 != 0, flags & 0x20 != 0) {
+        (true, true) => 12,
+        (true, false) => 10,
+        _ => 8,
+    };
+
+    Some(format!("av01.{}.{:02}{}.{:02}", profile, level, tier, depth))
+}
+
+// Audio object type of an AudioSpecificConfig
+fn aac_object_type(asc: &[u8]) -> Option<u32> {
+    let mut bits = BitReader::new(asc);
+    let object_type = bits.read(5)?;
+    if object_type == 31 {
+        return Some(32 + bits.read(6)?);
+    }
+
+    Some(object_type)
+}
+
+// "mp4a.40.2" from an AudioSpecificConfig
+pub fn aac_codec_string(asc: &[u8]) -> Option<String> {
+    Some(format!("mp4a.40.{}", aac_object_type(asc)?))
+}
+
+// Sample rate and channel count from an AudioSpecificConfig
+fn aac_audio_params(asc: &[u8]) -> Option<(u32, u32)> {
+    let mut bits = BitReader::new(asc);
+    if bits.read(5)? == 31 {
+        bits.skip(6)?;
+    }
+
+    let index = bits.read(4)?;
+    let rate = if index == 15 {
+        bits.read(24)?
+    } else {
+        *AAC_SAMPLE_RATES.get(index as usize)?
+    };
+    let channels = bits.read(4)?;
+
+    Some((rate, channels))
+}
+
+// Two byte AudioSpecificConfig for Matroska AAC tracks stored without CodecPrivate
+fn synthesize_aac_config(codec_id: &str, sample_rate: u32, channels: u32) -> Vec<u8> {
+    let object_type: u16 = match codec_id.rsplit('/').next() {
+        Some("MAIN") => 1,
+        Some("SSR") => 3,
+        Some("LTP") => 4,
+        _ => 2,
+    };
+    let index = AAC_SAMPLE_RATES
+        .iter()
+        .position(|rate| *rate == sample_rate)
+        .unwrap_or(3) as u16;
+
+    let config = (object_type << 11) | (index << 7) | ((channels.min(7) as u16) << 3);
+    config.to_be_bytes().to_vec()
+}
+
+// ISO BMFF VisualSampleEntry with the given configuration boxes
+pub fn visual_sample_entry(fourcc: &[u8; 4], width: u32, height: u32, children: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(86 + children.len());
+    write_box(&mut out, fourcc, |out| {
+        out.extend_from_slice(&[0; 6]);
+        put_u16(out, 1); // data_reference_index
+        out.extend_from_slice(&[0; 16]);
+        put_u16(out, width as u16);
+        put_u16(out, height as u16);
+        put_u32(out, 0x0048_0000); // 72 dpi
+        put_u32(out, 0x0048_0000);
+        put_u32(out, 0);
+        put_u16(out, 1); // frame_count
+        out.extend_from_slice(&[0; 32]); // compressorname
+        put_u16(out, 0x0018); // depth
+        put_u16(out, 0xFFFF); // pre_defined = -1
+        out.extend_from_slice(children);
+    });
+    out
+}
+
+// ISO BMFF AudioSampleEntry (version 0) with the given configuration boxes
+pub fn audio_sample_entry(fourcc: &[u8; 4], channels: u32, sample_rate: u32, children: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(36 + children.len());
+    write_box(&mut out, fourcc, |out| {
+        out.extend_from_slice(&[0; 6]);
+        put_u16(out, 1);
+        out.extend_from_slice(&[0; 8]);
+        put_u16(out, channels as u16);
+        put_u16(out, 16); // samplesize
+        put_u32(out, 0);
+        // Rates above 65535 don't fit the 16.16 field, the config box carries the real one
+        put_u32(out, sample_rate.min(0xFFFF) << 16);
+        out.extend_from_slice(children);
+    });
+    out
+}
+
+fn config_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + payload.len());
+    write_box(&mut out, kind, |out| out.extend_from_slice(payload));
+    out
+}
+
+// Write an MPEG-4 descriptor with its expandable length
+fn write_descriptor(out: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+    out.push(tag);
+    let length = payload.len() as u32;
+    out.extend_from_slice(&[
+        0x80 | ((length >> 21) & 0x7F) as u8,
+        0x80 | ((length >> 14) & 0x7F) as u8,
+        0x80 | ((length >> 7) & 0x7F) as u8,
+        (length & 0x7F) as u8,
+    ]);
+    out.extend_from_slice(payload);
+}
+
+// esds box describing an MPEG-4 audio stream
+fn esds_box(object_type: u8, decoder_specific: Option<&[u8]>) -> Vec<u8> {
+    let mut decoder_config = vec![object_type, 0x15]; // streamType audio, upStream 0, reserved 1
+    decoder_config.extend_from_slice(&[0; 3]); // bufferSizeDB
+    decoder_config.extend_from_slice(&[0; 8]); // maxBitrate, avgBitrate
+    if let Some(dsi) = decoder_specific {
+        write_descriptor(&mut decoder_config, 0x05, dsi);
+    }
+
+    let mut es = vec![0, 1, 0]; // ES_ID, flags
+    write_descriptor(&mut es, 0x04, &decoder_config);
+    write_descriptor(&mut es, 0x06, &[0x02]); // SLConfigDescriptor, predefined MP4
+
+    let mut out = Vec::new();
+    write_full_box(&mut out, b"esds", 0, 0, |out| write_descriptor(out, 0x03, &es));
+    out
+}
+
+// Parse the esds box payload (after version/flags) and return objectTypeIndication
+// and DecoderSpecificInfo
+pub fn parse_esds(payload: &[u8]) -> Option<(u8, Option<Vec<u8>>)> {
+    fn descriptor(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let tag = *data.first()?;
+        let mut length = 0usize;
+        let mut position = 1;
+        loop {
+            let byte = *data.get(position)?;
+            length = (length << 7) | (byte & 0x7F) as usize;
+            position += 1;
+            if byte & 0x80 == 0 || position > 4 {
+                break;
+            }
+        }
+        let body = data.get(position..position + length)?;
+        Some((tag, body, &data[position + length..]))
+    }
+
+    let (tag, es, _) = descriptor(payload)?;
+    if tag != 0x03 {
+        return None;
+    }
+
+    // ES_ID and flags, then optional fields announced by the flags
+    let flags = *es.get(2)?;
+    let mut rest = es.get(3..)?;
+    if flags & 0x80 != 0 {
+        rest = rest.get(2..)?;
+    }
+    if flags & 0x40 != 0 {
+        let url_length = *rest.first()? as usize;
+        rest = rest.get(1 + url_length..)?;
+    }
+    if flags & 0x20 != 0 {
+        rest = rest.get(2..)?;
+    }
+
+    let (tag, config, _) = descriptor(rest)?;
+    if tag != 0x04 {
+        return None;
+    }
+
+    let object_type = *config.first()?;
+    let dsi = config
+        .get(13..)
+        .and_then(descriptor)
+        .filter(|(tag, _, _)| *tag == 0x05)
+        .map(|(_, body, _)| body.to_vec());
+
+    Some((object_type, dsi))
+}
+
+// dac3 payload from the first AC-3 sync frame
+fn dac3_from_frame(frame: &[u8]) -> Option<(Vec<u8>, u32, u32)> {
+    let mut bits = BitReader::new(frame);
+    if bits.read(16)? != 0x0B77 {
+        return None;
+    }
+    bits.skip(16)?; // crc1
+    let fscod = bits.read(2)?;
+    let frmsizecod = bits.read(6)?;
+    let bsid = bits.read(5)?;
+    let bsmod = bits.read(3)?;
+    let acmod = bits.read(3)?;
+    if acmod & 1 != 0 && acmod != 1 {
+        bits.skip(2)?; // cmixlev
+    }
+    if acmod & 4 != 0 {
+        bits.skip(2)?; // surmixlev
+    }
+    if acmod == 2 {
+        bits.skip(2)?; // dsurmod
+    }
+    let lfeon = bits.read(1)?;
+
+    let bit_rate_code = frmsizecod >> 1;
+    let packed = (fscod << 22) | (bsid << 17) | (bsmod << 14) | (acmod << 11) | (lfeon << 10) | (bit_rate_code << 5);
+    let payload = packed.to_be_bytes()[1..].to_vec();
+
+    let rate = *AC3_SAMPLE_RATES.get(fscod as usize)?;
+    Some((payload, rate, AC3_CHANNELS[acmod as usize] + lfeon))
+}
+
+// dec3 payload from the first E-AC-3 sync frame. Only the independent substream is
+// described, which is what decoders use to set up their output.
+fn dec3_from_frame(frame: &[u8]) -> Option<(Vec<u8>, u32, u32)> {
+    let mut bits = BitReader::new(frame);
+    if bits.read(16)? != 0x0B77 {
+        return None;
+    }
+    bits.skip(2)?; // strmtyp
+    bits.skip(3)?; // substreamid
+    let frmsiz = bits.read(11)?;
+    let fscod = bits.read(2)?;
+    let (rate, blocks) = if fscod == 3 {
+        let fscod2 = bits.read(2)?;
+        ([24000, 22050, 16000].get(fscod2 as usize).copied()?, 6)
+    } else {
+        let numblkscod = bits.read(2)?;
+        (*AC3_SAMPLE_RATES.get(fscod as usize)?, [1, 2, 3, 6][numblkscod as usize])
+    };
+    let acmod = bits.read(3)?;
+    let lfeon = bits.read(1)?;
+    let bsid = bits.read(5)?;
+
+    let frame_bytes = (frmsiz + 1) * 2;
+    let data_rate = frame_bytes * 8 * rate / (blocks * 256) / 1000;
+
+    let mut payload = Vec::with_capacity(5);
+    put_u16(&mut payload, ((data_rate as u16) << 3) & 0xFFF8); // num_ind_sub = 0 means one
+    let independent = (fscod.min(2) << 22) | (bsid << 17) | (acmod << 9) | (lfeon << 8);
+    payload.extend_from_slice(&independent.to_be_bytes()[1..]);
+
+    Some((payload, rate, AC3_CHANNELS[acmod as usize] + lfeon))
+}
+
+// dOps payload from a Matroska OpusHead codec private (which is little-endian)
+fn dops_from_opus_head(head: &[u8]) -> Option<(Vec<u8>, u32)> {
+    if !head.starts_with(b"OpusHead") || head.len() < 19 {
+        return None;
+    }
+
+    let channels = head[9];
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]);
+    let input_rate = u32::from_le_bytes([head[12], head[13], head[14], head[15]]);
+    let gain = i16::from_le_bytes([head[16], head[17]]);
+    let mapping_family = head[18];
+
+    let mut payload = vec![0, channels];
+    put_u16(&mut payload, pre_skip);
+    put_u32(&mut payload, input_rate);
+    payload.extend_from_slice(&gain.to_be_bytes());
+    payload.push(mapping_family);
+    if mapping_family != 0 {
+        payload.extend_from_slice(head.get(19..21 + channels as usize)?);
+    }
+
+    Some((payload, channels as u32))
+}
+
+// dfLa payload (STREAMINFO only) from a Matroska FLAC codec private
+fn dfla_from_codec_private(private: &[u8]) -> Option<Vec<u8>> {
+    let blocks = private.strip_prefix(b"fLaC")?;
+    let length = u32::from_be_bytes([0, *blocks.get(1)?, *blocks.get(2)?, *blocks.get(3)?]) as usize;
+    if blocks[0] & 0x7F != 0 {
+        return None; // STREAMINFO must come first
+    }
+
+    let mut payload = vec![0, 0, 0, 0]; // version and flags
+    payload.push(0x80); // last-metadata-block flag, type STREAMINFO
+    payload.extend_from_slice(&blocks[1..4]);
+    payload.extend_from_slice(blocks.get(4..4 + length)?);
+    Some(payload)
+}
+
+// Fill in codec_string and sample_entry for a Matroska track, given its codec ID and
+// the first frame (needed for AC-3 family headers). Tracks we can't describe in ISO
+// BMFF are left without a sample entry and are skipped when packaging.
+pub fn describe_mkv_track(track: &mut Track, codec_id: &str, first_frame: Option<&[u8]>) {
+    let private = track.codec_private.clone().unwrap_or_default();
+    let width = track.width.unwrap_or(0);
+    let height = track.height.unwrap_or(0);
+    let channels = track.channels.unwrap_or(2);
+    let rate = track.sample_rate.unwrap_or(48000);
+
+    let described: Option<(String, Vec<u8>)> = match (track.kind, track.codec.as_str()) {
+        (TrackKind::Video, "h264") => avc_codec_string("avc1", &private).map(|codec| {
+            (codec, visual_sample_entry(b"avc1", width, height, &config_box(b"avcC", &private)))
+        }),
+        (TrackKind::Video, "hevc") => hevc_codec_string("hvc1", &private).map(|codec| {
+            (codec, visual_sample_entry(b"hvc1", width, height, &config_box(b"hvcC", &private)))
+        }),
+        (TrackKind::Video, "av1") => av1_codec_string(&private).map(|codec| {
+            (codec, visual_sample_entry(b"av01", width, height, &config_box(b"av1C", &private)))
+        }),
+        (TrackKind::Audio, "aac") => {
+            let asc = if private.is_empty() {
+                synthesize_aac_config(codec_id, rate, channels)
+            } else {
+                private.clone()
+            };
+            // SBR is only signalled by the codec ID when there is no CodecPrivate
+            track.profile = if codec_id.ends_with("/SBR") {
+                Some("HE-AAC".to_string())
+            } else {
+                aac_profile(&asc)
+            };
+            if let Some((asc_rate, asc_channels)) = aac_audio_params(&asc) {
+                track.sample_rate = track.sample_rate.or(Some(asc_rate));
+                if asc_channels > 0 {
+                    track.channels = track.channels.or(Some(asc_channels));
+                }
+            }
+            aac_codec_string(&asc).map(|codec| {
+                (codec, audio_sample_entry(b"mp4a", channels, rate, &esds_box(0x40, Some(&asc))))
+            })
+        }
+        (TrackKind::Audio, "mp3") => Some((
+            "mp4a.6B".to_string(),
+            audio_sample_entry(b"mp4a", channels, rate, &esds_box(0x6B, None)),
+        )),
+        (TrackKind::Audio, "ac3") => first_frame.and_then(dac3_from_frame).map(|(dac3, rate, channels)| {
+            (
+                "ac-3".to_string(),
+                audio_sample_entry(b"ac-3", channels, rate, &config_box(b"dac3", &dac3)),
+            )
+        }),
+        (TrackKind::Audio, "eac3") => first_frame.and_then(dec3_from_frame).map(|(dec3, rate, channels)| {
+            (
+                "ec-3".to_string(),
+                audio_sample_entry(b"ec-3", channels, rate, &config_box(b"dec3", &dec3)),
+            )
+        }),
+        (TrackKind::Audio, "opus") => dops_from_opus_head(&private).map(|(dops, channels)| {
+            (
+                "opus".to_string(),
+                audio_sample_entry(b"Opus", channels, 48000, &config_box(b"dOps", &dops)),
+            )
+        }),
+        (TrackKind::Audio, "flac") => dfla_from_codec_private(&private).map(|dfla| {
+            (
+                "fLaC".to_string(),
+                audio_sample_entry(b"fLaC", channels, rate, &config_box(b"dfLa", &dfla)),
+            )
+        }),
+        _ => None,
+    };
+
+    if let Some((codec_string, sample_entry)) = described {
+        track.codec_string = Some(codec_string);
+        track.sample_entry = Some(sample_entry);
+    }
+}
+
+// Work out the RFC 6381 codecs string for an MP4 sample entry from the configuration
+// boxes inside it
+pub fn mp4_codec_string(fourcc: &[u8; 4], children: &[(String, Vec<u8>)]) -> Option<String> {
+    let child = |kind: &str| children.iter().find(|(name, _)| name == kind).map(|(_, data)| data.as_slice());
+    let fourcc_str = std::str::from_utf8(fourcc).ok()?;
+
+    match fourcc {
+        b"avc1" | b"avc3" => avc_codec_string(fourcc_str, child("avcC")?),
+        b"hvc1" | b"hev1" => hevc_codec_string(fourcc_str, child("hvcC")?),
+        b"av01" => av1_codec_string(child("av1C")?),
+        b"mp4a" => {
+            // esds is a full box, skip version and flags
+            let (object_type, dsi) = parse_esds(child("esds")?.get(4..)?)?;
+            match object_type {
+                0x40 => aac_codec_string(dsi.as_deref()?),
+                0x69 | 0x6B => Some("mp4a.6B".to_string()),
+                _ => None,
+            }
+        }
+        b"ac-3" => Some("ac-3".to_string()),
+        b"ec-3" => Some("ec-3".to_string()),
+        b"Opus" => Some("opus".to_string()),
+        b"fLaC" => Some("fLaC".to_string()),
+        b"alac" => Some("alac".to_string()),
+        _ => None,
+    }
+}
+
+// Profile name and bit depth from an AVCDecoderConfigurationRecord
+fn avc_profile(avcc: &[u8]) -> (Option<String>, Option<u32>) {
+    let Some(&profile_idc) = avcc.get(1) else {
+        return (None, None);
+    };
+    let name = match profile_idc {
+        66 => "Baseline",
+        77 => "Main",
+        88 => "Extended",
+        100 => "High",
+        110 => "High 10",
+        122 => "High 4:2:2",
+        244 => "High 4:4:4",
+        _ => return (None, None),
+    };
+
+    // Records of the high profiles can end with the chroma format and bit depths,
+    // after the parameter sets
+    let extension_depth = || {
+        let mut position = 6;
+        for _ in 0..avcc.get(5)? & 0x1F {
+            position += 2 + u16::from_be_bytes([*avcc.get(position)?, *avcc.get(position + 1)?]) as usize;
+        }
+        let pps_count = *avcc.get(position)?;
+        position += 1;
+        for _ in 0..pps_count {
+            position += 2 + u16::from_be_bytes([*avcc.get(position)?, *avcc.get(position + 1)?]) as usize;
+        }
+        Some((avcc.get(position + 1)? & 0x07) as u32 + 8)
+    };
+    let bit_depth = match profile_idc {
+        66 | 77 | 88 => Some(8),
+        _ => extension_depth().or(match profile_idc {
+            100 => Some(8),
+            110 => Some(10),
+            _ => None,
+        }),
+    };
+
+    (Some(name.to_string()), bit_depth)
+}
+
+// Profile name and bit depth from an HEVCDecoderConfigurationRecord
+fn hevc_profile(hvcc: &[u8]) -> (Option<String>, Option<u32>) {
+    let name = match hvcc.get(1).map(|byte| byte & 0x1F) {
+        Some(1) => "Main",
+        Some(2) => "Main 10",
+        Some(3) => "Main Still Picture",
+        Some(4) => "Range Extensions",
+        _ => return (None, None),
+    };
+    let bit_depth = hvcc.get(17).map(|byte| (byte & 0x07) as u32 + 8);
+
+    (Some(name.to_string()), bit_depth)
+}
+
+// Profile name and bit depth from an AV1CodecConfigurationRecord
+fn av1_profile(av1c: &[u8]) -> (Option<String>, Option<u32>) {
+    let name = match av1c.get(1).map(|byte| byte >> 5) {
+        Some(0) => "Main",
+        Some(1) => "High",
+        Some(2) => "Professional",
+        _ => return (None, None),
+    };
+    let bit_depth = av1c.get(2).map(|flags| match (flags & 0x40 != 0, flags & 0x20 != 0) {
+        (true, true) => 12,
+        (true, false) => 10,
+        _ => 8,
+    });
+
+    (Some(name.to_string()), bit_depth)
+}
+
+// Profile name of an AudioSpecificConfig
+fn aac_profile(asc: &[u8]) -> Option<String> {
+    let name = match aac_object_type(asc)? {
+        1 => "Main",
+        2 => "LC",
+        3 => "SSR",
+        4 => "LTP",
+        5 => "HE-AAC",
+        23 => "LD",
+        29 => "HE-AACv2",
+        39 => "ELD",
+        _ => return None,
+    };
+
+    Some(name.to_string())
+}
+
+// DTS-HD extensions follow the core in the same frame. They start with a substream
+// header, and a lossless (XLL) asset in the substream makes it Master Audio.
+fn dts_profile(frame: &[u8]) -> Option<String> {
+    const SUBSTREAM_SYNC: [u8; 4] = [0x64, 0x58, 0x20, 0x25];
+    const XLL_SYNC: [u8; 4] = [0x41, 0xA2, 0x95, 0x47];
+
+    let substream = frame.windows(4).position(|window| window == SUBSTREAM_SYNC)?;
+    let name = if frame[substream..].windows(4).any(|window| window == XLL_SYNC) {
+        "DTS-HD MA"
+    } else {
+        "DTS-HD HRA"
+    };
+
+    Some(name.to_string())
+}
+
+// Fill in the profile and bit depth of a track unless the container already gave
+// them. `config` is the decoder configuration (avcC, hvcC or av1C payload, or the
+// AudioSpecificConfig of AAC) and `first_frame` the first frame of the track.
+pub fn describe_profile(track: &mut Track, config: &[u8], first_frame: Option<&[u8]>) {
+    let (profile, bit_depth) = match track.codec.as_str() {
+        "h264" => avc_profile(config),
+        "hevc" => hevc_profile(config),
+        "av1" => av1_profile(config),
+        "aac" => (aac_profile(config), None),
+        "dts" => (first_frame.and_then(dts_profile), None),
+        _ => (None, None),
+    };
+
+    track.profile = track.profile.take().or(profile);
+    track.bit_depth = track.bit_depth.or(bit_depth);
+}
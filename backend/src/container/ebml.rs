@@ -0,0 +1,212 @@
+use std::io::{self, BufReader, Read, Seek};
+
+// Element IDs used by the Matroska reader, with their class marker bits kept as in
+// the specification
+pub const EBML: u32 = 0x1A45_DFA3;
+pub const DOC_TYPE: u32 = 0x4282;
+pub const SEGMENT: u32 = 0x1853_8067;
+pub const SEEK_HEAD: u32 = 0x114D_9B74;
+pub const SEEK: u32 = 0x4DBB;
+pub const SEEK_ID: u32 = 0x53AB;
+pub const SEEK_POSITION: u32 = 0x53AC;
+pub const INFO: u32 = 0x1549_A966;
+pub const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+pub const DURATION: u32 = 0x4489;
+pub const TRACKS: u32 = 0x1654_AE6B;
+pub const TRACK_ENTRY: u32 = 0xAE;
+pub const TRACK_NUMBER: u32 = 0xD7;
+pub const TRACK_TYPE: u32 = 0x83;
+pub const FLAG_DEFAULT: u32 = 0x88;
+pub const FLAG_FORCED: u32 = 0x55AA;
+pub const DEFAULT_DURATION: u32 = 0x23_E383;
+pub const NAME: u32 = 0x536E;
+pub const LANGUAGE: u32 = 0x22_B59C;
+pub const LANGUAGE_BCP47: u32 = 0x22_B59D;
+pub const CODEC_ID: u32 = 0x86;
+pub const CODEC_PRIVATE: u32 = 0x63A2;
+pub const CONTENT_ENCODINGS: u32 = 0x6D80;
+pub const VIDEO: u32 = 0xE0;
+pub const PIXEL_WIDTH: u32 = 0xB0;
+pub const PIXEL_HEIGHT: u32 = 0xBA;
+pub const COLOUR: u32 = 0x55B0;
+pub const BITS_PER_CHANNEL: u32 = 0x55B2;
+pub const TRANSFER_CHARACTERISTICS: u32 = 0x55BA;
+pub const BLOCK_ADDITION_MAPPING: u32 = 0x41E4;
+pub const BLOCK_ADD_ID_TYPE: u32 = 0x41E7;
+pub const AUDIO: u32 = 0xE1;
+pub const SAMPLING_FREQUENCY: u32 = 0xB5;
+pub const CHANNELS: u32 = 0x9F;
+pub const CLUSTER: u32 = 0x1F43_B675;
+pub const CLUSTER_TIMESTAMP: u32 = 0xE7;
+pub const SIMPLE_BLOCK: u32 = 0xA3;
+pub const BLOCK_GROUP: u32 = 0xA0;
+pub const BLOCK: u32 = 0xA1;
+pub const BLOCK_DURATION: u32 = 0x9B;
+pub const REFERENCE_BLOCK: u32 = 0xFB;
+pub const CUES: u32 = 0x1C53_BB6B;
+pub const CHAPTERS: u32 = 0x1043_A770;
+pub const EDITION_ENTRY: u32 = 0x45B9;
+pub const EDITION_FLAG_HIDDEN: u32 = 0x45BD;
+pub const EDITION_FLAG_DEFAULT: u32 = 0x45DB;
+pub const CHAPTER_ATOM: u32 = 0xB6;
+pub const CHAPTER_TIME_START: u32 = 0x91;
+pub const CHAPTER_TIME_END: u32 = 0x92;
+pub const CHAPTER_FLAG_HIDDEN: u32 = 0x98;
+pub const CHAPTER_FLAG_ENABLED: u32 = 0x4598;
+pub const CHAPTER_DISPLAY: u32 = 0x80;
+pub const CHAP_STRING: u32 = 0x85;
+pub const TAGS: u32 = 0x1254_C367;
+pub const ATTACHMENTS: u32 = 0x1941_A469;
+
+// Size of the read buffer. Small on purpose: when indexing clusters most of the file
+// is skipped over and a larger buffer would just read frame data we never look at.
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// Header of an EBML element, `size` is None for elements of unknown size
+#[derive(Debug, Clone, Copy)]
+pub struct ElementHeader {
+    pub id: u32,
+    pub size: Option<u64>,
+    pub data_offset: u64,
+}
+
+impl ElementHeader {
+    // Offset just past this element, if its size is known
+    pub fn end(&self) -> Option<u64> {
+        self.size.map(|size| self.data_offset + size)
+    }
+}
+
+// Buffered reader that keeps track of its own position so that seeking within the
+// buffer doesn't cost a syscall
+pub struct EbmlReader<R> {
+    inner: BufReader<R>,
+    position: u64,
+}
+
+impl<R: Read + Seek> EbmlReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::with_capacity(BUFFER_SIZE, inner),
+            position: 0,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        let delta = offset as i64 - self.position as i64;
+        self.inner.seek_relative(delta)?;
+        self.position = offset;
+        Ok(())
+    }
+
+    pub fn skip(&mut self, count: u64) -> io::Result<()> {
+        self.seek_to(self.position + count)
+    }
+
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf)?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        self.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Read a variable-length integer, returning its value and encoded length. The
+    // marker bit is removed; an all-ones value (reserved for "unknown") is returned
+    // as u64::MAX.
+    pub fn read_vint(&mut self) -> io::Result<(u64, usize)> {
+        let first = self.read_u8()?;
+        let length = first.leading_zeros() as usize + 1;
+        if length > 8 {
+            return Err(invalid("Invalid EBML variable-length integer"));
+        }
+
+        let mut value = (first as u64) & (0xFF >> length);
+        let mut all_ones = value == (0xFF >> length);
+        for _ in 1..length {
+            let byte = self.read_u8()?;
+            all_ones &= byte == 0xFF;
+            value = (value << 8) | byte as u64;
+        }
+
+        Ok((if all_ones { u64::MAX } else { value }, length))
+    }
+
+    // Read an element ID, keeping its marker bits
+    pub fn read_id(&mut self) -> io::Result<u32> {
+        let first = self.read_u8()?;
+        let length = first.leading_zeros() as usize + 1;
+        if length > 4 {
+            return Err(invalid("Invalid EBML element ID"));
+        }
+
+        let mut id = first as u32;
+        for _ in 1..length {
+            id = (id << 8) | self.read_u8()? as u32;
+        }
+
+        Ok(id)
+    }
+
+    pub fn read_element_header(&mut self) -> io::Result<ElementHeader> {
+        let id = self.read_id()?;
+        let (size, _) = self.read_vint()?;
+
+        Ok(ElementHeader {
+            id,
+            size: if size == u64::MAX { None } else { Some(size) },
+            data_offset: self.position,
+        })
+    }
+
+    pub fn read_uint(&mut self, size: u64) -> io::Result<u64> {
+        if size > 8 {
+            return Err(invalid("EBML unsigned integer longer than 8 bytes"));
+        }
+
+        let mut value = 0u64;
+        for _ in 0..size {
+            value = (value << 8) | self.read_u8()? as u64;
+        }
+
+        Ok(value)
+    }
+
+    pub fn read_float(&mut self, size: u64) -> io::Result<f64> {
+        match size {
+            0 => Ok(0.0),
+            4 => Ok(f32::from_bits(self.read_uint(4)? as u32) as f64),
+            8 => Ok(f64::from_bits(self.read_uint(8)?)),
+            _ => Err(invalid("EBML float must be 4 or 8 bytes")),
+        }
+    }
+
+    pub fn read_binary(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        // Guard against corrupt sizes asking for gigabytes
+        if size > 16 * 1024 * 1024 {
+            return Err(invalid("EBML binary element too large"));
+        }
+
+        let mut data = vec![0u8; size as usize];
+        self.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn read_string(&mut self, size: u64) -> io::Result<String> {
+        let data = self.read_binary(size)?;
+        let text = String::from_utf8_lossy(&data);
+        Ok(text.trim_end_matches('\0').to_string())
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,303 @@
+use crate::container::index::{Sample, Track, TrackKind};
+
+// sample_flags for sync samples (depends on nothing) and for everything else
+// (depends on other samples, not a sync sample), ISO/IEC 14496-12 section 8.8.3
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+// Each remuxed track is written as the only track of its own fMP4 stream. Files with
+// several tracks number them from 1 in the order given.
+const OUTPUT_TRACK_ID: u32 = 1;
+
+pub fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+// Append a box, patching its size once the body has been written
+pub fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    put_u32(out, 0);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+pub fn write_full_box(out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(out, kind, |out| {
+        put_u32(out, (version as u32) << 24 | (flags & 0x00FF_FFFF));
+        body(out);
+    })
+}
+
+fn write_matrix(out: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000u32] {
+        put_u32(out, value);
+    }
+}
+
+// Pack an ISO 639-2/T code into the 15 bits mdhd uses, "und" when unknown
+fn packed_language(language: Option<&str>) -> u16 {
+    let code = language
+        .filter(|code| code.len() == 3 && code.bytes().all(|b| b.is_ascii_lowercase()))
+        .unwrap_or("und");
+
+    code.bytes()
+        .fold(0u16, |packed, letter| (packed << 5) | (letter - 0x60) as u16)
+}
+
+pub fn write_ftyp(out: &mut Vec<u8>) {
+    write_box(out, b"ftyp", |out| {
+        out.extend_from_slice(b"iso6");
+        put_u32(out, 0);
+        for brand in [b"iso6", b"mp41", b"cmfc", b"dash"] {
+            out.extend_from_slice(brand);
+        }
+    });
+}
+
+// Build the initialization segment (ftyp + moov) for one track. Returns None for
+// tracks without a sample entry.
+pub fn init_segment(track: &Track) -> Option<Vec<u8>> {
+    movie_header(&[track], None)
+}
+
+// ftyp + moov of a fragmented file holding `tracks`, with track IDs 1, 2, ... in that
+// order. A known duration is written to mvhd and mehd so that players of progressive
+// files can show it before reaching the last fragment. Returns None if a track has no
+// sample entry.
+pub fn movie_header(tracks: &[&Track], duration: Option<f64>) -> Option<Vec<u8>> {
+    let mut sample_entries = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        sample_entries.push(track.sample_entry.as_deref()?);
+    }
+
+    let entries_size: usize = sample_entries.iter().map(|entry| entry.len()).sum();
+    let duration_ms = duration.map_or(0, |seconds| (seconds * 1000.0).round() as u32);
+    let mut out = Vec::with_capacity(1024 * tracks.len() + entries_size);
+
+    write_ftyp(&mut out);
+    write_box(&mut out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            put_u32(out, 0); // creation_time
+            put_u32(out, 0); // modification_time
+            put_u32(out, 1000); // timescale
+            put_u32(out, duration_ms); // duration, 0 when unknown
+            put_u32(out, 0x0001_0000); // rate
+            put_u16(out, 0x0100); // volume
+            out.extend_from_slice(&[0; 10]);
+            write_matrix(out);
+            out.extend_from_slice(&[0; 24]);
+            put_u32(out, OUTPUT_TRACK_ID + tracks.len() as u32); // next_track_ID
+        });
+
+        for (index, (track, sample_entry)) in tracks.iter().zip(&sample_entries).enumerate() {
+            write_trak(out, track, OUTPUT_TRACK_ID + index as u32, sample_entry);
+        }
+
+        write_box(out, b"mvex", |out| {
+            if duration_ms > 0 {
+                write_full_box(out, b"mehd", 0, 0, |out| put_u32(out, duration_ms));
+            }
+            for index in 0..tracks.len() {
+                write_full_box(out, b"trex", 0, 0, |out| {
+                    put_u32(out, OUTPUT_TRACK_ID + index as u32);
+                    put_u32(out, 1); // default_sample_description_index
+                    put_u32(out, 0);
+                    put_u32(out, 0);
+                    put_u32(out, 0);
+                });
+            }
+        });
+    });
+
+    Some(out)
+}
+
+fn write_trak(out: &mut Vec<u8>, track: &Track, track_id: u32, sample_entry: &[u8]) {
+    write_box(out, b"trak", |out| {
+        write_full_box(out, b"tkhd", 0, 0x3, |out| {
+            put_u32(out, 0);
+            put_u32(out, 0);
+            put_u32(out, track_id);
+            put_u32(out, 0);
+            put_u32(out, 0); // duration
+            out.extend_from_slice(&[0; 8]);
+            put_u16(out, 0); // layer
+            put_u16(out, 0); // alternate_group
+            put_u16(out, if track.kind == TrackKind::Audio { 0x0100 } else { 0 });
+            put_u16(out, 0);
+            write_matrix(out);
+            put_u32(out, track.width.unwrap_or(0) << 16);
+            put_u32(out, track.height.unwrap_or(0) << 16);
+        });
+
+        write_box(out, b"mdia", |out| {
+            write_full_box(out, b"mdhd", 0, 0, |out| {
+                put_u32(out, 0);
+                put_u32(out, 0);
+                put_u32(out, track.timescale);
+                put_u32(out, 0);
+                put_u16(out, packed_language(track.language.as_deref()));
+                put_u16(out, 0);
+            });
+
+            let (handler, name): (&[u8; 4], &[u8]) = match track.kind {
+                TrackKind::Video => (b"vide", b"VideoHandler\0"),
+                TrackKind::Audio => (b"soun", b"SoundHandler\0"),
+                TrackKind::Subtitle => (b"text", b"TextHandler\0"),
+            };
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                put_u32(out, 0);
+                out.extend_from_slice(handler);
+                out.extend_from_slice(&[0; 12]);
+                out.extend_from_slice(name);
+            });
+
+            write_box(out, b"minf", |out| {
+                match track.kind {
+                    TrackKind::Video => write_full_box(out, b"vmhd", 0, 1, |out| {
+                        out.extend_from_slice(&[0; 8]);
+                    }),
+                    TrackKind::Audio => write_full_box(out, b"smhd", 0, 0, |out| {
+                        put_u32(out, 0);
+                    }),
+                    TrackKind::Subtitle => write_full_box(out, b"nmhd", 0, 0, |_| {}),
+                }
+
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        put_u32(out, 1);
+                        write_full_box(out, b"url ", 0, 1, |_| {});
+                    });
+                });
+
+                write_box(out, b"stbl", |out| {
+                    write_full_box(out, b"stsd", 0, 0, |out| {
+                        put_u32(out, 1);
+                        out.extend_from_slice(sample_entry);
+                    });
+                    write_full_box(out, b"stts", 0, 0, |out| put_u32(out, 0));
+                    write_full_box(out, b"stsc", 0, 0, |out| put_u32(out, 0));
+                    write_full_box(out, b"stsz", 0, 0, |out| {
+                        put_u32(out, 0);
+                        put_u32(out, 0);
+                    });
+                    write_full_box(out, b"stco", 0, 0, |out| put_u32(out, 0));
+                });
+            });
+        });
+    });
+}
+
+// Build the moof box and mdat header of a media segment. The sample data itself
+// follows the returned bytes, in the order of `samples`.
+pub fn segment_header(samples: &[Sample], sequence: u32) -> Vec<u8> {
+    fragment_header(&[samples], sequence)
+}
+
+// moof and mdat header of a fragment with one run of samples per track, track IDs
+// numbered like in movie_header. The mdat holds the runs back to back in that order;
+// tracks without samples in this fragment get no traf.
+pub fn fragment_header(runs: &[&[Sample]], sequence: u32) -> Vec<u8> {
+    let sample_count: usize = runs.iter().map(|run| run.len()).sum();
+    let mut out = Vec::with_capacity(64 + runs.len() * 64 + sample_count * 16);
+    // Where each trun's data_offset goes, and how far into the mdat payload its run starts
+    let mut data_offsets = Vec::with_capacity(runs.len());
+    let mut payload = 0u64;
+
+    write_box(&mut out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| put_u32(out, sequence));
+
+        for (index, samples) in runs.iter().enumerate() {
+            if samples.is_empty() {
+                continue;
+            }
+            let base_decode_time = samples[0].dts.max(0) as u64;
+
+            write_box(out, b"traf", |out| {
+                // default-base-is-moof: data offsets are relative to the start of moof
+                write_full_box(out, b"tfhd", 0, 0x02_0000, |out| put_u32(out, OUTPUT_TRACK_ID + index as u32));
+                write_full_box(out, b"tfdt", 1, 0, |out| put_u64(out, base_decode_time));
+
+                // data-offset, sample-duration, sample-size, sample-flags and signed
+                // composition time offsets for every sample
+                write_full_box(out, b"trun", 1, 0x00_0F01, |out| {
+                    put_u32(out, samples.len() as u32);
+                    data_offsets.push((out.len(), payload));
+                    put_u32(out, 0);
+
+                    for sample in samples.iter() {
+                        put_u32(out, sample.duration);
+                        put_u32(out, sample.size);
+                        put_u32(out, if sample.keyframe { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS });
+                        out.extend_from_slice(&sample.cts_offset.to_be_bytes());
+                    }
+                });
+            });
+
+            payload += samples.iter().map(|sample| sample.size as u64).sum::<u64>();
+        }
+    });
+
+    let large = payload + 8 > u32::MAX as u64;
+    let mdat_header_size = if large { 16 } else { 8 };
+
+    for (position, run_offset) in data_offsets {
+        let data_offset = (out.len() as u64 + mdat_header_size + run_offset) as u32;
+        out[position..position + 4].copy_from_slice(&data_offset.to_be_bytes());
+    }
+
+    if large {
+        put_u32(&mut out, 1);
+        out.extend_from_slice(b"mdat");
+        put_u64(&mut out, payload + 16);
+    } else {
+        put_u32(&mut out, (payload + 8) as u32);
+        out.extend_from_slice(b"mdat");
+    }
+
+    out
+}
+
+// One fragment as listed in a segment index
+pub struct SidxReference {
+    // moof + mdat, in bytes
+    pub size: u32,
+    // In the timescale of the reference track
+    pub duration: u32,
+}
+
+// Size of a sidx box with `references` entries, for laying out a file before writing it
+pub fn sidx_size(references: usize) -> usize {
+    // full box header, reference_ID, timescale, 64-bit times, reserved, reference_count
+    12 + 8 + 16 + 4 + references * 12
+}
+
+// Segment index of the fragments that directly follow it, letting players of
+// progressive fMP4 files map a seek time to a byte offset. Times are those of track 1.
+pub fn write_sidx(out: &mut Vec<u8>, timescale: u32, earliest_presentation_time: u64, references: &[SidxReference]) {
+    write_full_box(out, b"sidx", 1, 0, |out| {
+        put_u32(out, OUTPUT_TRACK_ID); // reference_ID
+        put_u32(out, timescale);
+        put_u64(out, earliest_presentation_time);
+        put_u64(out, 0); // first_offset
+        put_u16(out, 0);
+        put_u16(out, references.len() as u16);
+
+        for reference in references {
+            // reference_type 0 (media), starts_with_SAP, SAP_type 1
+            put_u32(out, reference.size & 0x7FFF_FFFF);
+            put_u32(out, reference.duration);
+            put_u32(out, 0x9000_0000);
+        }
+    });
+}
@@ -0,0 +1,455 @@
+use std::collections::VecDeque;
+use std::io::{Read, Seek};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::container::{mkv, mp4};
+use crate::error::{AppError, Result};
+use crate::streaming::conditional;
+use crate::streaming::file::validate_file;
+
+// Segments are cut at the first keyframe at least this far after the previous cut
+pub const TARGET_SEGMENT_SECONDS: f64 = 6.0;
+
+// How many parsed files to keep in memory. Indexes of long MKVs hold a few hundred
+// thousand samples, so this is kept small.
+const MAX_CACHED_INDEXES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Matroska,
+}
+
+impl Container {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Matroska => "matroska",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+impl TrackKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackKind::Video => "video",
+            TrackKind::Audio => "audio",
+            TrackKind::Subtitle => "subtitle",
+        }
+    }
+}
+
+// High dynamic range signalling of a video track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HdrFormat {
+    Hdr10,
+    Hlg,
+    DolbyVision,
+}
+
+impl HdrFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HdrFormat::Hdr10 => "hdr10",
+            HdrFormat::Hlg => "hlg",
+            HdrFormat::DolbyVision => "dolby_vision",
+        }
+    }
+
+    // From the transfer characteristics code point of ISO/IEC 23091-2, which MP4 colr
+    // boxes and Matroska Colour elements both use
+    pub fn from_transfer(transfer: u64) -> Option<Self> {
+        match transfer {
+            16 => Some(HdrFormat::Hdr10),
+            18 => Some(HdrFormat::Hlg),
+            _ => None,
+        }
+    }
+}
+
+// One coded frame (or subtitle event) and where its bytes live in the source file.
+// Timestamps are in the track's timescale.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub offset: u64,
+    pub size: u32,
+    pub dts: i64,
+    pub cts_offset: i32,
+    pub duration: u32,
+    pub keyframe: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Track {
+    // Track number (Matroska) or track ID (MP4) in the source file
+    pub number: u32,
+    pub kind: TrackKind,
+    // Short codec name such as h264, hevc, aac, ac3 or subrip, or the lowercased source
+    // codec ID when the codec is not one we know
+    pub codec: String,
+    // RFC 6381 codecs parameter, e.g. "avc1.640028", for tracks we can package
+    pub codec_string: Option<String>,
+    // ISO BMFF sample entry box used to build fMP4 init segments
+    pub sample_entry: Option<Vec<u8>>,
+    pub codec_private: Option<Vec<u8>>,
+    pub timescale: u32,
+    pub language: Option<String>,
+    pub name: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    // Codec profile, e.g. "High", "Main 10", "HE-AAC" or "DTS-HD MA"
+    pub profile: Option<String>,
+    pub bit_depth: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub hdr: Option<HdrFormat>,
+    pub samples: Vec<Sample>,
+}
+
+impl Track {
+    pub fn new(number: u32, kind: TrackKind, codec: &str, timescale: u32) -> Self {
+        Self {
+            number,
+            kind,
+            codec: codec.to_string(),
+            codec_string: None,
+            sample_entry: None,
+            codec_private: None,
+            timescale,
+            language: None,
+            name: None,
+            default: false,
+            forced: false,
+            width: None,
+            height: None,
+            sample_rate: None,
+            channels: None,
+            profile: None,
+            bit_depth: None,
+            frame_rate: None,
+            hdr: None,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn seconds(&self, ticks: i64) -> f64 {
+        ticks as f64 / self.timescale as f64
+    }
+
+    // Whether the track can be written into fMP4 segments without transcoding
+    pub fn is_packageable(&self) -> bool {
+        self.sample_entry.is_some() && !self.samples.is_empty()
+    }
+
+    // Presentation end of the last sample, in seconds
+    pub fn end_seconds(&self) -> f64 {
+        self.samples
+            .last()
+            .map_or(0.0, |last| self.seconds(last.dts + last.duration as i64))
+    }
+
+    // Total size of the samples in `range`, in bytes
+    pub fn byte_size(&self, range: Range<usize>) -> u64 {
+        self.samples[range].iter().map(|sample| sample.size as u64).sum()
+    }
+}
+
+// Where each segment starts and ends. Cuts follow the keyframes of the primary video
+// track; other tracks are split at the same times.
+#[derive(Debug, Clone)]
+pub struct SegmentPlan {
+    pub boundaries: Vec<f64>,
+    primary: Option<u32>,
+    primary_cuts: Vec<usize>,
+}
+
+impl SegmentPlan {
+    fn new(tracks: &[Track], duration: f64) -> Self {
+        let end = tracks
+            .iter()
+            .filter(|track| track.is_packageable())
+            .map(Track::end_seconds)
+            .fold(duration, f64::max);
+
+        let primary = tracks
+            .iter()
+            .find(|track| track.kind == TrackKind::Video && track.is_packageable());
+
+        let mut boundaries = vec![0.0];
+        let mut primary_cuts = Vec::new();
+
+        match primary {
+            Some(video) => {
+                primary_cuts.push(0);
+                for (index, sample) in video.samples.iter().enumerate().skip(1) {
+                    let time = video.seconds(sample.dts);
+                    let last = *boundaries.last().unwrap_or(&0.0);
+                    if sample.keyframe && time - last >= TARGET_SEGMENT_SECONDS {
+                        boundaries.push(time);
+                        primary_cuts.push(index);
+                    }
+                }
+                primary_cuts.push(video.samples.len());
+            }
+            None => {
+                let mut time = TARGET_SEGMENT_SECONDS;
+                while time < end {
+                    boundaries.push(time);
+                    time += TARGET_SEGMENT_SECONDS;
+                }
+            }
+        }
+
+        let last = *boundaries.last().unwrap_or(&0.0);
+        boundaries.push(end.max(last + 0.001));
+
+        Self {
+            boundaries,
+            primary: primary.map(|track| track.number),
+            primary_cuts,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.boundaries.len() - 1
+    }
+
+    // End of the last segment, i.e. the duration of the presentation
+    pub fn end(&self) -> f64 {
+        *self.boundaries.last().unwrap_or(&0.0)
+    }
+
+    pub fn duration(&self, segment: usize) -> f64 {
+        self.boundaries[segment + 1] - self.boundaries[segment]
+    }
+
+    // Indices of the samples of `track` that belong to `segment`
+    pub fn sample_range(&self, track: &Track, segment: usize) -> Range<usize> {
+        if self.primary == Some(track.number) {
+            return self.primary_cuts[segment]..self.primary_cuts[segment + 1];
+        }
+
+        let index_at = |boundary: usize| {
+            if boundary == 0 {
+                0
+            } else if boundary == self.len() {
+                track.samples.len()
+            } else {
+                let time = self.boundaries[boundary];
+                track.samples.partition_point(|sample| track.seconds(sample.dts) < time)
+            }
+        };
+
+        index_at(segment)..index_at(segment + 1)
+    }
+}
+
+// A chapter declared by the container, times in seconds
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
+}
+
+// Chapters as read from a container: start, end if given, and title
+pub type Chapters = Vec<(f64, Option<f64>, Option<String>)>;
+
+// Everything known about the streams of a media file
+#[derive(Debug, Clone)]
+pub struct MediaIndex {
+    pub container: Container,
+    // Duration declared by the container, or the end of the last sample if later
+    pub duration: f64,
+    pub tracks: Vec<Track>,
+    pub chapters: Vec<Chapter>,
+    pub segments: SegmentPlan,
+}
+
+impl MediaIndex {
+    pub fn new(container: Container, duration: f64, tracks: Vec<Track>) -> Self {
+        let segments = SegmentPlan::new(&tracks, duration);
+
+        Self {
+            container,
+            duration,
+            tracks,
+            chapters: Vec::new(),
+            segments,
+        }
+    }
+
+    // Add the chapters read from the container. Missing ends are taken from the next
+    // chapter or the end of the file, and chapters past the end are dropped.
+    pub fn with_chapters(mut self, mut chapters: Chapters) -> Self {
+        chapters.retain(|(start, _, _)| start.is_finite() && *start >= 0.0);
+        chapters.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let starts: Vec<f64> = chapters.iter().map(|(start, _, _)| *start).collect();
+        for (index, (start, end, title)) in chapters.into_iter().enumerate() {
+            let next = starts.get(index + 1).copied().unwrap_or(self.duration);
+            let end = end.filter(|end| *end > start).unwrap_or(next);
+            if self.duration > 0.0 && start >= self.duration {
+                break;
+            }
+
+            self.chapters.push(Chapter {
+                start,
+                end: if self.duration > 0.0 { end.min(self.duration) } else { end },
+                title: title.map(|title| title.trim().to_string()).filter(|title| !title.is_empty()),
+            });
+        }
+
+        self
+    }
+
+    pub fn track(&self, number: u32) -> Option<&Track> {
+        self.tracks.iter().find(|track| track.number == number)
+    }
+
+    // Peak and average bitrate of a track over the segment plan, in bits per second
+    pub fn bitrate(&self, track: &Track) -> (u64, u64) {
+        let plan = &self.segments;
+        let mut peak = 0.0f64;
+        let mut total_bytes = 0u64;
+
+        for segment in 0..plan.len() {
+            let bytes = track.byte_size(plan.sample_range(track, segment));
+            total_bytes += bytes;
+            let duration = plan.duration(segment);
+            if duration > 0.0 {
+                peak = peak.max(bytes as f64 * 8.0 / duration);
+            }
+        }
+
+        let average = if plan.end() > 0.0 {
+            total_bytes as f64 * 8.0 / plan.end()
+        } else {
+            0.0
+        };
+
+        (peak.ceil() as u64, average.ceil() as u64)
+    }
+
+    // Tracks that can be remuxed into fMP4, in source order
+    pub fn packageable_tracks(&self, kind: TrackKind) -> impl Iterator<Item = &Track> {
+        self.tracks
+            .iter()
+            .filter(move |track| track.kind == kind && track.is_packageable())
+    }
+}
+
+// Identify the container from the first bytes of a file
+pub fn detect_container(header: &[u8]) -> Option<Container> {
+    if header.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Some(Container::Matroska);
+    }
+
+    match header.get(4..8) {
+        Some(b"ftyp") | Some(b"moov") | Some(b"mdat") | Some(b"free") | Some(b"wide") => Some(Container::Mp4),
+        _ => None,
+    }
+}
+
+fn read_file(path: &Path, with_samples: bool) -> std::io::Result<MediaIndex> {
+    let mut header = [0u8; 16];
+    let mut file = std::fs::File::open(path)?;
+    let read = file.read(&mut header)?;
+    file.rewind()?;
+
+    match detect_container(&header[..read]) {
+        Some(Container::Mp4) => mp4::read(file, with_samples),
+        Some(Container::Matroska) => mkv::read(file, with_samples),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unsupported container, only MP4 and Matroska can be read",
+        )),
+    }
+}
+
+// Parse a media file and index every sample of every track. Blocking.
+pub fn index_file(path: &Path) -> std::io::Result<MediaIndex> {
+    read_file(path, true)
+}
+
+// Read only the track layout of a media file, for probing during scans. Blocking.
+pub fn probe_file(path: &Path) -> std::io::Result<MediaIndex> {
+    read_file(path, false)
+}
+
+// A parsed file together with the validators of the version it was parsed from
+pub struct IndexedFile {
+    pub path: PathBuf,
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+    pub index: MediaIndex,
+}
+
+// Small LRU of parsed files shared by the HLS, DASH and remux endpoints, so that a
+// player fetching segments one by one doesn't cause the file to be re-parsed each time
+#[derive(Default)]
+pub struct IndexCache {
+    entries: Mutex<VecDeque<Arc<IndexedFile>>>,
+}
+
+impl IndexCache {
+    pub async fn get(&self, path: &Path) -> Result<Arc<IndexedFile>> {
+        let metadata = validate_file(path).await?;
+        let last_modified = metadata.modified().ok();
+        let etag = conditional::file_etag(metadata.len(), last_modified);
+
+        if let Some(hit) = self.lookup(path, &etag) {
+            return Ok(hit);
+        }
+
+        let owned_path = path.to_path_buf();
+        let index = tokio::task::spawn_blocking(move || index_file(&owned_path))
+            .await
+            .map_err(|e| AppError::Server(format!("Indexing task failed: {}", e)))?
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::InvalidData | std::io::ErrorKind::Unsupported => {
+                    AppError::InvalidInput(format!("Cannot remux {}: {}", path.display(), e))
+                }
+                _ => AppError::Io(e),
+            })?;
+
+        let indexed = Arc::new(IndexedFile {
+            path: path.to_path_buf(),
+            etag,
+            last_modified,
+            index,
+        });
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|entry| entry.path != path);
+        entries.push_front(indexed.clone());
+        entries.truncate(MAX_CACHED_INDEXES);
+
+        Ok(indexed)
+    }
+
+    fn lookup(&self, path: &Path, etag: &str) -> Option<Arc<IndexedFile>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let position = entries
+            .iter()
+            .position(|entry| entry.path == path && entry.etag == etag)?;
+
+        // Move to the front so the least recently used entry is evicted first
+        let entry = entries.remove(position)?;
+        entries.push_front(entry.clone());
+        Some(entry)
+    }
+}
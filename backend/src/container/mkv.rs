@@ -0,0 +1,741 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+use crate::container::codec;
+use crate::container::ebml::{self, invalid, EbmlReader, ElementHeader};
+use crate::container::index::{Chapters, Container, HdrFormat, MediaIndex, Sample, Track, TrackKind};
+
+// Timescales used for remuxed tracks. Matroska stores times in nanoseconds (scaled by
+// TimestampScale), which doesn't fit the 32-bit durations of fMP4.
+const VIDEO_TIMESCALE: u32 = 90_000;
+const SUBTITLE_TIMESCALE: u32 = 1000;
+
+// BlockAddIDType values of the Dolby Vision configuration records
+const DOLBY_VISION_MAPPINGS: [u64; 3] = [
+    u32::from_be_bytes(*b"dvcC") as u64,
+    u32::from_be_bytes(*b"dvvC") as u64,
+    u32::from_be_bytes(*b"dvwC") as u64,
+];
+
+// How much of the first frame of a track is read to describe its codec
+const FIRST_FRAME_BYTES: u64 = 16 * 1024;
+
+// Level 1 elements; seeing one of these ends a cluster of unknown size
+const TOP_LEVEL_IDS: [u32; 8] = [
+    ebml::CLUSTER,
+    ebml::CUES,
+    ebml::TAGS,
+    ebml::CHAPTERS,
+    ebml::ATTACHMENTS,
+    ebml::TRACKS,
+    ebml::INFO,
+    ebml::SEEK_HEAD,
+];
+
+// A track as declared in the Tracks element
+struct TrackEntry {
+    track: Track,
+    codec_id: String,
+    default_duration: Option<u64>,
+    encoded: bool,
+}
+
+// A frame as found in a cluster, before timestamps are turned into samples
+struct Frame {
+    time: i64,
+    offset: u64,
+    size: u32,
+    keyframe: bool,
+    duration: Option<u64>,
+    // Position within a laced block, frames after the first get interpolated times
+    lace_index: u16,
+    lace_count: u16,
+}
+
+fn ticks(nanoseconds: i64, timescale: u32) -> i64 {
+    ((nanoseconds as i128 * timescale as i128 + 500_000_000) / 1_000_000_000) as i64
+}
+
+fn read_track_entry(reader: &mut EbmlReader<File>, end: u64) -> io::Result<Option<TrackEntry>> {
+    let mut number = 0u32;
+    let mut track_type = 0u64;
+    let mut codec_id = String::new();
+    let mut codec_private = None;
+    let mut default = true;
+    let mut forced = false;
+    let mut default_duration = None;
+    let mut name = None;
+    let mut language = None;
+    let mut language_bcp47 = None;
+    let mut encoded = false;
+    let mut width = None;
+    let mut height = None;
+    let mut sample_rate = None;
+    let mut channels = None;
+    let mut bit_depth = None;
+    let mut transfer = None;
+    let mut dolby_vision = false;
+
+    while reader.position() < end {
+        let header = reader.read_element_header()?;
+        let size = header.size.ok_or_else(|| invalid("Unknown-size element in TrackEntry"))?;
+
+        match header.id {
+            ebml::TRACK_NUMBER => number = reader.read_uint(size)? as u32,
+            ebml::TRACK_TYPE => track_type = reader.read_uint(size)?,
+            ebml::CODEC_ID => codec_id = reader.read_string(size)?,
+            ebml::CODEC_PRIVATE => codec_private = Some(reader.read_binary(size)?),
+            ebml::FLAG_DEFAULT => default = reader.read_uint(size)? != 0,
+            ebml::FLAG_FORCED => forced = reader.read_uint(size)? != 0,
+            ebml::DEFAULT_DURATION => default_duration = Some(reader.read_uint(size)?),
+            ebml::NAME => name = Some(reader.read_string(size)?),
+            ebml::LANGUAGE => language = Some(reader.read_string(size)?),
+            ebml::LANGUAGE_BCP47 => language_bcp47 = Some(reader.read_string(size)?),
+            ebml::CONTENT_ENCODINGS => {
+                encoded = true;
+                reader.skip(size)?;
+            }
+            ebml::VIDEO => {
+                let video_end = header.data_offset + size;
+                while reader.position() < video_end {
+                    let child = reader.read_element_header()?;
+                    let child_size = child.size.ok_or_else(|| invalid("Unknown-size element in Video"))?;
+                    match child.id {
+                        ebml::PIXEL_WIDTH => width = Some(reader.read_uint(child_size)? as u32),
+                        ebml::PIXEL_HEIGHT => height = Some(reader.read_uint(child_size)? as u32),
+                        ebml::COLOUR => {
+                            let colour_end = child.data_offset + child_size;
+                            while reader.position() < colour_end {
+                                let field = reader.read_element_header()?;
+                                let field_size =
+                                    field.size.ok_or_else(|| invalid("Unknown-size element in Colour"))?;
+                                match field.id {
+                                    ebml::BITS_PER_CHANNEL => {
+                                        bit_depth = Some(reader.read_uint(field_size)? as u32).filter(|bits| *bits > 0)
+                                    }
+                                    ebml::TRANSFER_CHARACTERISTICS => transfer = Some(reader.read_uint(field_size)?),
+                                    _ => reader.skip(field_size)?,
+                                }
+                            }
+                        }
+                        _ => reader.skip(child_size)?,
+                    }
+                }
+            }
+            ebml::BLOCK_ADDITION_MAPPING => {
+                let mapping_end = header.data_offset + size;
+                while reader.position() < mapping_end {
+                    let child = reader.read_element_header()?;
+                    let child_size =
+                        child.size.ok_or_else(|| invalid("Unknown-size element in BlockAdditionMapping"))?;
+                    match child.id {
+                        ebml::BLOCK_ADD_ID_TYPE => {
+                            dolby_vision |= DOLBY_VISION_MAPPINGS.contains(&reader.read_uint(child_size)?)
+                        }
+                        _ => reader.skip(child_size)?,
+                    }
+                }
+            }
+            ebml::AUDIO => {
+                let audio_end = header.data_offset + size;
+                while reader.position() < audio_end {
+                    let child = reader.read_element_header()?;
+                    let child_size = child.size.ok_or_else(|| invalid("Unknown-size element in Audio"))?;
+                    match child.id {
+                        ebml::SAMPLING_FREQUENCY => sample_rate = Some(reader.read_float(child_size)? as u32),
+                        ebml::CHANNELS => channels = Some(reader.read_uint(child_size)? as u32),
+                        _ => reader.skip(child_size)?,
+                    }
+                }
+            }
+            _ => reader.skip(size)?,
+        }
+    }
+
+    let (kind, timescale) = match track_type {
+        1 => (TrackKind::Video, VIDEO_TIMESCALE),
+        2 => (TrackKind::Audio, sample_rate.filter(|rate| *rate > 0).unwrap_or(48_000)),
+        17 => (TrackKind::Subtitle, SUBTITLE_TIMESCALE),
+        _ => return Ok(None),
+    };
+
+    let mut track = Track::new(number, kind, &codec::mkv_codec_name(&codec_id), timescale);
+    track.codec_private = codec_private;
+    track.default = default;
+    track.forced = forced;
+    track.name = name.filter(|name| !name.is_empty());
+    // The Matroska default language is English; "und" means unknown
+    track.language = language_bcp47
+        .or(language)
+        .or_else(|| Some("eng".to_string()))
+        .filter(|language| language != "und");
+    track.width = width;
+    track.height = height;
+    track.sample_rate = sample_rate;
+    track.channels = channels;
+    track.bit_depth = bit_depth;
+
+    if kind == TrackKind::Video {
+        track.frame_rate = default_duration.filter(|ns| *ns > 0).map(|ns| 1e9 / ns as f64);
+        track.hdr = if dolby_vision {
+            Some(HdrFormat::DolbyVision)
+        } else {
+            transfer.and_then(HdrFormat::from_transfer)
+        };
+    }
+
+    // Older muxers put the DTS flavour in the codec ID
+    track.profile = match codec_id.as_str() {
+        "A_DTS/LOSSLESS" => Some("DTS-HD MA".to_string()),
+        "A_DTS/EXPRESS" => Some("DTS Express".to_string()),
+        _ => None,
+    };
+
+    Ok(Some(TrackEntry {
+        track,
+        codec_id,
+        default_duration,
+        encoded,
+    }))
+}
+
+// Read a ChapterAtom into `chapters`. Hidden and disabled chapters are skipped, nested
+// ones ignored.
+fn read_chapter_atom(reader: &mut EbmlReader<File>, end: u64, chapters: &mut Chapters) -> io::Result<()> {
+    let mut start = None;
+    let mut chapter_end = None;
+    let mut title = None;
+    let mut visible = true;
+
+    while reader.position() < end {
+        let header = reader.read_element_header()?;
+        let size = header.size.ok_or_else(|| invalid("Unknown-size element in ChapterAtom"))?;
+        let nanoseconds = |value: u64| value as f64 / 1_000_000_000.0;
+
+        match header.id {
+            ebml::CHAPTER_TIME_START => start = Some(nanoseconds(reader.read_uint(size)?)),
+            ebml::CHAPTER_TIME_END => chapter_end = Some(nanoseconds(reader.read_uint(size)?)),
+            ebml::CHAPTER_FLAG_HIDDEN => visible &= reader.read_uint(size)? == 0,
+            ebml::CHAPTER_FLAG_ENABLED => visible &= reader.read_uint(size)? != 0,
+            // The first display is the one in the main language
+            ebml::CHAPTER_DISPLAY if title.is_none() => {
+                let display_end = header.data_offset + size;
+                while reader.position() < display_end {
+                    let child = reader.read_element_header()?;
+                    let child_size = child.size.ok_or_else(|| invalid("Unknown-size element in ChapterDisplay"))?;
+                    match child.id {
+                        ebml::CHAP_STRING => title = Some(reader.read_string(child_size)?),
+                        _ => reader.skip(child_size)?,
+                    }
+                }
+            }
+            _ => reader.skip(size)?,
+        }
+    }
+
+    if let Some(start) = start.filter(|_| visible) {
+        chapters.push((start, chapter_end, title));
+    }
+    Ok(())
+}
+
+// Read the Chapters element: the chapters of the default edition, or else of the
+// first one that isn't hidden
+fn read_chapters(reader: &mut EbmlReader<File>, end: u64) -> io::Result<Chapters> {
+    let mut editions: Vec<(bool, bool, Chapters)> = Vec::new();
+
+    while reader.position() < end {
+        let header = reader.read_element_header()?;
+        let size = header.size.ok_or_else(|| invalid("Unknown-size element in Chapters"))?;
+        if header.id != ebml::EDITION_ENTRY {
+            reader.skip(size)?;
+            continue;
+        }
+
+        let edition_end = header.data_offset + size;
+        let (mut default, mut hidden, mut chapters) = (false, false, Vec::new());
+        while reader.position() < edition_end {
+            let child = reader.read_element_header()?;
+            let child_size = child.size.ok_or_else(|| invalid("Unknown-size element in EditionEntry"))?;
+            match child.id {
+                ebml::EDITION_FLAG_DEFAULT => default = reader.read_uint(child_size)? != 0,
+                ebml::EDITION_FLAG_HIDDEN => hidden = reader.read_uint(child_size)? != 0,
+                ebml::CHAPTER_ATOM => read_chapter_atom(reader, child.data_offset + child_size, &mut chapters)?,
+                _ => reader.skip(child_size)?,
+            }
+        }
+        editions.push((default, hidden, chapters));
+    }
+
+    let chosen = editions
+        .iter()
+        .position(|(default, _, _)| *default)
+        .or_else(|| editions.iter().position(|(_, hidden, _)| !hidden))
+        .unwrap_or(0);
+
+    Ok(if chosen < editions.len() { editions.swap_remove(chosen).2 } else { Vec::new() })
+}
+
+// Read the Chapters element at an offset, if that is what is there
+fn read_chapters_at(reader: &mut EbmlReader<File>, position: u64) -> io::Result<Option<Chapters>> {
+    reader.seek_to(position)?;
+    let element = reader.read_element_header()?;
+    match (element.id, element.end()) {
+        (ebml::CHAPTERS, Some(end)) => read_chapters(reader, end).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// Where the SeekHead says the Chapters element is. Positions are relative to the
+// start of the segment's data.
+fn read_chapters_position(reader: &mut EbmlReader<File>, end: u64, segment_start: u64) -> io::Result<Option<u64>> {
+    let mut position = None;
+
+    while reader.position() < end {
+        let header = reader.read_element_header()?;
+        let size = header.size.ok_or_else(|| invalid("Unknown-size element in SeekHead"))?;
+        if header.id != ebml::SEEK {
+            reader.skip(size)?;
+            continue;
+        }
+
+        let seek_end = header.data_offset + size;
+        let (mut id, mut seek_position) = (None, None);
+        while reader.position() < seek_end {
+            let child = reader.read_element_header()?;
+            let child_size = child.size.ok_or_else(|| invalid("Unknown-size element in Seek"))?;
+            match child.id {
+                ebml::SEEK_ID => {
+                    id = Some(reader.read_binary(child_size)?.iter().fold(0u32, |id, byte| (id << 8) | *byte as u32))
+                }
+                ebml::SEEK_POSITION => seek_position = Some(reader.read_uint(child_size)?),
+                _ => reader.skip(child_size)?,
+            }
+        }
+
+        if id == Some(ebml::CHAPTERS) {
+            position = seek_position.map(|offset| segment_start + offset);
+        }
+    }
+
+    Ok(position)
+}
+
+// Read a Block or SimpleBlock and record its frames. `header` is the block element.
+fn read_block(
+    reader: &mut EbmlReader<File>,
+    header: &ElementHeader,
+    cluster_time: i64,
+    simple: bool,
+    frames: &mut HashMap<u32, Vec<Frame>>,
+) -> io::Result<Option<(u32, usize)>> {
+    let size = header.size.ok_or_else(|| invalid("Unknown-size block"))?;
+    let end = header.data_offset + size;
+
+    let (track_number, _) = reader.read_vint()?;
+    let Some(track_frames) = frames.get_mut(&(track_number as u32)) else {
+        reader.seek_to(end)?;
+        return Ok(None);
+    };
+
+    let mut timecode = [0u8; 2];
+    reader.read_exact(&mut timecode)?;
+    let time = cluster_time + i16::from_be_bytes(timecode) as i64;
+    let flags = reader.read_u8()?;
+    let keyframe = !simple || flags & 0x80 != 0;
+
+    let mut sizes = Vec::new();
+    let lacing = (flags >> 1) & 0x03;
+    if lacing != 0 {
+        let count = reader.read_u8()? as usize + 1;
+        match lacing {
+            // Xiph lacing
+            1 => {
+                for _ in 1..count {
+                    let mut frame_size = 0u64;
+                    loop {
+                        let byte = reader.read_u8()?;
+                        frame_size += byte as u64;
+                        if byte != 0xFF {
+                            break;
+                        }
+                    }
+                    sizes.push(frame_size);
+                }
+            }
+            // EBML lacing, sizes after the first are signed differences
+            3 => {
+                let (first, _) = reader.read_vint()?;
+                sizes.push(first);
+                for _ in 2..count {
+                    let (raw, length) = reader.read_vint()?;
+                    let bias = (1i64 << (7 * length - 1)) - 1;
+                    let previous = *sizes.last().unwrap_or(&0) as i64;
+                    let frame_size = previous + raw as i64 - bias;
+                    if frame_size < 0 {
+                        return Err(invalid("Invalid EBML lace size"));
+                    }
+                    sizes.push(frame_size as u64);
+                }
+            }
+            // Fixed-size lacing
+            _ => {
+                let remaining = end.saturating_sub(reader.position());
+                let frame_size = remaining / count as u64;
+                sizes.extend(std::iter::repeat_n(frame_size, count - 1));
+            }
+        }
+
+        let laced: u64 = sizes.iter().sum();
+        let remaining = end
+            .checked_sub(reader.position() + laced)
+            .ok_or_else(|| invalid("Block lace sizes exceed the block"))?;
+        sizes.push(remaining);
+    } else {
+        sizes.push(end.saturating_sub(reader.position()));
+    }
+
+    let lace_count = sizes.len() as u16;
+    let mut offset = reader.position();
+    for (lace_index, frame_size) in sizes.into_iter().enumerate() {
+        track_frames.push(Frame {
+            time,
+            offset,
+            size: frame_size as u32,
+            keyframe,
+            duration: None,
+            lace_index: lace_index as u16,
+            lace_count,
+        });
+        offset += frame_size;
+    }
+
+    reader.seek_to(end)?;
+    Ok(Some((track_number as u32, lace_count as usize)))
+}
+
+fn read_block_group(
+    reader: &mut EbmlReader<File>,
+    end: u64,
+    cluster_time: i64,
+    frames: &mut HashMap<u32, Vec<Frame>>,
+) -> io::Result<()> {
+    let mut block = None;
+    let mut duration = None;
+    let mut referenced = false;
+
+    while reader.position() < end {
+        let header = reader.read_element_header()?;
+        let size = header.size.ok_or_else(|| invalid("Unknown-size element in BlockGroup"))?;
+        match header.id {
+            ebml::BLOCK => block = read_block(reader, &header, cluster_time, false, frames)?,
+            ebml::BLOCK_DURATION => duration = Some(reader.read_uint(size)?),
+            ebml::REFERENCE_BLOCK => {
+                referenced = true;
+                reader.skip(size)?;
+            }
+            _ => reader.skip(size)?,
+        }
+    }
+
+    // BlockDuration and ReferenceBlock may come after the Block itself
+    if let Some((track_number, count)) = block {
+        if let Some(track_frames) = frames.get_mut(&track_number) {
+            let first = track_frames.len() - count;
+            for frame in &mut track_frames[first..] {
+                frame.keyframe = !referenced;
+                frame.duration = duration.map(|duration| duration / count as u64);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Read one cluster. Returns the header of the element that ended an unknown-size
+// cluster, if any.
+fn read_cluster(
+    reader: &mut EbmlReader<File>,
+    header: &ElementHeader,
+    file_size: u64,
+    frames: &mut HashMap<u32, Vec<Frame>>,
+) -> io::Result<Option<ElementHeader>> {
+    let end = header.end().unwrap_or(file_size).min(file_size);
+    let mut cluster_time = 0i64;
+
+    while reader.position() < end {
+        let child = match reader.read_element_header() {
+            Ok(child) => child,
+            // Files cut short mid-cluster still play up to the damage
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        if header.size.is_none() && TOP_LEVEL_IDS.contains(&child.id) {
+            return Ok(Some(child));
+        }
+
+        let Some(size) = child.size else {
+            return Err(invalid("Unknown-size element in Cluster"));
+        };
+        if child.data_offset + size > file_size {
+            return Ok(None);
+        }
+
+        match child.id {
+            ebml::CLUSTER_TIMESTAMP => cluster_time = reader.read_uint(size)? as i64,
+            ebml::SIMPLE_BLOCK => {
+                read_block(reader, &child, cluster_time, true, frames)?;
+            }
+            ebml::BLOCK_GROUP => read_block_group(reader, child.data_offset + size, cluster_time, frames)?,
+            _ => reader.skip(size)?,
+        }
+    }
+
+    Ok(None)
+}
+
+// Turn the frames of a track into samples. `shift` is added to every timestamp so
+// that the earliest frame of the file starts at zero.
+fn build_samples(entry: &TrackEntry, frames: &[Frame], timestamp_scale: u64, shift: i64) -> Vec<Sample> {
+    let timescale = entry.track.timescale;
+    let to_ticks = |time: i64| ticks((time + shift) * timestamp_scale as i64, timescale);
+    let default_duration = entry.default_duration.map(|ns| ticks(ns as i64, timescale));
+
+    // Presentation times, with laced frames spread over their block
+    let mut pts: Vec<i64> = Vec::with_capacity(frames.len());
+    for index in 0..frames.len() {
+        let frame = &frames[index];
+        let block_start = to_ticks(frame.time);
+        if frame.lace_index == 0 {
+            pts.push(block_start);
+            continue;
+        }
+
+        let step = match default_duration {
+            Some(step) => step,
+            None => {
+                let next = frames[index..]
+                    .iter()
+                    .find(|next| next.lace_index == 0)
+                    .map(|next| to_ticks(next.time))
+                    .unwrap_or(block_start);
+                (next - block_start).max(0) / frame.lace_count as i64
+            }
+        };
+        pts.push(block_start + step * frame.lace_index as i64);
+    }
+
+    // Decode times: frames are stored in decode order, so the n-th smallest
+    // presentation time is used as the n-th decode time
+    let dts: Vec<i64> = if entry.track.kind == TrackKind::Video {
+        let mut sorted = pts.clone();
+        sorted.sort_unstable();
+        sorted
+    } else {
+        pts.clone()
+    };
+
+    let mut samples = Vec::with_capacity(frames.len());
+    let mut previous_duration = default_duration.unwrap_or(0).max(0) as u32;
+    for (index, frame) in frames.iter().enumerate() {
+        let block_duration = frame
+            .duration
+            .map(|duration| ticks(duration as i64 * timestamp_scale as i64, timescale) as u32);
+
+        // Subtitle events don't run until the next one, their BlockDuration says how
+        // long they stay on screen
+        let duration = match (entry.track.kind, dts.get(index + 1)) {
+            (TrackKind::Subtitle, next) => block_duration
+                .or_else(|| next.map(|next| (next - dts[index]).max(0) as u32))
+                .unwrap_or(previous_duration),
+            (_, Some(next)) => (next - dts[index]).max(0) as u32,
+            (_, None) => block_duration
+                .or(default_duration.map(|duration| duration as u32))
+                .unwrap_or(previous_duration),
+        };
+        previous_duration = duration;
+
+        samples.push(Sample {
+            offset: frame.offset,
+            size: frame.size,
+            dts: dts[index],
+            cts_offset: (pts[index] - dts[index]).clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            duration,
+            keyframe: frame.keyframe || entry.track.kind != TrackKind::Video,
+        });
+    }
+
+    samples
+}
+
+// Read the track layout of a Matroska/WebM file. With `with_samples` every cluster is
+// walked and every frame indexed, otherwise only the first cluster is read, for the
+// first frames some codecs are described from.
+pub fn read(file: File, with_samples: bool) -> io::Result<MediaIndex> {
+    let file_size = file.metadata()?.len();
+    let mut reader = EbmlReader::new(file);
+
+    let header = reader.read_element_header()?;
+    if header.id != ebml::EBML {
+        return Err(invalid("Not an EBML file"));
+    }
+    let header_end = header.end().ok_or_else(|| invalid("Unknown-size EBML header"))?;
+    while reader.position() < header_end {
+        let child = reader.read_element_header()?;
+        let size = child.size.ok_or_else(|| invalid("Unknown-size element in EBML header"))?;
+        if child.id == ebml::DOC_TYPE {
+            let doc_type = reader.read_string(size)?;
+            if doc_type != "matroska" && doc_type != "webm" {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Unsupported EBML document type {}", doc_type),
+                ));
+            }
+        } else {
+            reader.skip(size)?;
+        }
+    }
+
+    let segment = reader.read_element_header()?;
+    if segment.id != ebml::SEGMENT {
+        return Err(invalid("Matroska file without a Segment"));
+    }
+    let segment_end = segment.end().unwrap_or(file_size).min(file_size);
+
+    let mut timestamp_scale = 1_000_000u64;
+    let mut duration = 0.0f64;
+    let mut entries: Vec<TrackEntry> = Vec::new();
+    let mut frames: HashMap<u32, Vec<Frame>> = HashMap::new();
+    let mut pending: Option<ElementHeader> = None;
+    let mut clusters = 0;
+    let mut chapters: Option<Chapters> = None;
+    let mut chapters_position = None;
+
+    loop {
+        let element = match pending.take() {
+            Some(element) => element,
+            None => {
+                if reader.position() >= segment_end {
+                    break;
+                }
+                match reader.read_element_header() {
+                    Ok(element) => element,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        match element.id {
+            ebml::INFO => {
+                let end = element.end().ok_or_else(|| invalid("Unknown-size Info"))?;
+                while reader.position() < end {
+                    let child = reader.read_element_header()?;
+                    let size = child.size.ok_or_else(|| invalid("Unknown-size element in Info"))?;
+                    match child.id {
+                        ebml::TIMESTAMP_SCALE => timestamp_scale = reader.read_uint(size)?.max(1),
+                        ebml::DURATION => duration = reader.read_float(size)?,
+                        _ => reader.skip(size)?,
+                    }
+                }
+            }
+            ebml::TRACKS => {
+                let end = element.end().ok_or_else(|| invalid("Unknown-size Tracks"))?;
+                while reader.position() < end {
+                    let child = reader.read_element_header()?;
+                    let size = child.size.ok_or_else(|| invalid("Unknown-size element in Tracks"))?;
+                    if child.id == ebml::TRACK_ENTRY {
+                        if let Some(entry) = read_track_entry(&mut reader, child.data_offset + size)? {
+                            frames.insert(entry.track.number, Vec::new());
+                            entries.push(entry);
+                        }
+                    } else {
+                        reader.skip(size)?;
+                    }
+                }
+            }
+            // Chapters are a nicety, a damaged Chapters element doesn't make the file unreadable
+            ebml::SEEK_HEAD => {
+                let end = element.end().ok_or_else(|| invalid("Unknown-size SeekHead"))?;
+                chapters_position = read_chapters_position(&mut reader, end, segment.data_offset).unwrap_or(None);
+                reader.seek_to(end)?;
+            }
+            ebml::CHAPTERS => {
+                let end = element.end().ok_or_else(|| invalid("Unknown-size Chapters"))?;
+                chapters = read_chapters(&mut reader, end).ok();
+                reader.seek_to(end.min(segment_end))?;
+            }
+            ebml::CLUSTER => {
+                if !with_samples && !entries.is_empty() && clusters > 0 {
+                    break;
+                }
+                clusters += 1;
+                pending = read_cluster(&mut reader, &element, file_size, &mut frames)?;
+                if pending.is_none() && element.size.is_none() {
+                    break;
+                }
+            }
+            _ => match element.end() {
+                Some(end) => reader.seek_to(end.min(segment_end))?,
+                None => return Err(invalid("Unknown-size top-level element")),
+            },
+        }
+    }
+
+    if entries.is_empty() {
+        return Err(invalid("Matroska file without tracks"));
+    }
+
+    // Muxers that write chapters after the clusters point to them from the SeekHead
+    if chapters.is_none() {
+        if let Some(position) = chapters_position.filter(|position| *position < segment_end) {
+            chapters = read_chapters_at(&mut reader, position).unwrap_or(None);
+        }
+    }
+
+    // Blocks can start slightly before zero (negative relative timecodes in the
+    // first cluster), fMP4 wants non-negative decode times
+    let earliest = frames
+        .values()
+        .filter_map(|track_frames| track_frames.iter().map(|frame| frame.time).min())
+        .min()
+        .unwrap_or(0);
+    let shift = (-earliest).max(0);
+
+    let mut tracks = Vec::with_capacity(entries.len());
+    for mut entry in entries {
+        let track_frames = frames.remove(&entry.track.number).unwrap_or_default();
+        entry.track.samples = build_samples(&entry, &track_frames, timestamp_scale, shift);
+
+        // AC-3 and E-AC-3 sample entries are described from the first frame, and
+        // DTS-HD extensions can only be seen in it
+        let first_frame = match (entry.track.codec.as_str(), track_frames.first()) {
+            ("ac3" | "eac3" | "dts", Some(frame)) => {
+                reader.seek_to(frame.offset)?;
+                Some(reader.read_binary((frame.size as u64).min(FIRST_FRAME_BYTES))?)
+            }
+            _ => None,
+        };
+
+        // Compressed tracks (usually header stripping) would need their frames
+        // rebuilt before remuxing, so they are left unpackaged
+        if !entry.encoded {
+            codec::describe_mkv_track(&mut entry.track, &entry.codec_id, first_frame.as_deref());
+        }
+        let private = entry.track.codec_private.clone().unwrap_or_default();
+        codec::describe_profile(&mut entry.track, &private, first_frame.as_deref());
+
+        // The frames of the first cluster were only read to describe the codecs
+        if !with_samples {
+            entry.track.samples.clear();
+        }
+        tracks.push(entry.track);
+    }
+
+    let duration = duration * timestamp_scale as f64 / 1_000_000_000.0;
+    let duration = tracks.iter().map(Track::end_seconds).fold(duration, f64::max);
+
+    Ok(MediaIndex::new(Container::Matroska, duration, tracks).with_chapters(chapters.unwrap_or_default()))
+}
@@ -0,0 +1,6 @@
+pub mod codec;
+pub mod ebml;
+pub mod fmp4;
+pub mod index;
+pub mod mkv;
+pub mod mp4;
\ No newline at end of file
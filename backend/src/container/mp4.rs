@@ -0,0 +1,786 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::container::codec;
+use crate::container::ebml::invalid;
+use crate::container::fmp4::write_box;
+use crate::container::index::{Chapters, Container, HdrFormat, MediaIndex, Sample, Track, TrackKind};
+
+// moov boxes above this size are treated as corrupt rather than read into memory
+const MAX_MOOV_SIZE: u64 = 256 * 1024 * 1024;
+
+// Upper bound for constant-size sample tables, which aren't limited by the box size
+const MAX_SAMPLES: usize = 10_000_000;
+
+// Big-endian reader over the payload of a box
+struct Bytes<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| invalid("Truncated MP4 box"))?;
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn skip(&mut self, count: usize) -> io::Result<()> {
+        self.take(count).map(|_| ())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let high = self.u32()? as u64;
+        Ok((high << 32) | self.u32()? as u64)
+    }
+
+    // Version and flags of a full box
+    fn full_box_header(&mut self) -> io::Result<(u8, u32)> {
+        let value = self.u32()?;
+        Ok(((value >> 24) as u8, value & 0x00FF_FFFF))
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+}
+
+// Iterate over the child boxes in a box payload
+struct Boxes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 8 {
+            return None;
+        }
+
+        let size = u32::from_be_bytes([self.data[0], self.data[1], self.data[2], self.data[3]]) as u64;
+        let kind = [self.data[4], self.data[5], self.data[6], self.data[7]];
+        let (header, size) = match size {
+            0 => (8, self.data.len() as u64),
+            1 => {
+                let large = u64::from_be_bytes(self.data.get(8..16)?.try_into().ok()?);
+                (16, large)
+            }
+            size => (8, size),
+        };
+
+        if size < header as u64 || size > self.data.len() as u64 {
+            self.data = &[];
+            return None;
+        }
+
+        let payload = &self.data[header..size as usize];
+        self.data = &self.data[size as usize..];
+        Some((kind, payload))
+    }
+}
+
+fn boxes(data: &[u8]) -> Boxes<'_> {
+    Boxes { data }
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(data).find(|(child, _)| child == kind).map(|(_, payload)| payload)
+}
+
+// Locate the moov box and read it into memory
+fn read_moov(file: &mut File) -> io::Result<Vec<u8>> {
+    let file_size = file.metadata()?.len();
+    let mut offset = 0u64;
+
+    while offset + 8 <= file_size {
+        let mut header = [0u8; 16];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header[..8])?;
+
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            file.read_exact(&mut header[8..16])?;
+            size = u64::from_be_bytes([
+                header[8], header[9], header[10], header[11], header[12], header[13], header[14], header[15],
+            ]);
+            header_size = 16;
+        } else if size == 0 {
+            size = file_size - offset;
+        }
+
+        if size < header_size {
+            return Err(invalid("Invalid MP4 box size"));
+        }
+
+        if &header[4..8] == b"moov" {
+            let payload_size = size - header_size;
+            if payload_size > MAX_MOOV_SIZE {
+                return Err(invalid("MP4 moov box too large"));
+            }
+
+            let mut moov = vec![0u8; payload_size as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(moov);
+        }
+
+        offset += size;
+    }
+
+    Err(invalid("MP4 file has no moov box"))
+}
+
+// Unpack the 15-bit mdhd language into an ISO 639-2/T code
+fn unpack_language(packed: u16) -> Option<String> {
+    let code: String = (0..3)
+        .rev()
+        .map(|shift| (((packed >> (shift * 5)) & 0x1F) as u8 + 0x60) as char)
+        .collect();
+
+    if code == "und" || !code.chars().all(|c| c.is_ascii_lowercase()) {
+        None
+    } else {
+        Some(code)
+    }
+}
+
+// Sample tables of one trak, as stored
+#[derive(Default)]
+struct SampleTables {
+    // (sample_count, sample_delta)
+    time_to_sample: Vec<(u32, u32)>,
+    // (sample_count, sample_offset)
+    composition_offsets: Vec<(u32, i32)>,
+    sync_samples: Option<Vec<u32>>,
+    sizes: Vec<u32>,
+    // (first_chunk, samples_per_chunk)
+    sample_to_chunk: Vec<(u32, u32)>,
+    chunk_offsets: Vec<u64>,
+}
+
+fn read_sample_tables(stbl: &[u8]) -> io::Result<SampleTables> {
+    let mut tables = SampleTables::default();
+
+    for (kind, payload) in boxes(stbl) {
+        let mut bytes = Bytes::new(payload);
+        match &kind {
+            b"stts" => {
+                bytes.full_box_header()?;
+                let count = bytes.u32()? as usize;
+                if count > bytes.remaining() / 8 {
+                    return Err(invalid("Corrupt stts box"));
+                }
+                for _ in 0..count {
+                    tables.time_to_sample.push((bytes.u32()?, bytes.u32()?));
+                }
+            }
+            b"ctts" => {
+                bytes.full_box_header()?;
+                let count = bytes.u32()? as usize;
+                if count > bytes.remaining() / 8 {
+                    return Err(invalid("Corrupt ctts box"));
+                }
+                // Version 0 offsets are unsigned on paper, but writers use negative
+                // values in practice
+                for _ in 0..count {
+                    tables.composition_offsets.push((bytes.u32()?, bytes.u32()? as i32));
+                }
+            }
+            b"stss" => {
+                bytes.full_box_header()?;
+                let count = bytes.u32()? as usize;
+                if count > bytes.remaining() / 4 {
+                    return Err(invalid("Corrupt stss box"));
+                }
+                let mut sync = Vec::with_capacity(count);
+                for _ in 0..count {
+                    sync.push(bytes.u32()?);
+                }
+                tables.sync_samples = Some(sync);
+            }
+            b"stsz" => {
+                bytes.full_box_header()?;
+                let uniform = bytes.u32()?;
+                let count = bytes.u32()? as usize;
+                if uniform != 0 {
+                    tables.sizes = vec![uniform; count.min(MAX_SAMPLES)];
+                } else {
+                    if count > bytes.remaining() / 4 {
+                        return Err(invalid("Corrupt stsz box"));
+                    }
+                    for _ in 0..count {
+                        tables.sizes.push(bytes.u32()?);
+                    }
+                }
+            }
+            b"stz2" => {
+                bytes.full_box_header()?;
+                bytes.skip(3)?;
+                let field_size = bytes.u8()?;
+                let count = bytes.u32()? as usize;
+                if count > bytes.remaining() * 2 {
+                    return Err(invalid("Corrupt stz2 box"));
+                }
+                if field_size == 4 {
+                    let packed = bytes.take(count.div_ceil(2))?;
+                    for index in 0..count {
+                        let byte = packed[index / 2];
+                        tables.sizes.push((if index % 2 == 0 { byte >> 4 } else { byte & 0x0F }) as u32);
+                    }
+                } else {
+                    for _ in 0..count {
+                        let size = match field_size {
+                            8 => bytes.u8()? as u32,
+                            16 => bytes.u16()? as u32,
+                            _ => return Err(invalid("Invalid stz2 field size")),
+                        };
+                        tables.sizes.push(size);
+                    }
+                }
+            }
+            b"stsc" => {
+                bytes.full_box_header()?;
+                let count = bytes.u32()? as usize;
+                if count > bytes.remaining() / 12 {
+                    return Err(invalid("Corrupt stsc box"));
+                }
+                for _ in 0..count {
+                    let first_chunk = bytes.u32()?;
+                    let samples_per_chunk = bytes.u32()?;
+                    bytes.skip(4)?; // sample_description_index
+                    tables.sample_to_chunk.push((first_chunk, samples_per_chunk));
+                }
+            }
+            b"stco" => {
+                bytes.full_box_header()?;
+                let count = bytes.u32()? as usize;
+                if count > bytes.remaining() / 4 {
+                    return Err(invalid("Corrupt stco box"));
+                }
+                for _ in 0..count {
+                    tables.chunk_offsets.push(bytes.u32()? as u64);
+                }
+            }
+            b"co64" => {
+                bytes.full_box_header()?;
+                let count = bytes.u32()? as usize;
+                if count > bytes.remaining() / 8 {
+                    return Err(invalid("Corrupt co64 box"));
+                }
+                for _ in 0..count {
+                    tables.chunk_offsets.push(bytes.u64()?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(tables)
+}
+
+// Expand the sample tables into one entry per sample
+fn build_samples(tables: &SampleTables, composition_shift: i64) -> Vec<Sample> {
+    let count = tables.sizes.len();
+    let mut samples = Vec::with_capacity(count);
+
+    // Offsets from the chunk layout
+    let mut offsets = Vec::with_capacity(count);
+    for (entry, (first_chunk, per_chunk)) in tables.sample_to_chunk.iter().enumerate() {
+        let last_chunk = tables
+            .sample_to_chunk
+            .get(entry + 1)
+            .map_or(tables.chunk_offsets.len() as u32, |next| next.0.saturating_sub(1));
+
+        for chunk in *first_chunk..=last_chunk {
+            let Some(mut offset) = tables.chunk_offsets.get(chunk.saturating_sub(1) as usize).copied() else {
+                break;
+            };
+            for _ in 0..*per_chunk {
+                let Some(size) = tables.sizes.get(offsets.len()) else {
+                    break;
+                };
+                offsets.push(offset);
+                offset += *size as u64;
+            }
+        }
+    }
+
+    let mut deltas = tables
+        .time_to_sample
+        .iter()
+        .flat_map(|(count, delta)| std::iter::repeat_n(*delta, *count as usize));
+    let mut composition = tables
+        .composition_offsets
+        .iter()
+        .flat_map(|(count, offset)| std::iter::repeat_n(*offset, *count as usize));
+    let mut sync = tables.sync_samples.as_ref().map(|sync| sync.iter().peekable());
+
+    let mut dts = 0i64;
+    for (index, offset) in offsets.into_iter().enumerate() {
+        let duration = deltas.next().unwrap_or(0);
+        let cts_offset = composition.next().unwrap_or(0) as i64 + composition_shift;
+        let number = index as u32 + 1;
+        let keyframe = match sync.as_mut() {
+            Some(sync) => {
+                while sync.next_if(|sample| **sample < number).is_some() {}
+                sync.next_if(|sample| **sample == number).is_some()
+            }
+            None => true,
+        };
+
+        samples.push(Sample {
+            offset,
+            size: tables.sizes[index],
+            dts,
+            cts_offset: cts_offset.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            duration,
+            keyframe,
+        });
+        dts += duration as i64;
+    }
+
+    samples
+}
+
+// Offset to add to composition times so that presentation starts where the edit list
+// says it does. Only the common shapes are honored: an optional leading empty edit
+// followed by one normal edit.
+fn edit_list_shift(elst: &[u8], movie_timescale: u32, media_timescale: u32) -> io::Result<i64> {
+    let mut bytes = Bytes::new(elst);
+    let (version, _) = bytes.full_box_header()?;
+    let count = bytes.u32()?;
+
+    let mut delay = 0i64;
+    for _ in 0..count {
+        let (segment_duration, media_time) = if version == 1 {
+            (bytes.u64()?, bytes.u64()? as i64)
+        } else {
+            (bytes.u32()? as u64, bytes.u32()? as i32 as i64)
+        };
+        bytes.skip(4)?; // media_rate
+
+        if media_time == -1 {
+            delay += segment_duration as i64 * media_timescale as i64 / movie_timescale.max(1) as i64;
+        } else {
+            return Ok(delay - media_time);
+        }
+    }
+
+    Ok(delay)
+}
+
+// An audio sample entry rewritten for fMP4, with the values read from it
+struct AudioEntry {
+    sample_entry: Vec<u8>,
+    channels: u32,
+    sample_rate: u32,
+    children: Vec<([u8; 4], Vec<u8>)>,
+}
+
+// Rebuild QuickTime sound description v1/v2 entries as plain version 0 entries, which
+// is all ISO BMFF players accept. Configuration boxes nested in a `wave` box are moved
+// up into the entry.
+fn normalize_audio_entry(fourcc: &[u8; 4], entry: &[u8]) -> io::Result<AudioEntry> {
+    let mut bytes = Bytes::new(entry);
+    bytes.skip(8)?; // reserved, data_reference_index
+    let version = bytes.u16()?;
+    bytes.skip(6)?; // revision, vendor
+    let mut channels = bytes.u16()? as u32;
+    bytes.skip(6)?; // samplesize, compression_id, packet_size
+    let mut sample_rate = bytes.u32()? >> 16;
+
+    match version {
+        1 => bytes.skip(16)?,
+        2 => {
+            bytes.skip(4)?; // sizeOfStructOnly
+            sample_rate = f64::from_bits(bytes.u64()?) as u32;
+            channels = bytes.u32()?;
+            bytes.skip(20)?;
+        }
+        _ => {}
+    }
+
+    let mut children = Vec::new();
+    for (kind, payload) in boxes(&entry[bytes.position..]) {
+        if &kind == b"wave" {
+            for (inner, inner_payload) in boxes(payload) {
+                if !matches!(&inner, b"frma" | b"enda" | b"\0\0\0\0") {
+                    children.push((inner, inner_payload.to_vec()));
+                }
+            }
+        } else {
+            children.push((kind, payload.to_vec()));
+        }
+    }
+
+    // Some files declare 0 channels in v2 entries and rely on the config box
+    if channels == 0 {
+        channels = 2;
+    }
+
+    let mut encoded = Vec::new();
+    for (kind, payload) in &children {
+        write_box(&mut encoded, kind, |out| out.extend_from_slice(payload));
+    }
+
+    let sample_entry = if version == 0 {
+        let mut out = Vec::new();
+        write_box(&mut out, fourcc, |out| out.extend_from_slice(entry));
+        out
+    } else {
+        codec::audio_sample_entry(fourcc, channels, sample_rate, &encoded)
+    };
+
+    Ok(AudioEntry {
+        sample_entry,
+        channels,
+        sample_rate,
+        children,
+    })
+}
+
+// Parse the stsd box and fill in the codec fields of the track
+// Transfer characteristics of an nclx (or QuickTime nclc) colr box
+fn colour_transfer(colr: &[u8]) -> Option<u64> {
+    match colr.get(..4)? {
+        b"nclx" | b"nclc" => Some(u16::from_be_bytes([*colr.get(6)?, *colr.get(7)?]) as u64),
+        _ => None,
+    }
+}
+
+// Average frame rate from the sample count and total duration in stts
+fn stts_frame_rate(stts: &[u8], timescale: u32) -> io::Result<Option<f64>> {
+    let mut bytes = Bytes::new(stts);
+    bytes.full_box_header()?;
+    let entries = bytes.u32()?;
+    let mut count = 0u64;
+    let mut duration = 0u64;
+    for _ in 0..entries {
+        let sample_count = bytes.u32()? as u64;
+        count += sample_count;
+        duration += sample_count * bytes.u32()? as u64;
+    }
+
+    Ok((duration > 0).then(|| count as f64 * timescale as f64 / duration as f64))
+}
+
+fn describe_track(track: &mut Track, stsd: &[u8]) -> io::Result<()> {
+    let mut bytes = Bytes::new(stsd);
+    bytes.full_box_header()?;
+    let count = bytes.u32()?;
+    if count == 0 {
+        return Ok(());
+    }
+
+    // Files with several sample descriptions are rare; only the first one is used
+    let Some((fourcc, entry)) = boxes(&stsd[bytes.position..]).next() else {
+        return Ok(());
+    };
+
+    track.codec = codec::mp4_codec_name(&fourcc);
+
+    let children: Vec<([u8; 4], Vec<u8>)> = match track.kind {
+        TrackKind::Video => {
+            let mut header = Bytes::new(entry);
+            header.skip(24)?;
+            track.width = Some(header.u16()? as u32);
+            track.height = Some(header.u16()? as u32);
+
+            let mut sample_entry = Vec::with_capacity(entry.len() + 8);
+            write_box(&mut sample_entry, &fourcc, |out| out.extend_from_slice(entry));
+            track.sample_entry = Some(sample_entry);
+
+            boxes(entry.get(78..).unwrap_or_default())
+                .map(|(kind, payload)| (kind, payload.to_vec()))
+                .collect()
+        }
+        TrackKind::Audio => {
+            let audio = normalize_audio_entry(&fourcc, entry)?;
+            track.channels = Some(audio.channels);
+            track.sample_rate = Some(audio.sample_rate);
+            track.sample_entry = Some(audio.sample_entry);
+            audio.children
+        }
+        TrackKind::Subtitle => Vec::new(),
+    };
+
+    let named: Vec<(String, Vec<u8>)> = children
+        .into_iter()
+        .map(|(kind, payload)| (String::from_utf8_lossy(&kind).into_owned(), payload))
+        .collect();
+
+    let esds = named
+        .iter()
+        .find(|(kind, _)| kind == "esds")
+        .and_then(|(_, esds)| codec::parse_esds(esds.get(4..)?));
+
+    if track.kind == TrackKind::Audio && &fourcc == b"mp4a" {
+        // MP3 in MP4 is stored as mp4a with an MPEG-1 audio object type
+        if let Some((0x69 | 0x6B, _)) = esds {
+            track.codec = "mp3".to_string();
+        }
+    }
+
+    if track.kind == TrackKind::Video {
+        let dolby_vision = matches!(&fourcc, b"dvh1" | b"dvhe" | b"dav1" | b"dva1" | b"dvav")
+            || named.iter().any(|(kind, _)| matches!(kind.as_str(), "dvcC" | "dvvC" | "dvwC"));
+        track.hdr = if dolby_vision {
+            Some(HdrFormat::DolbyVision)
+        } else {
+            named
+                .iter()
+                .find(|(kind, _)| kind == "colr")
+                .and_then(|(_, colr)| colour_transfer(colr))
+                .and_then(HdrFormat::from_transfer)
+        };
+    }
+
+    if let Some(config) = named
+        .iter()
+        .find(|(kind, _)| matches!(kind.as_str(), "avcC" | "hvcC" | "av1C" | "esds" | "dOps" | "dfLa"))
+    {
+        track.codec_private = Some(config.1.clone());
+    }
+
+    // The DTS flavour is in the sample entry type
+    track.profile = match &fourcc {
+        b"dtsl" => Some("DTS-HD MA".to_string()),
+        b"dtsh" => Some("DTS-HD HRA".to_string()),
+        b"dtse" => Some("DTS Express".to_string()),
+        b"dtsx" => Some("DTS:X".to_string()),
+        _ => None,
+    };
+    let config = match (track.codec.as_str(), esds) {
+        ("aac", Some((_, Some(asc)))) => asc,
+        _ => track.codec_private.clone().unwrap_or_default(),
+    };
+    codec::describe_profile(track, &config, None);
+
+    track.codec_string = codec::mp4_codec_string(&fourcc, &named);
+    if track.codec_string.is_none() {
+        // Without a codecs string players can't tell whether they support the track
+        track.sample_entry = None;
+    }
+
+    Ok(())
+}
+
+fn read_track(trak: &[u8], movie_timescale: u32, with_samples: bool) -> io::Result<Option<Track>> {
+    let Some(tkhd) = find_box(trak, b"tkhd") else {
+        return Ok(None);
+    };
+    let mut bytes = Bytes::new(tkhd);
+    let (version, flags) = bytes.full_box_header()?;
+    bytes.skip(if version == 1 { 16 } else { 8 })?;
+    let track_id = bytes.u32()?;
+
+    let Some(mdia) = find_box(trak, b"mdia") else {
+        return Ok(None);
+    };
+
+    let mdhd = find_box(mdia, b"mdhd").ok_or_else(|| invalid("MP4 track without mdhd"))?;
+    let mut bytes = Bytes::new(mdhd);
+    let (version, _) = bytes.full_box_header()?;
+    bytes.skip(if version == 1 { 16 } else { 8 })?;
+    let timescale = bytes.u32()?;
+    bytes.skip(if version == 1 { 8 } else { 4 })?;
+    let language = unpack_language(bytes.u16()?);
+
+    let handler = find_box(mdia, b"hdlr")
+        .and_then(|hdlr| hdlr.get(8..12))
+        .unwrap_or_default();
+    let kind = match handler {
+        b"vide" => TrackKind::Video,
+        b"soun" => TrackKind::Audio,
+        b"sbtl" | b"text" | b"subt" | b"clcp" => TrackKind::Subtitle,
+        _ => return Ok(None),
+    };
+
+    if timescale == 0 {
+        return Err(invalid("MP4 track with zero timescale"));
+    }
+
+    let mut track = Track::new(track_id, kind, "", timescale);
+    track.language = language;
+    // Bit 0 is track_enabled; disabled tracks are usually alternates
+    track.default = flags & 1 != 0;
+
+    let stbl = find_box(mdia, b"minf")
+        .and_then(|minf| find_box(minf, b"stbl"))
+        .ok_or_else(|| invalid("MP4 track without sample table"))?;
+
+    if let Some(stsd) = find_box(stbl, b"stsd") {
+        describe_track(&mut track, stsd)?;
+    }
+    if kind == TrackKind::Video {
+        if let Some(stts) = find_box(stbl, b"stts") {
+            track.frame_rate = stts_frame_rate(stts, timescale)?;
+        }
+    }
+
+    if with_samples {
+        let shift = match find_box(trak, b"edts").and_then(|edts| find_box(edts, b"elst")) {
+            Some(elst) => edit_list_shift(elst, movie_timescale, timescale)?,
+            None => 0,
+        };
+
+        let tables = read_sample_tables(stbl)?;
+        track.samples = build_samples(&tables, shift);
+    }
+
+    Ok(Some(track))
+}
+
+// Chapter tracks carry a title per chapter; more than this is not a chapter list
+const MAX_CHAPTERS: usize = 1000;
+
+fn track_id(trak: &[u8]) -> Option<u32> {
+    let mut bytes = Bytes::new(find_box(trak, b"tkhd")?);
+    let (version, _) = bytes.full_box_header().ok()?;
+    bytes.skip(if version == 1 { 16 } else { 8 }).ok()?;
+    bytes.u32().ok()
+}
+
+// IDs of the QuickTime chapter tracks, which other tracks reference with tref/chap
+fn chapter_track_ids(moov: &[u8]) -> Vec<u32> {
+    boxes(moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .filter_map(|(_, trak)| find_box(trak, b"tref").and_then(|tref| find_box(tref, b"chap")))
+        .flat_map(|chap| chap.chunks_exact(4).map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]])))
+        .collect()
+}
+
+// Titles of a QuickTime chapter track: each sample is a 16-bit length and the text
+fn read_chapter_track(file: &mut File, track: &Track) -> io::Result<Chapters> {
+    let mut chapters = Vec::new();
+
+    for sample in track.samples.iter().take(MAX_CHAPTERS) {
+        let mut payload = vec![0u8; sample.size.min(1024) as usize];
+        file.seek(SeekFrom::Start(sample.offset))?;
+        file.read_exact(&mut payload)?;
+
+        let length = payload
+            .get(..2)
+            .map_or(0, |length| u16::from_be_bytes([length[0], length[1]]) as usize);
+        let text = payload.get(2..2 + length).unwrap_or_default();
+        let title = match text {
+            [0xFE, 0xFF, utf16 @ ..] => encoding_rs::UTF_16BE.decode_without_bom_handling(utf16).0.into_owned(),
+            _ => String::from_utf8_lossy(text).into_owned(),
+        };
+
+        let start = track.seconds(sample.dts + sample.cts_offset as i64);
+        chapters.push((start, Some(start + track.seconds(sample.duration as i64)), Some(title)));
+    }
+
+    Ok(chapters)
+}
+
+// Nero chapters (moov/udta/chpl): start times in 100 ns units and a title each
+fn read_nero_chapters(moov: &[u8]) -> io::Result<Chapters> {
+    let Some(chpl) = find_box(moov, b"udta").and_then(|udta| find_box(udta, b"chpl")) else {
+        return Ok(Vec::new());
+    };
+
+    let mut bytes = Bytes::new(chpl);
+    let (version, _) = bytes.full_box_header()?;
+    if version == 1 {
+        bytes.skip(4)?;
+    }
+
+    let count = bytes.u8()? as usize;
+    let mut chapters = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = bytes.u64()? as f64 / 10_000_000.0;
+        let length = bytes.u8()? as usize;
+        let title = String::from_utf8_lossy(bytes.take(length)?).into_owned();
+        chapters.push((start, None, Some(title)));
+    }
+
+    Ok(chapters)
+}
+
+// Read the track layout of an MP4 file. With `with_samples` every sample is indexed,
+// otherwise only the track descriptions are read.
+pub fn read(mut file: File, with_samples: bool) -> io::Result<MediaIndex> {
+    let moov = read_moov(&mut file)?;
+
+    let mvhd = find_box(&moov, b"mvhd").ok_or_else(|| invalid("MP4 moov without mvhd"))?;
+    let mut bytes = Bytes::new(mvhd);
+    let (version, _) = bytes.full_box_header()?;
+    bytes.skip(if version == 1 { 16 } else { 8 })?;
+    let timescale = bytes.u32()?;
+    let duration = if version == 1 { bytes.u64()? } else { bytes.u32()? as u64 };
+
+    let chapter_ids = chapter_track_ids(&moov);
+    let mut chapter_track = None;
+
+    let mut tracks = Vec::new();
+    for (kind, payload) in boxes(&moov) {
+        if &kind != b"trak" {
+            continue;
+        }
+
+        // Chapter tracks are text tracks, but not subtitles anyone would pick
+        if track_id(payload).is_some_and(|id| chapter_ids.contains(&id)) {
+            if chapter_track.is_none() {
+                chapter_track = read_track(payload, timescale, true).ok().flatten();
+            }
+            continue;
+        }
+
+        if let Some(track) = read_track(payload, timescale, with_samples)? {
+            tracks.push(track);
+        }
+    }
+
+    // Damaged chapters don't make the file unreadable
+    let mut chapters = chapter_track
+        .and_then(|track| read_chapter_track(&mut file, &track).ok())
+        .unwrap_or_default();
+    if chapters.is_empty() {
+        chapters = read_nero_chapters(&moov).unwrap_or_default();
+    }
+
+    // Only one enabled track of each kind is the default
+    for kind in [TrackKind::Video, TrackKind::Audio, TrackKind::Subtitle] {
+        let mut seen = false;
+        for track in tracks.iter_mut().filter(|track| track.kind == kind) {
+            track.default = track.default && !seen;
+            seen |= track.default;
+        }
+    }
+
+    let duration = if timescale > 0 && duration != u32::MAX as u64 && duration != u64::MAX {
+        duration as f64 / timescale as f64
+    } else {
+        0.0
+    };
+    let duration = tracks.iter().map(Track::end_seconds).fold(duration, f64::max);
+
+    Ok(MediaIndex::new(Container::Mp4, duration, tracks).with_chapters(chapters))
+}
+
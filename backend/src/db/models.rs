@@ -19,6 +19,8 @@ pub struct Media {
     pub added_at: DateTime<Utc>,
     pub last_watched: Option<DateTime<Utc>>,
     pub watch_count: i32,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub missing_since: Option<DateTime<Utc>>, // set while its file is gone
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -43,6 +45,7 @@ pub struct Episode {
     pub still_path: Option<String>,
     pub air_date: Option<String>,
     pub runtime: Option<i32>,
+    pub missing_since: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -94,6 +97,132 @@ pub struct WatchProgress {
     pub completed: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct MediaFile {
+    pub id: String,
+    pub media_id: String,
+    pub episode_id: Option<String>,
+    pub path: String,
+    pub container: String,
+    pub duration: Option<f64>,
+    pub bitrate: Option<i64>,
+    pub size: i64,
+    pub modified_at: Option<DateTime<Utc>>,
+    pub probed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct MediaStream {
+    pub id: String,
+    pub file_id: String,
+    pub stream_index: i64,
+    pub kind: String, // video, audio, subtitle
+    pub codec: String,
+    pub codec_string: Option<String>,
+    pub profile: Option<String>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub is_default: bool,
+    pub is_forced: bool,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub frame_rate: Option<f64>,
+    pub bit_depth: Option<i64>,
+    pub hdr: Option<String>,
+    pub channels: Option<i64>,
+    pub channel_layout: Option<String>,
+    pub sample_rate: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Subtitle {
+    pub id: String,
+    pub media_id: String,
+    pub episode_id: Option<String>,
+    pub path: String,
+    pub stream_index: Option<i64>, // embedded track number, None for subtitle files
+    pub format: String, // subrip, ass, webvtt, microdvd, mov_text
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub is_forced: bool,
+    pub is_sdh: bool,
+    pub is_default: bool,
+    pub is_uploaded: bool,
+    pub time_offset: f64, // seconds
+    pub added_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct SubtitleOffset {
+    pub user_id: String,
+    pub subtitle_id: String,
+    pub time_offset: f64, // seconds
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Marker {
+    pub id: String,
+    pub media_id: String,
+    pub episode_id: Option<String>,
+    pub marker_type: String, // chapter, intro, credits, recap
+    pub start_time: f64, // seconds
+    pub end_time: f64,
+    pub title: Option<String>,
+    pub source: String, // file, manual, detected
+    pub confidence: Option<f64>, // 0 to 1, for detected markers
+    pub added_at: DateTime<Utc>,
+}
+
+// Seek preview thumbnails of a movie or episode and where their generation is at
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Trickplay {
+    pub id: String,
+    pub media_id: String,
+    pub episode_id: Option<String>,
+    pub status: String, // queued, running, ready, failed
+    pub file_etag: String,
+    pub interval: f64, // seconds between thumbnails
+    pub width: i32,
+    pub height: i32,
+    pub columns: i32,
+    pub rows: i32,
+    pub thumbnails: i32,
+    pub sprites: i32,
+    pub has_bif: bool,
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Whether a user may download, set by an administrator
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct DownloadPermission {
+    pub user_id: String,
+    pub can_download: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+// A background job and its progress
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Job {
+    pub id: String,
+    pub kind: String, // scan
+    pub library_id: Option<String>,
+    pub paths: Option<String>, // folders of a partial scan, one per line
+    pub status: String, // queued, running, completed, failed, cancelled
+    pub total: i64,
+    pub processed: i64,
+    pub added: i64,
+    pub existing: i64,
+    pub missing: i64,
+    pub removed: i64,
+    pub errors: i64,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
 // Create DTOs (Data Transfer Objects) for incoming requests
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -112,4 +241,64 @@ pub struct UpdateProgressDto {
     pub position: i32,
     pub duration: i32,
     pub completed: Option<bool>,
-}
\ No newline at end of file
+}
+// What a client can play, posted to the playback endpoints. Containers and codecs use
+// the names found in media_streams (mp4, matroska, webm; h264, hevc, aac, ac3, ...).
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceProfileDto {
+    pub containers: Vec<String>,
+    pub video_codecs: Vec<String>,
+    pub audio_codecs: Vec<String>,
+    pub max_width: Option<i64>,
+    pub max_height: Option<i64>,
+    pub max_bitrate: Option<i64>, // bits per second
+    pub max_audio_channels: Option<i64>,
+    pub hdr_formats: Vec<String>, // hdr10, hlg, dolby_vision
+    pub protocols: Vec<String>, // hls, dash
+}
+
+// Details of an uploaded subtitle file, given in the query string next to the file
+#[derive(Debug, FromForm)]
+pub struct SubtitleUploadDto {
+    pub filename: String, // only the extension is used, to tell the format
+    pub episode: Option<String>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub forced: bool,
+    pub sdh: bool,
+}
+
+// A user's own timing correction in seconds; positive values show cues later
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubtitleOffsetDto {
+    pub user_id: String,
+    pub offset: f64,
+}
+
+// A timing correction applied to a subtitle for everyone, added to earlier ones
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubtitleShiftDto {
+    pub offset: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadPermissionDto {
+    pub can_download: bool,
+}
+
+// Where a skip marker starts and ends, in seconds
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMarkerDto {
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
+}
+
+// Where a player is, sent every so often while it plays
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackHeartbeatDto {
+    pub position: Option<f64>, // seconds
+    pub paused: Option<bool>,
+}
@@ -36,6 +36,18 @@ pub async fn get_media_by_type(pool: &Pool<Sqlite>, media_type: &str) -> Result<
     Ok(media)
 }
 
+// Mark a media item as changed so cached copies of it and its details are revalidated
+pub async fn touch_media(pool: &Pool<Sqlite>, id: &str) -> Result<()> {
+    sqlx::query("UPDATE media SET updated_at = ? WHERE id = ?")
+        .bind(Utc::now())
+        .bind(id)
+        .execute(pool)
+        .await
+        .map_err(AppError::Database)?;
+    
+    Ok(())
+}
+
 // Library queries
 pub async fn get_all_libraries(pool: &Pool<Sqlite>) -> Result<Vec<Library>> {
     let libraries = sqlx::query_as::<_, Library>("SELECT * FROM libraries ORDER BY name")
@@ -185,10 +197,11 @@ pub async fn update_watch_progress(
     if completed {
         sqlx::query(
             "UPDATE media 
-             SET last_watched = ?, watch_count = watch_count + 1 
+             SET last_watched = ?, watch_count = watch_count + 1, updated_at = ? 
              WHERE id = ?"
         )
         .bind(now)
+        .bind(now)
         .bind(&progress.media_id)
         .execute(pool)
         .await
@@ -204,4 +217,272 @@ pub async fn update_watch_progress(
     .map_err(AppError::Database)?;
     
     Ok(created_progress)
-}
\ No newline at end of file
+}
+// Media file queries
+pub async fn get_media_files_by_media_id(pool: &Pool<Sqlite>, media_id: &str) -> Result<Vec<MediaFile>> {
+    let files = sqlx::query_as::<_, MediaFile>(
+        "SELECT * FROM media_files WHERE media_id = ? ORDER BY path"
+    )
+    .bind(media_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(files)
+}
+
+pub async fn get_media_file_by_path(pool: &Pool<Sqlite>, path: &str) -> Result<Option<MediaFile>> {
+    let file = sqlx::query_as::<_, MediaFile>("SELECT * FROM media_files WHERE path = ?")
+        .bind(path)
+        .fetch_optional(pool)
+        .await
+        .map_err(AppError::Database)?;
+    
+    Ok(file)
+}
+
+pub async fn get_streams_by_file_id(pool: &Pool<Sqlite>, file_id: &str) -> Result<Vec<MediaStream>> {
+    let streams = sqlx::query_as::<_, MediaStream>(
+        "SELECT * FROM media_streams WHERE file_id = ? ORDER BY stream_index"
+    )
+    .bind(file_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(streams)
+}
+
+// Subtitle queries
+pub async fn get_subtitles(pool: &Pool<Sqlite>, media_id: &str, episode_id: Option<&str>) -> Result<Vec<Subtitle>> {
+    let subtitles = sqlx::query_as::<_, Subtitle>(
+        "SELECT * FROM subtitles WHERE media_id = ? AND episode_id IS ? ORDER BY language, is_forced, path"
+    )
+    .bind(media_id)
+    .bind(episode_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(subtitles)
+}
+
+// Subtitles of a movie, or of every episode of a show
+pub async fn get_subtitles_by_media_id(pool: &Pool<Sqlite>, media_id: &str) -> Result<Vec<Subtitle>> {
+    let subtitles = sqlx::query_as::<_, Subtitle>(
+        "SELECT * FROM subtitles WHERE media_id = ? ORDER BY episode_id, language, is_forced, path"
+    )
+    .bind(media_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(subtitles)
+}
+
+pub async fn get_subtitle_by_id(pool: &Pool<Sqlite>, id: &str) -> Result<Subtitle> {
+    let subtitle = sqlx::query_as::<_, Subtitle>("SELECT * FROM subtitles WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(AppError::Database)?
+        .ok_or_else(|| AppError::NotFound(format!("Subtitle with id {} not found", id)))?;
+    
+    Ok(subtitle)
+}
+
+pub async fn get_subtitle_offset(pool: &Pool<Sqlite>, user_id: &str, subtitle_id: &str) -> Result<Option<SubtitleOffset>> {
+    let offset = sqlx::query_as::<_, SubtitleOffset>(
+        "SELECT * FROM subtitle_offsets WHERE user_id = ? AND subtitle_id = ?"
+    )
+    .bind(user_id)
+    .bind(subtitle_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(offset)
+}
+
+// Save a user's offset for a subtitle, or forget it when it is zero
+pub async fn set_subtitle_offset(pool: &Pool<Sqlite>, user_id: &str, subtitle_id: &str, offset: f64) -> Result<()> {
+    if offset == 0.0 {
+        sqlx::query("DELETE FROM subtitle_offsets WHERE user_id = ? AND subtitle_id = ?")
+            .bind(user_id)
+            .bind(subtitle_id)
+            .execute(pool)
+            .await
+            .map_err(AppError::Database)?;
+        return Ok(());
+    }
+
+    sqlx::query(
+        "INSERT INTO subtitle_offsets (user_id, subtitle_id, time_offset, updated_at) VALUES (?, ?, ?, ?)
+         ON CONFLICT(user_id, subtitle_id) DO UPDATE SET time_offset = excluded.time_offset, updated_at = excluded.updated_at"
+    )
+    .bind(user_id)
+    .bind(subtitle_id)
+    .bind(offset)
+    .bind(Utc::now())
+    .execute(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(())
+}
+
+// Markers of a movie, or of an episode of a show
+pub async fn get_markers(pool: &Pool<Sqlite>, media_id: &str, episode_id: Option<&str>) -> Result<Vec<Marker>> {
+    let markers = sqlx::query_as::<_, Marker>(
+        "SELECT * FROM markers WHERE media_id = ? AND episode_id IS ? ORDER BY start_time, marker_type"
+    )
+    .bind(media_id)
+    .bind(episode_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(markers)
+}
+
+// Markers of a movie, or of every episode of a show
+pub async fn get_markers_by_media_id(pool: &Pool<Sqlite>, media_id: &str) -> Result<Vec<Marker>> {
+    let markers = sqlx::query_as::<_, Marker>(
+        "SELECT * FROM markers WHERE media_id = ? ORDER BY episode_id, start_time, marker_type"
+    )
+    .bind(media_id)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(markers)
+}
+
+// The marker of a type for a movie or episode: set by hand, else read from the file,
+// else detected
+pub async fn get_marker_by_type(
+    pool: &Pool<Sqlite>,
+    media_id: &str,
+    episode_id: Option<&str>,
+    marker_type: &str,
+) -> Result<Option<Marker>> {
+    let marker = sqlx::query_as::<_, Marker>(
+        "SELECT * FROM markers WHERE media_id = ? AND episode_id IS ? AND marker_type = ?
+         ORDER BY CASE source WHEN 'manual' THEN 0 WHEN 'file' THEN 1 ELSE 2 END, start_time LIMIT 1"
+    )
+    .bind(media_id)
+    .bind(episode_id)
+    .bind(marker_type)
+    .fetch_optional(pool)
+    .await
+    .map_err(AppError::Database)?;
+    
+    Ok(marker)
+}
+
+// Detected markers waiting for review, least confident first, optionally only those of
+// one season or below a confidence
+pub async fn get_detected_markers(
+    pool: &Pool<Sqlite>,
+    season_id: Option<&str>,
+    max_confidence: Option<f64>,
+) -> Result<Vec<Marker>> {
+    let markers = sqlx::query_as::<_, Marker>(
+        "SELECT m.* FROM markers m LEFT JOIN episodes e ON m.episode_id = e.id
+         WHERE m.source = 'detected' AND (? IS NULL OR e.season_id = ?) AND (? IS NULL OR m.confidence <= ?)
+         ORDER BY m.confidence, m.media_id, e.episode_number"
+    )
+    .bind(season_id)
+    .bind(season_id)
+    .bind(max_confidence)
+    .bind(max_confidence)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(markers)
+}
+
+// Seek preview thumbnails of a movie, or of an episode of a show
+pub async fn get_trickplay(pool: &Pool<Sqlite>, media_id: &str, episode_id: Option<&str>) -> Result<Option<Trickplay>> {
+    let trickplay = sqlx::query_as::<_, Trickplay>(
+        "SELECT * FROM trickplay WHERE media_id = ? AND episode_id IS ?"
+    )
+    .bind(media_id)
+    .bind(episode_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(trickplay)
+}
+
+pub async fn get_season_by_id(pool: &Pool<Sqlite>, id: &str) -> Result<Season> {
+    sqlx::query_as::<_, Season>("SELECT * FROM seasons WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(AppError::Database)?
+        .ok_or_else(|| AppError::NotFound(format!("Season with id {} not found", id)))
+}
+
+pub async fn get_download_permission(pool: &Pool<Sqlite>, user_id: &str) -> Result<Option<DownloadPermission>> {
+    let permission = sqlx::query_as::<_, DownloadPermission>(
+        "SELECT * FROM download_permissions WHERE user_id = ?"
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(permission)
+}
+
+pub async fn set_download_permission(pool: &Pool<Sqlite>, user_id: &str, can_download: bool) -> Result<DownloadPermission> {
+    sqlx::query(
+        "INSERT INTO download_permissions (user_id, can_download, updated_at) VALUES (?, ?, ?)
+         ON CONFLICT(user_id) DO UPDATE SET can_download = excluded.can_download, updated_at = excluded.updated_at"
+    )
+    .bind(user_id)
+    .bind(can_download)
+    .bind(Utc::now())
+    .execute(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    get_download_permission(pool, user_id)
+        .await?
+        .ok_or_else(|| AppError::Server(format!("Download permission of {} was not saved", user_id)))
+}
+
+pub async fn get_job(pool: &Pool<Sqlite>, id: &str) -> Result<Job> {
+    sqlx::query_as::<_, Job>("SELECT * FROM jobs WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(AppError::Database)?
+        .ok_or_else(|| AppError::NotFound(format!("Job with id {} not found", id)))
+}
+
+// Most recent jobs first, optionally of one library or in one status
+pub async fn get_jobs(
+    pool: &Pool<Sqlite>,
+    library_id: Option<&str>,
+    status: Option<&str>,
+    limit: i64,
+) -> Result<Vec<Job>> {
+    let jobs = sqlx::query_as::<_, Job>(
+        "SELECT * FROM jobs WHERE (? IS NULL OR library_id = ?) AND (? IS NULL OR status = ?)
+         ORDER BY created_at DESC LIMIT ?"
+    )
+    .bind(library_id)
+    .bind(library_id)
+    .bind(status)
+    .bind(status)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(jobs)
+}
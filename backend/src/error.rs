@@ -23,9 +23,18 @@ pub enum AppError {
     
     #[error("Authentication error: {0}")]
     Auth(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
     
     #[error("Server error: {0}")]
     Server(String),
+
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Too many streams: {0}")]
+    TooManyStreams(String),
 }
 
 impl<'r> Responder<'r, 'static> for AppError {
@@ -37,7 +46,10 @@ impl<'r> Responder<'r, 'static> for AppError {
             AppError::Io(e) => (Status::InternalServerError, e.to_string()),
             AppError::ExternalApi(msg) => (Status::BadGateway, msg),
             AppError::Auth(msg) => (Status::Unauthorized, msg),
+            AppError::Forbidden(msg) => (Status::Forbidden, msg),
             AppError::Server(msg) => (Status::InternalServerError, msg),
+            AppError::Unavailable(msg) => (Status::ServiceUnavailable, msg),
+            AppError::TooManyStreams(msg) => (Status::TooManyRequests, msg),
         };
         
         let body = json!({
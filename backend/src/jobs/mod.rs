@@ -0,0 +1,3 @@
+pub mod progress;
+pub mod queue;
+pub mod watcher;
\ No newline at end of file
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Counters of a running scan, shared between the scanner and the job queue, which
+// records them in the jobs table as they go up
+#[derive(Debug, Default)]
+pub struct ScanProgress {
+    total: AtomicUsize,
+    processed: AtomicUsize,
+    added: AtomicUsize,
+    existing: AtomicUsize,
+    missing: AtomicUsize,
+    removed: AtomicUsize,
+    errors: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+// What the scanner made of one file
+pub enum Outcome {
+    Added,
+    Existing,
+    // Not a movie or an episode, or outside the library
+    Skipped,
+}
+
+// The counters at one point in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub total: usize,
+    pub processed: usize,
+    pub added: usize,
+    pub existing: usize,
+    pub missing: usize,
+    pub removed: usize,
+    pub errors: usize,
+}
+
+impl ScanProgress {
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn file_done(&self, outcome: Outcome) {
+        match outcome {
+            Outcome::Added => self.added.fetch_add(1, Ordering::Relaxed),
+            Outcome::Existing => self.existing.fetch_add(1, Ordering::Relaxed),
+            Outcome::Skipped => 0,
+        };
+        self.processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // An item whose file is gone, kept until the grace period is over
+    pub fn missing(&self) {
+        self.missing.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // An item removed since its file stayed gone
+    pub fn removed(&self) {
+        self.removed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A file that couldn't be probed or searched for subtitles, which doesn't stop
+    // the scan
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            total: self.total.load(Ordering::Relaxed),
+            processed: self.processed.load(Ordering::Relaxed),
+            added: self.added.load(Ordering::Relaxed),
+            existing: self.existing.load(Ordering::Relaxed),
+            missing: self.missing.load(Ordering::Relaxed),
+            removed: self.removed.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    // Ask the scan to stop after the file it is on
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
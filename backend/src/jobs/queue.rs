@@ -0,0 +1,441 @@
+use chrono::Utc;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Orbit, Rocket};
+use sqlx::{Pool, Sqlite};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, Semaphore};
+
+use crate::db::generate_id;
+use crate::db::models::Job;
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::jobs::progress::{Counts, ScanProgress};
+use crate::media::sandbox::PathSandbox;
+use crate::media::scanner;
+use crate::trickplay::generator::TrickplayGenerator;
+
+pub const KIND_SCAN: &str = "scan";
+
+pub const STATUS_QUEUED: &str = "queued";
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_COMPLETED: &str = "completed";
+pub const STATUS_FAILED: &str = "failed";
+pub const STATUS_CANCELLED: &str = "cancelled";
+
+// How often the counters of a running scan are written to the jobs table
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Runs library scans in the background. Jobs live in the jobs table, where a unique
+// index allows one queued or running scan per library, so a library is never scanned
+// twice at once. Scans of different libraries run side by side, up to a limit.
+#[derive(Clone)]
+pub struct JobQueue {
+    queue: Arc<Mutex<VecDeque<String>>>,
+    // Progress of the jobs being run, by job id
+    running: Arc<Mutex<HashMap<String, Arc<ScanProgress>>>>,
+    wake: Arc<Notify>,
+    permits: Arc<Semaphore>,
+    // How long an item's file may be gone before scans remove the item
+    grace: chrono::Duration,
+}
+
+async fn record_counts(db: &Pool<Sqlite>, id: &str, counts: Counts) -> Result<()> {
+    sqlx::query(
+        "UPDATE jobs SET total = ?, processed = ?, added = ?, existing = ?, missing = ?, removed = ?, errors = ?
+         WHERE id = ?"
+    )
+    .bind(counts.total as i64)
+    .bind(counts.processed as i64)
+    .bind(counts.added as i64)
+    .bind(counts.existing as i64)
+    .bind(counts.missing as i64)
+    .bind(counts.removed as i64)
+    .bind(counts.errors as i64)
+    .bind(id)
+    .execute(db)
+    .await
+    .map_err(AppError::Database)?;
+    Ok(())
+}
+
+// Folders that aren't inside another of them. A file in two folders of a scan would
+// be scanned twice, and added twice.
+pub fn outermost(dirs: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+    dirs.sort();
+    let mut kept: Vec<PathBuf> = Vec::new();
+    for dir in dirs {
+        if !kept.iter().any(|outer| dir.starts_with(outer)) {
+            kept.push(dir);
+        }
+    }
+    kept
+}
+
+fn join_paths(dirs: &[PathBuf]) -> String {
+    dirs.iter().map(|dir| dir.to_string_lossy()).collect::<Vec<_>>().join("\n")
+}
+
+// Record how a job ended, with its final counters
+async fn finish(db: &Pool<Sqlite>, id: &str, status: &str, counts: Counts, error: Option<&str>) -> Result<()> {
+    record_counts(db, id, counts).await?;
+    sqlx::query("UPDATE jobs SET status = ?, error = ?, finished_at = ? WHERE id = ?")
+        .bind(status)
+        .bind(error)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(db)
+        .await
+        .map_err(AppError::Database)?;
+    Ok(())
+}
+
+impl JobQueue {
+    // SCAN_CONCURRENCY is how many libraries may be scanned at once, 2 by default.
+    // MISSING_GRACE_DAYS is how long a missing file's item is kept, 7 days by default;
+    // with 0 it is removed by the first scan that doesn't find the file.
+    pub fn from_env() -> Self {
+        let concurrency = env::var("SCAN_CONCURRENCY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value: &usize| value > 0)
+            .unwrap_or(2);
+        let grace_days = env::var("MISSING_GRACE_DAYS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value: &f64| value >= 0.0)
+            .unwrap_or(7.0);
+
+        Self {
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            wake: Arc::new(Notify::new()),
+            permits: Arc::new(Semaphore::new(concurrency)),
+            grace: chrono::Duration::seconds((grace_days * 86400.0) as i64),
+        }
+    }
+
+    fn queue(&self) -> std::sync::MutexGuard<'_, VecDeque<String>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn running(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<ScanProgress>>> {
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, id: String) {
+        let mut queue = self.queue();
+        if !queue.contains(&id) {
+            queue.push_back(id);
+            self.wake.notify_one();
+        }
+    }
+
+    // Queue a scan of a library. When one is already queued or running, that one is
+    // returned instead, with `false`.
+    pub async fn enqueue_scan(&self, db: &Pool<Sqlite>, library_id: &str) -> Result<(Job, bool)> {
+        loop {
+            let id = generate_id();
+            let inserted = sqlx::query(
+                "INSERT INTO jobs (id, kind, library_id, status, created_at) VALUES (?, ?, ?, ?, ?)
+                 ON CONFLICT DO NOTHING"
+            )
+            .bind(&id)
+            .bind(KIND_SCAN)
+            .bind(library_id)
+            .bind(STATUS_QUEUED)
+            .bind(Utc::now())
+            .execute(db)
+            .await
+            .map_err(AppError::Database)?
+            .rows_affected()
+                > 0;
+
+            if inserted {
+                self.push(id.clone());
+                return Ok((queries::get_job(db, &id).await?, true));
+            }
+
+            // Unless it finished in the meantime, then a new one is queued after all
+            if let Some(active) = self.active_scan(db, library_id).await? {
+                // A partial scan that hasn't started yet scans the whole library instead
+                if active.status == STATUS_QUEUED && active.paths.is_some() {
+                    sqlx::query("UPDATE jobs SET paths = NULL WHERE id = ? AND status = ?")
+                        .bind(&active.id)
+                        .bind(STATUS_QUEUED)
+                        .execute(db)
+                        .await
+                        .map_err(AppError::Database)?;
+                    return Ok((queries::get_job(db, &active.id).await?, false));
+                }
+                return Ok((active, false));
+            }
+        }
+    }
+
+    // Queue a scan of some folders of a library, where files changed. A scan of the
+    // library that hasn't started yet takes the folders in instead. While one is
+    // running, nothing is queued and None is returned, to try again once it is done.
+    pub async fn enqueue_partial_scan(
+        &self,
+        db: &Pool<Sqlite>,
+        library_id: &str,
+        dirs: &[PathBuf],
+    ) -> Result<Option<Job>> {
+        let dirs = outermost(dirs.iter().cloned());
+        loop {
+            let id = generate_id();
+            let inserted = sqlx::query(
+                "INSERT INTO jobs (id, kind, library_id, paths, status, created_at) VALUES (?, ?, ?, ?, ?, ?)
+                 ON CONFLICT DO NOTHING"
+            )
+            .bind(&id)
+            .bind(KIND_SCAN)
+            .bind(library_id)
+            .bind(join_paths(&dirs))
+            .bind(STATUS_QUEUED)
+            .bind(Utc::now())
+            .execute(db)
+            .await
+            .map_err(AppError::Database)?
+            .rows_affected()
+                > 0;
+
+            if inserted {
+                self.push(id.clone());
+                return Ok(Some(queries::get_job(db, &id).await?));
+            }
+
+            let Some(active) = self.active_scan(db, library_id).await? else {
+                continue;
+            };
+            if active.status == STATUS_RUNNING {
+                return Ok(None);
+            }
+            // Already scanning the whole library
+            let Some(queued) = &active.paths else {
+                return Ok(Some(active));
+            };
+
+            let merged = outermost(queued.lines().map(PathBuf::from).chain(dirs.iter().cloned()));
+            let updated = sqlx::query("UPDATE jobs SET paths = ? WHERE id = ? AND status = ? AND paths = ?")
+                .bind(join_paths(&merged))
+                .bind(&active.id)
+                .bind(STATUS_QUEUED)
+                .bind(queued)
+                .execute(db)
+                .await
+                .map_err(AppError::Database)?
+                .rows_affected()
+                > 0;
+            if updated {
+                return Ok(Some(queries::get_job(db, &active.id).await?));
+            }
+        }
+    }
+
+    // The scan of a library that is queued or running, if any
+    pub async fn active_scan(&self, db: &Pool<Sqlite>, library_id: &str) -> Result<Option<Job>> {
+        sqlx::query_as::<_, Job>("SELECT * FROM jobs WHERE kind = ? AND library_id = ? AND status IN (?, ?)")
+            .bind(KIND_SCAN)
+            .bind(library_id)
+            .bind(STATUS_QUEUED)
+            .bind(STATUS_RUNNING)
+            .fetch_optional(db)
+            .await
+            .map_err(AppError::Database)
+    }
+
+    // Cancel a job. A queued job is cancelled right away, a running scan stops after
+    // the file it is on.
+    pub async fn cancel(&self, db: &Pool<Sqlite>, id: &str) -> Result<Job> {
+        let job = queries::get_job(db, id).await?;
+        if job.status != STATUS_QUEUED && job.status != STATUS_RUNNING {
+            return Err(AppError::InvalidInput(format!("Job {} is already {}", id, job.status)));
+        }
+
+        self.queue().retain(|queued| queued != id);
+        let cancelled = sqlx::query("UPDATE jobs SET status = ?, finished_at = ? WHERE id = ? AND status = ?")
+            .bind(STATUS_CANCELLED)
+            .bind(Utc::now())
+            .bind(id)
+            .bind(STATUS_QUEUED)
+            .execute(db)
+            .await
+            .map_err(AppError::Database)?
+            .rows_affected()
+            > 0;
+
+        // Started running, which it records only once it can be stopped
+        if !cancelled {
+            if let Some(progress) = self.running().get(id) {
+                progress.cancel();
+            }
+        }
+
+        queries::get_job(db, id).await
+    }
+
+    // Queue again what a previous run didn't finish. Scans that were running start over,
+    // scanning a file twice only re-checks it.
+    async fn resume(&self, db: &Pool<Sqlite>) -> Result<()> {
+        sqlx::query("UPDATE jobs SET status = ?, started_at = NULL WHERE status = ?")
+            .bind(STATUS_QUEUED)
+            .bind(STATUS_RUNNING)
+            .execute(db)
+            .await
+            .map_err(AppError::Database)?;
+
+        let pending: Vec<String> = sqlx::query_scalar("SELECT id FROM jobs WHERE status = ? ORDER BY created_at")
+            .bind(STATUS_QUEUED)
+            .fetch_all(db)
+            .await
+            .map_err(AppError::Database)?;
+
+        for id in pending {
+            self.push(id);
+        }
+        Ok(())
+    }
+
+    async fn run(self, db: Pool<Sqlite>, sandbox: PathSandbox, trickplay: TrickplayGenerator) {
+        if let Err(e) = self.resume(&db).await {
+            tracing::warn!("Failed to resume library scans: {}", e);
+        }
+
+        loop {
+            let Ok(permit) = self.permits.clone().acquire_owned().await else {
+                return;
+            };
+            let job = self.queue().pop_front();
+            let Some(id) = job else {
+                drop(permit);
+                self.wake.notified().await;
+                continue;
+            };
+
+            let queue = self.clone();
+            let (db, sandbox, trickplay) = (db.clone(), sandbox.clone(), trickplay.clone());
+            tokio::spawn(async move {
+                queue.process(&db, &sandbox, &trickplay, &id).await;
+                drop(permit);
+            });
+        }
+    }
+
+    async fn process(&self, db: &Pool<Sqlite>, sandbox: &PathSandbox, trickplay: &TrickplayGenerator, id: &str) {
+        let progress = Arc::new(ScanProgress::default());
+        self.running().insert(id.to_string(), progress.clone());
+
+        // A job cancelled while it was queued is left alone
+        let claimed = sqlx::query("UPDATE jobs SET status = ?, started_at = ? WHERE id = ? AND status = ?")
+            .bind(STATUS_RUNNING)
+            .bind(Utc::now())
+            .bind(id)
+            .bind(STATUS_QUEUED)
+            .execute(db)
+            .await
+            .map(|result| result.rows_affected() > 0);
+
+        match claimed {
+            Ok(true) => {
+                let result = self.scan(db, sandbox, trickplay, id, &progress).await;
+                let (status, error) = match &result {
+                    Ok(()) if progress.is_cancelled() => (STATUS_CANCELLED, None),
+                    Ok(()) => (STATUS_COMPLETED, None),
+                    Err(e) => {
+                        tracing::warn!("Library scan {} failed: {}", id, e);
+                        (STATUS_FAILED, Some(e.to_string()))
+                    }
+                };
+                if let Err(e) = finish(db, id, status, progress.counts(), error.as_deref()).await {
+                    tracing::warn!("Failed to record the end of library scan {}: {}", id, e);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => tracing::warn!("Failed to start library scan {}: {}", id, e),
+        }
+
+        self.running().remove(id);
+    }
+
+    async fn scan(
+        &self,
+        db: &Pool<Sqlite>,
+        sandbox: &PathSandbox,
+        trickplay: &TrickplayGenerator,
+        id: &str,
+        progress: &ScanProgress,
+    ) -> Result<()> {
+        let job = queries::get_job(db, id).await?;
+        let library_id = job
+            .library_id
+            .ok_or_else(|| AppError::Server(format!("Scan {} has no library", id)))?;
+        let library = queries::get_library_by_id(db, &library_id).await?;
+        let root = sandbox.library_root(&library).await?;
+        let scope: Option<Vec<PathBuf>> = job.paths.as_ref().map(|paths| paths.lines().map(PathBuf::from).collect());
+
+        match &scope {
+            Some(dirs) => tracing::info!("Scanning {} folders of library {}", dirs.len(), library.name),
+            None => tracing::info!("Scanning library: {} at path {}", library.name, library.path),
+        }
+
+        // Counters are recorded as they change, for clients following the job
+        let scanning = scanner::scan_library(db, &library, &root, scope.as_deref(), progress, self.grace);
+        tokio::pin!(scanning);
+        let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut recorded = None;
+        let results = loop {
+            tokio::select! {
+                results = &mut scanning => break results?,
+                _ = ticker.tick() => {
+                    let counts = progress.counts();
+                    if recorded != Some(counts) {
+                        if let Err(e) = record_counts(db, id, counts).await {
+                            tracing::warn!("Failed to record progress of library scan {}: {}", id, e);
+                        }
+                        recorded = Some(counts);
+                    }
+                }
+            }
+        };
+        tracing::info!("Library scan of {} finished: {}", library.name, results);
+
+        if !progress.is_cancelled() {
+            // Seek previews of new and changed videos are made in the background
+            let queued = trickplay.enqueue_library(db, &library).await?;
+            if queued > 0 {
+                tracing::info!("Queued seek previews of {} videos in {}", queued, library.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runs the queued jobs in the background once the server is up
+pub struct JobWorker;
+
+#[rocket::async_trait]
+impl Fairing for JobWorker {
+    fn info(&self) -> Info {
+        Info {
+            name: "Background job runner",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let (Some(queue), Some(db), Some(sandbox), Some(trickplay)) = (
+            rocket.state::<JobQueue>(),
+            rocket.state::<Pool<Sqlite>>(),
+            rocket.state::<PathSandbox>(),
+            rocket.state::<TrickplayGenerator>(),
+        ) {
+            tokio::spawn(queue.clone().run(db.clone(), sandbox.clone(), trickplay.clone()));
+        }
+    }
+}
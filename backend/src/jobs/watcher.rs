@@ -0,0 +1,277 @@
+use notify::event::{EventKind, ModifyKind};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Orbit, Rocket};
+use sqlx::{Pool, Sqlite};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{Instant, Interval};
+
+use crate::db::queries;
+use crate::error::Result;
+use crate::jobs::queue::JobQueue;
+
+// Watches the folders of the libraries that are scanned automatically. Once changes
+// in a library have settled, which a torrent finishing or a folder being copied takes
+// a while to do, the folders where they happened are scanned. Libraries are also
+// scanned in full every so often, for network mounts whose changes aren't reported.
+#[derive(Clone)]
+pub struct LibraryWatcher {
+    // Libraries were created or deleted, so the watched folders are looked up again
+    reload: Arc<Notify>,
+    // How long a library has to go without changes before it is scanned
+    debounce: Duration,
+    // Time between full scans, None when there are none
+    full_scan_interval: Option<Duration>,
+}
+
+// Changed folders of a library and when they may be scanned
+struct Pending {
+    dirs: HashSet<PathBuf>,
+    due: Instant,
+}
+
+// The folder to scan for a changed path: itself if it is a folder, else the folder it
+// is or was in. Changes to the library's own folder scan all of it.
+fn changed_dir(root: &Path, path: &Path) -> PathBuf {
+    let dir = if path.is_dir() { path } else { path.parent().unwrap_or(root) };
+    if dir.starts_with(root) {
+        dir.to_path_buf()
+    } else {
+        root.to_path_buf()
+    }
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+impl LibraryWatcher {
+    // WATCH_DEBOUNCE_SECONDS is how long a library has to be quiet before its changed
+    // folders are scanned, 10 seconds by default. FULL_SCAN_INTERVAL_HOURS is the time
+    // between full scans of the watched libraries, 6 hours by default; 0 turns them off.
+    pub fn from_env() -> Self {
+        let debounce = env::var("WATCH_DEBOUNCE_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value: &f64| value >= 0.0)
+            .unwrap_or(10.0);
+        let interval_hours = env::var("FULL_SCAN_INTERVAL_HOURS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value: &f64| value >= 0.0)
+            .unwrap_or(6.0);
+
+        Self {
+            reload: Arc::new(Notify::new()),
+            debounce: Duration::from_secs_f64(debounce),
+            full_scan_interval: (interval_hours > 0.0).then(|| Duration::from_secs_f64(interval_hours * 3600.0)),
+        }
+    }
+
+    // Look up the libraries to watch again, after one was created or deleted
+    pub fn reload(&self) {
+        self.reload.notify_one();
+    }
+
+    async fn run(self, db: Pool<Sqlite>, jobs: JobQueue) {
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let mut watcher = match notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        }) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                tracing::warn!("Can't watch library folders, they are only scanned in full: {}", e);
+                None
+            }
+        };
+
+        // Library folders by library id
+        let mut watched: HashMap<String, PathBuf> = HashMap::new();
+        let mut pending: HashMap<String, Pending> = HashMap::new();
+        let mut full_scans = self
+            .full_scan_interval
+            .map(|interval| tokio::time::interval_at(Instant::now() + interval, interval));
+
+        if let Err(e) = self.sync(&db, watcher.as_mut(), &mut watched).await {
+            tracing::warn!("Failed to watch library folders: {}", e);
+        }
+
+        loop {
+            let next_due = pending.values().map(|changes| changes.due).min();
+
+            tokio::select! {
+                Some(event) = events.recv() => match event {
+                    Ok(event) => self.changed(&event, &watched, &mut pending),
+                    Err(e) => tracing::warn!("Failed to watch library folders: {}", e),
+                },
+                _ = self.reload.notified() => {
+                    if let Err(e) = self.sync(&db, watcher.as_mut(), &mut watched).await {
+                        tracing::warn!("Failed to watch library folders: {}", e);
+                    }
+                    pending.retain(|library_id, _| watched.contains_key(library_id));
+                }
+                _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                    self.scan_changes(&db, &jobs, &mut pending).await;
+                }
+                _ = tick(&mut full_scans) => {
+                    if let Err(e) = self.scan_all(&db, &jobs).await {
+                        tracing::warn!("Failed to queue full library scans: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Watch the folders of the libraries that are scanned automatically, and stop
+    // watching the ones of libraries that are gone
+    async fn sync(
+        &self,
+        db: &Pool<Sqlite>,
+        watcher: Option<&mut RecommendedWatcher>,
+        watched: &mut HashMap<String, PathBuf>,
+    ) -> Result<()> {
+        let wanted: HashMap<String, PathBuf> = queries::get_all_libraries(db)
+            .await?
+            .into_iter()
+            .filter(|library| library.scan_automatically)
+            .map(|library| (library.id, PathBuf::from(library.path)))
+            .collect();
+
+        let Some(watcher) = watcher else {
+            return Ok(());
+        };
+
+        watched.retain(|library_id, path| {
+            let keep = wanted.get(library_id) == Some(path);
+            if !keep {
+                let _ = watcher.unwatch(path);
+            }
+            keep
+        });
+        for (library_id, path) in wanted {
+            if watched.contains_key(&library_id) {
+                continue;
+            }
+            // Tried again on the next reload, such as once a mount is up. Until then the
+            // library still gets its full scans.
+            match watcher.watch(&path, RecursiveMode::Recursive) {
+                Ok(()) => {
+                    tracing::info!("Watching {} for changes", path.display());
+                    watched.insert(library_id, path);
+                }
+                Err(e) => tracing::warn!("Can't watch {}, it is only scanned in full: {}", path.display(), e),
+            }
+        }
+        Ok(())
+    }
+
+    // Note the folders an event changed, putting off their library's scan
+    fn changed(&self, event: &Event, watched: &HashMap<String, PathBuf>, pending: &mut HashMap<String, Pending>) {
+        // Reading a file, or changing its permissions, changes nothing a scan sees
+        if matches!(event.kind, EventKind::Access(_) | EventKind::Modify(ModifyKind::Metadata(_))) {
+            return;
+        }
+
+        let due = Instant::now() + self.debounce;
+        let mut note = |library_id: &str, dir: PathBuf| {
+            let changes = pending.entry(library_id.to_string()).or_insert_with(|| Pending {
+                dirs: HashSet::new(),
+                due,
+            });
+            changes.dirs.insert(dir);
+            changes.due = due;
+        };
+
+        // Changes were dropped, so every watched library is scanned
+        if event.need_rescan() {
+            for (library_id, root) in watched {
+                note(library_id, root.clone());
+            }
+            return;
+        }
+
+        for path in &event.paths {
+            // The innermost library, for libraries inside others
+            let library = watched
+                .iter()
+                .filter(|(_, root)| path.starts_with(root))
+                .max_by_key(|(_, root)| root.as_os_str().len());
+            if let Some((library_id, root)) = library {
+                note(library_id, changed_dir(root, path));
+            }
+        }
+    }
+
+    // Queue scans of the libraries whose changes have settled. A library that is
+    // being scanned keeps its changes until that scan is done.
+    async fn scan_changes(&self, db: &Pool<Sqlite>, jobs: &JobQueue, pending: &mut HashMap<String, Pending>) {
+        let now = Instant::now();
+        let settled: Vec<String> = pending
+            .iter()
+            .filter(|(_, changes)| changes.due <= now)
+            .map(|(library_id, _)| library_id.clone())
+            .collect();
+
+        for library_id in settled {
+            let Some(mut changes) = pending.remove(&library_id) else {
+                continue;
+            };
+            let dirs: Vec<PathBuf> = changes.dirs.iter().cloned().collect();
+
+            match jobs.enqueue_partial_scan(db, &library_id, &dirs).await {
+                Ok(Some(job)) => tracing::info!("Queued scan {} of {} changed folders", job.id, dirs.len()),
+                Ok(None) => {
+                    changes.due = now + self.debounce.max(Duration::from_secs(1));
+                    pending.insert(library_id, changes);
+                }
+                Err(e) => tracing::warn!("Failed to queue a scan of changed folders: {}", e),
+            }
+        }
+    }
+
+    // Queue a full scan of every library that is scanned automatically
+    async fn scan_all(&self, db: &Pool<Sqlite>, jobs: &JobQueue) -> Result<()> {
+        for library in queries::get_all_libraries(db).await? {
+            if library.scan_automatically {
+                let (job, queued) = jobs.enqueue_scan(db, &library.id).await?;
+                if queued {
+                    tracing::info!("Queued full scan {} of {}", job.id, library.name);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Starts watching the libraries once the server is up
+pub struct WatchWorker;
+
+#[rocket::async_trait]
+impl Fairing for WatchWorker {
+    fn info(&self) -> Info {
+        Info {
+            name: "Library watcher",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let (Some(watcher), Some(db), Some(jobs)) = (
+            rocket.state::<LibraryWatcher>(),
+            rocket.state::<Pool<Sqlite>>(),
+            rocket.state::<JobQueue>(),
+        ) {
+            tokio::spawn(watcher.clone().run(db.clone(), jobs.clone()));
+        }
+    }
+}
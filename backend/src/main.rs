@@ -2,17 +2,22 @@
 
 use dotenvy::dotenv;
 use rocket::{Rocket, Build};
-use rocket::fs::{FileServer, relative};
-use rocket::serde::json::{Json, Value, json};
+use rocket::serde::json::{Value, json};
 use rocket_cors::{AllowedOrigins, CorsOptions};
 use sqlx::sqlite::SqlitePoolOptions;
 use std::env;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod api;
+mod container;
 mod db;
+mod jobs;
 mod media;
 mod metadata;
+mod streaming;
+mod subtitles;
+mod transcode;
+mod trickplay;
 mod error;
 
 #[get("/")]
@@ -41,6 +46,46 @@ async fn run_migrations(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Err
         .execute(pool)
         .await?;
     
+    // Columns added after the initial schema, missing from older databases
+    add_column_if_missing(pool, "media", "updated_at", "TIMESTAMP").await?;
+    add_column_if_missing(pool, "media", "missing_since", "TIMESTAMP").await?;
+    add_column_if_missing(pool, "episodes", "missing_since", "TIMESTAMP").await?;
+    add_column_if_missing(pool, "media", "content_hash", "TEXT").await?;
+    add_column_if_missing(pool, "episodes", "content_hash", "TEXT").await?;
+
+    // Indexes on added columns, which the schema can't have on older databases
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_media_content_hash ON media(content_hash)")
+        .execute(pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_episodes_content_hash ON episodes(content_hash)")
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// Add a column to an existing table unless it is already there
+async fn add_column_if_missing(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let has_column = sqlx::query(
+        "SELECT 1 FROM pragma_table_info(?) WHERE name = ?"
+    )
+    .bind(table)
+    .bind(column)
+    .fetch_optional(pool)
+    .await?;
+
+    if has_column.is_none() {
+        tracing::info!("Adding {} column to {} table", column, table);
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+    }
+
     Ok(())
 }
 
@@ -106,6 +151,9 @@ async fn rocket() -> Rocket<Build> {
         .to_cors()
         .expect("CORS configuration error");
     
+    let transcode_config = transcode::session::TranscodeConfig::from_env();
+    let intro_detector = media::intro::IntroDetector::new(transcode_config.ffmpeg.clone());
+
     // Build the Rocket instance
     rocket::build()
         .mount("/", routes![index, health_check])
@@ -114,7 +162,20 @@ async fn rocket() -> Rocket<Build> {
             api::media::get_media,
             api::media::get_media_by_type,
             api::media::stream_media,
+            api::media::hls_master,
+            api::media::hls_playlist,
+            api::media::hls_init,
+            api::media::hls_segment,
+            api::media::hls_subtitles,
+            api::media::dash_manifest,
+            api::media::get_playback_plan,
+            api::media::sign_stream,
             api::media::get_media_details,
+            api::media::get_trickplay,
+            api::media::generate_trickplay,
+            api::media::trickplay_vtt,
+            api::media::trickplay_bif,
+            api::media::trickplay_sprite,
         ])
         .mount("/api/libraries", routes![
             api::library::get_libraries,
@@ -137,8 +198,84 @@ async fn rocket() -> Rocket<Build> {
             api::episodes::get_episodes_by_media,
             api::episodes::get_episodes_by_season,
             api::episodes::stream_episode,
+            api::episodes::hls_master,
+            api::episodes::hls_playlist,
+            api::episodes::hls_init,
+            api::episodes::hls_segment,
+            api::episodes::hls_subtitles,
+            api::episodes::dash_manifest,
+            api::episodes::get_playback_plan,
+            api::episodes::sign_stream,
+            api::episodes::get_trickplay,
+            api::episodes::generate_trickplay,
+            api::episodes::trickplay_vtt,
+            api::episodes::trickplay_bif,
+            api::episodes::trickplay_sprite,
+        ])
+        .mount("/api/subtitles", routes![
+            api::subtitles::get_subtitles,
+            api::subtitles::get_subtitle_file,
+            api::subtitles::upload_subtitle,
+            api::subtitles::delete_subtitle,
+            api::subtitles::get_offset,
+            api::subtitles::set_offset,
+            api::subtitles::shift_subtitle,
+        ])
+        .mount("/api/markers", routes![
+            api::markers::get_markers,
+            api::markers::set_marker,
+            api::markers::delete_marker,
+            api::markers::detect_intros,
+            api::markers::get_detection,
+            api::markers::get_detected_markers,
+            api::markers::accept_marker,
+        ])
+        .mount(api::transcode::MOUNT_POINT, routes![
+            api::transcode::get_sessions,
+            api::transcode::get_segment,
+            api::transcode::stop_session,
+        ])
+        .mount("/api/sessions", routes![
+            api::sessions::get_sessions,
+            api::sessions::get_session,
+            api::sessions::heartbeat,
+            api::sessions::stop_session,
+        ])
+        .mount("/api/downloads", routes![
+            api::downloads::sign_media,
+            api::downloads::download_media,
+            api::downloads::sign_episode,
+            api::downloads::download_episode,
+            api::downloads::sign_season,
+            api::downloads::download_season,
+            api::downloads::get_permission,
+            api::downloads::set_permission,
+        ])
+        .mount("/api/jobs", routes![
+            api::jobs::get_jobs,
+            api::jobs::get_job,
+            api::jobs::cancel_job,
         ])
         .register("/", catchers![not_found])
         .manage(pool)
+        .manage(container::index::IndexCache::default())
+        .manage(transcode::session::TranscodeManager::new(transcode_config))
+        .manage(intro_detector)
+        .manage(trickplay::generator::TrickplayGenerator::new(trickplay::generator::TrickplayConfig::from_env()))
+        .manage(subtitles::cache::SubtitleCache::from_env())
+        .manage(subtitles::upload::SubtitleUploads::from_env())
+        .manage(streaming::signing::UrlSigner::from_env())
+        .manage(media::sandbox::PathSandbox::from_env())
+        .manage(api::downloads::DownloadPolicy::from_env())
+        .manage(streaming::sessions::PlaybackSessions::from_env())
+        .manage(streaming::throttle::StreamLimits::from_env())
+        .manage(jobs::queue::JobQueue::from_env())
+        .manage(jobs::watcher::LibraryWatcher::from_env())
         .attach(cors)
+        .attach(streaming::conditional::DefaultCacheControl)
+        .attach(transcode::session::TranscodeReaper)
+        .attach(streaming::sessions::SessionReaper)
+        .attach(trickplay::generator::TrickplayWorker)
+        .attach(jobs::queue::JobWorker)
+        .attach(jobs::watcher::WatchWorker)
 }
\ No newline at end of file
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::error::{AppError, Result};
+
+// Audio is decoded to mono at this rate; intros are recognizable well below 4 kHz
+const SAMPLE_RATE: usize = 8000;
+const FRAME_SIZE: usize = 2048;
+// 32 ms between frames, so that the same audio in two files lines up within 16 ms
+const HOP_SIZE: usize = 256;
+pub const FRAME_SECONDS: f64 = HOP_SIZE as f64 / SAMPLE_RATE as f64;
+
+// 33 bands between these frequencies give the 32 bits of a frame's hash
+const BANDS: usize = 33;
+const MIN_FREQUENCY: f64 = 300.0;
+const MAX_FREQUENCY: f64 = 3000.0;
+
+// Frames of two files are the same audio when their hashes differ in at most this many bits
+const MAX_BIT_ERRORS: u32 = 5;
+// Unmatched stretches shorter than this don't break a common segment
+const MAX_GAP_SECONDS: f64 = 1.0;
+// Hashes this common in a file (a second's worth) are silence or a drone and say
+// nothing about alignment
+const MAX_HASH_REPEATS: usize = 32;
+// Offsets between the files that are checked for a common segment, by votes
+const CANDIDATE_OFFSETS: usize = 5;
+const MIN_VOTES: usize = 10;
+
+// Frame hashes of the start of a file's audio
+pub type Fingerprint = Vec<u32>;
+
+// Audio shared by two files: where it is in each, in seconds, and how closely the frames
+// in it match
+#[derive(Debug, Clone, Copy)]
+pub struct CommonSegment {
+    pub start_a: f64,
+    pub end_a: f64,
+    pub start_b: f64,
+    pub end_b: f64,
+    pub score: f64,
+}
+
+fn spawn_error(ffmpeg: &Path, e: std::io::Error) -> AppError {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        AppError::Server(format!(
+            "ffmpeg not found at '{}', set FFMPEG_PATH to enable intro detection",
+            ffmpeg.display()
+        ))
+    } else {
+        AppError::Server(format!("Failed to run ffmpeg: {}", e))
+    }
+}
+
+// Decode the first `seconds` of a file's default audio track as mono PCM
+async fn decode_audio(ffmpeg: &Path, path: &Path, seconds: f64) -> Result<Vec<f32>> {
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", "-nostdin", "-loglevel", "error", "-t"])
+        .arg(format!("{:.3}", seconds))
+        .arg("-i")
+        .arg(path)
+        .args(["-vn", "-sn", "-dn", "-ac", "1", "-ar"])
+        .arg(SAMPLE_RATE.to_string())
+        .args(["-f", "s16le", "-"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| spawn_error(ffmpeg, e))?;
+
+    if !output.status.success() {
+        return Err(AppError::Server(format!(
+            "ffmpeg failed to decode {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(output
+        .stdout
+        .chunks_exact(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0)
+        .collect())
+}
+
+// Fingerprint the first `seconds` of a file's audio
+pub async fn fingerprint_file(ffmpeg: &Path, path: &Path, seconds: f64) -> Result<Fingerprint> {
+    let samples = decode_audio(ffmpeg, path, seconds).await?;
+    tokio::task::spawn_blocking(move || fingerprint(&samples))
+        .await
+        .map_err(|e| AppError::Server(format!("Fingerprinting task failed: {}", e)))
+}
+
+// In-place radix-2 FFT of a power-of-two length signal
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f64;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + length / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+// FFT bins where each of the log-spaced bands starts, plus where the last one ends
+fn band_edges() -> Vec<usize> {
+    let bin_width = SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+    (0..=BANDS)
+        .map(|band| {
+            let frequency = MIN_FREQUENCY * (MAX_FREQUENCY / MIN_FREQUENCY).powf(band as f64 / BANDS as f64);
+            (frequency / bin_width).round() as usize
+        })
+        .collect()
+}
+
+// Hash every frame by which of each pair of neighbouring bands is louder. That shape of
+// the spectrum survives re-encoding and volume changes, and barely changes when the
+// frames of two files are a little out of step.
+pub fn fingerprint(samples: &[f32]) -> Fingerprint {
+    let window: Vec<f64> = (0..FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / FRAME_SIZE as f64).cos())
+        .collect();
+    let edges = band_edges();
+
+    let mut hashes = Vec::new();
+    let mut re = vec![0.0; FRAME_SIZE];
+    let mut im = vec![0.0; FRAME_SIZE];
+
+    for start in (0..samples.len().saturating_sub(FRAME_SIZE)).step_by(HOP_SIZE) {
+        for (i, sample) in samples[start..start + FRAME_SIZE].iter().enumerate() {
+            re[i] = *sample as f64 * window[i];
+            im[i] = 0.0;
+        }
+        fft(&mut re, &mut im);
+
+        let energies: Vec<f64> = edges
+            .windows(2)
+            .map(|band| (band[0]..band[1].max(band[0] + 1)).map(|bin| re[bin] * re[bin] + im[bin] * im[bin]).sum())
+            .collect();
+
+        let mut hash = 0u32;
+        for band in 0..BANDS - 1 {
+            if energies[band] > energies[band + 1] {
+                hash |= 1 << band;
+            }
+        }
+        hashes.push(hash);
+    }
+
+    hashes
+}
+
+// Longest stretch of frames of `a` matching the frames of `b` shifted by `offset`,
+// bridging short gaps. Returns the first and last matching frame of `a` and the share
+// of frames in between that match.
+fn longest_run(a: &[u32], b: &[u32], offset: isize) -> Option<(usize, usize, f64)> {
+    let max_gap = (MAX_GAP_SECONDS / FRAME_SECONDS) as usize;
+    let mut best: Option<(usize, usize, usize)> = None;
+    let mut current: Option<(usize, usize, usize)> = None;
+
+    for (i, hash) in a.iter().enumerate() {
+        let j = i as isize + offset;
+        if j < 0 || j as usize >= b.len() {
+            continue;
+        }
+        if (hash ^ b[j as usize]).count_ones() > MAX_BIT_ERRORS {
+            continue;
+        }
+
+        current = match current {
+            Some((start, end, matched)) if i - end <= max_gap => Some((start, i, matched + 1)),
+            _ => Some((i, i, 1)),
+        };
+        let (start, end, matched) = current.unwrap();
+        if best.is_none_or(|(best_start, best_end, _)| end - start > best_end - best_start) {
+            best = Some((start, end, matched));
+        }
+    }
+
+    best.map(|(start, end, matched)| (start, end, matched as f64 / (end - start + 1) as f64))
+}
+
+// The longest stretch of audio two fingerprints share that lasts between `min_seconds`
+// and `max_seconds`. Offsets between the files are found by voting with hashes they
+// have in common, then checked frame by frame.
+pub fn common_segment(a: &[u32], b: &[u32], min_seconds: f64, max_seconds: f64) -> Option<CommonSegment> {
+    let mut positions: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, hash) in a.iter().enumerate() {
+        positions.entry(*hash).or_default().push(i);
+    }
+
+    let mut votes: HashMap<isize, usize> = HashMap::new();
+    for (j, hash) in b.iter().enumerate() {
+        let Some(found) = positions.get(hash).filter(|found| found.len() <= MAX_HASH_REPEATS) else {
+            continue;
+        };
+        for i in found {
+            *votes.entry(j as isize - *i as isize).or_default() += 1;
+        }
+    }
+
+    let mut offsets: Vec<(isize, usize)> = votes.into_iter().filter(|(_, count)| *count >= MIN_VOTES).collect();
+    offsets.sort_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+
+    offsets
+        .iter()
+        .take(CANDIDATE_OFFSETS)
+        .filter_map(|(offset, _)| {
+            let (start, end, score) = longest_run(a, b, *offset)?;
+            let duration = (end - start + 1) as f64 * FRAME_SECONDS;
+            (duration >= min_seconds && duration <= max_seconds).then(|| CommonSegment {
+                start_a: start as f64 * FRAME_SECONDS,
+                end_a: (end + 1) as f64 * FRAME_SECONDS,
+                start_b: (start as isize + offset) as f64 * FRAME_SECONDS,
+                end_b: (end as isize + offset + 1) as f64 * FRAME_SECONDS,
+                score,
+            })
+        })
+        .max_by(|x, y| (x.end_a - x.start_a).total_cmp(&(y.end_a - y.start_a)))
+}
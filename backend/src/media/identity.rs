@@ -0,0 +1,55 @@
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::error::{AppError, Result};
+
+// Bytes read from each end of a file
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+// What a file's content is recognized by after a move or rename: SHA-256 of its size
+// and of its first and last 64 KiB. Reading only the ends keeps it fast on large
+// videos, and their headers and indexes are different from one video to the next.
+fn partial_hash(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE as usize);
+    file.by_ref().take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    hasher.update(&sample);
+
+    // The whole file is in the first sample when it is that small
+    if size > SAMPLE_SIZE {
+        sample.clear();
+        file.seek(SeekFrom::Start(size.saturating_sub(SAMPLE_SIZE).max(SAMPLE_SIZE)))?;
+        file.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+        hasher.update(&sample);
+    }
+
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+pub async fn content_hash(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || partial_hash(&path))
+        .await
+        .map_err(|e| AppError::Server(format!("Hashing task failed: {}", e)))?
+        .map_err(AppError::Io)
+}
+
+// Record the content hash of a movie, or of an episode of a show
+pub async fn store_hash(db: &Pool<Sqlite>, media_id: &str, episode_id: Option<&str>, hash: &str) -> Result<()> {
+    match episode_id {
+        Some(episode_id) => sqlx::query("UPDATE episodes SET content_hash = ? WHERE id = ?").bind(hash).bind(episode_id),
+        None => sqlx::query("UPDATE media SET content_hash = ? WHERE id = ?").bind(hash).bind(media_id),
+    }
+    .execute(db)
+    .await
+    .map_err(AppError::Database)?;
+    Ok(())
+}
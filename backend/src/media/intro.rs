@@ -0,0 +1,303 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+use crate::db::generate_id;
+use crate::db::models::Episode;
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::media::fingerprint::{self, CommonSegment, Fingerprint};
+use crate::media::markers::{INTRO, SOURCE_DETECTED, SOURCE_FILE, SOURCE_MANUAL};
+use crate::media::sandbox::PathSandbox;
+
+// How much of the start of every episode is searched for the intro
+const ANALYZED_SECONDS: f64 = 600.0;
+// Shorter common audio is a jingle or a sound effect, longer is a repeated episode
+const MIN_INTRO_SECONDS: f64 = 15.0;
+const MAX_INTRO_SECONDS: f64 = 150.0;
+// Intros found by comparing with different episodes agree within this much
+const AGREEMENT_SECONDS: f64 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+}
+
+impl DetectionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DetectionStatus::Queued => "queued",
+            DetectionStatus::Running => "running",
+            DetectionStatus::Finished => "finished",
+            DetectionStatus::Failed => "failed",
+        }
+    }
+}
+
+// Progress and outcome of the intro detection of a season
+#[derive(Debug, Clone)]
+pub struct DetectionJob {
+    pub season_id: String,
+    pub status: DetectionStatus,
+    pub episodes: usize,
+    // Episodes fingerprinted so far
+    pub analyzed: usize,
+    // Episodes an intro was found for
+    pub detected: usize,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+// Runs intro detection jobs in the background, one season at a time since every job
+// decodes the audio of a whole season
+#[derive(Clone)]
+pub struct IntroDetector {
+    ffmpeg: PathBuf,
+    jobs: Arc<Mutex<HashMap<String, DetectionJob>>>,
+    permit: Arc<Semaphore>,
+}
+
+impl IntroDetector {
+    pub fn new(ffmpeg: PathBuf) -> Self {
+        Self {
+            ffmpeg,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            permit: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    fn jobs(&self) -> std::sync::MutexGuard<'_, HashMap<String, DetectionJob>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update(&self, season_id: &str, change: impl FnOnce(&mut DetectionJob)) {
+        if let Some(job) = self.jobs().get_mut(season_id) {
+            change(job);
+        }
+    }
+
+    pub fn job(&self, season_id: &str) -> Result<DetectionJob> {
+        self.jobs()
+            .get(season_id)
+            .cloned()
+            .ok_or_else(|| AppError::NotFound(format!("No intro detection for season {}", season_id)))
+    }
+
+    // Queue the detection of a season's intros. A season already queued or running
+    // isn't queued again.
+    pub async fn start(&self, db: &Pool<Sqlite>, sandbox: &PathSandbox, season_id: &str) -> Result<DetectionJob> {
+        let episodes = queries::get_episodes_by_season_id(db, season_id).await?;
+        if episodes.len() < 2 {
+            return Err(AppError::InvalidInput(
+                "Intros are found by comparing episodes, the season needs at least two".to_string(),
+            ));
+        }
+
+        let job = {
+            let mut jobs = self.jobs();
+            if let Some(job) = jobs.get(season_id) {
+                if matches!(job.status, DetectionStatus::Queued | DetectionStatus::Running) {
+                    return Ok(job.clone());
+                }
+            }
+
+            let job = DetectionJob {
+                season_id: season_id.to_string(),
+                status: DetectionStatus::Queued,
+                episodes: episodes.len(),
+                analyzed: 0,
+                detected: 0,
+                error: None,
+                started_at: Utc::now(),
+                finished_at: None,
+            };
+            jobs.insert(season_id.to_string(), job.clone());
+            job
+        };
+
+        let detector = self.clone();
+        let db = db.clone();
+        let sandbox = sandbox.clone();
+        let season_id = season_id.to_string();
+        tokio::spawn(async move {
+            let _permit = detector.permit.acquire().await;
+            detector.update(&season_id, |job| job.status = DetectionStatus::Running);
+
+            let result = detector.detect(&db, &sandbox, &season_id, &episodes).await;
+            if let Err(e) = &result {
+                tracing::warn!("Intro detection of season {} failed: {}", season_id, e);
+            }
+            detector.update(&season_id, |job| {
+                job.status = match result {
+                    Ok(_) => DetectionStatus::Finished,
+                    Err(_) => DetectionStatus::Failed,
+                };
+                job.error = result.err().map(|e| e.to_string());
+                job.finished_at = Some(Utc::now());
+            });
+        });
+
+        Ok(job)
+    }
+
+    async fn detect(
+        &self,
+        db: &Pool<Sqlite>,
+        sandbox: &PathSandbox,
+        season_id: &str,
+        episodes: &[Episode],
+    ) -> Result<()> {
+        let mut analyzed: Vec<&Episode> = Vec::new();
+        let mut fingerprints: Vec<Fingerprint> = Vec::new();
+        let mut last_error = None;
+        for episode in episodes {
+            let path = Path::new(&episode.path);
+            let fingerprint = match sandbox.check_file(db, path).await {
+                Ok(()) => fingerprint::fingerprint_file(&self.ffmpeg, path, ANALYZED_SECONDS).await,
+                Err(e) => Err(e),
+            };
+            match fingerprint {
+                Ok(fingerprint) => {
+                    analyzed.push(episode);
+                    fingerprints.push(fingerprint);
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping {} in intro detection: {}", episode.path, e);
+                    last_error = Some(e);
+                }
+            }
+            self.update(season_id, |job| job.analyzed += 1);
+        }
+
+        // Most likely every episode failed the same way, e.g. without ffmpeg
+        if fingerprints.len() < 2 {
+            return Err(last_error
+                .unwrap_or_else(|| AppError::Server("Fewer than two episodes could be analyzed".to_string())));
+        }
+
+        let intros = tokio::task::spawn_blocking(move || find_intros(&fingerprints))
+            .await
+            .map_err(|e| AppError::Server(format!("Intro detection task failed: {}", e)))?;
+
+        let mut detected = 0;
+        for (episode, intro) in analyzed.iter().zip(intros) {
+            if let Some((start, end, confidence)) = intro {
+                if save_intro(db, episode, start, end, confidence).await? {
+                    detected += 1;
+                }
+            }
+        }
+
+        if let Some(episode) = analyzed.first() {
+            queries::touch_media(db, &episode.media_id).await?;
+        }
+        self.update(season_id, |job| job.detected = detected);
+        tracing::info!("Detected intros in {} of {} episodes of season {}", detected, episodes.len(), season_id);
+        Ok(())
+    }
+}
+
+// Intro of every episode as start, end and confidence. Every episode is compared with
+// every other; the intro is where most comparisons agree, and the confidence is the
+// share of other episodes that agree times how closely their audio matched.
+fn find_intros(fingerprints: &[Fingerprint]) -> Vec<Option<(f64, f64, f64)>> {
+    let mut found: Vec<Vec<(f64, f64, f64)>> = vec![Vec::new(); fingerprints.len()];
+    for a in 0..fingerprints.len() {
+        for b in a + 1..fingerprints.len() {
+            if let Some(CommonSegment { start_a, end_a, start_b, end_b, score }) =
+                fingerprint::common_segment(&fingerprints[a], &fingerprints[b], MIN_INTRO_SECONDS, MAX_INTRO_SECONDS)
+            {
+                found[a].push((start_a, end_a, score));
+                found[b].push((start_b, end_b, score));
+            }
+        }
+    }
+
+    let others = (fingerprints.len() - 1) as f64;
+    found
+        .iter()
+        .map(|segments| {
+            let agreeing = |(start, end, _): &(f64, f64, f64)| -> Vec<(f64, f64, f64)> {
+                segments
+                    .iter()
+                    .filter(|other| (other.0 - start).abs() <= AGREEMENT_SECONDS && (other.1 - end).abs() <= AGREEMENT_SECONDS)
+                    .copied()
+                    .collect()
+            };
+
+            let best = segments.iter().map(agreeing).max_by(|x, y| {
+                x.len().cmp(&y.len()).then_with(|| {
+                    let score = |segments: &Vec<(f64, f64, f64)>| segments.iter().map(|s| s.2).sum::<f64>();
+                    score(x).total_cmp(&score(y))
+                })
+            })?;
+
+            let median = |mut values: Vec<f64>| {
+                values.sort_by(f64::total_cmp);
+                values[values.len() / 2]
+            };
+            let start = median(best.iter().map(|s| s.0).collect());
+            let end = median(best.iter().map(|s| s.1).collect());
+            let score = best.iter().map(|s| s.2).sum::<f64>() / best.len() as f64;
+            let confidence = (best.len() as f64 / others).min(1.0) * score;
+
+            Some((start, end, (confidence * 1000.0).round() / 1000.0))
+        })
+        .collect()
+}
+
+// Replace an episode's detected intro. Intros set by hand or read from chapters are
+// kept, and nothing is detected for those episodes. Returns whether a marker was saved.
+async fn save_intro(db: &Pool<Sqlite>, episode: &Episode, start: f64, end: f64, confidence: f64) -> Result<bool> {
+    let mut tx = db.begin().await.map_err(AppError::Database)?;
+
+    let known: Option<String> = sqlx::query_scalar(
+        "SELECT id FROM markers WHERE media_id = ? AND episode_id = ? AND marker_type = ? AND source IN (?, ?)"
+    )
+    .bind(&episode.media_id)
+    .bind(&episode.id)
+    .bind(INTRO)
+    .bind(SOURCE_MANUAL)
+    .bind(SOURCE_FILE)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(AppError::Database)?;
+    if known.is_some() {
+        return Ok(false);
+    }
+
+    sqlx::query("DELETE FROM markers WHERE media_id = ? AND episode_id = ? AND marker_type = ? AND source = ?")
+        .bind(&episode.media_id)
+        .bind(&episode.id)
+        .bind(INTRO)
+        .bind(SOURCE_DETECTED)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+
+    sqlx::query(
+        "INSERT INTO markers (id, media_id, episode_id, marker_type, start_time, end_time, source, confidence)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(generate_id())
+    .bind(&episode.media_id)
+    .bind(&episode.id)
+    .bind(INTRO)
+    .bind(start)
+    .bind(end)
+    .bind(SOURCE_DETECTED)
+    .bind(confidence)
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::Database)?;
+
+    tx.commit().await.map_err(AppError::Database)?;
+    Ok(true)
+}
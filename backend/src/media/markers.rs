@@ -0,0 +1,109 @@
+use crate::container::index::Chapter;
+use crate::db::generate_id;
+use crate::error::{AppError, Result};
+
+pub const CHAPTER: &str = "chapter";
+pub const INTRO: &str = "intro";
+pub const CREDITS: &str = "credits";
+pub const RECAP: &str = "recap";
+
+// Markers that can be set by hand; chapters only come from the files
+pub const SKIP_MARKER_TYPES: [&str; 3] = [INTRO, CREDITS, RECAP];
+
+// Where a marker came from
+pub const SOURCE_FILE: &str = "file";
+pub const SOURCE_MANUAL: &str = "manual";
+// Found by comparing the audio of the episodes of a season
+pub const SOURCE_DETECTED: &str = "detected";
+
+// Skip marker a chapter stands for, judging by its title ("Opening", "End Credits",
+// "Previously On", ...). Long titles are names of scenes, not sections.
+pub fn chapter_marker_type(title: &str) -> Option<&'static str> {
+    let title = title.to_lowercase();
+    let words: Vec<&str> = title.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).collect();
+    if words.is_empty() || words.len() > 3 {
+        return None;
+    }
+
+    let has = |candidates: &[&str]| words.iter().any(|word| candidates.contains(word));
+    if has(&["recap", "previously"]) {
+        Some(RECAP)
+    } else if has(&["intro", "opening", "op"]) {
+        Some(INTRO)
+    } else if has(&["credits", "outro", "ending", "ed"]) {
+        Some(CREDITS)
+    } else {
+        None
+    }
+}
+
+// Replace the markers read from a movie's or episode's file with its current chapters.
+// Chapters named like an intro, credits or recap also become skip markers of that
+// type, unless one was set by hand, and replace a detected one.
+pub async fn replace_file_markers(
+    db: &mut sqlx::SqliteConnection,
+    media_id: &str,
+    episode_id: Option<&str>,
+    chapters: &[Chapter],
+) -> Result<()> {
+    sqlx::query("DELETE FROM markers WHERE media_id = ? AND episode_id IS ? AND source = ?")
+        .bind(media_id)
+        .bind(episode_id)
+        .bind(SOURCE_FILE)
+        .execute(&mut *db)
+        .await
+        .map_err(AppError::Database)?;
+
+    let manual: Vec<String> = sqlx::query_scalar(
+        "SELECT DISTINCT marker_type FROM markers WHERE media_id = ? AND episode_id IS ? AND source = ?"
+    )
+    .bind(media_id)
+    .bind(episode_id)
+    .bind(SOURCE_MANUAL)
+    .fetch_all(&mut *db)
+    .await
+    .map_err(AppError::Database)?;
+
+    let mut skip_types: Vec<&str> = Vec::new();
+    for chapter in chapters {
+        let mut types = vec![CHAPTER];
+        // Only the first chapter of each kind, "Opening Part 2" isn't another intro
+        if let Some(marker_type) = chapter.title.as_deref().and_then(chapter_marker_type) {
+            if !manual.iter().any(|manual| manual == marker_type) && !skip_types.contains(&marker_type) {
+                skip_types.push(marker_type);
+                types.push(marker_type);
+            }
+        }
+
+        if types.len() > 1 {
+            sqlx::query("DELETE FROM markers WHERE media_id = ? AND episode_id IS ? AND marker_type = ? AND source = ?")
+                .bind(media_id)
+                .bind(episode_id)
+                .bind(types[1])
+                .bind(SOURCE_DETECTED)
+                .execute(&mut *db)
+                .await
+                .map_err(AppError::Database)?;
+        }
+
+        for marker_type in types {
+            sqlx::query(
+                "INSERT INTO markers (id, media_id, episode_id, marker_type, start_time, end_time, title, source)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(generate_id())
+            .bind(media_id)
+            .bind(episode_id)
+            .bind(marker_type)
+            .bind(chapter.start)
+            .bind(chapter.end)
+            .bind(&chapter.title)
+            .bind(SOURCE_FILE)
+            .execute(&mut *db)
+            .await
+            .map_err(AppError::Database)?;
+        }
+    }
+
+    Ok(())
+}
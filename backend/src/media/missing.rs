@@ -0,0 +1,283 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Pool, Sqlite};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+use crate::db::models::Library;
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::jobs::progress::ScanProgress;
+
+// A movie or episode already in the database, as a scan finds it on disk or not
+#[derive(Debug, Clone)]
+pub struct KnownItem {
+    pub media_id: String,
+    pub episode_id: Option<String>,
+    pub path: String,
+    // Size of the file when it was last probed
+    pub size: Option<i64>,
+    pub missing_since: Option<DateTime<Utc>>,
+    pub content_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+    Movie,
+    Episode,
+}
+
+impl KnownItem {
+    fn table_and_id(&self) -> (&'static str, &str) {
+        match &self.episode_id {
+            Some(episode_id) => ("episodes", episode_id),
+            None => ("media", &self.media_id),
+        }
+    }
+}
+
+type KnownRow = (String, Option<String>, String, Option<i64>, Option<DateTime<Utc>>, Option<String>);
+
+fn known_items(rows: Vec<KnownRow>) -> HashMap<String, KnownItem> {
+    rows.into_iter()
+        .map(|(media_id, episode_id, path, size, missing_since, content_hash)| {
+            let item = KnownItem { media_id, episode_id, path: path.clone(), size, missing_since, content_hash };
+            (path, item)
+        })
+        .collect()
+}
+
+// What the paths of a library's files start with. The separator keeps /media/tv from
+// taking in /media/tv2, which matters now that scans remove what they don't find.
+pub fn path_prefix(library: &Library) -> String {
+    format!("{}{}", library.path.trim_end_matches(MAIN_SEPARATOR), MAIN_SEPARATOR)
+}
+
+// The movies of a library, by path
+pub async fn known_movies(db: &Pool<Sqlite>, library: &Library) -> Result<HashMap<String, KnownItem>> {
+    let prefix = path_prefix(library);
+    let rows: Vec<KnownRow> = sqlx::query_as(
+        "SELECT m.id, NULL, m.path, f.size, m.missing_since, m.content_hash FROM media m
+         LEFT JOIN media_files f ON f.path = m.path
+         WHERE m.type = 'movie' AND substr(m.path, 1, length(?)) = ?"
+    )
+    .bind(&prefix)
+    .bind(&prefix)
+    .fetch_all(db)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(known_items(rows))
+}
+
+// The episodes of a library, by path
+pub async fn known_episodes(db: &Pool<Sqlite>, library: &Library) -> Result<HashMap<String, KnownItem>> {
+    let prefix = path_prefix(library);
+    let rows: Vec<KnownRow> = sqlx::query_as(
+        "SELECT e.media_id, e.id, e.path, f.size, e.missing_since, e.content_hash FROM episodes e
+         LEFT JOIN media_files f ON f.path = e.path
+         WHERE substr(e.path, 1, length(?)) = ?"
+    )
+    .bind(&prefix)
+    .bind(&prefix)
+    .fetch_all(db)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(known_items(rows))
+}
+
+// Known items whose file isn't among the files found on disk. A partial scan only
+// looks in the folders of its `scope`, so the items elsewhere aren't its to check.
+pub fn vanished(known: &HashMap<String, KnownItem>, files: &[PathBuf], scope: Option<&[PathBuf]>) -> Vec<KnownItem> {
+    let on_disk: HashSet<String> = files.iter().map(|path| path.to_string_lossy().to_string()).collect();
+    known
+        .values()
+        .filter(|item| scope.is_none_or(|dirs| dirs.iter().any(|dir| Path::new(&item.path).starts_with(dir))))
+        .filter(|item| !on_disk.contains(&item.path))
+        .cloned()
+        .collect()
+}
+
+// Take the item that a new file with content hash `hash` was moved or renamed from. The
+// library's vanished items come first, then items of the same kind anywhere whose file
+// no longer exists, for files moved between libraries. Items from before content
+// hashes were kept match on the same file name and size instead.
+pub async fn take_moved(
+    db: &Pool<Sqlite>,
+    vanished: &mut Vec<KnownItem>,
+    path: &Path,
+    hash: Option<&str>,
+    kind: ItemKind,
+) -> Result<Option<KnownItem>> {
+    let size = std::fs::metadata(path).ok().map(|metadata| metadata.len() as i64);
+    let index = vanished.iter().position(|item| match &item.content_hash {
+        Some(known) => hash == Some(known.as_str()),
+        None => {
+            Path::new(&item.path).file_name() == path.file_name()
+                && size.is_some()
+                && item.size.is_none_or(|known| Some(known) == size)
+        }
+    });
+    if let Some(index) = index {
+        return Ok(Some(vanished.swap_remove(index)));
+    }
+
+    let Some(hash) = hash else {
+        return Ok(None);
+    };
+    let rows: Vec<KnownRow> = match kind {
+        ItemKind::Movie => sqlx::query_as(
+            "SELECT m.id, NULL, m.path, f.size, m.missing_since, m.content_hash FROM media m
+             LEFT JOIN media_files f ON f.path = m.path
+             WHERE m.type = 'movie' AND m.content_hash = ?"
+        ),
+        ItemKind::Episode => sqlx::query_as(
+            "SELECT e.media_id, e.id, e.path, f.size, e.missing_since, e.content_hash FROM episodes e
+             LEFT JOIN media_files f ON f.path = e.path
+             WHERE e.content_hash = ?"
+        ),
+    }
+    .bind(hash)
+    .fetch_all(db)
+    .await
+    .map_err(AppError::Database)?;
+
+    // A copy leaves the original in place, which keeps its item
+    for item in known_items(rows).into_values() {
+        if !tokio::fs::try_exists(&item.path).await.unwrap_or(true) {
+            return Ok(Some(item));
+        }
+    }
+    Ok(None)
+}
+
+// Point a moved item at its file's new path. Its probed details and embedded subtitles
+// move with it, so the file isn't probed again; watch progress and metadata stay.
+pub async fn relocate(db: &Pool<Sqlite>, item: &KnownItem, path: &str, hash: Option<&str>) -> Result<()> {
+    let (table, id) = item.table_and_id();
+    let mut tx = db.begin().await.map_err(AppError::Database)?;
+
+    sqlx::query(&format!(
+        "UPDATE {} SET path = ?, content_hash = COALESCE(?, content_hash), missing_since = NULL WHERE id = ?",
+        table
+    ))
+        .bind(path)
+        .bind(hash)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+    sqlx::query("UPDATE media_files SET path = ? WHERE path = ?")
+        .bind(path)
+        .bind(&item.path)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+    sqlx::query("UPDATE subtitles SET path = ? WHERE path = ? AND stream_index IS NOT NULL")
+        .bind(path)
+        .bind(&item.path)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+
+    tx.commit().await.map_err(AppError::Database)?;
+    tracing::info!("Moved {} to {}", item.path, path);
+    queries::touch_media(db, &item.media_id).await
+}
+
+// Clear the mark of an item whose file is back
+pub async fn found(db: &Pool<Sqlite>, item: &KnownItem) -> Result<()> {
+    if item.missing_since.is_none() {
+        return Ok(());
+    }
+
+    let (table, id) = item.table_and_id();
+    sqlx::query(&format!("UPDATE {} SET missing_since = NULL WHERE id = ?", table))
+        .bind(id)
+        .execute(db)
+        .await
+        .map_err(AppError::Database)?;
+    tracing::info!("{} is back", item.path);
+    queries::touch_media(db, &item.media_id).await
+}
+
+// Mark the items whose file is gone as missing, and remove the ones that have been
+// missing for longer than `grace`. Returns how many were marked and removed.
+pub async fn mark_missing(
+    db: &Pool<Sqlite>,
+    vanished: Vec<KnownItem>,
+    grace: Duration,
+    progress: &ScanProgress,
+) -> Result<(usize, usize)> {
+    let now = Utc::now();
+    let (mut missing, mut removed) = (0, 0);
+
+    for item in vanished {
+        let (table, id) = item.table_and_id();
+        let since = item.missing_since.unwrap_or(now);
+        let changed = item.missing_since.is_none() || now - since >= grace;
+
+        if now - since >= grace {
+            // Its files, streams, subtitles, markers and progress go with it
+            sqlx::query(&format!("DELETE FROM {} WHERE id = ?", table))
+                .bind(id)
+                .execute(db)
+                .await
+                .map_err(AppError::Database)?;
+            tracing::info!("Removed {}, whose file is gone", item.path);
+            removed += 1;
+            progress.removed();
+        } else {
+            if item.missing_since.is_none() {
+                sqlx::query(&format!("UPDATE {} SET missing_since = ? WHERE id = ?", table))
+                    .bind(now)
+                    .bind(id)
+                    .execute(db)
+                    .await
+                    .map_err(AppError::Database)?;
+                tracing::info!("{} is missing", item.path);
+            }
+            missing += 1;
+            progress.missing();
+        }
+
+        if changed && item.episode_id.is_some() {
+            // The show lists its episodes
+            queries::touch_media(db, &item.media_id).await?;
+        }
+    }
+
+    Ok((missing, removed))
+}
+
+// Remove the seasons and shows of a library that have no episodes left
+pub async fn prune_shows(db: &Pool<Sqlite>, library: &Library) -> Result<()> {
+    let prefix = path_prefix(library);
+
+    let seasons = sqlx::query(
+        "DELETE FROM seasons WHERE id NOT IN (SELECT season_id FROM episodes)
+         AND media_id IN (SELECT id FROM media WHERE type = 'tvshow' AND substr(path, 1, length(?)) = ?)"
+    )
+    .bind(&prefix)
+    .bind(&prefix)
+    .execute(db)
+    .await
+    .map_err(AppError::Database)?
+    .rows_affected();
+
+    let shows = sqlx::query(
+        "DELETE FROM media WHERE type = 'tvshow' AND substr(path, 1, length(?)) = ?
+         AND id NOT IN (SELECT media_id FROM episodes)"
+    )
+    .bind(&prefix)
+    .bind(&prefix)
+    .execute(db)
+    .await
+    .map_err(AppError::Database)?
+    .rows_affected();
+
+    if seasons > 0 || shows > 0 {
+        tracing::info!("Removed {} empty seasons and {} empty shows from {}", seasons, shows, library.name);
+    }
+    Ok(())
+}
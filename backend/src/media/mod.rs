@@ -1 +1,9 @@
-pub mod scanner;
\ No newline at end of file
+pub mod fingerprint;
+pub mod identity;
+pub mod intro;
+pub mod markers;
+pub mod missing;
+pub mod probe;
+pub mod sandbox;
+pub mod scanner;
+pub mod sidecars;
\ No newline at end of file
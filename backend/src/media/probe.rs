@@ -0,0 +1,188 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Sqlite};
+use std::path::Path;
+
+use crate::container::index::{self, Track, TrackKind};
+use crate::db::generate_id;
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::media::markers;
+use crate::subtitles::{embedded, sidecar};
+
+// Usual name of a channel count, as shown next to the codec ("DTS-HD MA 7.1")
+fn channel_layout(channels: u32) -> Option<String> {
+    let layout = match channels {
+        0 => return None,
+        1 => "mono",
+        2 => "stereo",
+        3 => "2.1",
+        6 => "5.1",
+        7 => "6.1",
+        8 => "7.1",
+        other => return Some(format!("{} channels", other)),
+    };
+
+    Some(layout.to_string())
+}
+
+async fn insert_stream(db: &mut sqlx::SqliteConnection, file_id: &str, track: &Track) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO media_streams
+         (id, file_id, stream_index, kind, codec, codec_string, profile, language, title, is_default, is_forced,
+          width, height, frame_rate, bit_depth, hdr, channels, channel_layout, sample_rate)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(generate_id())
+    .bind(file_id)
+    .bind(track.number as i64)
+    .bind(track.kind.as_str())
+    .bind(&track.codec)
+    .bind(&track.codec_string)
+    .bind(&track.profile)
+    .bind(&track.language)
+    .bind(&track.name)
+    .bind(track.default)
+    .bind(track.forced)
+    .bind(track.width.map(i64::from))
+    .bind(track.height.map(i64::from))
+    .bind(track.frame_rate)
+    .bind(track.bit_depth.map(i64::from))
+    .bind(track.hdr.map(|hdr| hdr.as_str()))
+    .bind(track.channels.map(i64::from))
+    .bind(track.channels.and_then(channel_layout))
+    .bind(track.sample_rate.map(i64::from))
+    .execute(db)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(())
+}
+
+// List a text subtitle track of a video next to its subtitle files, so it can be
+// picked and extracted like one
+async fn insert_subtitle(
+    db: &mut sqlx::SqliteConnection,
+    media_id: &str,
+    episode_id: Option<&str>,
+    path: &str,
+    track: &Track,
+) -> Result<()> {
+    let language = track.language.as_deref().filter(|language| *language != "und");
+    let sdh = track.name.as_deref().is_some_and(|name| name.to_ascii_uppercase().contains("SDH"));
+
+    sqlx::query(
+        "INSERT INTO subtitles (id, media_id, episode_id, path, stream_index, format, language, title, is_forced, is_sdh, is_default)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(generate_id())
+    .bind(media_id)
+    .bind(episode_id)
+    .bind(path)
+    .bind(track.number as i64)
+    .bind(&track.codec)
+    .bind(language.map(|language| sidecar::language_code(language).unwrap_or(language)))
+    .bind(&track.name)
+    .bind(track.forced)
+    .bind(sdh)
+    .bind(track.default)
+    .execute(db)
+    .await
+    .map_err(AppError::Database)?;
+
+    Ok(())
+}
+
+// Read the container, duration, tracks and chapters of a file into media_files,
+// media_streams and markers. Files that haven't changed since they were last probed
+// are skipped. Returns whether the file was probed.
+pub async fn probe_file(db: &Pool<Sqlite>, media_id: &str, episode_id: Option<&str>, path: &Path) -> Result<bool> {
+    let metadata = tokio::fs::metadata(path).await?;
+    let size = metadata.len() as i64;
+    let modified_at: Option<DateTime<Utc>> = metadata.modified().ok().map(DateTime::from);
+    let path_str = path.to_string_lossy().to_string();
+
+    if let Some(existing) = queries::get_media_file_by_path(db, &path_str).await? {
+        if existing.size == size && existing.modified_at == modified_at && existing.media_id == media_id {
+            return Ok(false);
+        }
+    }
+
+    let owned_path = path.to_path_buf();
+    let probed = tokio::task::spawn_blocking(move || index::probe_file(&owned_path))
+        .await
+        .map_err(|e| AppError::Server(format!("Probing task failed: {}", e)))?;
+
+    let media_index = match probed {
+        Ok(media_index) => media_index,
+        // Containers we can't read (AVI, WMV, ...) and damaged files just have no details
+        Err(e) if matches!(
+            e.kind(),
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::Unsupported | std::io::ErrorKind::UnexpectedEof
+        ) => {
+            tracing::debug!("Not probing {}: {}", path.display(), e);
+            return Ok(false);
+        }
+        Err(e) => return Err(AppError::Io(e)),
+    };
+
+    let duration = Some(media_index.duration).filter(|duration| *duration > 0.0);
+    let bitrate = duration.map(|duration| (size as f64 * 8.0 / duration).round() as i64);
+    let file_id = generate_id();
+
+    let mut tx = db.begin().await.map_err(AppError::Database)?;
+
+    sqlx::query("DELETE FROM media_streams WHERE file_id IN (SELECT id FROM media_files WHERE path = ?)")
+        .bind(&path_str)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+    sqlx::query("DELETE FROM media_files WHERE path = ?")
+        .bind(&path_str)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+    sqlx::query("DELETE FROM subtitles WHERE path = ? AND stream_index IS NOT NULL")
+        .bind(&path_str)
+        .execute(&mut *tx)
+        .await
+        .map_err(AppError::Database)?;
+
+    sqlx::query(
+        "INSERT INTO media_files (id, media_id, episode_id, path, container, duration, bitrate, size, modified_at, probed_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&file_id)
+    .bind(media_id)
+    .bind(episode_id)
+    .bind(&path_str)
+    .bind(media_index.container.as_str())
+    .bind(duration)
+    .bind(bitrate)
+    .bind(size)
+    .bind(modified_at)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await
+    .map_err(AppError::Database)?;
+
+    for track in &media_index.tracks {
+        insert_stream(&mut tx, &file_id, track).await?;
+        if track.kind == TrackKind::Subtitle && embedded::is_text_codec(&track.codec) {
+            insert_subtitle(&mut tx, media_id, episode_id, &path_str, track).await?;
+        }
+    }
+    markers::replace_file_markers(&mut tx, media_id, episode_id, &media_index.chapters).await?;
+
+    tx.commit().await.map_err(AppError::Database)?;
+
+    // The item's details now include the file
+    queries::touch_media(db, media_id).await?;
+
+    tracing::info!(
+        "Probed {} ({} tracks, {} chapters)",
+        path.display(),
+        media_index.tracks.len(),
+        media_index.chapters.len()
+    );
+    Ok(true)
+}
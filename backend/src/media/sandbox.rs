@@ -0,0 +1,126 @@
+use sqlx::{Pool, Sqlite};
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::db::models::Library;
+use crate::db::queries;
+use crate::error::{AppError, Result};
+
+// System directories that can't be a library, be inside one or contain one
+const PROTECTED_PATHS: [&str; 16] = [
+    "/bin", "/boot", "/dev", "/etc", "/lib", "/lib32", "/lib64", "/proc",
+    "/root", "/run", "/sbin", "/sys", "/usr", "/var/lib", "/var/log", "/var/run",
+];
+
+// Keeps the server to the library folders. Paths are compared after resolving symlinks
+// and `..`, so neither a link in a library nor a crafted path in the database leads
+// anywhere else.
+#[derive(Clone)]
+pub struct PathSandbox {
+    // LIBRARY_ROOTS, the folders libraries have to be in; anywhere but the protected
+    // paths when unset
+    roots: Option<Vec<PathBuf>>,
+}
+
+// The path with symlinks resolved, or NotFound when it doesn't exist
+pub async fn canonicalize(path: &Path) -> Result<PathBuf> {
+    tokio::fs::canonicalize(path).await.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => AppError::NotFound(format!("Path does not exist: {}", path.display())),
+        _ => AppError::Io(e),
+    })
+}
+
+// Whether either path is inside the other
+fn overlaps(a: &Path, b: &Path) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+impl PathSandbox {
+    // LIBRARY_ROOTS is a list of folders separated like PATH. Roots that don't exist
+    // are left out.
+    pub fn from_env() -> Self {
+        let roots = env::var_os("LIBRARY_ROOTS").filter(|roots| !roots.is_empty()).map(|roots| {
+            env::split_paths(&roots)
+                .filter_map(|root| match std::fs::canonicalize(&root) {
+                    Ok(root) => Some(root),
+                    Err(e) => {
+                        tracing::warn!("Ignoring library root {}: {}", root.display(), e);
+                        None
+                    }
+                })
+                .collect()
+        });
+
+        Self { roots }
+    }
+
+    // Whether a folder may hold a library: an allowed root or inside one, and clear of
+    // the protected system paths
+    fn check_root(&self, path: &Path) -> Result<()> {
+        if path.parent().is_none() || PROTECTED_PATHS.iter().any(|protected| overlaps(path, Path::new(protected))) {
+            return Err(AppError::InvalidInput(format!("{} is a system path and can't be a library", path.display())));
+        }
+        if let Some(roots) = &self.roots {
+            if !roots.iter().any(|root| path.starts_with(root)) {
+                return Err(AppError::InvalidInput(format!(
+                    "{} is outside the folders set in LIBRARY_ROOTS",
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // The resolved path of a new library's folder. Libraries can't overlap, or the
+    // same files would be scanned into both.
+    pub async fn check_new_library(&self, db: &Pool<Sqlite>, path: &str) -> Result<PathBuf> {
+        let canonical = canonicalize(Path::new(path)).await?;
+        self.check_root(&canonical)?;
+
+        for library in queries::get_all_libraries(db).await? {
+            let existing = canonicalize(Path::new(&library.path)).await.unwrap_or_else(|_| PathBuf::from(&library.path));
+            if overlaps(&canonical, &existing) {
+                return Err(AppError::InvalidInput(format!(
+                    "{} overlaps the library {} at {}",
+                    canonical.display(),
+                    library.name,
+                    library.path
+                )));
+            }
+        }
+
+        Ok(canonical)
+    }
+
+    // The resolved folder of a library about to be scanned, checked again in case
+    // LIBRARY_ROOTS changed since the library was added
+    pub async fn library_root(&self, library: &Library) -> Result<PathBuf> {
+        let root = canonicalize(Path::new(&library.path)).await?;
+        self.check_root(&root)?;
+        Ok(root)
+    }
+
+    // Check that a file about to be served is inside one of the libraries. Rows of
+    // deleted libraries and paths leading out through symlinks are refused.
+    pub async fn check_file(&self, db: &Pool<Sqlite>, path: &Path) -> Result<()> {
+        let canonical = canonicalize(path).await?;
+
+        for library in queries::get_all_libraries(db).await? {
+            let Ok(root) = self.library_root(&library).await else {
+                continue;
+            };
+            if canonical.starts_with(&root) {
+                return Ok(());
+            }
+        }
+
+        tracing::warn!("Refused to serve {}, which is outside every library", path.display());
+        Err(AppError::Forbidden(format!("{} is not in a library", path.display())))
+    }
+}
+
+// Whether a file found while scanning a library really is inside it, and not a
+// symlink to somewhere else
+pub fn in_library(root: &Path, path: &Path) -> bool {
+    std::fs::canonicalize(path).is_ok_and(|canonical| canonical.starts_with(root))
+}
@@ -1,13 +1,18 @@
+use chrono::Duration;
 use sqlx::{Pool, Sqlite, Row};
 use walkdir::WalkDir;
-use std::path::{Path};
+use std::path::{Path, PathBuf};
 use std::collections::HashSet;
 use chrono::Utc;
 use std::ffi::OsStr;
 
 use crate::db::models::Library;
 use crate::db::generate_id;
+use crate::db::queries;
 use crate::error::{AppError, Result};
+use crate::jobs::progress::{Outcome, ScanProgress};
+use crate::media::{identity, missing, probe, sandbox, sidecars};
+use crate::media::missing::{ItemKind, KnownItem};
 
 // Video file extensions
 const VIDEO_EXTENSIONS: [&str; 10] = [
@@ -19,218 +24,365 @@ const AUDIO_EXTENSIONS: [&str; 8] = [
     "mp3", "flac", "m4a", "wav", "ogg", "aac", "wma", "aiff"
 ];
 
-pub async fn scan_movies(db: &Pool<Sqlite>, library: &Library) -> Result<serde_json::Value> {
+// Scan a library by its media type, counting what is found in `progress` and stopping
+// early when it is cancelled. Items whose file is gone are marked missing, and removed
+// once they have been missing for `grace`. With a `scope`, only the files in those
+// folders of the library are scanned, and only their items can go missing.
+pub async fn scan_library(
+    db: &Pool<Sqlite>,
+    library: &Library,
+    root: &Path,
+    scope: Option<&[PathBuf]>,
+    progress: &ScanProgress,
+    grace: Duration,
+) -> Result<serde_json::Value> {
+    match library.media_type.as_str() {
+        "movie" => scan_movies(db, library, root, scope, progress, grace).await,
+        "tvshow" => scan_tv_shows(db, library, root, scope, progress, grace).await,
+        "music" => scan_music(db, library).await,
+        _ => Err(AppError::InvalidInput(
+            format!("Invalid media type: {}. Must be 'movie', 'tvshow', or 'music'", library.media_type)
+        )),
+    }
+}
+
+// Scan a movie library. `root` is the library's resolved folder, files that resolve to
+// somewhere outside it are skipped.
+pub async fn scan_movies(
+    db: &Pool<Sqlite>,
+    library: &Library,
+    root: &Path,
+    scope: Option<&[PathBuf]>,
+    progress: &ScanProgress,
+    grace: Duration,
+) -> Result<serde_json::Value> {
     let mut added_count = 0;
     let mut existing_count = 0;
+    let mut moved_count = 0;
+    let mut probed_count = 0;
+    let mut subtitle_count = 0;
     let library_path = Path::new(&library.path);
     
     // Get existing media paths in this library
-    let path_pattern = format!("{}%", library.path);
-    let existing_rows = sqlx::query("SELECT path FROM media WHERE path LIKE ? AND type = 'movie'")
-        .bind(&path_pattern)
-        .fetch_all(db)
-        .await
-        .map_err(AppError::Database)?;
-    
-    let existing_paths: HashSet<String> = existing_rows
-        .iter()
-        .map(|row| row.get::<String, _>("path"))
-        .collect();
+    let existing_paths = missing::known_movies(db, library).await?;
     
     // Find all video files in the library
-    for entry in WalkDir::new(library_path).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
+    let files = scan_files(library_path, scope).await?;
+    progress.set_total(files.len());
+    let mut vanished = missing::vanished(&existing_paths, &files, scope);
+    for path in &files {
+        if progress.is_cancelled() {
+            break;
+        }
+        let path = path.as_path();
+        if !sandbox::in_library(root, path) {
+            tracing::warn!("Skipping {}, which links to outside the library", path.display());
+            progress.file_done(Outcome::Skipped);
+            continue;
+        }
+        let path_str = path.to_string_lossy().to_string();
         
-        // Check if it's a video file
-        if path.is_file() && is_video_file(path) {
-            let path_str = path.to_string_lossy().to_string();
-            
-            // Skip if already in database
-            if existing_paths.contains(&path_str) {
-                existing_count += 1;
-                continue;
+        // Skip if already in database, only re-probing the file if it changed
+        if let Some(known) = existing_paths.get(&path_str) {
+            missing::found(db, known).await?;
+            existing_count += 1;
+            let probed = probe_quietly(db, &known.media_id, None, path, progress).await;
+            if probed {
+                probed_count += 1;
             }
+            if probed || known.content_hash.is_none() {
+                rehash_quietly(db, known, path, progress).await?;
+            }
+            subtitle_count += sync_sidecars_quietly(db, &known.media_id, None, path, progress).await;
+            progress.file_done(Outcome::Existing);
+            continue;
+        }
+        
+        // A movie whose file was moved or renamed, here or from another library, keeps
+        // its details and watch history
+        let hash = hash_quietly(path, progress).await;
+        if let Some(moved) = missing::take_moved(db, &mut vanished, path, hash.as_deref(), ItemKind::Movie).await? {
+            missing::relocate(db, &moved, &path_str, hash.as_deref()).await?;
+            existing_count += 1;
+            moved_count += 1;
+            if probe_quietly(db, &moved.media_id, None, path, progress).await {
+                probed_count += 1;
+            }
+            subtitle_count += sync_sidecars_quietly(db, &moved.media_id, None, path, progress).await;
+            progress.file_done(Outcome::Existing);
+            continue;
+        }
+        
+        // Extract movie info from path
+        if let Some(title) = extract_movie_title(path) {
+            let year = extract_year_from_filename(path);
             
-            // Extract movie info from path
-            if let Some(title) = extract_movie_title(path) {
-                let year = extract_year_from_filename(path);
-                
-                // Add to database
-                let id = generate_id();
-                let now = Utc::now();
-                
-                sqlx::query(
-                    "INSERT INTO media (id, title, type, year, path, is_directory, added_at, watch_count) 
-                     VALUES (?, ?, ?, ?, ?, ?, ?, 0)"
-                )
-                .bind(&id)
-                .bind(&title)
-                .bind("movie")
-                .bind(year)
-                .bind(&path_str)
-                .bind(false) // Movie is not a directory
-                .bind(now)
-                .execute(db)
-                .await
-                .map_err(AppError::Database)?;
-                
-                added_count += 1;
-                tracing::info!("Added movie: {} ({})", title, path_str);
+            // Add to database
+            let id = generate_id();
+            let now = Utc::now();
+            
+            sqlx::query(
+                "INSERT INTO media (id, title, type, year, path, is_directory, added_at, watch_count, updated_at, content_hash) 
+                 VALUES (?, ?, ?, ?, ?, ?, ?, 0, ?, ?)"
+            )
+            .bind(&id)
+            .bind(&title)
+            .bind("movie")
+            .bind(year)
+            .bind(&path_str)
+            .bind(false) // Movie is not a directory
+            .bind(now)
+            .bind(now)
+            .bind(&hash)
+            .execute(db)
+            .await
+            .map_err(AppError::Database)?;
+            
+            added_count += 1;
+            tracing::info!("Added movie: {} ({})", title, path_str);
+            
+            if probe_quietly(db, &id, None, path, progress).await {
+                probed_count += 1;
             }
+            subtitle_count += sync_sidecars_quietly(db, &id, None, path, progress).await;
+            progress.file_done(Outcome::Added);
+        } else {
+            progress.file_done(Outcome::Skipped);
         }
     }
     
+    // What wasn't found is only known once every file was looked at
+    let (missing_count, removed_count) = if progress.is_cancelled() {
+        (0, 0)
+    } else {
+        missing::mark_missing(db, vanished, grace, progress).await?
+    };
+    
     Ok(serde_json::json!({
         "added": added_count,
         "existing": existing_count,
+        "moved": moved_count,
+        "missing": missing_count,
+        "removed": removed_count,
+        "probed": probed_count,
+        "subtitles": subtitle_count,
         "libraryId": library.id,
         "libraryName": library.name
     }))
 }
 
-pub async fn scan_tv_shows(db: &Pool<Sqlite>, library: &Library) -> Result<serde_json::Value> {
+// Scan a TV library, see scan_movies
+pub async fn scan_tv_shows(
+    db: &Pool<Sqlite>,
+    library: &Library,
+    root: &Path,
+    scope: Option<&[PathBuf]>,
+    progress: &ScanProgress,
+    grace: Duration,
+) -> Result<serde_json::Value> {
     let mut added_shows = 0;
     let mut added_seasons = 0;
     let mut added_episodes = 0;
     let mut existing_episodes = 0;
+    let mut moved_episodes = 0;
+    let mut probed_episodes = 0;
+    let mut added_subtitles = 0;
     
     let library_path = Path::new(&library.path);
     
     // Get existing TV episode paths
-    let path_pattern = format!("{}%", library.path);
-    let existing_rows = sqlx::query("SELECT path FROM episodes WHERE path LIKE ?")
-        .bind(&path_pattern)
-        .fetch_all(db)
-        .await
-        .map_err(AppError::Database)?;
-    
-    let existing_paths: HashSet<String> = existing_rows
-        .iter()
-        .map(|row| row.get::<String, _>("path"))
-        .collect();
+    let existing_paths = missing::known_episodes(db, library).await?;
     
     // Map to track TV shows we've already processed
     let mut processed_shows: HashSet<String> = HashSet::new();
     
     // Find all video files in the library
-    for entry in WalkDir::new(library_path).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
+    let files = scan_files(library_path, scope).await?;
+    progress.set_total(files.len());
+    let mut vanished = missing::vanished(&existing_paths, &files, scope);
+    for path in &files {
+        if progress.is_cancelled() {
+            break;
+        }
+        let path = path.as_path();
+        if !sandbox::in_library(root, path) {
+            tracing::warn!("Skipping {}, which links to outside the library", path.display());
+            progress.file_done(Outcome::Skipped);
+            continue;
+        }
+        let path_str = path.to_string_lossy().to_string();
         
-        // Check if it's a video file
-        if path.is_file() && is_video_file(path) {
-            let path_str = path.to_string_lossy().to_string();
-            
-            // Skip if already in database
-            if existing_paths.contains(&path_str) {
-                existing_episodes += 1;
-                continue;
+        // Skip if already in database, only re-probing the file if it changed
+        if let Some(known) = existing_paths.get(&path_str) {
+            missing::found(db, known).await?;
+            existing_episodes += 1;
+            let probed = probe_quietly(db, &known.media_id, known.episode_id.as_deref(), path, progress).await;
+            if probed {
+                probed_episodes += 1;
             }
+            if probed || known.content_hash.is_none() {
+                rehash_quietly(db, known, path, progress).await?;
+            }
+            added_subtitles += sync_sidecars_quietly(db, &known.media_id, known.episode_id.as_deref(), path, progress).await;
+            progress.file_done(Outcome::Existing);
+            continue;
+        }
+        
+        // An episode whose file was moved or renamed stays in its show and season
+        let hash = hash_quietly(path, progress).await;
+        if let Some(moved) = missing::take_moved(db, &mut vanished, path, hash.as_deref(), ItemKind::Episode).await? {
+            missing::relocate(db, &moved, &path_str, hash.as_deref()).await?;
+            existing_episodes += 1;
+            moved_episodes += 1;
+            if probe_quietly(db, &moved.media_id, moved.episode_id.as_deref(), path, progress).await {
+                probed_episodes += 1;
+            }
+            added_subtitles += sync_sidecars_quietly(db, &moved.media_id, moved.episode_id.as_deref(), path, progress).await;
+            progress.file_done(Outcome::Existing);
+            continue;
+        }
+        
+        // Try to parse as a TV episode
+        if let Some((show_title, season_num, episode_num)) = extract_tv_info(path) {
+            // Check if we already have this show
+            let show_path = path.parent().unwrap().parent().unwrap().to_string_lossy().to_string();
             
-            // Try to parse as a TV episode
-            if let Some((show_title, season_num, episode_num)) = extract_tv_info(path) {
-                // Check if we already have this show
-                let show_path = path.parent().unwrap().parent().unwrap().to_string_lossy().to_string();
-                
-                let show_id = if !processed_shows.contains(&show_title) {
-                    // Create new show
-                    let id = generate_id();
-                    let now = Utc::now();
-                    
-                    sqlx::query(
-                        "INSERT INTO media (id, title, type, path, is_directory, added_at, watch_count) 
-                         VALUES (?, ?, ?, ?, ?, ?, 0)"
-                    )
-                    .bind(&id)
-                    .bind(&show_title)
-                    .bind("tvshow")
-                    .bind(&show_path)
-                    .bind(true) // TV show is a directory
-                    .bind(now)
-                    .execute(db)
-                    .await
-                    .map_err(AppError::Database)?;
-                    
-                    processed_shows.insert(show_title.clone());
-                    added_shows += 1;
-                    tracing::info!("Added TV show: {}", show_title);
-                    
-                    id
-                } else {
-                    // Get existing show ID
-                    let row = sqlx::query("SELECT id FROM media WHERE title = ? AND type = 'tvshow'")
-                        .bind(&show_title)
-                        .fetch_one(db)
-                        .await
-                        .map_err(AppError::Database)?;
-                    
-                    row.get::<String, _>("id")
-                };
+            // Shows from earlier scans are found by their folder, which partial scans of
+            // a single folder depend on
+            let known_show: Option<String> = sqlx::query_scalar("SELECT id FROM media WHERE type = 'tvshow' AND path = ?")
+                .bind(&show_path)
+                .fetch_optional(db)
+                .await
+                .map_err(AppError::Database)?;
+            
+            let show_id = if let Some(id) = known_show {
+                id
+            } else if !processed_shows.contains(&show_title) {
+                // Create new show
+                let id = generate_id();
+                let now = Utc::now();
                 
-                // Check if we already have this season
-                let season_row = sqlx::query(
-                    "SELECT id FROM seasons WHERE media_id = ? AND season_number = ?"
+                sqlx::query(
+                    "INSERT INTO media (id, title, type, path, is_directory, added_at, watch_count, updated_at) 
+                     VALUES (?, ?, ?, ?, ?, ?, 0, ?)"
                 )
-                .bind(&show_id)
-                .bind(season_num)
-                .fetch_optional(db)
+                .bind(&id)
+                .bind(&show_title)
+                .bind("tvshow")
+                .bind(&show_path)
+                .bind(true) // TV show is a directory
+                .bind(now)
+                .bind(now)
+                .execute(db)
                 .await
                 .map_err(AppError::Database)?;
                 
-                let season_id = if let Some(row) = season_row {
-                    row.get::<String, _>("id")
-                } else {
-                    // Create new season
-                    let id = generate_id();
-                    let season_title = format!("Season {}", season_num);
-                    
-                    sqlx::query(
-                        "INSERT INTO seasons (id, media_id, season_number, title) 
-                         VALUES (?, ?, ?, ?)"
-                    )
-                    .bind(&id)
-                    .bind(&show_id)
-                    .bind(season_num)
-                    .bind(&season_title)
-                    .execute(db)
+                processed_shows.insert(show_title.clone());
+                added_shows += 1;
+                tracing::info!("Added TV show: {}", show_title);
+                
+                id
+            } else {
+                // Get existing show ID
+                let row = sqlx::query("SELECT id FROM media WHERE title = ? AND type = 'tvshow'")
+                    .bind(&show_title)
+                    .fetch_one(db)
                     .await
                     .map_err(AppError::Database)?;
-                    
-                    added_seasons += 1;
-                    tracing::info!("Added season {} for show {}", season_num, show_title);
-                    
-                    id
-                };
                 
-                // Create episode
-                let episode_id = generate_id();
-                let file_name = path.file_name().unwrap().to_str().unwrap();
-                let episode_title = extract_episode_title(file_name, episode_num);
+                row.get::<String, _>("id")
+            };
+            
+            // Check if we already have this season
+            let season_row = sqlx::query(
+                "SELECT id FROM seasons WHERE media_id = ? AND season_number = ?"
+            )
+            .bind(&show_id)
+            .bind(season_num)
+            .fetch_optional(db)
+            .await
+            .map_err(AppError::Database)?;
+            
+            let season_id = if let Some(row) = season_row {
+                row.get::<String, _>("id")
+            } else {
+                // Create new season
+                let id = generate_id();
+                let season_title = format!("Season {}", season_num);
                 
                 sqlx::query(
-                    "INSERT INTO episodes (id, media_id, season_id, episode_number, title, path) 
-                     VALUES (?, ?, ?, ?, ?, ?)"
+                    "INSERT INTO seasons (id, media_id, season_number, title) 
+                     VALUES (?, ?, ?, ?)"
                 )
-                .bind(&episode_id)
+                .bind(&id)
                 .bind(&show_id)
-                .bind(&season_id)
-                .bind(episode_num)
-                .bind(&episode_title)
-                .bind(&path_str)
+                .bind(season_num)
+                .bind(&season_title)
                 .execute(db)
                 .await
                 .map_err(AppError::Database)?;
                 
-                added_episodes += 1;
-                tracing::info!("Added episode {} for show {} season {}", episode_num, show_title, season_num);
+                added_seasons += 1;
+                tracing::info!("Added season {} for show {}", season_num, show_title);
+                
+                id
+            };
+            
+            // Create episode
+            let episode_id = generate_id();
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            let episode_title = extract_episode_title(file_name, episode_num);
+            
+            sqlx::query(
+                "INSERT INTO episodes (id, media_id, season_id, episode_number, title, path, content_hash) 
+                 VALUES (?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(&episode_id)
+            .bind(&show_id)
+            .bind(&season_id)
+            .bind(episode_num)
+            .bind(&episode_title)
+            .bind(&path_str)
+            .bind(&hash)
+            .execute(db)
+            .await
+            .map_err(AppError::Database)?;
+            
+            // The show's details now list one more episode
+            queries::touch_media(db, &show_id).await?;
+            
+            added_episodes += 1;
+            tracing::info!("Added episode {} for show {} season {}", episode_num, show_title, season_num);
+            
+            if probe_quietly(db, &show_id, Some(&episode_id), path, progress).await {
+                probed_episodes += 1;
             }
+            added_subtitles += sync_sidecars_quietly(db, &show_id, Some(&episode_id), path, progress).await;
+            progress.file_done(Outcome::Added);
+        } else {
+            progress.file_done(Outcome::Skipped);
         }
     }
     
+    // Seasons and shows go once their last episode is removed
+    let (missing_episodes, removed_episodes) = if progress.is_cancelled() {
+        (0, 0)
+    } else {
+        let counts = missing::mark_missing(db, vanished, grace, progress).await?;
+        missing::prune_shows(db, library).await?;
+        counts
+    };
+    
     Ok(serde_json::json!({
         "addedShows": added_shows,
         "addedSeasons": added_seasons,
         "addedEpisodes": added_episodes,
         "existingEpisodes": existing_episodes,
+        "movedEpisodes": moved_episodes,
+        "missingEpisodes": missing_episodes,
+        "removedEpisodes": removed_episodes,
+        "probedEpisodes": probed_episodes,
+        "subtitles": added_subtitles,
         "libraryId": library.id,
         "libraryName": library.name
     }))
@@ -247,6 +399,94 @@ pub async fn scan_music(db: &Pool<Sqlite>, library: &Library) -> Result<serde_js
 
 // Helper functions
 
+// The video files a scan goes through: the whole library's, or those in the folders
+// of a partial scan. A folder that is gone has none left.
+async fn scan_files(library_path: &Path, scope: Option<&[PathBuf]>) -> Result<Vec<PathBuf>> {
+    let Some(dirs) = scope else {
+        return video_files(library_path).await;
+    };
+
+    let mut files = Vec::new();
+    for dir in dirs {
+        files.extend(video_files(dir).await?);
+    }
+    Ok(files)
+}
+
+// Video files in a library, listed before scanning them so that the scan's progress
+// has a total. The walk blocks, so it runs off the async workers.
+async fn video_files(library_path: &Path) -> Result<Vec<PathBuf>> {
+    let library_path = library_path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        WalkDir::new(library_path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|entry| entry.into_path())
+            .filter(|path| path.is_file() && is_video_file(path))
+            .collect()
+    })
+    .await
+    .map_err(|e| AppError::Server(format!("Failed to list library files: {}", e)))
+}
+
+// Probe a file for its technical details. A file that can't be probed doesn't fail
+// the scan, it just has no details.
+async fn probe_quietly(
+    db: &Pool<Sqlite>,
+    media_id: &str,
+    episode_id: Option<&str>,
+    path: &Path,
+    progress: &ScanProgress,
+) -> bool {
+    match probe::probe_file(db, media_id, episode_id, path).await {
+        Ok(probed) => probed,
+        Err(e) => {
+            tracing::warn!("Failed to probe {}: {}", path.display(), e);
+            progress.error();
+            false
+        }
+    }
+}
+
+// Hash a file's content, which is how it is recognized after a move. A file that
+// can't be read is only recognized by its path.
+async fn hash_quietly(path: &Path, progress: &ScanProgress) -> Option<String> {
+    match identity::content_hash(path).await {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            tracing::warn!("Failed to hash {}: {}", path.display(), e);
+            progress.error();
+            None
+        }
+    }
+}
+
+// Hash a known item's file again, after it changed or if it never was
+async fn rehash_quietly(db: &Pool<Sqlite>, known: &KnownItem, path: &Path, progress: &ScanProgress) -> Result<()> {
+    match hash_quietly(path, progress).await {
+        Some(hash) => identity::store_hash(db, &known.media_id, known.episode_id.as_deref(), &hash).await,
+        None => Ok(()),
+    }
+}
+
+// Pick up the subtitle files next to a video, without failing the scan either
+async fn sync_sidecars_quietly(
+    db: &Pool<Sqlite>,
+    media_id: &str,
+    episode_id: Option<&str>,
+    path: &Path,
+    progress: &ScanProgress,
+) -> usize {
+    match sidecars::sync_sidecars(db, media_id, episode_id, path, is_video_file).await {
+        Ok(added) => added,
+        Err(e) => {
+            tracing::warn!("Failed to look for subtitles of {}: {}", path.display(), e);
+            progress.error();
+            0
+        }
+    }
+}
+
 fn is_video_file(path: &Path) -> bool {
     if let Some(ext) = path.extension().and_then(OsStr::to_str) {
         VIDEO_EXTENSIONS.contains(&ext.to_lowercase().as_str())
@@ -255,7 +495,7 @@ fn is_video_file(path: &Path) -> bool {
     }
 }
 
-fn is_audio_file(path: &Path) -> bool {
+pub fn is_audio_file(path: &Path) -> bool {
     if let Some(ext) = path.extension().and_then(OsStr::to_str) {
         AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str())
     } else {
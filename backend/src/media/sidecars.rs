@@ -0,0 +1,95 @@
+use sqlx::{Pool, Sqlite, Row};
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::db::generate_id;
+use crate::error::{AppError, Result};
+use crate::subtitles::sidecar;
+
+// Bring the subtitles of a video in line with the subtitle files next to it: new
+// files are added and rows of files that are gone are removed. Returns how many
+// subtitles were added.
+pub async fn sync_sidecars(
+    db: &Pool<Sqlite>,
+    media_id: &str,
+    episode_id: Option<&str>,
+    video: &Path,
+    is_video: fn(&Path) -> bool,
+) -> Result<usize> {
+    let owned_video = video.to_path_buf();
+    let found = tokio::task::spawn_blocking(move || sidecar::find_sidecars(&owned_video, is_video))
+        .await
+        .map_err(|e| AppError::Server(format!("Subtitle search failed: {}", e)))??;
+
+    let existing: HashSet<String> = sqlx::query(
+        "SELECT path FROM subtitles
+         WHERE media_id = ? AND episode_id IS ? AND stream_index IS NULL AND is_uploaded = 0"
+    )
+        .bind(media_id)
+        .bind(episode_id)
+        .fetch_all(db)
+        .await
+        .map_err(AppError::Database)?
+        .iter()
+        .map(|row| row.get::<String, _>("path"))
+        .collect();
+
+    let mut found_paths = HashSet::new();
+    let mut added = 0;
+
+    for sidecar in &found {
+        let path = sidecar.path.to_string_lossy().to_string();
+        found_paths.insert(path.clone());
+        if existing.contains(&path) {
+            continue;
+        }
+
+        // A file moved over from another item's folder keeps its row, under the new owner
+        let moved = sqlx::query(
+            "UPDATE subtitles SET media_id = ?, episode_id = ? WHERE path = ? AND stream_index IS NULL"
+        )
+        .bind(media_id)
+        .bind(episode_id)
+        .bind(&path)
+        .execute(db)
+        .await
+        .map_err(AppError::Database)?;
+
+        if moved.rows_affected() > 0 {
+            tracing::info!("Moved subtitles: {}", path);
+            continue;
+        }
+
+        sqlx::query(
+            "INSERT INTO subtitles (id, media_id, episode_id, path, format, language, title, is_forced, is_sdh, is_default)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(generate_id())
+        .bind(media_id)
+        .bind(episode_id)
+        .bind(&path)
+        .bind(sidecar.format.as_str())
+        .bind(&sidecar.language)
+        .bind(&sidecar.title)
+        .bind(sidecar.forced)
+        .bind(sidecar.sdh)
+        .bind(sidecar.default)
+        .execute(db)
+        .await
+        .map_err(AppError::Database)?;
+
+        added += 1;
+        tracing::info!("Added subtitles: {}", path);
+    }
+
+    for path in existing.difference(&found_paths) {
+        sqlx::query("DELETE FROM subtitles WHERE path = ? AND stream_index IS NULL")
+            .bind(path)
+            .execute(db)
+            .await
+            .map_err(AppError::Database)?;
+        tracing::info!("Removed subtitles: {}", path);
+    }
+
+    Ok(added)
+}
@@ -0,0 +1,200 @@
+use chrono::{DateTime, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{HeaderMap, Status};
+use rocket::request::Request;
+use rocket::response::{Responder, Response};
+use std::time::SystemTime;
+
+// Format a timestamp as an IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn format_http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+// Parse an IMF-fixdate as sent in If-Modified-Since and If-Range
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+// HTTP dates only have one second resolution, so compare at that granularity
+fn same_second(date: DateTime<Utc>, time: SystemTime) -> bool {
+    date.timestamp() == DateTime::<Utc>::from(time).timestamp()
+}
+
+// Evaluate an If-Range header (RFC 7233 section 3.2). The Range header is only
+// honoured when the validator still matches the current file: an entity tag must be
+// strong and identical, a date must equal Last-Modified exactly.
+pub fn if_range_matches(if_range: &str, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+    let if_range = if_range.trim();
+
+    if if_range.starts_with('"') {
+        return match etag {
+            Some(etag) => !etag.starts_with("W/") && etag == if_range,
+            None => false,
+        };
+    }
+
+    if if_range.starts_with("W/") {
+        return false;
+    }
+
+    match (parse_http_date(if_range), last_modified) {
+        (Some(date), Some(modified)) => same_second(date, modified),
+        _ => false,
+    }
+}
+
+// Cache-Control for media bytes: files rarely change, and ETag/Last-Modified let
+// clients revalidate cheaply once the day is up
+pub const CACHE_CONTROL_STREAM: &str = "private, max-age=86400";
+
+// Cache-Control for JSON resources: always revalidate, usually answered with a 304
+pub const CACHE_CONTROL_METADATA: &str = "private, no-cache";
+
+// Cache-Control for everything else under /api (mutations, progress, listings)
+pub const CACHE_CONTROL_NONE: &str = "no-store";
+
+// Build a weak entity tag from a version-like value, e.g. size and mtime of a file
+pub fn weak_etag(parts: &[u64]) -> String {
+    let tag = parts
+        .iter()
+        .map(|part| format!("{:x}", part))
+        .collect::<Vec<_>>()
+        .join("-");
+
+    format!("W/\"{}\"", tag)
+}
+
+// Weak ETag for a file on disk, changes whenever its size or modification time does
+pub fn file_etag(size: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64);
+
+    weak_etag(&[size, modified])
+}
+
+// Strip the weak indicator so tags can be compared with the weak comparison function
+fn opaque_tag(tag: &str) -> &str {
+    let tag = tag.trim();
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+// Evaluate If-None-Match and If-Modified-Since (RFC 7232 section 6). Returns true
+// when the client's cached copy is still current and a 304 should be sent instead.
+pub fn is_not_modified(
+    headers: &HeaderMap<'_>,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    // If-None-Match takes precedence, If-Modified-Since is ignored when it is present
+    if let Some(if_none_match) = headers.get_one("If-None-Match") {
+        let etag = match etag {
+            Some(etag) => opaque_tag(etag),
+            None => return false,
+        };
+
+        return if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || opaque_tag(candidate) == etag);
+    }
+
+    if let Some(if_modified_since) = headers.get_one("If-Modified-Since") {
+        if let (Some(date), Some(modified)) = (parse_http_date(if_modified_since), last_modified) {
+            return DateTime::<Utc>::from(modified).timestamp() <= date.timestamp();
+        }
+    }
+
+    false
+}
+
+// Respond with the validators and caching policy of a resource
+pub fn cache_headers(
+    response: &mut Response<'_>,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+    cache_control: &'static str,
+) {
+    if let Some(etag) = etag {
+        response.set_raw_header("ETag", etag.to_string());
+    }
+
+    if let Some(modified) = last_modified {
+        response.set_raw_header("Last-Modified", format_http_date(modified));
+    }
+
+    response.set_raw_header("Cache-Control", cache_control);
+}
+
+// A 304 Not Modified carrying the same validators a 200 would have
+pub fn not_modified<'r>(
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+    cache_control: &'static str,
+) -> Response<'r> {
+    let mut response = Response::build().status(Status::NotModified).finalize();
+    cache_headers(&mut response, etag, last_modified, cache_control);
+    response
+}
+
+// A JSON (or any other) response with validators, answered with 304 when the
+// client already holds the current version
+pub struct Cached<R> {
+    inner: R,
+    etag: String,
+    last_modified: Option<SystemTime>,
+    cache_control: &'static str,
+}
+
+impl<R> Cached<R> {
+    pub fn new(inner: R, etag: String, last_modified: Option<SystemTime>) -> Self {
+        Self {
+            inner,
+            etag,
+            last_modified,
+            cache_control: CACHE_CONTROL_METADATA,
+        }
+    }
+
+    // Use a different caching policy than the metadata default
+    pub fn with_cache_control(mut self, cache_control: &'static str) -> Self {
+        self.cache_control = cache_control;
+        self
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Cached<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'o> {
+        if is_not_modified(req.headers(), Some(&self.etag), self.last_modified) {
+            return Ok(not_modified(Some(&self.etag), self.last_modified, self.cache_control));
+        }
+
+        let mut response = self.inner.respond_to(req)?;
+        cache_headers(&mut response, Some(&self.etag), self.last_modified, self.cache_control);
+        Ok(response)
+    }
+}
+
+// Fairing that marks API responses without an explicit caching policy as uncacheable,
+// so browsers never reuse stale listings or progress
+pub struct DefaultCacheControl;
+
+#[rocket::async_trait]
+impl Fairing for DefaultCacheControl {
+    fn info(&self) -> Info {
+        Info {
+            name: "Default Cache-Control",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, response: &mut Response<'r>) {
+        if req.uri().path().starts_with("/api/") && !response.headers().contains("Cache-Control") {
+            response.set_raw_header("Cache-Control", CACHE_CONTROL_NONE);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{Responder, Response};
+use std::fmt::Write;
+use std::io::Cursor;
+
+use crate::container::index::{IndexedFile, MediaIndex, Track, TrackKind};
+use crate::error::{AppError, Result};
+use crate::streaming::conditional::Cached;
+use crate::streaming::segment::derived_etag;
+use crate::subtitles::embedded;
+
+// Where the fMP4 segments live relative to the manifest. DASH and HLS share the
+// segments, so the manifest points into the HLS track directories.
+pub const DASH_SEGMENT_BASE: &str = "../hls/";
+
+// An MPD manifest
+pub struct Manifest(String);
+
+impl<'r> Responder<'r, 'static> for Manifest {
+    fn respond_to(self, _req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        Ok(Response::build()
+            .status(Status::Ok)
+            .header(ContentType::new("application", "dash+xml"))
+            .sized_body(self.0.len(), Cursor::new(self.0))
+            .finalize())
+    }
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// ISO 8601 duration in seconds as used by MPD attributes, e.g. "PT5025.120S"
+fn format_duration(seconds: f64) -> String {
+    format!("PT{:.3}S", seconds.max(0.0))
+}
+
+// SegmentTimeline of a track. Each entry uses the decode time and duration of the
+// samples actually in the segment, so they line up with the tfdt of the fragments.
+fn segment_timeline(index: &MediaIndex, track: &Track) -> String {
+    let plan = &index.segments;
+    let mut entries: Vec<(i64, u64)> = Vec::with_capacity(plan.len());
+
+    for segment in 0..plan.len() {
+        let samples = &track.samples[plan.sample_range(track, segment)];
+        let entry = match (samples.first(), samples.last()) {
+            (Some(first), Some(last)) => {
+                let end = last.dts + last.duration as i64;
+                (first.dts, (end - first.dts).max(1) as u64)
+            }
+            // Segments the track has no samples in (e.g. audio ending before video)
+            // still need an entry to keep $Number$ in step with the other tracks
+            _ => {
+                let start = (plan.boundaries[segment] * track.timescale as f64).round() as i64;
+                let duration = (plan.duration(segment) * track.timescale as f64).round().max(1.0) as u64;
+                (start, duration)
+            }
+        };
+        entries.push(entry);
+    }
+
+    // Runs of contiguous segments with the same duration collapse into one S element
+    let mut timeline = String::new();
+    let mut position = 0;
+    while position < entries.len() {
+        let (start, duration) = entries[position];
+        let mut repeat = 0;
+        while let Some((next_start, next_duration)) = entries.get(position + repeat + 1) {
+            let expected = start + (duration * (repeat as u64 + 1)) as i64;
+            if *next_duration != duration || *next_start != expected {
+                break;
+            }
+            repeat += 1;
+        }
+
+        if repeat > 0 {
+            let _ = writeln!(timeline, "            <S t=\"{}\" d=\"{}\" r=\"{}\"/>", start, duration, repeat);
+        } else {
+            let _ = writeln!(timeline, "            <S t=\"{}\" d=\"{}\"/>", start, duration);
+        }
+        position += repeat + 1;
+    }
+
+    timeline
+}
+
+fn segment_template(index: &MediaIndex, track: &Track, base: &str, query: &str) -> String {
+    let query = escape_xml(query);
+    format!(
+        "        <SegmentTemplate timescale=\"{}\" initialization=\"{}{}/init.mp4{}\" media=\"{}{}/$Number$.m4s{}\" startNumber=\"0\">\n          <SegmentTimeline>\n{}          </SegmentTimeline>\n        </SegmentTemplate>\n",
+        track.timescale,
+        base,
+        track.number,
+        query,
+        base,
+        track.number,
+        query,
+        segment_timeline(index, track)
+    )
+}
+
+// Opening tag of an adaptation set with the attributes common to every kind
+fn adaptation_set(id: u32, kind: &str, mime_type: &str, track: &Track) -> String {
+    let mut element = format!(
+        "    <AdaptationSet id=\"{}\" contentType=\"{}\" mimeType=\"{}\"",
+        id, kind, mime_type
+    );
+    if let Some(language) = &track.language {
+        let _ = write!(element, " lang=\"{}\"", escape_xml(language));
+    }
+    if kind != "text" {
+        element.push_str(" segmentAlignment=\"true\" startWithSAP=\"1\"");
+    }
+    element.push_str(">\n");
+
+    if let Some(name) = &track.name {
+        let _ = writeln!(element, "      <Label>{}</Label>", escape_xml(name));
+    }
+
+    element
+}
+
+// Static MPD describing the same fMP4 segments as the HLS playlists, one adaptation
+// set per track so clients can pick audio and subtitle tracks independently. `base`
+// is the path from the manifest to the segment routes and `query` is appended to
+// every URL.
+pub fn manifest(index: &MediaIndex, base: &str, query: &str) -> Result<String> {
+    let videos: Vec<&Track> = index.packageable_tracks(TrackKind::Video).collect();
+    let audios: Vec<&Track> = index.packageable_tracks(TrackKind::Audio).collect();
+    let texts: Vec<&Track> = index.tracks.iter().filter(|track| embedded::is_text_track(track)).collect();
+
+    if videos.is_empty() && audios.is_empty() {
+        return Err(AppError::InvalidInput(
+            "None of the tracks in this file can be streamed without transcoding".to_string(),
+        ));
+    }
+
+    let duration = format_duration(index.segments.end());
+    let mut mpd = String::new();
+    let _ = writeln!(mpd, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    let _ = writeln!(
+        mpd,
+        "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"static\" mediaPresentationDuration=\"{}\" minBufferTime=\"PT2S\">",
+        duration
+    );
+    let _ = writeln!(mpd, "  <Period id=\"0\" start=\"PT0S\" duration=\"{}\">", duration);
+
+    let default_audio = audios
+        .iter()
+        .find(|track| track.default)
+        .or(audios.first())
+        .map(|track| track.number);
+
+    for video in &videos {
+        let (peak, _) = index.bitrate(video);
+        mpd.push_str(&adaptation_set(video.number, "video", "video/mp4", video));
+        let _ = write!(
+            mpd,
+            "      <Representation id=\"{}\" codecs=\"{}\" bandwidth=\"{}\"",
+            video.number,
+            video.codec_string.as_deref().unwrap_or_default(),
+            peak.max(1)
+        );
+        if let (Some(width), Some(height)) = (video.width, video.height) {
+            let _ = write!(mpd, " width=\"{}\" height=\"{}\"", width, height);
+        }
+        mpd.push_str(">\n");
+        mpd.push_str(&segment_template(index, video, base, query));
+        mpd.push_str("      </Representation>\n    </AdaptationSet>\n");
+    }
+
+    for audio in &audios {
+        let (peak, _) = index.bitrate(audio);
+        mpd.push_str(&adaptation_set(audio.number, "audio", "audio/mp4", audio));
+        let role = if Some(audio.number) == default_audio { "main" } else { "alternate" };
+        let _ = writeln!(mpd, "      <Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"{}\"/>", role);
+        let _ = write!(
+            mpd,
+            "      <Representation id=\"{}\" codecs=\"{}\" bandwidth=\"{}\"",
+            audio.number,
+            audio.codec_string.as_deref().unwrap_or_default(),
+            peak.max(1)
+        );
+        if let Some(rate) = audio.sample_rate {
+            let _ = write!(mpd, " audioSamplingRate=\"{}\"", rate);
+        }
+        mpd.push_str(">\n");
+        if let Some(channels) = audio.channels {
+            let _ = writeln!(
+                mpd,
+                "        <AudioChannelConfiguration schemeIdUri=\"urn:mpeg:dash:23003:3:audio_channel_configuration:2011\" value=\"{}\"/>",
+                channels
+            );
+        }
+        mpd.push_str(&segment_template(index, audio, base, query));
+        mpd.push_str("      </Representation>\n    </AdaptationSet>\n");
+    }
+
+    // Text tracks are delivered as a single WebVTT file each
+    for text in &texts {
+        mpd.push_str(&adaptation_set(text.number, "text", "text/vtt", text));
+        let role = if text.forced { "forced-subtitle" } else { "subtitle" };
+        let _ = writeln!(mpd, "      <Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"{}\"/>", role);
+        let _ = writeln!(mpd, "      <Representation id=\"{}\" bandwidth=\"256\">", text.number);
+        let _ = writeln!(
+            mpd,
+            "        <BaseURL>{}{}/subtitles.vtt{}</BaseURL>",
+            base,
+            text.number,
+            escape_xml(query)
+        );
+        mpd.push_str("      </Representation>\n    </AdaptationSet>\n");
+    }
+
+    let _ = writeln!(mpd, "  </Period>");
+    let _ = writeln!(mpd, "</MPD>");
+
+    Ok(mpd)
+}
+
+// MPD response for an indexed file
+pub fn manifest_response(indexed: &IndexedFile, base: &str, query: &str) -> Result<Cached<Manifest>> {
+    let mpd = manifest(&indexed.index, base, query)?;
+    let etag = derived_etag(&indexed.etag, "mpd");
+    Ok(Cached::new(Manifest(mpd), etag, indexed.last_modified))
+}
@@ -0,0 +1,488 @@
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{Builder as ResponseBuilder, Responder, Response};
+use std::collections::VecDeque;
+use std::io::{Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, ReadBuf, Take};
+use uuid::Uuid;
+
+use crate::error::{AppError, Result};
+use crate::streaming::conditional;
+use crate::streaming::mime;
+use crate::streaming::range::{parse_range_header, RangeRequest};
+use crate::streaming::throttle;
+
+// Size of each read from disk while streaming a file to the client
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// Async reader over a byte window of a file. Rocket pulls one chunk at a time and
+// only reads the next one once the previous chunk has been written to the socket,
+// so memory use stays bounded no matter how large the file or the range is.
+pub struct FileRangeReader {
+    inner: Take<tokio::fs::File>,
+    start: u64,
+    end: u64,
+}
+
+impl FileRangeReader {
+    // Open `path` positioned at `range.start`, yielding at most `range.end - range.start` bytes
+    pub fn open(path: &Path, range: Range<u64>) -> std::io::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start(range.start))?;
+
+        Ok(Self {
+            inner: tokio::fs::File::from_std(file).take(range.end - range.start),
+            start: range.start,
+            end: range.end,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+impl AsyncRead for FileRangeReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+// Seeking is relative to the start of the window and never moves past its end
+impl AsyncSeek for FileRangeReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let limit = self.inner.limit();
+        let current = self.end - limit;
+        let target = match position {
+            SeekFrom::Start(offset) => self.start.saturating_add(offset),
+            SeekFrom::End(offset) => self.end.saturating_add_signed(offset),
+            SeekFrom::Current(offset) => current.saturating_add_signed(offset),
+        };
+        let target = target.clamp(self.start, self.end);
+
+        Pin::new(self.inner.get_mut()).start_seek(SeekFrom::Start(target))?;
+        let end = self.end;
+        self.inner.set_limit(end - target);
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let start = self.start;
+        Pin::new(self.inner.get_mut())
+            .poll_complete(cx)
+            .map_ok(|position| position - start)
+    }
+}
+
+// A byte range of the file, or generated bytes spliced in between
+enum Extent {
+    File(Range<u64>),
+    Bytes(std::io::Cursor<Vec<u8>>),
+}
+
+// Async reader over a list of byte ranges of one file, read back to back through a
+// single file handle. Used for fMP4 segments, whose samples are scattered over the
+// source file, and for remuxed files, which also interleave generated box headers.
+pub struct ExtentReader {
+    inner: Take<tokio::fs::File>,
+    extents: VecDeque<Extent>,
+    // Length of the extent being seeked to, set once the seek completes
+    seeking: Option<u64>,
+    length: u64,
+}
+
+impl ExtentReader {
+    pub fn open(path: &Path, extents: impl IntoIterator<Item = Range<u64>>) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let mut reader = Self {
+            inner: tokio::fs::File::from_std(file).take(0),
+            extents: VecDeque::new(),
+            seeking: None,
+            length: 0,
+        };
+
+        for extent in extents {
+            reader.push_extent(extent);
+        }
+
+        Ok(reader)
+    }
+
+    // Adjacent extents are merged so contiguous samples are read in one go
+    pub fn push_extent(&mut self, extent: Range<u64>) {
+        self.length += extent.end - extent.start;
+        match self.extents.back_mut() {
+            Some(Extent::File(last)) if last.end == extent.start => last.end = extent.end,
+            _ if extent.is_empty() => {}
+            _ => self.extents.push_back(Extent::File(extent)),
+        }
+    }
+
+    pub fn push_bytes(&mut self, bytes: Vec<u8>) {
+        if !bytes.is_empty() {
+            self.length += bytes.len() as u64;
+            self.extents.push_back(Extent::Bytes(std::io::Cursor::new(bytes)));
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+}
+
+impl AsyncRead for ExtentReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if let Some(length) = self.seeking {
+                match Pin::new(self.inner.get_mut()).poll_complete(cx) {
+                    Poll::Ready(Ok(_)) => {
+                        self.seeking = None;
+                        self.inner.set_limit(length);
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            if self.inner.limit() == 0 {
+                match self.extents.pop_front() {
+                    None => return Poll::Ready(Ok(())),
+                    Some(Extent::File(extent)) => {
+                        Pin::new(self.inner.get_mut()).start_seek(SeekFrom::Start(extent.start))?;
+                        self.seeking = Some(extent.end - extent.start);
+                    }
+                    Some(Extent::Bytes(mut cursor)) => {
+                        let position = cursor.position() as usize;
+                        let remaining = cursor.get_ref().len() - position;
+                        let count = remaining.min(buf.remaining());
+                        buf.put_slice(&cursor.get_ref()[position..position + count]);
+                        cursor.set_position((position + count) as u64);
+
+                        if count < remaining {
+                            self.extents.push_front(Extent::Bytes(cursor));
+                        }
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+                continue;
+            }
+
+            let before = buf.filled().len();
+            return match Pin::new(&mut self.inner).poll_read(cx, buf) {
+                // The file shrank since it was indexed
+                Poll::Ready(Ok(())) if buf.filled().len() == before && buf.remaining() > 0 => {
+                    Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()))
+                }
+                other => other,
+            };
+        }
+    }
+}
+
+// One piece of a chained body
+enum BodyPart {
+    Bytes(std::io::Cursor<Vec<u8>>),
+    File(FileRangeReader),
+    Extents(ExtentReader),
+}
+
+// Reader that plays a sequence of in-memory buffers and file readers back to back,
+// for bodies such as multipart/byteranges or fMP4 segments (generated headers
+// followed by file data). Only one chunk of file data is in memory at a time.
+#[derive(Default)]
+pub struct ChainReader {
+    parts: VecDeque<BodyPart>,
+    length: u64,
+}
+
+impl ChainReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_bytes(&mut self, bytes: Vec<u8>) {
+        self.length += bytes.len() as u64;
+        self.parts.push_back(BodyPart::Bytes(std::io::Cursor::new(bytes)));
+    }
+
+    pub fn push_file(&mut self, reader: FileRangeReader) {
+        self.length += reader.len();
+        self.parts.push_back(BodyPart::File(reader));
+    }
+
+    pub fn push_extents(&mut self, reader: ExtentReader) {
+        self.length += reader.len();
+        self.parts.push_back(BodyPart::Extents(reader));
+    }
+
+    // Body of a multipart/byteranges response (RFC 7233 appendix A)
+    pub fn multipart(
+        path: &Path,
+        ranges: &[Range<u64>],
+        content_type: &ContentType,
+        file_size: u64,
+        boundary: &str,
+    ) -> std::io::Result<Self> {
+        let mut body = Self::new();
+
+        for (index, range) in ranges.iter().enumerate() {
+            // The CRLF before every boundary belongs to the delimiter, not the data
+            let leading = if index == 0 { "--" } else { "\r\n--" };
+            let header = format!(
+                "{}{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                leading, boundary, content_type, range.start, range.end - 1, file_size
+            );
+
+            body.push_bytes(header.into_bytes());
+            body.push_file(FileRangeReader::open(path, range.clone())?);
+        }
+
+        body.push_bytes(format!("\r\n--{}--\r\n", boundary).into_bytes());
+        Ok(body)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+}
+
+impl AsyncRead for ChainReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while let Some(part) = self.parts.front_mut() {
+            let before = buf.filled().len();
+            let poll = match part {
+                BodyPart::Bytes(cursor) => Pin::new(cursor).poll_read(cx, buf),
+                BodyPart::File(reader) => Pin::new(reader).poll_read(cx, buf),
+                BodyPart::Extents(reader) => Pin::new(reader).poll_read(cx, buf),
+            };
+
+            match poll {
+                // An empty read means this part is exhausted, move on to the next one
+                Poll::Ready(Ok(())) if buf.filled().len() == before && buf.remaining() > 0 => {
+                    self.parts.pop_front();
+                }
+                other => return other,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Check that `path` is a readable regular file and return its metadata
+pub async fn validate_file(path: &Path) -> Result<std::fs::Metadata> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => Ok(metadata),
+        Ok(_) => Err(AppError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Path is not a regular file: {}", path.display()),
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(AppError::NotFound(format!("Media file not found: {}", path.display())))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(AppError::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("Access is denied to file: {}", path.display()),
+            )))
+        }
+        Err(e) => Err(AppError::Io(e)),
+    }
+}
+
+// Read the first few bytes of a file for content type sniffing
+async fn read_header(path: &Path) -> Result<Vec<u8>> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut header = Vec::with_capacity(mime::SNIFF_LENGTH);
+    file.take(mime::SNIFF_LENGTH as u64).read_to_end(&mut header).await?;
+    Ok(header)
+}
+
+// Content-Disposition that makes browsers save a response as `filename`. The name is
+// also given in RFC 5987 form, since the quoted one can only hold ASCII.
+pub fn attachment(filename: &str) -> Header<'static> {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+
+    Header::new(
+        "Content-Disposition",
+        format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded),
+    )
+}
+
+// A file on disk served with support for range requests and seeking
+pub struct RangeFile {
+    file_path: PathBuf,
+    content_type: ContentType,
+    file_size: u64,
+    last_modified: Option<SystemTime>,
+    etag: String,
+    // Name to save the file as, for downloads
+    download_name: Option<String>,
+}
+
+impl RangeFile {
+    // Validate the file and detect its content type from the extension or signature
+    pub async fn open(file_path: PathBuf) -> Result<Self> {
+        let metadata = validate_file(&file_path).await?;
+        let header = read_header(&file_path).await?;
+        let content_type = mime::detect(&file_path, &header);
+        let last_modified = metadata.modified().ok();
+
+        Ok(Self {
+            file_path,
+            content_type,
+            file_size: metadata.len(),
+            last_modified,
+            etag: conditional::file_etag(metadata.len(), last_modified),
+            download_name: None,
+        })
+    }
+
+    // Serve the file as an attachment saved as `name`
+    pub fn download_as(mut self, name: String) -> Self {
+        self.download_name = Some(name);
+        self
+    }
+
+    pub fn len(&self) -> u64 {
+        self.file_size
+    }
+
+    // Decide which ranges to serve, ignoring Range when an If-Range validator is stale
+    fn requested_ranges(&self, req: &Request<'_>) -> RangeRequest {
+        let range_header = match req.headers().get_one("Range") {
+            Some(value) => value,
+            None => return RangeRequest::Full,
+        };
+
+        if let Some(if_range) = req.headers().get_one("If-Range") {
+            if !conditional::if_range_matches(if_range, Some(&self.etag), self.last_modified) {
+                return RangeRequest::Full;
+            }
+        }
+
+        parse_range_header(range_header, self.file_size)
+    }
+
+    // Headers shared by every response for this file
+    fn base_response<'r>(&self) -> ResponseBuilder<'r> {
+        let mut response = Response::build();
+        response
+            .header(Header::new("Accept-Ranges", "bytes"))
+            .header(Header::new("ETag", self.etag.clone()))
+            .header(Header::new("Cache-Control", conditional::CACHE_CONTROL_STREAM));
+
+        if let Some(modified) = self.last_modified {
+            response.header(Header::new("Last-Modified", conditional::format_http_date(modified)));
+        }
+        if let Some(name) = &self.download_name {
+            response.header(attachment(name));
+        }
+
+        response
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RangeFile {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        // The client's cached copy is still current, skip the body entirely
+        if conditional::is_not_modified(req.headers(), Some(&self.etag), self.last_modified) {
+            return Ok(conditional::not_modified(
+                Some(&self.etag),
+                self.last_modified,
+                conditional::CACHE_CONTROL_STREAM,
+            ));
+        }
+
+        let whole_file = 0..self.file_size;
+        let (partial, ranges) = match self.requested_ranges(req) {
+            RangeRequest::Full => (false, vec![whole_file]),
+            RangeRequest::Partial(ranges) => (true, ranges),
+            RangeRequest::Unsatisfiable => {
+                // No range overlaps the file, return 416 Range Not Satisfiable
+                let response = self.base_response()
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new("Content-Range", format!("bytes */{}", self.file_size)))
+                    .finalize();
+
+                return Ok(response);
+            }
+        };
+
+        let mut response = self.base_response();
+
+        if ranges.len() > 1 {
+            // Several ranges, send them as parts of a multipart/byteranges body
+            let boundary = Uuid::new_v4().simple().to_string();
+            let reader = ChainReader::multipart(
+                &self.file_path, &ranges, &self.content_type, self.file_size, &boundary,
+            )
+            .map_err(|e| {
+                tracing::error!("Failed to open {}: {}", self.file_path.display(), e);
+                Status::InternalServerError
+            })?;
+
+            response
+                .status(Status::PartialContent)
+                .header(ContentType::new("multipart", "byteranges").with_params(("boundary", boundary)))
+                .raw_header("Content-Length", reader.len().to_string())
+                .streamed_body(throttle::pace(req, reader))
+                .max_chunk_size(STREAM_CHUNK_SIZE);
+
+            return Ok(response.finalize());
+        }
+
+        let byte_range = ranges[0].clone();
+        let reader = FileRangeReader::open(&self.file_path, byte_range.clone()).map_err(|e| {
+            tracing::error!("Failed to open {}: {}", self.file_path.display(), e);
+            Status::InternalServerError
+        })?;
+
+        let length = reader.len();
+        response
+            .header(self.content_type)
+            .sized_body(length as usize, throttle::pace(req, reader))
+            // Set after the body, replacing the body resets the chunk size
+            .max_chunk_size(STREAM_CHUNK_SIZE);
+
+        if partial {
+            response.status(Status::PartialContent).header(Header::new(
+                "Content-Range",
+                format!("bytes {}-{}/{}", byte_range.start, byte_range.end - 1, self.file_size),
+            ));
+        } else {
+            response.status(Status::Ok);
+        }
+
+        Ok(response.finalize())
+    }
+}
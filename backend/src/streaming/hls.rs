@@ -0,0 +1,207 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromParam, Request};
+use rocket::response::{Responder, Response};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io::Cursor;
+
+use crate::container::index::{IndexedFile, MediaIndex, Track, TrackKind};
+use crate::error::{AppError, Result};
+use crate::streaming::conditional::Cached;
+use crate::streaming::segment::derived_etag;
+
+// fMP4 segments and EXT-X-MAP in media playlists need protocol version 7
+const HLS_VERSION: u32 = 7;
+
+// File name of media segment `n` of a track, relative to its playlist
+pub struct SegmentName(pub usize);
+
+impl<'a> FromParam<'a> for SegmentName {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> std::result::Result<Self, Self::Error> {
+        param
+            .strip_suffix(".m4s")
+            .and_then(|number| number.parse().ok())
+            .map(SegmentName)
+            .ok_or(param)
+    }
+}
+
+// An m3u8 playlist
+pub struct Playlist(pub String);
+
+impl<'r> Responder<'r, 'static> for Playlist {
+    fn respond_to(self, _req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        Ok(Response::build()
+            .status(Status::Ok)
+            .header(ContentType::new("application", "vnd.apple.mpegurl"))
+            .sized_body(self.0.len(), Cursor::new(self.0))
+            .finalize())
+    }
+}
+
+// Display name of a track in a rendition group
+fn rendition_name(track: &Track) -> String {
+    track
+        .name
+        .clone()
+        .or_else(|| track.language.clone())
+        .unwrap_or_else(|| format!("Track {}", track.number))
+}
+
+fn quoted(value: &str) -> String {
+    // Quoted strings can't contain double quotes or line breaks
+    value.replace(['"', '\r', '\n'], "'")
+}
+
+// Master playlist listing one variant per video track, with every audio track as an
+// alternative rendition. `query` is appended to every URI so that episode selection
+// carries over to the media playlists.
+pub fn master_playlist(index: &MediaIndex, query: &str) -> Result<String> {
+    let videos: Vec<&Track> = index.packageable_tracks(TrackKind::Video).collect();
+    let audios: Vec<&Track> = index.packageable_tracks(TrackKind::Audio).collect();
+
+    if videos.is_empty() && audios.is_empty() {
+        return Err(AppError::InvalidInput(
+            "None of the tracks in this file can be streamed without transcoding".to_string(),
+        ));
+    }
+
+    let mut playlist = String::new();
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:{}", HLS_VERSION);
+    let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+
+    if videos.is_empty() {
+        // Audio only, each track is its own variant
+        for audio in &audios {
+            let (peak, average) = index.bitrate(audio);
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-STREAM-INF:BANDWIDTH={},AVERAGE-BANDWIDTH={},CODECS=\"{}\"",
+                peak.max(1),
+                average.max(1),
+                audio.codec_string.as_deref().unwrap_or_default()
+            );
+            let _ = writeln!(playlist, "{}/index.m3u8{}", audio.number, query);
+        }
+
+        return Ok(playlist);
+    }
+
+    let default_audio = audios
+        .iter()
+        .find(|track| track.default)
+        .or(audios.first())
+        .map(|track| track.number);
+
+    let mut names = HashSet::new();
+    for audio in &audios {
+        let mut name = quoted(&rendition_name(audio));
+        if !names.insert(name.clone()) {
+            name = format!("{} ({})", name, audio.number);
+            names.insert(name.clone());
+        }
+
+        let is_default = Some(audio.number) == default_audio;
+        let mut line = format!(
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"{}\",DEFAULT={},AUTOSELECT=YES",
+            name,
+            if is_default { "YES" } else { "NO" }
+        );
+        if let Some(language) = &audio.language {
+            let _ = write!(line, ",LANGUAGE=\"{}\"", quoted(language));
+        }
+        if let Some(channels) = audio.channels {
+            let _ = write!(line, ",CHANNELS=\"{}\"", channels);
+        }
+        let _ = writeln!(playlist, "{},URI=\"{}/index.m3u8{}\"", line, audio.number, query);
+    }
+
+    // Variants must advertise the codecs of every rendition they may be paired with,
+    // and their bandwidth must cover the largest one
+    let mut audio_codecs: Vec<&str> = Vec::new();
+    for audio in &audios {
+        let codec = audio.codec_string.as_deref().unwrap_or_default();
+        if !audio_codecs.contains(&codec) {
+            audio_codecs.push(codec);
+        }
+    }
+    let (audio_peak, audio_average) = audios
+        .iter()
+        .map(|audio| index.bitrate(audio))
+        .fold((0, 0), |max, (peak, average)| (max.0.max(peak), max.1.max(average)));
+
+    for video in &videos {
+        let (peak, average) = index.bitrate(video);
+        let mut codecs = vec![video.codec_string.as_deref().unwrap_or_default()];
+        codecs.extend(audio_codecs.iter().copied());
+
+        let mut line = format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},AVERAGE-BANDWIDTH={},CODECS=\"{}\"",
+            (peak + audio_peak).max(1),
+            (average + audio_average).max(1),
+            codecs.join(",")
+        );
+        if let (Some(width), Some(height)) = (video.width, video.height) {
+            let _ = write!(line, ",RESOLUTION={}x{}", width, height);
+        }
+        if !audios.is_empty() {
+            line.push_str(",AUDIO=\"audio\"");
+        }
+
+        let _ = writeln!(playlist, "{}", line);
+        let _ = writeln!(playlist, "{}/index.m3u8{}", video.number, query);
+    }
+
+    Ok(playlist)
+}
+
+// VOD media playlist of a track. Every track shares the same segment boundaries, so
+// the playlists only differ by the directory they are served from.
+pub fn media_playlist(index: &MediaIndex, query: &str) -> String {
+    let plan = &index.segments;
+    let target_duration = (0..plan.len())
+        .map(|segment| plan.duration(segment))
+        .fold(0.0f64, f64::max)
+        .round()
+        .max(1.0) as u64;
+
+    let mut playlist = String::new();
+    let _ = writeln!(playlist, "#EXTM3U");
+    let _ = writeln!(playlist, "#EXT-X-VERSION:{}", HLS_VERSION);
+    let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration);
+    let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:0");
+    let _ = writeln!(playlist, "#EXT-X-PLAYLIST-TYPE:VOD");
+    let _ = writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS");
+    let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"init.mp4{}\"", query);
+
+    for segment in 0..plan.len() {
+        let _ = writeln!(playlist, "#EXTINF:{:.6},", plan.duration(segment));
+        let _ = writeln!(playlist, "{}.m4s{}", segment, query);
+    }
+
+    let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+    playlist
+}
+
+// Master playlist response for an indexed file
+pub fn master_response(indexed: &IndexedFile, query: &str) -> Result<Cached<Playlist>> {
+    let playlist = master_playlist(&indexed.index, query)?;
+    let etag = derived_etag(&indexed.etag, "master");
+    Ok(Cached::new(Playlist(playlist), etag, indexed.last_modified))
+}
+
+// Media playlist response for one track of an indexed file
+pub fn media_response(indexed: &IndexedFile, track_number: u32, query: &str) -> Result<Cached<Playlist>> {
+    indexed
+        .index
+        .track(track_number)
+        .filter(|track| track.is_packageable())
+        .ok_or_else(|| AppError::NotFound(format!("No streamable track {}", track_number)))?;
+
+    let playlist = media_playlist(&indexed.index, query);
+    let etag = derived_etag(&indexed.etag, &format!("{}-playlist", track_number));
+    Ok(Cached::new(Playlist(playlist), etag, indexed.last_modified))
+}
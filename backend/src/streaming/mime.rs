@@ -0,0 +1,144 @@
+use rocket::http::ContentType;
+use std::path::Path;
+
+// Number of leading bytes needed to recognise every signature below
+pub const SNIFF_LENGTH: usize = 64;
+
+// Content types for every extension the scanner accepts (see VIDEO_EXTENSIONS and
+// AUDIO_EXTENSIONS in media/scanner.rs), plus the ones trailers and sidecar files use
+const REGISTRY: &[(&str, &str, &str)] = &[
+    // Video
+    ("mp4", "video", "mp4"),
+    ("m4v", "video", "x-m4v"),
+    ("mkv", "video", "x-matroska"),
+    ("webm", "video", "webm"),
+    ("avi", "video", "x-msvideo"),
+    ("mov", "video", "quicktime"),
+    ("wmv", "video", "x-ms-wmv"),
+    ("mpg", "video", "mpeg"),
+    ("mpeg", "video", "mpeg"),
+    ("ts", "video", "mp2t"),
+    ("m2ts", "video", "mp2t"),
+    ("flv", "video", "x-flv"),
+    ("ogv", "video", "ogg"),
+    // Audio
+    ("mp3", "audio", "mpeg"),
+    ("flac", "audio", "flac"),
+    ("m4a", "audio", "mp4"),
+    ("m4b", "audio", "mp4"),
+    ("wav", "audio", "wav"),
+    ("ogg", "audio", "ogg"),
+    ("oga", "audio", "ogg"),
+    ("opus", "audio", "ogg"),
+    ("aac", "audio", "aac"),
+    ("wma", "audio", "x-ms-wma"),
+    ("aiff", "audio", "aiff"),
+    ("aif", "audio", "aiff"),
+    // Images and text served alongside media
+    ("jpg", "image", "jpeg"),
+    ("jpeg", "image", "jpeg"),
+    ("png", "image", "png"),
+    ("webp", "image", "webp"),
+    ("vtt", "text", "vtt"),
+    ("srt", "application", "x-subrip"),
+];
+
+// Look up the content type for a file extension (case-insensitive)
+pub fn from_extension(ext: &str) -> Option<ContentType> {
+    let ext = ext.to_lowercase();
+    REGISTRY
+        .iter()
+        .find(|(known, _, _)| *known == ext)
+        .map(|(_, top, sub)| ContentType::new(*top, *sub))
+}
+
+// Identify a media file from its leading bytes
+pub fn sniff(header: &[u8]) -> Option<ContentType> {
+    let starts = |magic: &[u8]| header.starts_with(magic);
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    // ISO base media (MP4, M4A, MOV): size followed by an `ftyp` box
+    if at(4, b"ftyp") {
+        let brand = header.get(8..12).unwrap_or_default();
+        return Some(match brand {
+            b"qt  " => ContentType::new("video", "quicktime"),
+            b"M4A " | b"M4B " => ContentType::new("audio", "mp4"),
+            b"M4V " | b"M4VH" | b"M4VP" => ContentType::new("video", "x-m4v"),
+            _ => ContentType::new("video", "mp4"),
+        });
+    }
+
+    // Old QuickTime files that start straight with a movie or data atom
+    if at(4, b"moov") || at(4, b"mdat") || at(4, b"wide") {
+        return Some(ContentType::new("video", "quicktime"));
+    }
+
+    // EBML: the DocType element tells Matroska and WebM apart
+    if starts(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        let is_webm = header.windows(4).any(|w| w == b"webm");
+        return Some(if is_webm {
+            ContentType::new("video", "webm")
+        } else {
+            ContentType::new("video", "x-matroska")
+        });
+    }
+
+    if starts(b"RIFF") {
+        return match header.get(8..12) {
+            Some(b"AVI ") => Some(ContentType::new("video", "x-msvideo")),
+            Some(b"WAVE") => Some(ContentType::new("audio", "wav")),
+            _ => None,
+        };
+    }
+
+    if starts(b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+        return Some(ContentType::new("audio", "aiff"));
+    }
+
+    // ASF container, used by both WMV and WMA
+    if starts(&[0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11]) {
+        return Some(ContentType::new("video", "x-ms-wmv"));
+    }
+
+    if starts(b"FLV\x01") {
+        return Some(ContentType::new("video", "x-flv"));
+    }
+
+    if starts(b"OggS") {
+        return Some(ContentType::new("audio", "ogg"));
+    }
+
+    if starts(b"fLaC") {
+        return Some(ContentType::new("audio", "flac"));
+    }
+
+    // MPEG program stream pack header or elementary video sequence header
+    if starts(&[0x00, 0x00, 0x01, 0xBA]) || starts(&[0x00, 0x00, 0x01, 0xB3]) {
+        return Some(ContentType::new("video", "mpeg"));
+    }
+
+    if starts(b"ID3") {
+        return Some(ContentType::new("audio", "mpeg"));
+    }
+
+    // ADTS AAC and MPEG audio frames both begin with an 11/12-bit sync word; ADTS
+    // has layer bits set to zero which MPEG audio never uses
+    if header.len() >= 2 && header[0] == 0xFF && header[1] & 0xF0 == 0xF0 && header[1] & 0x06 == 0 {
+        return Some(ContentType::new("audio", "aac"));
+    }
+    if header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0 {
+        return Some(ContentType::new("audio", "mpeg"));
+    }
+
+    None
+}
+
+// Determine the content type of a file, preferring its extension and falling back to
+// the file signature when the extension is missing or unknown
+pub fn detect(path: &Path, header: &[u8]) -> ContentType {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(from_extension)
+        .or_else(|| sniff(header))
+        .unwrap_or(ContentType::Binary)
+}
@@ -0,0 +1,13 @@
+pub mod conditional;
+pub mod dash;
+pub mod file;
+pub mod hls;
+pub mod mime;
+pub mod playback;
+pub mod range;
+pub mod remux;
+pub mod segment;
+pub mod signing;
+pub mod zip;
+pub mod sessions;
+pub mod throttle;
\ No newline at end of file
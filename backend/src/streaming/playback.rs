@@ -0,0 +1,267 @@
+use sqlx::{Pool, Sqlite};
+use std::path::Path;
+
+use crate::db::models::{DeviceProfileDto, MediaFile, MediaStream};
+use crate::db::queries;
+use crate::error::Result;
+use crate::media::probe;
+use crate::streaming::remux::FORMAT_MP4;
+use crate::streaming::sessions;
+use crate::streaming::signing::StreamToken;
+use crate::transcode::profile::Profile;
+
+// Codecs a WebM file can hold
+const WEBM_CODECS: [&str; 6] = ["vp8", "vp9", "av1", "opus", "vorbis", "webvtt"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayMethod {
+    // The file as is
+    DirectPlay,
+    // The same tracks repackaged as fMP4, progressively or over HLS or DASH
+    Remux,
+    // Re-encoded by ffmpeg with one of the transcoding profiles
+    Transcode,
+}
+
+impl PlayMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlayMethod::DirectPlay => "direct_play",
+            PlayMethod::Remux => "remux",
+            PlayMethod::Transcode => "transcode",
+        }
+    }
+}
+
+// Where an item is served for each play method. The remuxed MP4 and the transcoding
+// sessions are options of the direct URL.
+pub struct PlaybackUrls {
+    pub direct: String,
+    pub hls: String,
+    pub dash: String,
+}
+
+impl PlaybackUrls {
+    // The URLs as handed out by the sign routes, with when they stop working
+    pub fn signed_json(&self, token: &StreamToken) -> serde_json::Value {
+        serde_json::json!({
+            "url": self.direct,
+            "hls": self.hls,
+            "dash": self.dash,
+            "userId": token.user,
+            "expiresAt": token.expires_at(),
+            "sessionId": sessions::session_id(token),
+        })
+    }
+}
+
+pub struct Decision {
+    pub method: PlayMethod,
+    // http for progressive MP4 remuxes, hls or dash for segmented ones
+    pub protocol: Option<&'static str>,
+    pub profile: Option<Profile>,
+    // Why the file can't be played directly
+    pub reasons: Vec<String>,
+}
+
+fn supports(list: &[String], value: &str) -> bool {
+    list.iter().any(|item| item.eq_ignore_ascii_case(value))
+}
+
+fn exceeds(value: Option<i64>, limit: Option<i64>) -> bool {
+    matches!((value, limit), (Some(value), Some(limit)) if value > limit)
+}
+
+// The track a player picks by default
+pub fn default_stream<'a>(streams: &'a [MediaStream], kind: &str) -> Option<&'a MediaStream> {
+    streams
+        .iter()
+        .find(|stream| stream.kind == kind && stream.is_default)
+        .or_else(|| streams.iter().find(|stream| stream.kind == kind))
+}
+
+fn container_supported(file: &MediaFile, streams: &[MediaStream], device: &DeviceProfileDto) -> bool {
+    match file.container.as_str() {
+        "mp4" => ["mp4", "m4v", "mov"].iter().any(|name| supports(&device.containers, name)),
+        "matroska" => {
+            supports(&device.containers, "matroska")
+                || supports(&device.containers, "mkv")
+                || (supports(&device.containers, "webm")
+                    && streams.iter().all(|stream| WEBM_CODECS.contains(&stream.codec.as_str())))
+        }
+        other => supports(&device.containers, other),
+    }
+}
+
+// Largest transcoding profile the device can take
+fn transcode_profile(device: &DeviceProfileDto, has_video: bool) -> Profile {
+    if !has_video {
+        return Profile::AudioAac;
+    }
+
+    let fits_1080p = !exceeds(Some(1080), device.max_height)
+        && !exceeds(Some(1920), device.max_width)
+        && !exceeds(Some(Profile::Video1080p.bitrate()), device.max_bitrate);
+    if fits_1080p {
+        Profile::Video1080p
+    } else {
+        Profile::Video720p
+    }
+}
+
+// Decide how a device should play a file. Without a probed file (containers we can't
+// read) there is nothing to check, so it gets transcoded.
+pub fn decide(file: Option<&MediaFile>, streams: &[MediaStream], device: &DeviceProfileDto) -> Decision {
+    let Some(file) = file else {
+        return Decision {
+            method: PlayMethod::Transcode,
+            protocol: Some("hls"),
+            profile: Some(transcode_profile(device, true)),
+            reasons: vec!["The file's container can't be read".to_string()],
+        };
+    };
+
+    let video = default_stream(streams, "video");
+    let audio = default_stream(streams, "audio");
+    let mut reasons = Vec::new();
+
+    if let Some(video) = video {
+        if !supports(&device.video_codecs, &video.codec) {
+            reasons.push(format!("Video codec {} is not supported", video.codec));
+        }
+        if exceeds(video.width, device.max_width) || exceeds(video.height, device.max_height) {
+            reasons.push(format!(
+                "Resolution {}x{} is above the device's maximum",
+                video.width.unwrap_or(0),
+                video.height.unwrap_or(0)
+            ));
+        }
+        if let Some(hdr) = &video.hdr {
+            if !supports(&device.hdr_formats, hdr) {
+                reasons.push(format!("HDR format {} is not supported", hdr));
+            }
+        }
+    }
+
+    if let Some(audio) = audio {
+        if !supports(&device.audio_codecs, &audio.codec) {
+            reasons.push(format!("Audio codec {} is not supported", audio.codec));
+        }
+        if exceeds(audio.channels, device.max_audio_channels) {
+            reasons.push(format!(
+                "{} audio channels are more than the device's maximum of {}",
+                audio.channels.unwrap_or(0),
+                device.max_audio_channels.unwrap_or(0)
+            ));
+        }
+    }
+
+    if exceeds(file.bitrate, device.max_bitrate) {
+        reasons.push(format!(
+            "Bitrate {} kbps is above the device's maximum of {} kbps",
+            file.bitrate.unwrap_or(0) / 1000,
+            device.max_bitrate.unwrap_or(0) / 1000
+        ));
+    }
+
+    let transcode = |reasons: Vec<String>| Decision {
+        method: PlayMethod::Transcode,
+        protocol: Some("hls"),
+        profile: Some(transcode_profile(device, video.is_some())),
+        reasons,
+    };
+
+    // Anything wrong with the tracks themselves needs a transcode
+    if !reasons.is_empty() {
+        return transcode(reasons);
+    }
+
+    if container_supported(file, streams, device) {
+        return Decision {
+            method: PlayMethod::DirectPlay,
+            protocol: None,
+            profile: None,
+            reasons,
+        };
+    }
+
+    // The tracks are fine, only the container isn't
+    reasons.push(format!("Container {} is not supported", file.container));
+
+    let packageable = [video, audio]
+        .into_iter()
+        .flatten()
+        .all(|stream| stream.codec_string.is_some());
+    // A progressive MP4 plays and seeks like the original file, so it comes first
+    let protocol = if supports(&device.containers, "mp4") {
+        Some("http")
+    } else {
+        ["hls", "dash"]
+            .into_iter()
+            .find(|protocol| supports(&device.protocols, protocol))
+    };
+
+    match (packageable, protocol) {
+        (true, Some(protocol)) => Decision {
+            method: PlayMethod::Remux,
+            protocol: Some(protocol),
+            profile: None,
+            reasons,
+        },
+        (false, _) => {
+            reasons.push("The tracks can't be remuxed to fMP4".to_string());
+            transcode(reasons)
+        }
+        (true, None) => {
+            reasons.push("The device supports neither MP4, HLS nor DASH".to_string());
+            transcode(reasons)
+        }
+    }
+}
+
+fn with_param(url: &str, name: &str, value: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}={}", url, separator, name, value)
+}
+
+// Playback plan of a file for a device: how to play it, at which URL, and why it
+// can't be played directly if it can't. The file is probed first if it is new or has
+// changed since the last scan.
+pub async fn plan(
+    db: &Pool<Sqlite>,
+    media_id: &str,
+    episode_id: Option<&str>,
+    path: &Path,
+    device: &DeviceProfileDto,
+    urls: &PlaybackUrls,
+) -> Result<serde_json::Value> {
+    probe::probe_file(db, media_id, episode_id, path).await?;
+
+    let file = queries::get_media_file_by_path(db, &path.to_string_lossy()).await?;
+    let streams = match &file {
+        Some(file) => queries::get_streams_by_file_id(db, &file.id).await?,
+        None => Vec::new(),
+    };
+
+    let decision = decide(file.as_ref(), &streams, device);
+    let url = match (decision.method, decision.profile) {
+        (PlayMethod::Transcode, Some(profile)) => with_param(&urls.direct, "profile", profile.name()),
+        (PlayMethod::Remux, _) => match decision.protocol {
+            Some("http") => with_param(&urls.direct, "format", FORMAT_MP4),
+            Some("dash") => urls.dash.clone(),
+            _ => urls.hls.clone(),
+        },
+        _ => urls.direct.clone(),
+    };
+
+    Ok(serde_json::json!({
+        "method": decision.method.as_str(),
+        "protocol": decision.protocol.unwrap_or("http"),
+        "url": url,
+        "profile": decision.profile.map(|profile| profile.name()),
+        "reasons": decision.reasons,
+        "container": file.as_ref().map(|file| file.container.clone()),
+        "videoStream": default_stream(&streams, "video").map(|stream| stream.stream_index),
+        "audioStream": default_stream(&streams, "audio").map(|stream| stream.stream_index),
+    }))
+}
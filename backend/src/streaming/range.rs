@@ -0,0 +1,108 @@
+use std::ops::Range;
+
+// Upper bound on the number of parts in a multipart/byteranges response. Requests
+// asking for more (after overlapping ranges are merged) are answered with the full
+// file, as RFC 7233 section 6.1 allows, instead of thousands of tiny parts.
+pub const MAX_RANGES: usize = 32;
+
+// Outcome of evaluating a Range header against a file of known size
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    // No usable Range header: serve the whole file with 200
+    Full,
+    // One or more satisfiable ranges in ascending order, overlaps merged
+    Partial(Vec<Range<u64>>),
+    // A valid header none of whose ranges overlap the file: 416
+    Unsatisfiable,
+}
+
+// Parse range header values like "bytes=0-1023", "bytes=-500" or "bytes=0-99,200-299".
+// Headers that are not valid byte ranges are ignored rather than rejected, per RFC 7233.
+pub fn parse_range_header(range_header: &str, file_size: u64) -> RangeRequest {
+    let range_set = match range_header.trim().strip_prefix("bytes=") {
+        Some(set) => set,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    let mut any_spec = false;
+
+    for spec in range_set.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        any_spec = true;
+        match parse_range_spec(spec, file_size) {
+            Ok(Some(range)) => ranges.push(range),
+            Ok(None) => {}
+            Err(()) => return RangeRequest::Full,
+        }
+    }
+
+    if !any_spec {
+        return RangeRequest::Full;
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    let ranges = coalesce(ranges);
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    RangeRequest::Partial(ranges)
+}
+
+// Parse a single byte-range-spec. Returns Err for invalid syntax and Ok(None) for a
+// well-formed range that doesn't overlap the file.
+fn parse_range_spec(spec: &str, file_size: u64) -> std::result::Result<Option<Range<u64>>, ()> {
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = (start.trim(), end.trim());
+
+    // Suffix range like "-500" (the last 500 bytes)
+    if start.is_empty() {
+        let suffix = end.parse::<u64>().map_err(|_| ())?;
+        if suffix == 0 || file_size == 0 {
+            return Ok(None);
+        }
+        return Ok(Some(file_size.saturating_sub(suffix)..file_size));
+    }
+
+    let start = start.parse::<u64>().map_err(|_| ())?;
+
+    // Handle ranges like "1000-" (from 1000 to end)
+    let end = if end.is_empty() {
+        None
+    } else {
+        Some(end.parse::<u64>().map_err(|_| ())?)
+    };
+
+    if let Some(end) = end {
+        if end < start {
+            return Err(());
+        }
+    }
+
+    if start >= file_size {
+        return Ok(None);
+    }
+
+    // Ensure end doesn't exceed file size, +1 because Range is exclusive on the end
+    let end = end.map_or(file_size, |end| end.saturating_add(1).min(file_size));
+
+    Ok(Some(start..end))
+}
+
+// Sort ranges and merge any that overlap or touch
+fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
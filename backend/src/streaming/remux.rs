@@ -0,0 +1,292 @@
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{Builder as ResponseBuilder, Responder, Response};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::container::fmp4::{self, SidxReference};
+use crate::container::index::{IndexedFile, MediaIndex, Sample, Track, TrackKind};
+use crate::error::{AppError, Result};
+use crate::streaming::conditional;
+use crate::streaming::file::{ExtentReader, STREAM_CHUNK_SIZE};
+use crate::streaming::range::{parse_range_header, RangeRequest};
+use crate::streaming::segment::derived_etag;
+use crate::streaming::throttle;
+
+// Value of `?format=` on the stream routes that selects the remuxed file
+pub const FORMAT_MP4: &str = "mp4";
+
+// A piece of the remuxed file: generated boxes, or sample data of the source
+enum Part {
+    Bytes(Vec<u8>),
+    Samples(Vec<Range<u64>>),
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Bytes(bytes) => bytes.len() as u64,
+            Part::Samples(extents) => extents.iter().map(|extent| extent.end - extent.start).sum(),
+        }
+    }
+}
+
+// The default video and audio tracks of a file rewritten as one fragmented MP4 on the
+// fly, with a fragment per segment of the plan. Every box size follows from the index,
+// so the whole file is laid out up front: Range requests address the remuxed bytes,
+// and a sidx lets players map a seek time to a byte offset.
+pub struct RemuxedFile {
+    path: PathBuf,
+    content_type: ContentType,
+    parts: Vec<Part>,
+    size: u64,
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+// The default track of a kind, or None if the file has none. Tracks that exist but
+// can't be remuxed are an error rather than silently dropped.
+fn remuxed_track(index: &MediaIndex, kind: TrackKind) -> Result<Option<&Track>> {
+    let mut tracks = index.tracks.iter().filter(|track| track.kind == kind).peekable();
+    let Some(first) = tracks.peek().copied() else {
+        return Ok(None);
+    };
+
+    let packageable: Vec<&Track> = tracks.filter(|track| track.is_packageable()).collect();
+    match packageable.iter().find(|track| track.default).or(packageable.first()) {
+        Some(track) => Ok(Some(*track)),
+        None => Err(AppError::InvalidInput(format!(
+            "The {} track ({}) cannot be remuxed to MP4, use a transcoding profile instead",
+            kind.as_str(),
+            first.codec
+        ))),
+    }
+}
+
+// Byte ranges of samples in the source, adjacent ones merged
+fn sample_extents(runs: &[&[Sample]]) -> Vec<Range<u64>> {
+    let mut extents: Vec<Range<u64>> = Vec::new();
+    for sample in runs.iter().flat_map(|run| run.iter()) {
+        let extent = sample.offset..sample.offset + sample.size as u64;
+        match extents.last_mut() {
+            Some(last) if last.end == extent.start => last.end = extent.end,
+            _ => extents.push(extent),
+        }
+    }
+
+    extents
+}
+
+impl RemuxedFile {
+    // With `start` (in seconds) the file begins at the fragment containing that time,
+    // for players that seek by reloading the stream instead of with Range requests
+    pub fn new(indexed: &IndexedFile, start: Option<f64>) -> Result<Self> {
+        let index = &indexed.index;
+        let plan = &index.segments;
+
+        let tracks: Vec<&Track> = [
+            remuxed_track(index, TrackKind::Video)?,
+            remuxed_track(index, TrackKind::Audio)?,
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if tracks.is_empty() {
+            return Err(AppError::InvalidInput("This file has no tracks to remux".to_string()));
+        }
+
+        let first = match start {
+            None => 0,
+            Some(start) if start.is_finite() && start >= 0.0 => plan.boundaries[..plan.len()]
+                .partition_point(|boundary| *boundary <= start)
+                .saturating_sub(1),
+            Some(start) => return Err(AppError::InvalidInput(format!("Invalid start time: {}", start))),
+        };
+
+        let header = fmp4::movie_header(&tracks, Some(plan.end()))
+            .ok_or_else(|| AppError::Server("Missing sample entry".to_string()))?;
+
+        let mut fragments = Vec::with_capacity(plan.len() - first);
+        for segment in first..plan.len() {
+            let runs: Vec<&[Sample]> = tracks
+                .iter()
+                .map(|track| &track.samples[plan.sample_range(track, segment)])
+                .collect();
+            // Sequence numbers start at 1
+            fragments.push((fmp4::fragment_header(&runs, segment as u32 + 1), sample_extents(&runs)));
+        }
+
+        let mut parts = vec![Part::Bytes(header)];
+
+        // sidx counts references in 16 bits, which is over a hundred hours of fragments
+        if fragments.len() <= u16::MAX as usize {
+            let timescale = tracks[0].timescale;
+            let ticks = |seconds: f64| (seconds * timescale as f64).round() as u64;
+            let references: Vec<SidxReference> = fragments
+                .iter()
+                .enumerate()
+                .map(|(offset, (header, extents))| {
+                    let segment = first + offset;
+                    let payload: u64 = extents.iter().map(|extent| extent.end - extent.start).sum();
+                    SidxReference {
+                        size: (header.len() as u64 + payload) as u32,
+                        duration: (ticks(plan.boundaries[segment + 1]) - ticks(plan.boundaries[segment])) as u32,
+                    }
+                })
+                .collect();
+
+            let mut sidx = Vec::with_capacity(fmp4::sidx_size(references.len()));
+            fmp4::write_sidx(&mut sidx, timescale, ticks(plan.boundaries[first]), &references);
+            parts.push(Part::Bytes(sidx));
+        }
+
+        for (header, extents) in fragments {
+            parts.push(Part::Bytes(header));
+            parts.push(Part::Samples(extents));
+        }
+
+        let content_type = if tracks.iter().any(|track| track.kind == TrackKind::Video) {
+            ContentType::MP4
+        } else {
+            ContentType::new("audio", "mp4")
+        };
+
+        Ok(Self {
+            path: indexed.path.clone(),
+            content_type,
+            size: parts.iter().map(Part::len).sum(),
+            parts,
+            etag: derived_etag(&indexed.etag, &format!("mp4-{}", first)),
+            last_modified: indexed.last_modified,
+        })
+    }
+
+    // Reader over `range` of the remuxed file
+    fn into_reader(self, range: Range<u64>) -> std::io::Result<ExtentReader> {
+        let mut reader = ExtentReader::open(&self.path, std::iter::empty())?;
+        let mut position = 0;
+
+        for part in self.parts {
+            let part_start = position;
+            position += part.len();
+            if position <= range.start {
+                continue;
+            }
+            if part_start >= range.end {
+                break;
+            }
+
+            // Window of this part to send, relative to its start
+            let from = range.start.saturating_sub(part_start);
+            let to = range.end.min(position) - part_start;
+
+            match part {
+                Part::Bytes(mut bytes) => {
+                    bytes.truncate(to as usize);
+                    bytes.drain(..from as usize);
+                    reader.push_bytes(bytes);
+                }
+                Part::Samples(extents) => {
+                    let mut offset = 0;
+                    for extent in extents {
+                        let length = extent.end - extent.start;
+                        let (start, end) = (from.max(offset), to.min(offset + length));
+                        if start < end {
+                            reader.push_extent(extent.start + start - offset..extent.start + end - offset);
+                        }
+                        offset += length;
+                        if offset >= to {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(reader)
+    }
+
+    // Decide which range to serve, ignoring Range when an If-Range validator is stale.
+    // Several ranges are answered with the whole file, as RFC 7233 allows.
+    fn requested_range(&self, req: &Request<'_>) -> RangeRequest {
+        let Some(range_header) = req.headers().get_one("Range") else {
+            return RangeRequest::Full;
+        };
+
+        if let Some(if_range) = req.headers().get_one("If-Range") {
+            if !conditional::if_range_matches(if_range, Some(&self.etag), self.last_modified) {
+                return RangeRequest::Full;
+            }
+        }
+
+        match parse_range_header(range_header, self.size) {
+            RangeRequest::Partial(ranges) if ranges.len() > 1 => RangeRequest::Full,
+            other => other,
+        }
+    }
+
+    fn base_response<'r>(&self) -> ResponseBuilder<'r> {
+        let mut response = Response::build();
+        response
+            .header(Header::new("Accept-Ranges", "bytes"))
+            .header(Header::new("ETag", self.etag.clone()))
+            .header(Header::new("Cache-Control", conditional::CACHE_CONTROL_STREAM));
+
+        if let Some(modified) = self.last_modified {
+            response.header(Header::new("Last-Modified", conditional::format_http_date(modified)));
+        }
+
+        response
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RemuxedFile {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        if conditional::is_not_modified(req.headers(), Some(&self.etag), self.last_modified) {
+            return Ok(conditional::not_modified(
+                Some(&self.etag),
+                self.last_modified,
+                conditional::CACHE_CONTROL_STREAM,
+            ));
+        }
+
+        let mut response = self.base_response();
+        let size = self.size;
+
+        let (partial, range) = match self.requested_range(req) {
+            RangeRequest::Full => (false, 0..size),
+            RangeRequest::Partial(ranges) => (true, ranges[0].clone()),
+            RangeRequest::Unsatisfiable => {
+                return Ok(response
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new("Content-Range", format!("bytes */{}", size)))
+                    .finalize());
+            }
+        };
+
+        let path = self.path.clone();
+        response.header(self.content_type.clone());
+        let reader = self.into_reader(range.clone()).map_err(|e| {
+            tracing::error!("Failed to open {}: {}", path.display(), e);
+            Status::InternalServerError
+        })?;
+
+        response
+            .raw_header("Content-Length", reader.len().to_string())
+            .streamed_body(throttle::pace(req, reader))
+            // Set after the body, replacing the body resets the chunk size
+            .max_chunk_size(STREAM_CHUNK_SIZE);
+
+        if partial {
+            response.status(Status::PartialContent).header(Header::new(
+                "Content-Range",
+                format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+            ));
+        } else {
+            response.status(Status::Ok);
+        }
+
+        Ok(response.finalize())
+    }
+}
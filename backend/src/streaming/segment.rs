@@ -0,0 +1,120 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{Responder, Response};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::container::fmp4;
+use crate::container::index::{IndexedFile, Track, TrackKind};
+use crate::error::{AppError, Result};
+use crate::streaming::conditional::{self, Cached};
+use crate::streaming::file::{ChainReader, ExtentReader, STREAM_CHUNK_SIZE};
+use crate::streaming::throttle;
+
+// An fMP4 initialization or media segment of one track, remuxed from the source file
+// on the fly. The generated box headers are sent first, followed by the sample data
+// read straight from the source.
+pub struct Fragment {
+    path: PathBuf,
+    content_type: ContentType,
+    header: Vec<u8>,
+    extents: Vec<Range<u64>>,
+}
+
+// Entity tag of a resource derived from a file, changes whenever the file does
+pub fn derived_etag(file_etag: &str, resource: &str) -> String {
+    format!("{}-{}\"", file_etag.trim_end_matches('"'), resource)
+}
+
+fn packageable_track(indexed: &IndexedFile, track_number: u32) -> Result<&Track> {
+    let track = indexed
+        .index
+        .track(track_number)
+        .ok_or_else(|| AppError::NotFound(format!("Track not found: {}", track_number)))?;
+
+    if !track.is_packageable() {
+        return Err(AppError::InvalidInput(format!(
+            "Track {} ({}) cannot be remuxed to fMP4",
+            track_number, track.codec
+        )));
+    }
+
+    Ok(track)
+}
+
+fn content_type(track: &Track) -> ContentType {
+    match track.kind {
+        TrackKind::Audio => ContentType::new("audio", "mp4"),
+        _ => ContentType::MP4,
+    }
+}
+
+impl Fragment {
+    // Initialization segment (ftyp + moov) of a track
+    pub fn init(indexed: &IndexedFile, track_number: u32) -> Result<Cached<Self>> {
+        let track = packageable_track(indexed, track_number)?;
+        let header = fmp4::init_segment(track)
+            .ok_or_else(|| AppError::Server(format!("No sample entry for track {}", track_number)))?;
+
+        let fragment = Self {
+            path: indexed.path.clone(),
+            content_type: content_type(track),
+            header,
+            extents: Vec::new(),
+        };
+
+        let etag = derived_etag(&indexed.etag, &format!("{}-init", track_number));
+        Ok(Cached::new(fragment, etag, indexed.last_modified).with_cache_control(conditional::CACHE_CONTROL_STREAM))
+    }
+
+    // Media segment (moof + mdat) number `segment` of a track
+    pub fn media(indexed: &IndexedFile, track_number: u32, segment: usize) -> Result<Cached<Self>> {
+        let track = packageable_track(indexed, track_number)?;
+        let plan = &indexed.index.segments;
+        if segment >= plan.len() {
+            return Err(AppError::NotFound(format!("Segment not found: {}", segment)));
+        }
+
+        let samples = &track.samples[plan.sample_range(track, segment)];
+        // Sequence numbers start at 1
+        let header = fmp4::segment_header(samples, segment as u32 + 1);
+        let extents = samples
+            .iter()
+            .map(|sample| sample.offset..sample.offset + sample.size as u64)
+            .collect();
+
+        let fragment = Self {
+            path: indexed.path.clone(),
+            content_type: content_type(track),
+            header,
+            extents,
+        };
+
+        let etag = derived_etag(&indexed.etag, &format!("{}-{}", track_number, segment));
+        Ok(Cached::new(fragment, etag, indexed.last_modified).with_cache_control(conditional::CACHE_CONTROL_STREAM))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Fragment {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut body = ChainReader::new();
+        body.push_bytes(self.header);
+
+        if !self.extents.is_empty() {
+            let reader = ExtentReader::open(&self.path, self.extents).map_err(|e| {
+                tracing::error!("Failed to open {}: {}", self.path.display(), e);
+                Status::InternalServerError
+            })?;
+            body.push_extents(reader);
+        }
+
+        let length = body.len();
+        Ok(Response::build()
+            .status(Status::Ok)
+            .header(self.content_type)
+            .raw_header("Content-Length", length.to_string())
+            .streamed_body(throttle::pace(req, body))
+            .max_chunk_size(STREAM_CHUNK_SIZE)
+            .finalize())
+    }
+}
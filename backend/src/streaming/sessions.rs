@@ -0,0 +1,475 @@
+use chrono::{DateTime, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::{Orbit, Rocket};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::streaming::playback::PlayMethod;
+use crate::streaming::signing::StreamToken;
+use crate::transcode::profile::Profile;
+
+// Sessions without a request, a heartbeat or a response still being sent in this long
+// are dropped. Players that buffer a whole file send heartbeats to stay listed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+// Id of the playback session a signed URL belongs to. Every request made with the
+// same URL, be it a range of the file or a segment of its playlist, is part of the
+// same session. The id is a hash so that listing sessions doesn't give away URLs.
+pub fn session_id(token: &StreamToken) -> String {
+    signature_session_id(&token.signature)
+}
+
+fn signature_session_id(signature: &str) -> String {
+    Sha256::digest(signature.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// The device a request came from
+pub struct Client {
+    pub user_agent: Option<String>,
+    pub address: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Client {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Client {
+            user_agent: req.headers().get_one("User-Agent").map(String::from),
+            address: req.client_ip(),
+        })
+    }
+}
+
+// What a stream request plays: the media item, or the episode for episodes
+pub struct Stream<'a> {
+    pub media_id: Option<&'a str>,
+    pub episode_id: Option<&'a str>,
+    pub path: &'a Path,
+    pub method: PlayMethod,
+}
+
+impl<'a> Stream<'a> {
+    // A movie or music file, or an episode of a show given with `?episode=`
+    pub fn media(id: &'a str, episode: Option<&'a str>, path: &'a Path, method: PlayMethod) -> Self {
+        Self { media_id: Some(id), episode_id: episode, path, method }
+    }
+
+    pub fn episode(id: &'a str, path: &'a Path, method: PlayMethod) -> Self {
+        Self { media_id: None, episode_id: Some(id), path, method }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaybackSession {
+    pub id: String,
+    pub user: String,
+    pub user_agent: Option<String>,
+    pub address: Option<IpAddr>,
+    pub media_id: String,
+    pub episode_id: Option<String>,
+    pub title: String,
+    // e.g. "S01E02 - Title"
+    pub episode: Option<String>,
+    pub method: PlayMethod,
+    pub profile: Option<Profile>,
+    // Bits per second sent to the player
+    pub bitrate: Option<i64>,
+    pub duration: Option<f64>,
+    // Where the player is and whether it's paused, as of its last heartbeat
+    pub position: Option<f64>,
+    pub paused: bool,
+    pub transcode_session: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    // Unix time at which the session's URL expires
+    expires: i64,
+    last_active: Instant,
+    // Responses of the session still being sent, such as a player reading the whole
+    // file with one request
+    open_bodies: usize,
+    // Set when the session is stopped, which ends its responses
+    stopped: Arc<AtomicBool>,
+}
+
+impl PlaybackSession {
+    // Whether it plays a movie or music file, or an episode when one is given
+    fn plays(&self, media_id: Option<&str>, episode_id: Option<&str>) -> bool {
+        match episode_id {
+            Some(episode_id) => self.episode_id.as_deref() == Some(episode_id),
+            None => self.episode_id.is_none() && media_id == Some(self.media_id.as_str()),
+        }
+    }
+
+    // What a stop blocks for the user: the episode, or the movie or music file
+    fn item(&self) -> &str {
+        self.episode_id.as_deref().unwrap_or(&self.media_id)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.open_bodies == 0 && self.last_active.elapsed() > IDLE_TIMEOUT
+    }
+
+    fn seen(&mut self) {
+        self.last_seen = Utc::now();
+        self.last_active = Instant::now();
+    }
+}
+
+// Who is streaming what. Sessions are kept in memory and start with the first request
+// of a signed URL. A stopped session's URL is refused until it expires, and so is
+// playing the same item again as the same user, with any URL.
+#[derive(Clone)]
+pub struct PlaybackSessions {
+    sessions: Arc<Mutex<HashMap<String, PlaybackSession>>>,
+    // Ids of stopped sessions, with when their URL expires
+    stopped: Arc<Mutex<HashMap<String, i64>>>,
+    // Users and the items they were stopped playing, with when the URL expires
+    stopped_items: Arc<Mutex<HashMap<(String, String), i64>>>,
+    max_per_user: Option<usize>,
+}
+
+impl PlaybackSessions {
+    // MAX_STREAMS_PER_USER is how many streams a user can play at once; unset means
+    // no limit
+    pub fn from_env() -> Self {
+        let max_per_user = env::var("MAX_STREAMS_PER_USER")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value| value > 0);
+
+        Self {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            stopped: Arc::new(Mutex::new(HashMap::new())),
+            stopped_items: Arc::new(Mutex::new(HashMap::new())),
+            max_per_user,
+        }
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, PlaybackSession>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stopped(&self) -> std::sync::MutexGuard<'_, HashMap<String, i64>> {
+        self.stopped.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stopped_items(&self) -> std::sync::MutexGuard<'_, HashMap<(String, String), i64>> {
+        self.stopped_items.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_stopped(&self, id: &str) -> Result<()> {
+        if self.stopped().contains_key(id) {
+            return Err(AppError::Forbidden(format!("Playback session {} was stopped", id)));
+        }
+        Ok(())
+    }
+
+    // Check that a user wasn't stopped playing an item, which new URLs of the item are
+    // refused for until the stopped one expires
+    pub fn check_item_stopped(&self, user: &str, media_id: Option<&str>, episode_id: Option<&str>) -> Result<()> {
+        let item = episode_id.or(media_id).unwrap_or_default();
+        if self.stopped_items().contains_key(&(user.to_string(), item.to_string())) {
+            return Err(AppError::Forbidden(format!("Playback of {} by {} was stopped", item, user)));
+        }
+        Ok(())
+    }
+
+    // Check that a user may start streaming an item. Every session of the user that
+    // is playing counts, be it sending a response or kept going by requests and
+    // heartbeats, except one already playing the item, which a new URL of the same
+    // item replaces.
+    pub fn check_limit(&self, user: &str, media_id: Option<&str>, episode_id: Option<&str>) -> Result<()> {
+        self.check_item_stopped(user, media_id, episode_id)?;
+        let Some(max) = self.max_per_user else {
+            return Ok(());
+        };
+
+        let playing = self
+            .sessions()
+            .values()
+            .filter(|session| session.user == user && !session.is_idle() && !session.plays(media_id, episode_id))
+            .count();
+        if playing >= max {
+            return Err(AppError::TooManyStreams(format!(
+                "{} can play {} stream{} at once, stop one to start another",
+                user,
+                max,
+                if max == 1 { "" } else { "s" }
+            )));
+        }
+        Ok(())
+    }
+
+    // Count a verified stream request towards its session, starting the session with
+    // its first request. Requests of stopped sessions are refused, and so are new
+    // sessions of users already playing as many streams as they may.
+    pub async fn track(
+        &self,
+        db: &Pool<Sqlite>,
+        token: &StreamToken,
+        client: &Client,
+        stream: Stream<'_>,
+    ) -> Result<String> {
+        let id = session_id(token);
+        self.check_stopped(&id)?;
+        if let Some(session) = self.sessions().get_mut(&id) {
+            session.seen();
+            return Ok(id);
+        }
+
+        self.check_limit(&token.user, stream.media_id, stream.episode_id)?;
+        let session = self.describe(db, &id, token, client, stream).await?;
+        tracing::info!(
+            "{} started playing {} ({}) on {}",
+            session.user,
+            session.title,
+            session.method.as_str(),
+            session.user_agent.as_deref().unwrap_or("an unknown device")
+        );
+        let mut sessions = self.sessions();
+        // It replaces the user's earlier sessions of the item, such as the one of a
+        // player before it was reloaded
+        sessions.retain(|other, earlier| {
+            other == &id
+                || earlier.user != session.user
+                || !earlier.plays(Some(&session.media_id), session.episode_id.as_deref())
+        });
+        // Another request of the same URL may have started it in the meantime
+        sessions.entry(id.clone()).or_insert(session);
+        Ok(id)
+    }
+
+    // A new session, with the item's titles and the file's bitrate
+    async fn describe(
+        &self,
+        db: &Pool<Sqlite>,
+        id: &str,
+        token: &StreamToken,
+        client: &Client,
+        stream: Stream<'_>,
+    ) -> Result<PlaybackSession> {
+        let (media_id, episode) = match stream.episode_id {
+            Some(episode_id) => {
+                let (media_id, title, episode_number, season_number) =
+                    sqlx::query_as::<_, (String, String, i32, i32)>(
+                        "SELECT e.media_id, e.title, e.episode_number, s.season_number
+                         FROM episodes e JOIN seasons s ON s.id = e.season_id WHERE e.id = ?",
+                    )
+                    .bind(episode_id)
+                    .fetch_optional(db)
+                    .await
+                    .map_err(AppError::Database)?
+                    .ok_or_else(|| AppError::NotFound(format!("Episode not found: {}", episode_id)))?;
+                (media_id, Some(format!("S{:02}E{:02} - {}", season_number, episode_number, title)))
+            }
+            None => (stream.media_id.unwrap_or_default().to_string(), None),
+        };
+        let media = queries::get_media_by_id(db, &media_id).await?;
+
+        // Probed files know their bitrate, others are estimated from their size
+        let file = queries::get_media_file_by_path(db, &stream.path.to_string_lossy()).await?;
+        let duration = file.as_ref().and_then(|file| file.duration);
+        let bitrate = file.as_ref().and_then(|file| {
+            file.bitrate
+                .or_else(|| duration.filter(|d| *d > 0.0).map(|d| (file.size as f64 * 8.0 / d) as i64))
+        });
+
+        let now = Utc::now();
+        Ok(PlaybackSession {
+            id: id.to_string(),
+            user: token.user.clone(),
+            user_agent: client.user_agent.clone(),
+            address: client.address,
+            media_id,
+            episode_id: stream.episode_id.map(String::from),
+            title: media.title,
+            episode,
+            method: stream.method,
+            profile: None,
+            bitrate,
+            duration,
+            position: None,
+            paused: false,
+            transcode_session: None,
+            started_at: now,
+            last_seen: now,
+            expires: token.expires,
+            last_active: Instant::now(),
+            open_bodies: 0,
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Note that a session's URL started a transcoding session, whose segments are then
+    // part of the playback session
+    pub fn transcoding(&self, token: &StreamToken, transcode_id: &str, profile: Profile) {
+        if let Some(session) = self.sessions().get_mut(&session_id(token)) {
+            session.method = PlayMethod::Transcode;
+            session.profile = Some(profile);
+            session.bitrate = Some(profile.bitrate());
+            session.transcode_session = Some(transcode_id.to_string());
+        }
+    }
+
+    // Count a segment request of a transcoding session towards its playback session
+    pub fn track_transcode(&self, transcode_id: &str) -> Result<()> {
+        let mut sessions = self.sessions();
+        let session = sessions
+            .values_mut()
+            .find(|session| session.transcode_session.as_deref() == Some(transcode_id));
+        match session {
+            Some(session) => {
+                session.seen();
+                Ok(())
+            }
+            // Stopped sessions are forgotten, their transcodes with them
+            None if self.stopped().contains_key(transcode_id) => Err(AppError::Forbidden(format!(
+                "Transcoding session {} was stopped",
+                transcode_id
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // A player reporting where it is, which keeps its session alive while it plays
+    // from its buffer
+    pub fn heartbeat(&self, id: &str, position: Option<f64>, paused: Option<bool>) -> Result<PlaybackSession> {
+        self.check_stopped(id)?;
+        let mut sessions = self.sessions();
+        let session = sessions
+            .get_mut(id)
+            .ok_or_else(|| AppError::NotFound(format!("Playback session not found: {}", id)))?;
+
+        session.seen();
+        if position.is_some() {
+            session.position = position;
+        }
+        if let Some(paused) = paused {
+            session.paused = paused;
+        }
+        Ok(session.clone())
+    }
+
+    pub fn get(&self, id: &str) -> Result<PlaybackSession> {
+        self.sessions()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| AppError::NotFound(format!("Playback session not found: {}", id)))
+    }
+
+    pub fn list(&self) -> Vec<PlaybackSession> {
+        let mut sessions: Vec<PlaybackSession> = self.sessions().values().cloned().collect();
+        sessions.sort_by_key(|session| session.started_at);
+        sessions
+    }
+
+    // End a session: further requests with its URL, and with the URLs of its
+    // transcode's segments, are refused until the URL expires
+    pub fn stop(&self, id: &str) -> Result<PlaybackSession> {
+        let session = self
+            .sessions()
+            .remove(id)
+            .ok_or_else(|| AppError::NotFound(format!("Playback session not found: {}", id)))?;
+
+        // Responses still being sent end with an error
+        session.stopped.store(true, Ordering::Relaxed);
+        let mut stopped = self.stopped();
+        stopped.insert(session.id.clone(), session.expires);
+        if let Some(transcode_id) = &session.transcode_session {
+            stopped.insert(transcode_id.clone(), session.expires);
+        }
+        self.stopped_items()
+            .insert((session.user.clone(), session.item().to_string()), session.expires);
+        tracing::info!("Stopped playback session {} of {} ({})", session.id, session.user, session.title);
+        Ok(session)
+    }
+
+    // Count a response body of the session a signed URL started, while it is sent
+    pub fn open_body(&self, signature: &str) -> Option<OpenBody> {
+        let id = signature_session_id(signature);
+        let stopped = {
+            let mut sessions = self.sessions();
+            let session = sessions.get_mut(&id)?;
+            session.open_bodies += 1;
+            session.stopped.clone()
+        };
+        Some(OpenBody { sessions: self.clone(), id, stopped })
+    }
+
+    fn reap(&self) {
+        self.sessions().retain(|id, session| {
+            let idle = session.is_idle();
+            if idle {
+                tracing::info!("Playback session {} of {} went idle", id, session.user);
+            }
+            !idle
+        });
+
+        let now = Utc::now().timestamp();
+        self.stopped().retain(|_, expires| *expires >= now);
+        self.stopped_items().retain(|_, expires| *expires >= now);
+    }
+}
+
+// A response body of a session being sent. The session isn't idle while it is open,
+// and counts as seen when it is done.
+pub struct OpenBody {
+    sessions: PlaybackSessions,
+    id: String,
+    stopped: Arc<AtomicBool>,
+}
+
+impl OpenBody {
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for OpenBody {
+    fn drop(&mut self) {
+        if let Some(session) = self.sessions.sessions().get_mut(&self.id) {
+            session.open_bodies = session.open_bodies.saturating_sub(1);
+            session.seen();
+        }
+    }
+}
+
+// Drops idle sessions, and stopped ones once their URL has expired
+pub struct SessionReaper;
+
+#[rocket::async_trait]
+impl Fairing for SessionReaper {
+    fn info(&self) -> Info {
+        Info {
+            name: "Playback session reaper",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(sessions) = rocket.state::<PlaybackSessions>().cloned() {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(REAP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    sessions.reap();
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use chrono::{DateTime, Utc};
+use rocket::http::RawStr;
+use sha2::{Digest, Sha256};
+use std::env;
+
+use crate::error::{AppError, Result};
+
+// Long enough to watch a film with a few pauses; playlists pass their signature on to
+// the segments, so it has to outlast the playback
+const DEFAULT_TTL_SECONDS: i64 = 6 * 60 * 60;
+// SHA-256 works on 64 byte blocks, HMAC pads the key to one
+const BLOCK_SIZE: usize = 64;
+
+// Signature of a stream URL, in its query string. It is only valid for the item it was
+// made for, which is the episode for episodes and the media item otherwise.
+#[derive(Debug, Clone, FromForm)]
+pub struct StreamToken {
+    pub user: String,
+    // Unix time after which the URL stops working
+    pub expires: i64,
+    pub signature: String,
+}
+
+impl StreamToken {
+    // The token as query string parameters, without the leading `?`
+    pub fn query(&self) -> String {
+        format!(
+            "user={}&expires={}&signature={}",
+            RawStr::new(&self.user).percent_encode(),
+            self.expires,
+            self.signature
+        )
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.expires, 0)
+    }
+}
+
+// Signs stream URLs with a key only the server knows, so that they can be handed to
+// players that can't send credentials, like VLC or a Chromecast
+pub struct UrlSigner {
+    key: Vec<u8>,
+    ttl: i64,
+}
+
+impl UrlSigner {
+    // STREAM_SIGNING_KEY keeps URLs valid across restarts; without it every start
+    // makes up a new key. STREAM_URL_TTL is how many seconds URLs are valid for.
+    pub fn from_env() -> Self {
+        let key = match env::var("STREAM_SIGNING_KEY") {
+            Ok(key) if !key.is_empty() => key.into_bytes(),
+            _ => {
+                tracing::warn!("STREAM_SIGNING_KEY is not set, stream URLs won't survive a restart");
+                [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()]
+                    .iter()
+                    .flat_map(|id| *id.as_bytes())
+                    .collect()
+            }
+        };
+        let ttl = env::var("STREAM_URL_TTL")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .filter(|ttl| *ttl > 0)
+            .unwrap_or(DEFAULT_TTL_SECONDS);
+
+        Self { key, ttl }
+    }
+
+    // Token letting `user` stream `item` until the URL expires
+    pub fn sign(&self, item: &str, user: &str) -> StreamToken {
+        self.sign_until(item, user, Utc::now().timestamp() + self.ttl)
+    }
+
+    pub fn sign_until(&self, item: &str, user: &str, expires: i64) -> StreamToken {
+        StreamToken {
+            user: user.to_string(),
+            expires,
+            signature: self.signature(item, user, expires),
+        }
+    }
+
+    // Check that a request to stream `item` came with an unexpired URL signed for it
+    pub fn verify(&self, item: &str, token: Option<StreamToken>) -> Result<StreamToken> {
+        let token = token.ok_or_else(|| {
+            AppError::Auth("Stream URLs have to be signed, get one from the item's sign endpoint".to_string())
+        })?;
+
+        let expected = self.signature(item, &token.user, token.expires);
+        if !constant_time_eq(expected.as_bytes(), token.signature.as_bytes()) {
+            return Err(AppError::Auth("Invalid stream URL signature".to_string()));
+        }
+        if token.expires < Utc::now().timestamp() {
+            return Err(AppError::Auth("Stream URL has expired".to_string()));
+        }
+        Ok(token)
+    }
+
+    // Hex HMAC-SHA256 of the item, user and expiry. Lengths are included so that no two
+    // different combinations sign the same bytes.
+    fn signature(&self, item: &str, user: &str, expires: i64) -> String {
+        let message = format!("{}:{}\n{}:{}\n{}", item.len(), item, user.len(), user, expires);
+        hmac_sha256(&self.key, message.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+// HMAC as in RFC 2104
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+// Compare without returning early, so that response times don't give away how much of
+// a forged signature is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
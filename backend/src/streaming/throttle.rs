@@ -0,0 +1,207 @@
+use rocket::request::Request;
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::streaming::sessions::{OpenBody, PlaybackSessions};
+
+// How long a bucket may save up for, so that a stream can burst after a pause
+const BURST_SECONDS: f64 = 1.0;
+
+// A bandwidth limit in bits per second, e.g. `20M`, `800k` or `1.5G`
+fn parse_rate(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last()? {
+        'k' | 'K' => (&value[..value.len() - 1], 1e3),
+        'm' | 'M' => (&value[..value.len() - 1], 1e6),
+        'g' | 'G' => (&value[..value.len() - 1], 1e9),
+        _ => (value, 1.0),
+    };
+    let bits = number.trim().parse::<f64>().ok()? * multiplier;
+    // Bytes per second
+    (bits > 0.0).then_some(bits / 8.0)
+}
+
+fn rate_from_env(name: &str) -> Option<f64> {
+    let value = env::var(name).ok().filter(|value| !value.is_empty())?;
+    let rate = parse_rate(&value);
+    if rate.is_none() {
+        tracing::warn!("Ignoring {}={}, expected bits per second such as 20M", name, value);
+    }
+    rate
+}
+
+// Whether a client is on the local network rather than coming in over the internet
+fn is_lan(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_lan(IpAddr::V4(v4)),
+            // Unique local fc00::/7 and link local fe80::/10
+            None => v6.is_loopback() || (v6.segments()[0] & 0xfe00) == 0xfc00 || (v6.segments()[0] & 0xffc0) == 0xfe80,
+        },
+    }
+}
+
+// Token bucket refilled at `rate` bytes per second. Readers take what they sent and
+// wait off any debt before reading more, so streams sharing a bucket share its rate.
+struct Bucket {
+    rate: f64,
+    // Bytes available, negative when in debt, and when it was last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Arc<Self> {
+        Arc::new(Self {
+            rate,
+            state: Mutex::new((rate * BURST_SECONDS, Instant::now())),
+        })
+    }
+
+    // Take `bytes`, returning how long to wait before sending more
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let (tokens, last) = *state;
+        let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.rate).min(self.rate * BURST_SECONDS)
+            - bytes as f64;
+        *state = (tokens, now);
+
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+}
+
+// Buckets shared by the responses of a user or a client, kept while a response uses them
+struct Shared<K> {
+    rate: Option<f64>,
+    buckets: Mutex<HashMap<K, Weak<Bucket>>>,
+}
+
+impl<K: std::hash::Hash + Eq + Clone> Shared<K> {
+    fn new(rate: Option<f64>) -> Self {
+        Self { rate, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn bucket(&self, key: &K) -> Option<Arc<Bucket>> {
+        let rate = self.rate?;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bucket) = buckets.get(key).and_then(Weak::upgrade) {
+            return Some(bucket);
+        }
+
+        buckets.retain(|_, bucket| bucket.strong_count() > 0);
+        let bucket = Bucket::new(rate);
+        buckets.insert(key.clone(), Arc::downgrade(&bucket));
+        Some(bucket)
+    }
+}
+
+// Bandwidth limits of the streaming responses: one for the whole server, one per user,
+// and one per client that depends on whether it is on the LAN or remote. A response is
+// paced by every limit that applies to it.
+pub struct StreamLimits {
+    global: Option<Arc<Bucket>>,
+    users: Shared<String>,
+    lan: Shared<IpAddr>,
+    remote: Shared<IpAddr>,
+}
+
+impl StreamLimits {
+    // STREAM_BANDWIDTH_LIMIT, USER_BANDWIDTH_LIMIT, LAN_BANDWIDTH_LIMIT and
+    // REMOTE_BANDWIDTH_LIMIT in bits per second; unset means unlimited
+    pub fn from_env() -> Self {
+        Self {
+            global: rate_from_env("STREAM_BANDWIDTH_LIMIT").map(Bucket::new),
+            users: Shared::new(rate_from_env("USER_BANDWIDTH_LIMIT")),
+            lan: Shared::new(rate_from_env("LAN_BANDWIDTH_LIMIT")),
+            remote: Shared::new(rate_from_env("REMOTE_BANDWIDTH_LIMIT")),
+        }
+    }
+
+    fn buckets(&self, user: Option<&str>, address: Option<IpAddr>) -> Vec<Arc<Bucket>> {
+        let mut buckets: Vec<Arc<Bucket>> = self.global.iter().cloned().collect();
+        if let Some(user) = user {
+            buckets.extend(self.users.bucket(&user.to_string()));
+        }
+        if let Some(address) = address {
+            let clients = if is_lan(address) { &self.lan } else { &self.remote };
+            buckets.extend(clients.bucket(&address));
+        }
+        buckets
+    }
+}
+
+// Pace the body of a streaming response to the limits that apply to its request. The
+// user is the one its URL was signed for. The body keeps the playback session of its
+// URL going until it is sent.
+pub fn pace<R: AsyncRead + Unpin>(req: &Request<'_>, reader: R) -> Throttled<R> {
+    let buckets = match req.rocket().state::<StreamLimits>() {
+        Some(limits) => {
+            let user = req.query_value::<&str>("user").and_then(|user| user.ok());
+            limits.buckets(user, req.client_ip())
+        }
+        None => Vec::new(),
+    };
+
+    let body = req.rocket().state::<PlaybackSessions>().and_then(|sessions| {
+        let signature = req.query_value::<&str>("signature")?.ok()?;
+        sessions.open_body(signature)
+    });
+
+    Throttled { inner: reader, buckets, delay: None, body }
+}
+
+pub struct Throttled<R> {
+    inner: R,
+    buckets: Vec<Arc<Bucket>>,
+    // Wait before the next read, for what the last one took
+    delay: Option<Pin<Box<Sleep>>>,
+    body: Option<OpenBody>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if self.body.as_ref().is_some_and(OpenBody::is_stopped) {
+            return Poll::Ready(Err(std::io::Error::other("Playback session was stopped")));
+        }
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - before;
+
+        if read > 0 {
+            let wait = self.buckets.iter().map(|bucket| bucket.take(read)).max().unwrap_or_default();
+            if !wait.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Seeking is left to the reader, for bodies whose size Rocket works out itself
+impl<R: AsyncSeek + Unpin> AsyncSeek for Throttled<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: std::io::SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
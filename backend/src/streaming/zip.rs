@@ -0,0 +1,339 @@
+use bytes::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures::Stream;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{Responder, Response};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+
+use crate::error::Result;
+use crate::streaming::conditional;
+use crate::streaming::file::{attachment, validate_file, STREAM_CHUNK_SIZE};
+use crate::streaming::throttle;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+
+// Sizes and CRC follow the data, and names are UTF-8
+const FLAGS: u16 = 0x0008 | 0x0800;
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+// Made by a Unix system, so that external attributes hold file permissions
+const MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+// Regular file, rw-r--r--
+const FILE_ATTRIBUTES: u32 = 0o100644 << 16;
+
+// Fields that don't fit their 16 or 32 bits are set to all ones and given in ZIP64
+// records instead
+const MAX_16: u64 = 0xffff;
+const MAX_32: u64 = 0xffff_ffff;
+
+const LOCAL_HEADER_SIZE: u64 = 30;
+// ZIP64 extra field of a local header, with both sizes
+const ZIP64_LOCAL_EXTRA_SIZE: u64 = 20;
+const CENTRAL_HEADER_SIZE: u64 = 46;
+const ZIP64_END_SIZE: u64 = 56;
+const ZIP64_LOCATOR_SIZE: u64 = 20;
+const END_SIZE: u64 = 22;
+
+// CRC-32 as used by ZIP (reflected, polynomial 0xedb88320)
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+};
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, byte| CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+// A file in the archive, with where its local header starts
+struct Entry {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    modified: (u16, u16),
+    offset: u64,
+}
+
+impl Entry {
+    fn zip64(&self) -> bool {
+        self.size >= MAX_32
+    }
+
+    fn descriptor_size(&self) -> u64 {
+        if self.zip64() { 24 } else { 16 }
+    }
+
+    // The sizes when they overflow, then the offset when it does
+    fn zip64_fields(&self) -> Vec<u64> {
+        let mut fields = Vec::new();
+        if self.zip64() {
+            fields.extend([self.size, self.size]);
+        }
+        if self.offset >= MAX_32 {
+            fields.push(self.offset);
+        }
+        fields
+    }
+
+    // Streaming readers only expect a ZIP64 data descriptor after a local header with a
+    // ZIP64 extra field
+    fn local_header_size(&self) -> u64 {
+        LOCAL_HEADER_SIZE + self.name.len() as u64 + if self.zip64() { ZIP64_LOCAL_EXTRA_SIZE } else { 0 }
+    }
+
+    fn central_header_size(&self) -> u64 {
+        let fields = self.zip64_fields().len() as u64;
+        CENTRAL_HEADER_SIZE + self.name.len() as u64 + if fields > 0 { 4 + 8 * fields } else { 0 }
+    }
+
+    fn local_header(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(self.local_header_size() as usize);
+        header.extend(LOCAL_HEADER.to_le_bytes());
+        header.extend(self.version().to_le_bytes());
+        header.extend(FLAGS.to_le_bytes());
+        header.extend(0u16.to_le_bytes()); // stored
+        header.extend(self.modified.0.to_le_bytes());
+        header.extend(self.modified.1.to_le_bytes());
+        // CRC and sizes are in the data descriptor
+        header.extend(0u32.to_le_bytes());
+        let size: u32 = if self.zip64() { MAX_32 as u32 } else { 0 };
+        header.extend(size.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend((self.name.len() as u16).to_le_bytes());
+        let extra = if self.zip64() { ZIP64_LOCAL_EXTRA_SIZE as u16 } else { 0 };
+        header.extend(extra.to_le_bytes());
+        header.extend(self.name.as_bytes());
+
+        if self.zip64() {
+            header.extend(1u16.to_le_bytes());
+            header.extend(16u16.to_le_bytes());
+            header.extend([0u8; 16]);
+        }
+        header
+    }
+
+    fn data_descriptor(&self, crc: u32) -> Vec<u8> {
+        let mut descriptor = Vec::with_capacity(self.descriptor_size() as usize);
+        descriptor.extend(DATA_DESCRIPTOR.to_le_bytes());
+        descriptor.extend(crc.to_le_bytes());
+        if self.zip64() {
+            descriptor.extend(self.size.to_le_bytes());
+            descriptor.extend(self.size.to_le_bytes());
+        } else {
+            descriptor.extend((self.size as u32).to_le_bytes());
+            descriptor.extend((self.size as u32).to_le_bytes());
+        }
+        descriptor
+    }
+
+    fn central_header(&self, crc: u32, out: &mut Vec<u8>) {
+        let fields = self.zip64_fields();
+        let size = self.size.min(MAX_32) as u32;
+
+        out.extend(CENTRAL_HEADER.to_le_bytes());
+        out.extend(MADE_BY.to_le_bytes());
+        out.extend(self.version().to_le_bytes());
+        out.extend(FLAGS.to_le_bytes());
+        out.extend(0u16.to_le_bytes());
+        out.extend(self.modified.0.to_le_bytes());
+        out.extend(self.modified.1.to_le_bytes());
+        out.extend(crc.to_le_bytes());
+        out.extend(size.to_le_bytes());
+        out.extend(size.to_le_bytes());
+        out.extend((self.name.len() as u16).to_le_bytes());
+        let extra = if fields.is_empty() { 0 } else { 4 + 8 * fields.len() as u16 };
+        out.extend(extra.to_le_bytes());
+        out.extend([0u8; 6]); // comment length, disk, internal attributes
+        out.extend(FILE_ATTRIBUTES.to_le_bytes());
+        out.extend((self.offset.min(MAX_32) as u32).to_le_bytes());
+        out.extend(self.name.as_bytes());
+
+        if !fields.is_empty() {
+            out.extend(1u16.to_le_bytes());
+            out.extend((8 * fields.len() as u16).to_le_bytes());
+            for field in fields {
+                out.extend(field.to_le_bytes());
+            }
+        }
+    }
+
+    fn version(&self) -> u16 {
+        if self.zip64() || self.offset >= MAX_32 { VERSION_ZIP64 } else { VERSION }
+    }
+}
+
+// MS-DOS time and date of a modification time, in UTC
+fn dos_time(time: Option<SystemTime>) -> (u16, u16) {
+    let time: DateTime<Utc> = time.map_or_else(Utc::now, DateTime::from);
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    (
+        ((time.hour() << 11) | (time.minute() << 5) | (time.second() / 2)) as u16,
+        ((((time.year() - 1980).min(127) as u32) << 9) | (time.month() << 5) | time.day()) as u16,
+    )
+}
+
+// Files sent as one ZIP archive, written as it is sent so nothing is staged on disk.
+// Files are stored as they are, since video and audio don't compress, and their CRCs
+// are computed on the way. Every size is known up front, so the response has a
+// Content-Length and downloads show their progress.
+pub struct ZipArchive {
+    filename: String,
+    entries: Vec<Entry>,
+    // Where the central directory starts, after the last file
+    central_offset: u64,
+    size: u64,
+}
+
+impl ZipArchive {
+    // `files` are names in the archive, with `/` between folders, and the files to
+    // put there
+    pub async fn new(filename: String, files: Vec<(String, PathBuf)>) -> Result<Self> {
+        let mut entries = Vec::with_capacity(files.len());
+        let mut offset = 0;
+        for (name, path) in files {
+            let metadata = validate_file(&path).await?;
+            let entry = Entry {
+                name,
+                path,
+                size: metadata.len(),
+                modified: dos_time(metadata.modified().ok()),
+                offset,
+            };
+            offset += entry.local_header_size() + entry.size + entry.descriptor_size();
+            entries.push(entry);
+        }
+
+        let central_size: u64 = entries.iter().map(Entry::central_header_size).sum();
+        let mut archive = Self {
+            filename,
+            entries,
+            central_offset: offset,
+            size: 0,
+        };
+        archive.size = offset
+            + central_size
+            + if archive.zip64_end(central_size) { ZIP64_END_SIZE + ZIP64_LOCATOR_SIZE } else { 0 }
+            + END_SIZE;
+        Ok(archive)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    fn zip64_end(&self, central_size: u64) -> bool {
+        self.entries.len() as u64 >= MAX_16 || self.central_offset >= MAX_32 || central_size >= MAX_32
+    }
+
+    // Central directory and end records, once the CRC of every file is known
+    fn central_directory(&self, crcs: &[u32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (entry, crc) in self.entries.iter().zip(crcs) {
+            entry.central_header(*crc, &mut out);
+        }
+        let central_size = out.len() as u64;
+        let count = self.entries.len() as u64;
+
+        if self.zip64_end(central_size) {
+            let zip64_end_offset = self.central_offset + central_size;
+            out.extend(ZIP64_END.to_le_bytes());
+            out.extend((ZIP64_END_SIZE - 12).to_le_bytes());
+            out.extend(MADE_BY.to_le_bytes());
+            out.extend(VERSION_ZIP64.to_le_bytes());
+            out.extend([0u8; 8]); // this disk, disk with the central directory
+            out.extend(count.to_le_bytes());
+            out.extend(count.to_le_bytes());
+            out.extend(central_size.to_le_bytes());
+            out.extend(self.central_offset.to_le_bytes());
+
+            out.extend(ZIP64_LOCATOR.to_le_bytes());
+            out.extend(0u32.to_le_bytes());
+            out.extend(zip64_end_offset.to_le_bytes());
+            out.extend(1u32.to_le_bytes()); // disks
+        }
+
+        out.extend(END.to_le_bytes());
+        out.extend([0u8; 4]); // this disk, disk with the central directory
+        out.extend((count.min(MAX_16) as u16).to_le_bytes());
+        out.extend((count.min(MAX_16) as u16).to_le_bytes());
+        out.extend((central_size.min(MAX_32) as u32).to_le_bytes());
+        out.extend((self.central_offset.min(MAX_32) as u32).to_le_bytes());
+        out.extend(0u16.to_le_bytes()); // comment length
+        out
+    }
+
+    // The archive, one chunk at a time. A file that shrank since the archive was laid
+    // out ends the stream with an error rather than a corrupt archive.
+    fn into_stream(self) -> impl Stream<Item = std::io::Result<Bytes>> + Send {
+        async_stream::try_stream! {
+            let mut crcs = Vec::with_capacity(self.entries.len());
+            let mut buffer = vec![0u8; STREAM_CHUNK_SIZE];
+
+            for entry in &self.entries {
+                yield Bytes::from(entry.local_header());
+
+                let mut file = tokio::fs::File::open(&entry.path).await?.take(entry.size);
+                let mut crc = 0;
+                let mut remaining = entry.size;
+                loop {
+                    let read = file.read(&mut buffer).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    crc = crc32_update(crc, &buffer[..read]);
+                    remaining -= read as u64;
+                    yield Bytes::copy_from_slice(&buffer[..read]);
+                }
+                if remaining > 0 {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("{} changed while it was being archived", entry.path.display()),
+                    ))?;
+                }
+
+                yield Bytes::from(entry.data_descriptor(crc));
+                crcs.push(crc);
+            }
+
+            yield Bytes::from(self.central_directory(&crcs));
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ZipArchive {
+    fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .status(Status::Ok)
+            .header(ContentType::ZIP)
+            .header(attachment(&self.filename))
+            .header(Header::new("Cache-Control", conditional::CACHE_CONTROL_NONE))
+            .raw_header("Content-Length", self.size.to_string())
+            .streamed_body(throttle::pace(req, StreamReader::new(Box::pin(self.into_stream()))))
+            // Set after the body, replacing the body resets the chunk size
+            .max_chunk_size(STREAM_CHUNK_SIZE);
+
+        Ok(response.finalize())
+    }
+}
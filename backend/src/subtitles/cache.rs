@@ -0,0 +1,109 @@
+use rocket::http::ContentType;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::container::index::IndexCache;
+use crate::error::{AppError, Result};
+use crate::streaming::conditional::{self, Cached};
+use crate::streaming::file::validate_file;
+use crate::streaming::segment::derived_etag;
+use crate::subtitles::{embedded, webvtt};
+
+// Embedded text subtitle tracks converted to WebVTT, kept on disk. Extracting a track
+// means reading the whole container, so it is only done once per version of a file.
+pub struct SubtitleCache {
+    dir: PathBuf,
+}
+
+fn short_hash(value: &str) -> String {
+    Sha256::digest(value.as_bytes())[..12].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl SubtitleCache {
+    pub fn from_env() -> Self {
+        let dir = env::var("SUBTITLE_CACHE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir().join("mediarest-subtitles"));
+
+        Self { dir }
+    }
+
+    // Cached files are named <path hash>-<track>-<version hash>.vtt, so that the
+    // outdated copies of a track share a prefix
+    fn file_prefix(video: &Path, track: u32) -> String {
+        format!("{}-{}-", short_hash(&video.to_string_lossy()), track)
+    }
+
+    // WebVTT of an embedded text track, extracted now unless this version of the
+    // file was extracted before
+    pub async fn webvtt(&self, indexes: &IndexCache, video: &Path, track: u32) -> Result<Cached<(ContentType, String)>> {
+        let metadata = validate_file(video).await?;
+        let last_modified = metadata.modified().ok();
+        let file_etag = conditional::file_etag(metadata.len(), last_modified);
+        let etag = derived_etag(&file_etag, &format!("{}-vtt", track));
+        let document = self.document(indexes, video, track, &file_etag).await?;
+
+        Ok(Cached::new((ContentType::new("text", "vtt"), document), etag, last_modified)
+            .with_cache_control(conditional::CACHE_CONTROL_STREAM))
+    }
+
+    // The WebVTT document of a track, for the version of the file with the given ETag
+    pub async fn document(&self, indexes: &IndexCache, video: &Path, track: u32, file_etag: &str) -> Result<String> {
+        let prefix = Self::file_prefix(video, track);
+        let cached = self.dir.join(format!("{}{}.vtt", prefix, short_hash(file_etag)));
+
+        match tokio::fs::read_to_string(&cached).await {
+            Ok(document) => Ok(document),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let document = self.extract(indexes, video, track).await?;
+                self.store(&prefix, &cached, &document).await;
+                Ok(document)
+            }
+            Err(e) => Err(AppError::Io(e)),
+        }
+    }
+
+    async fn extract(&self, indexes: &IndexCache, video: &Path, track_number: u32) -> Result<String> {
+        let indexed = indexes.get(video).await?;
+        let track = indexed
+            .index
+            .track(track_number)
+            .filter(|track| embedded::is_text_track(track))
+            .ok_or_else(|| AppError::NotFound(format!("No text subtitle track {}", track_number)))?
+            .clone();
+
+        let source = indexed.path.clone();
+        let cues = tokio::task::spawn_blocking(move || embedded::read_cues(&source, &track))
+            .await
+            .map_err(|e| AppError::Server(format!("Subtitle extraction task failed: {}", e)))??;
+
+        tracing::info!("Extracted subtitle track {} of {}", track_number, video.display());
+        Ok(webvtt::render(&cues))
+    }
+
+    // Write a new copy and drop those of older versions of the file. The cache is only
+    // an optimization, so failing to write it is logged and otherwise ignored.
+    async fn store(&self, prefix: &str, cached: &Path, document: &str) {
+        let result = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+
+            let mut entries = tokio::fs::read_dir(&self.dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_name().to_string_lossy().starts_with(prefix) {
+                    tokio::fs::remove_file(entry.path()).await?;
+                }
+            }
+
+            // Written under a temporary name so a concurrent read never sees half a file
+            let partial = cached.with_extension("vtt.tmp");
+            tokio::fs::write(&partial, document).await?;
+            tokio::fs::rename(&partial, cached).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            tracing::warn!("Failed to cache subtitles in {}: {}", self.dir.display(), e);
+        }
+    }
+}
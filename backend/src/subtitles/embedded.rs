@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::container::index::{Track, TrackKind};
+use crate::subtitles::webvtt::{self, Cue};
+
+// Whether a subtitle codec is text we can turn into WebVTT. Bitmap subtitles (PGS,
+// VobSub) are not.
+pub fn is_text_codec(codec: &str) -> bool {
+    matches!(codec, "subrip" | "ass" | "webvtt" | "mov_text")
+}
+
+// Whether a parsed track is a text subtitle track with events to extract
+pub fn is_text_track(track: &Track) -> bool {
+    track.kind == TrackKind::Subtitle && is_text_codec(&track.codec) && !track.samples.is_empty()
+}
+
+// Turn the payload of one subtitle sample into WebVTT cue text
+fn sample_text(codec: &str, payload: &[u8]) -> String {
+    match codec {
+        // Matroska stores ASS events as
+        // ReadOrder, Layer, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+        "ass" => {
+            let line = String::from_utf8_lossy(payload);
+            let text = line.splitn(9, ',').nth(8).unwrap_or_default();
+            webvtt::from_ass_text(text)
+        }
+        // 3GPP timed text: 16-bit length, the text, then optional style boxes
+        "mov_text" => {
+            let length = payload
+                .get(..2)
+                .map_or(0, |length| u16::from_be_bytes([length[0], length[1]]) as usize);
+            let text = payload.get(2..2 + length).unwrap_or_default();
+            webvtt::from_subrip_text(&String::from_utf8_lossy(text))
+        }
+        "webvtt" => String::from_utf8_lossy(payload).into_owned(),
+        _ => webvtt::from_subrip_text(&String::from_utf8_lossy(payload)),
+    }
+}
+
+// Read every event of a text subtitle track from the source file. Blocking.
+pub fn read_cues(path: &Path, track: &Track) -> io::Result<Vec<Cue>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut cues = Vec::with_capacity(track.samples.len());
+    let mut payload = Vec::new();
+
+    for sample in &track.samples {
+        // Subtitle events are small, anything bigger is corrupt or not text
+        if sample.size > 64 * 1024 {
+            continue;
+        }
+
+        payload.resize(sample.size as usize, 0);
+        reader.seek(SeekFrom::Start(sample.offset))?;
+        reader.read_exact(&mut payload)?;
+
+        let start = track.seconds(sample.dts + sample.cts_offset as i64);
+        cues.push(Cue {
+            start,
+            end: start + track.seconds(sample.duration as i64),
+            text: sample_text(&track.codec, &payload),
+        });
+    }
+
+    Ok(cues)
+}
@@ -0,0 +1,6 @@
+pub mod cache;
+pub mod embedded;
+pub mod sidecar;
+pub mod text;
+pub mod upload;
+pub mod webvtt;
\ No newline at end of file
@@ -0,0 +1,225 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::subtitles::text::SubtitleFormat;
+
+// Folders next to a video that subtitle files are commonly shipped in
+const SUBTITLE_FOLDERS: [&str; 2] = ["subs", "subtitles"];
+
+// Language names and ISO 639-1/639-2 codes seen in subtitle file names, mapped to the
+// ISO 639-1 code a <track srclang> expects
+const LANGUAGES: [(&str, &[&str]); 30] = [
+    ("en", &["en", "eng", "english"]),
+    ("fr", &["fr", "fre", "fra", "french", "francais"]),
+    ("de", &["de", "ger", "deu", "german", "deutsch"]),
+    ("es", &["es", "spa", "spanish", "espanol"]),
+    ("it", &["it", "ita", "italian", "italiano"]),
+    ("pt", &["pt", "por", "portuguese", "pob", "ptbr"]),
+    ("nl", &["nl", "dut", "nld", "dutch"]),
+    ("sv", &["sv", "swe", "swedish"]),
+    ("no", &["no", "nor", "nob", "norwegian"]),
+    ("da", &["da", "dan", "danish"]),
+    ("fi", &["fi", "fin", "finnish"]),
+    ("pl", &["pl", "pol", "polish"]),
+    ("cs", &["cs", "cze", "ces", "czech"]),
+    ("sk", &["sk", "slo", "slk", "slovak"]),
+    ("hu", &["hu", "hun", "hungarian"]),
+    ("ro", &["ro", "rum", "ron", "romanian"]),
+    ("hr", &["hr", "hrv", "croatian"]),
+    ("sr", &["sr", "srp", "serbian"]),
+    ("bg", &["bg", "bul", "bulgarian"]),
+    ("ru", &["ru", "rus", "russian"]),
+    ("uk", &["uk", "ukr", "ukrainian"]),
+    ("el", &["el", "gre", "ell", "greek"]),
+    ("tr", &["tr", "tur", "turkish"]),
+    ("he", &["he", "heb", "hebrew"]),
+    ("ar", &["ar", "ara", "arabic"]),
+    ("hi", &["hi", "hin", "hindi"]),
+    ("th", &["th", "tha", "thai"]),
+    ("ja", &["ja", "jpn", "japanese"]),
+    ("ko", &["ko", "kor", "korean"]),
+    ("zh", &["zh", "chi", "zho", "chs", "cht", "chinese"]),
+];
+
+// A subtitle file belonging to a video, with what its name says about it
+#[derive(Debug, Clone)]
+pub struct Sidecar {
+    pub path: PathBuf,
+    pub format: SubtitleFormat,
+    pub language: Option<String>,
+    // Name tags that aren't flags or a language, e.g. "Commentary"
+    pub title: Option<String>,
+    pub forced: bool,
+    pub sdh: bool,
+    pub default: bool,
+}
+
+// ISO 639-1 code of a language tag such as "en", "eng", "English" or "pt-BR"
+pub fn language_code(tag: &str) -> Option<&'static str> {
+    let tag = tag.to_ascii_lowercase();
+    let primary = tag.split(['-', '_']).next().unwrap_or_default();
+
+    LANGUAGES
+        .iter()
+        .find(|(_, names)| names.contains(&tag.as_str()) || names.contains(&primary))
+        .map(|(code, _)| *code)
+}
+
+// Read the flags and language from the dot-separated tags of a name, e.g. the
+// "en.forced" of Movie.en.forced.srt
+fn parse_tags(sidecar: &mut Sidecar, tags: &str) {
+    let mut title = Vec::new();
+
+    for tag in tags.split('.').map(str::trim).filter(|tag| !tag.is_empty()) {
+        match tag.to_ascii_lowercase().as_str() {
+            "forced" | "foreign" => sidecar.forced = true,
+            "sdh" | "cc" | "hoh" => sidecar.sdh = true,
+            // "hi" is Hindi unless a language was already given
+            "hi" if sidecar.language.is_some() => sidecar.sdh = true,
+            "default" => sidecar.default = true,
+            _ => match language_code(tag) {
+                Some(code) if sidecar.language.is_none() => sidecar.language = Some(code.to_string()),
+                _ => title.push(tag),
+            },
+        }
+    }
+
+    if !title.is_empty() {
+        sidecar.title = Some(title.join(" "));
+    }
+}
+
+// MicroDVD files are text starting with "{frame}"; VobSub .sub files are MPEG data
+fn is_microdvd(path: &Path) -> bool {
+    let mut header = [0u8; 64];
+    let read = fs::File::open(path).and_then(|mut file| file.read(&mut header)).unwrap_or(0);
+    let text = String::from_utf8_lossy(&header[..read]);
+    text.trim_start_matches('\u{feff}').trim_start().starts_with('{')
+}
+
+fn sidecar(path: &Path, tags: &str) -> Option<Sidecar> {
+    let format = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .and_then(SubtitleFormat::from_extension)?;
+
+    if format == SubtitleFormat::MicroDvd && !is_microdvd(path) {
+        return None;
+    }
+
+    let mut sidecar = Sidecar {
+        path: path.to_path_buf(),
+        format,
+        language: None,
+        title: None,
+        forced: false,
+        sdh: false,
+        default: false,
+    };
+    parse_tags(&mut sidecar, tags);
+    Some(sidecar)
+}
+
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    files.sort();
+    files
+}
+
+// Tags of a subtitle named after the video ("Movie.en.srt" for "Movie.mkv"), None if
+// it is named after something else
+fn tags_after_stem<'a>(file_stem: &'a str, video_stem: &str) -> Option<&'a str> {
+    if file_stem.len() < video_stem.len() || !file_stem.is_char_boundary(video_stem.len()) {
+        return None;
+    }
+
+    let (name, rest) = file_stem.split_at(video_stem.len());
+    if !name.eq_ignore_ascii_case(video_stem) {
+        return None;
+    }
+
+    match rest {
+        "" => Some(""),
+        _ => rest.strip_prefix('.'),
+    }
+}
+
+// Tags of a file in a subtitle folder, where names like "2_English" are common
+fn folder_tags(file_stem: &str) -> String {
+    let name = file_stem.trim_start_matches(|c: char| c.is_ascii_digit());
+    let name = name.strip_prefix('_').unwrap_or(file_stem);
+    name.replace('_', ".")
+}
+
+// Find the subtitle files of a video: files named after it in its folder or in a
+// Subs/Subtitles folder, files in Subs/<video name>/, and any file in Subs/ when the
+// video is the only one in its folder. Blocking.
+pub fn find_sidecars(video: &Path, is_video: impl Fn(&Path) -> bool) -> io::Result<Vec<Sidecar>> {
+    let Some(dir) = video.parent() else {
+        return Ok(Vec::new());
+    };
+    let video_stem = video.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+    let stem_of = |path: &Path| path.file_stem().and_then(OsStr::to_str).unwrap_or_default().to_string();
+
+    let files = files_in(dir);
+    let only_video = files.iter().filter(|path| is_video(path)).count() == 1;
+    let mut sidecars = Vec::new();
+
+    // VobSub pairs are bitmaps we can't convert
+    let has_idx = |path: &Path| path.with_extension("idx").exists();
+
+    for path in &files {
+        if let Some(tags) = tags_after_stem(&stem_of(path), video_stem) {
+            if !has_idx(path) {
+                sidecars.extend(sidecar(path, tags));
+            }
+        }
+    }
+
+    let folders = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .is_some_and(|name| SUBTITLE_FOLDERS.contains(&name.to_ascii_lowercase().as_str()))
+        });
+
+    for folder in folders {
+        for path in files_in(&folder) {
+            let stem = stem_of(&path);
+            if has_idx(&path) {
+                continue;
+            }
+            if let Some(tags) = tags_after_stem(&stem, video_stem) {
+                sidecars.extend(sidecar(&path, tags));
+            } else if only_video {
+                sidecars.extend(sidecar(&path, &folder_tags(&stem)));
+            }
+        }
+
+        let episode_folder = folder.join(video_stem);
+        if episode_folder.is_dir() {
+            for path in files_in(&episode_folder) {
+                if !has_idx(&path) {
+                    sidecars.extend(sidecar(&path, &folder_tags(&stem_of(&path))));
+                }
+            }
+        }
+    }
+
+    Ok(sidecars)
+}
@@ -0,0 +1,302 @@
+use encoding_rs::{Encoding, BIG5, EUC_KR, GBK, SHIFT_JIS, UTF_16BE, UTF_16LE};
+
+use crate::subtitles::webvtt::{self, Cue};
+
+// MicroDVD times cues in frames; this is the rate assumed when neither the file nor
+// the video says otherwise
+const DEFAULT_FRAME_RATE: f64 = 23.976;
+
+// Text subtitle file formats we can convert to WebVTT. Names match the codec names
+// of embedded subtitle tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    SubRip,
+    Ass,
+    WebVtt,
+    MicroDvd,
+}
+
+impl SubtitleFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "srt" => Some(SubtitleFormat::SubRip),
+            "ass" | "ssa" => Some(SubtitleFormat::Ass),
+            "vtt" => Some(SubtitleFormat::WebVtt),
+            "sub" => Some(SubtitleFormat::MicroDvd),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [SubtitleFormat::SubRip, SubtitleFormat::Ass, SubtitleFormat::WebVtt, SubtitleFormat::MicroDvd]
+            .into_iter()
+            .find(|format| format.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubtitleFormat::SubRip => "subrip",
+            SubtitleFormat::Ass => "ass",
+            SubtitleFormat::WebVtt => "webvtt",
+            SubtitleFormat::MicroDvd => "microdvd",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SubtitleFormat::SubRip => "srt",
+            SubtitleFormat::Ass => "ass",
+            SubtitleFormat::WebVtt => "vtt",
+            SubtitleFormat::MicroDvd => "sub",
+        }
+    }
+}
+
+// Legacy code page usually used for subtitles in a language (ISO 639-1)
+fn legacy_encoding(language: &str) -> Option<&'static Encoding> {
+    let encoding = match language {
+        "ru" | "uk" | "bg" | "sr" => encoding_rs::WINDOWS_1251,
+        "pl" | "cs" | "sk" | "hu" | "ro" | "hr" => encoding_rs::WINDOWS_1250,
+        "el" => encoding_rs::WINDOWS_1253,
+        "tr" => encoding_rs::WINDOWS_1254,
+        "he" => encoding_rs::WINDOWS_1255,
+        "ar" => encoding_rs::WINDOWS_1256,
+        "th" => encoding_rs::WINDOWS_874,
+        "ja" => SHIFT_JIS,
+        "ko" => EUC_KR,
+        "zh" => GBK,
+        _ => return None,
+    };
+    Some(encoding)
+}
+
+// UTF-16 without a byte order mark shows up as a zero byte next to every ASCII character
+fn utf16_without_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+    let sample = &bytes[..bytes.len().min(512) & !1];
+    if sample.len() < 8 {
+        return None;
+    }
+
+    let zeros_at = |parity: usize| sample.iter().skip(parity).step_by(2).filter(|b| **b == 0).count();
+    let half = sample.len() / 2;
+    if zeros_at(1) * 2 > half {
+        Some(UTF_16LE)
+    } else if zeros_at(0) * 2 > half {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+// Best guess at the encoding of a subtitle file that isn't UTF-8
+fn guess_encoding(bytes: &[u8], language: Option<&str>) -> &'static Encoding {
+    if let Some(encoding) = utf16_without_bom(bytes) {
+        return encoding;
+    }
+    if let Some(encoding) = language.and_then(legacy_encoding) {
+        return encoding;
+    }
+
+    // CJK text is mostly double-byte characters; Western text with a few accents
+    // almost never decodes cleanly in these
+    let high = bytes.iter().filter(|b| **b >= 0x80).count();
+    if high * 5 > bytes.len() {
+        for encoding in [SHIFT_JIS, GBK, EUC_KR, BIG5] {
+            if encoding.decode_without_bom_handling_and_without_replacement(bytes).is_some() {
+                return encoding;
+            }
+        }
+    }
+
+    // Cyrillic text has no ASCII letters at all, accented Latin text has a few non-ASCII ones
+    let ascii_letters = bytes.iter().filter(|b| b.is_ascii_alphabetic()).count();
+    if high > ascii_letters {
+        encoding_rs::WINDOWS_1251
+    } else {
+        encoding_rs::WINDOWS_1252
+    }
+}
+
+// Decode a subtitle file: by its byte order mark, as UTF-8 when valid, or else in the
+// code page its language (or failing that, its content) suggests
+pub fn decode(bytes: &[u8], language: Option<&str>) -> String {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+        return encoding.decode_without_bom_handling(&bytes[bom_length..]).0.into_owned();
+    }
+
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+
+    let encoding = guess_encoding(bytes, language);
+    tracing::debug!("Decoding subtitles as {}", encoding.name());
+    encoding.decode_without_bom_handling(bytes).0.into_owned()
+}
+
+// Parse "01:02:03,456", "02:03.456" or ASS's "1:02:03.45" into seconds
+pub fn parse_timestamp(value: &str) -> Option<f64> {
+    let value = value.trim().replace(',', ".");
+    let mut parts = value.rsplit(':');
+    let seconds: f64 = parts.next()?.parse().ok()?;
+
+    let mut total = seconds;
+    let mut unit = 60.0;
+    for part in parts {
+        total += part.parse::<u64>().ok()? as f64 * unit;
+        unit *= 60.0;
+    }
+
+    (seconds.is_finite() && total >= 0.0).then_some(total)
+}
+
+// "00:00:01,000 --> 00:00:02,500", with anything after the end time (WebVTT cue
+// settings, SubRip coordinates) ignored
+fn parse_timing(line: &str) -> Option<(f64, f64)> {
+    let (start, rest) = line.split_once("-->")?;
+    let end = rest.split_whitespace().next()?;
+    Some((parse_timestamp(start)?, parse_timestamp(end)?))
+}
+
+// Cue text lines, without the blank lines and counter that separate it from the next cue
+fn finish_cue(start: f64, end: f64, mut lines: Vec<&str>) -> Cue {
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    let counter = lines.len() >= 2
+        && lines[lines.len() - 1].trim().chars().all(|c| c.is_ascii_digit())
+        && lines[lines.len() - 2].trim().is_empty();
+    if counter {
+        lines.pop();
+    }
+
+    Cue {
+        start,
+        end,
+        text: webvtt::from_subrip_text(lines.join("\n").trim()),
+    }
+}
+
+// SubRip. Cues are found by their timing lines, so missing counters or blank lines
+// between cues don't matter.
+pub fn parse_subrip(text: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut current: Option<(f64, f64)> = None;
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        if let Some(timing) = parse_timing(line) {
+            if let Some((start, end)) = current.replace(timing) {
+                cues.push(finish_cue(start, end, std::mem::take(&mut lines)));
+            }
+        } else if current.is_some() {
+            lines.push(line);
+        }
+    }
+
+    if let Some((start, end)) = current {
+        cues.push(finish_cue(start, end, lines));
+    }
+
+    cues
+}
+
+// ASS/SSA. Dialogue lines are laid out by the Format line of the [Events] section.
+pub fn parse_ass(text: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut in_events = false;
+    let mut format: Vec<String> = ["layer", "start", "end", "style", "name", "marginl", "marginr", "marginv", "effect", "text"]
+        .iter()
+        .map(|field| field.to_string())
+        .collect();
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+        if !in_events {
+            continue;
+        }
+
+        if let Some(fields) = line.strip_prefix("Format:") {
+            format = fields.split(',').map(|field| field.trim().to_ascii_lowercase()).collect();
+        } else if let Some(fields) = line.strip_prefix("Dialogue:") {
+            let values: Vec<&str> = fields.splitn(format.len(), ',').collect();
+            let field = |name: &str| {
+                format
+                    .iter()
+                    .position(|field| field == name)
+                    .and_then(|index| values.get(index).copied())
+            };
+
+            let (Some(start), Some(end), Some(text)) = (
+                field("start").and_then(parse_timestamp),
+                field("end").and_then(parse_timestamp),
+                field("text"),
+            ) else {
+                continue;
+            };
+            cues.push(Cue {
+                start,
+                end,
+                text: webvtt::from_ass_text(text),
+            });
+        }
+    }
+
+    // Events are listed by layer and style, not necessarily by time
+    cues.sort_by(|a, b| a.start.total_cmp(&b.start));
+    cues
+}
+
+// MicroDVD: "{start frame}{end frame}text", lines separated by "|". A first cue of
+// "{1}{1}25.000" gives the frame rate.
+pub fn parse_microdvd(text: &str, frame_rate: Option<f64>) -> Vec<Cue> {
+    let mut frame_rate = frame_rate.filter(|rate| *rate > 0.0).unwrap_or(DEFAULT_FRAME_RATE);
+    let mut cues = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let Some((start, rest)) = line.trim().strip_prefix('{').and_then(|line| line.split_once('}')) else {
+            continue;
+        };
+        let Some((end, text)) = rest.strip_prefix('{').and_then(|rest| rest.split_once('}')) else {
+            continue;
+        };
+        let (Ok(start), Ok(end)) = (start.parse::<u64>(), end.parse::<u64>()) else {
+            continue;
+        };
+
+        if number == 0 && start <= 1 && end <= 1 {
+            if let Ok(rate) = text.trim().parse::<f64>() {
+                if rate > 0.0 {
+                    frame_rate = rate;
+                }
+                continue;
+            }
+        }
+
+        cues.push(Cue {
+            start: start as f64 / frame_rate,
+            end: end as f64 / frame_rate,
+            // Formatting codes like {y:i} are dropped along with other braces
+            text: webvtt::from_ass_text(&text.replace('|', "\\N")),
+        });
+    }
+
+    cues
+}
+
+// Convert the contents of a subtitle file to a WebVTT document. WebVTT files are only
+// decoded, so that their styling and positioning survive.
+pub fn to_webvtt(bytes: &[u8], format: SubtitleFormat, language: Option<&str>, frame_rate: Option<f64>) -> String {
+    let text = decode(bytes, language);
+
+    let cues = match format {
+        SubtitleFormat::WebVtt if text.trim_start().starts_with("WEBVTT") => return text,
+        SubtitleFormat::WebVtt | SubtitleFormat::SubRip => parse_subrip(&text),
+        SubtitleFormat::Ass => parse_ass(&text),
+        SubtitleFormat::MicroDvd => parse_microdvd(&text, frame_rate),
+    };
+
+    webvtt::render(&cues)
+}
@@ -0,0 +1,68 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::error::{AppError, Result};
+use crate::subtitles::text::{self, SubtitleFormat};
+
+// Subtitle files are small; anything bigger than this isn't one
+pub const MAX_UPLOAD_SIZE: u64 = 5 * 1024 * 1024;
+
+// Folder that uploaded subtitle files are kept in, outside of every library so that
+// scans never see or remove them
+pub struct SubtitleUploads {
+    dir: PathBuf,
+}
+
+impl SubtitleUploads {
+    pub fn from_env() -> Self {
+        let dir = env::var("SUBTITLE_UPLOAD_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("uploads/subtitles"));
+
+        Self { dir }
+    }
+
+    // Check that an upload is a subtitle file in the format its name says, i.e. that
+    // it has cues once converted
+    pub fn validate(bytes: &[u8], format: SubtitleFormat, language: Option<&str>) -> Result<()> {
+        if bytes.is_empty() {
+            return Err(AppError::InvalidInput("The subtitle file is empty".to_string()));
+        }
+
+        let document = text::to_webvtt(bytes, format, language, None);
+        if !document.lines().any(|line| line.contains("-->")) {
+            return Err(AppError::InvalidInput(format!(
+                "The file has no {} subtitles in it",
+                format.as_str()
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Store an uploaded file under the id of its subtitle row
+    pub async fn save(&self, id: &str, format: SubtitleFormat, bytes: &[u8]) -> Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(format!("{}.{}", id, format.extension()));
+        tokio::fs::write(&path, bytes).await?;
+
+        // Stored as an absolute path like the library files, so the working directory
+        // of later runs doesn't matter
+        Ok(tokio::fs::canonicalize(&path).await?)
+    }
+
+    // Remove an uploaded file; one that is already gone is not an error. Files outside
+    // the upload folder are never touched.
+    pub async fn remove(&self, path: &Path) -> Result<()> {
+        let dir = tokio::fs::canonicalize(&self.dir).await.unwrap_or_else(|_| self.dir.clone());
+        if !path.starts_with(&dir) {
+            tracing::warn!("Not removing {}, which is not an uploaded file", path.display());
+            return Ok(());
+        }
+
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(AppError::Io(e)),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use crate::subtitles::text::parse_timestamp;
+
+// A timed subtitle cue, times in seconds
+#[derive(Debug, Clone)]
+pub struct Cue {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+// Format seconds as a WebVTT timestamp, e.g. "01:02:03.456"
+pub fn format_timestamp(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+// Render cues as a WebVTT document
+pub fn render(cues: &[Cue]) -> String {
+    let mut document = String::from("WEBVTT\n\n");
+
+    for cue in cues {
+        // A blank line would end the cue early and "-->" would start a new one
+        let text: Vec<&str> = cue.text.lines().filter(|line| !line.trim().is_empty()).collect();
+        if text.is_empty() {
+            continue;
+        }
+
+        let _ = writeln!(
+            document,
+            "{} --> {}",
+            format_timestamp(cue.start),
+            format_timestamp(cue.end.max(cue.start))
+        );
+        let _ = writeln!(document, "{}\n", text.join("\n").replace("-->", "->"));
+    }
+
+    document
+}
+
+// Move every cue of a WebVTT document by some seconds. Only timing lines change, so
+// styling and cue settings are kept; cues moved before the start are clamped to it.
+pub fn shift(document: &str, seconds: f64) -> String {
+    if seconds == 0.0 {
+        return document.to_string();
+    }
+
+    let mut shifted = String::with_capacity(document.len());
+    for line in document.lines() {
+        let timing = line.split_once("-->").and_then(|(start, rest)| {
+            let rest = rest.trim_start();
+            let (end, settings) = rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()));
+            Some((parse_timestamp(start)?, parse_timestamp(end)?, settings))
+        });
+
+        match timing {
+            Some((start, end, settings)) => {
+                let _ = writeln!(
+                    shifted,
+                    "{} --> {}{}",
+                    format_timestamp(start + seconds),
+                    format_timestamp(end + seconds),
+                    settings
+                );
+            }
+            None => {
+                shifted.push_str(line);
+                shifted.push('\n');
+            }
+        }
+    }
+
+    shifted
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// Convert SubRip markup to WebVTT. Italic, bold and underline tags are kept since
+// WebVTT has the same ones; anything else (usually <font>) is dropped.
+pub fn from_subrip_text(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(open) = rest.find('<') {
+        output.push_str(&escape(&rest[..open]));
+        let Some(close) = rest[open..].find('>') else {
+            output.push_str(&escape(&rest[open..]));
+            rest = "";
+            break;
+        };
+
+        let tag = rest[open + 1..open + close].trim().to_ascii_lowercase();
+        if matches!(tag.as_str(), "i" | "b" | "u" | "/i" | "/b" | "/u") {
+            let _ = write!(output, "<{}>", tag);
+        }
+        rest = &rest[open + close + 1..];
+    }
+
+    output.push_str(&escape(rest));
+    output
+}
+
+// Convert the text field of an ASS/SSA dialogue line to WebVTT. Override blocks such
+// as {\an8} or {\i1} are removed, hard line breaks become new lines.
+pub fn from_ass_text(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut in_override = false;
+
+    for c in text.chars() {
+        match c {
+            '{' => in_override = true,
+            '}' if in_override => in_override = false,
+            _ if in_override => {}
+            _ => output.push(c),
+        }
+    }
+
+    let output = output.replace("\\N", "\n").replace("\\n", "\n").replace("\\h", " ");
+    escape(&output)
+}
@@ -0,0 +1,2 @@
+pub mod profile;
+pub mod session;
\ No newline at end of file
@@ -0,0 +1,73 @@
+use crate::error::{AppError, Result};
+
+// Output formats the transcoder can produce. Video profiles are H.264 + stereo AAC
+// capped at a height, which every browser and TV can play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Video1080p,
+    Video720p,
+    AudioAac,
+}
+
+impl Profile {
+    pub const ALL: [Profile; 3] = [Profile::Video1080p, Profile::Video720p, Profile::AudioAac];
+
+    // Parse the value of a `?profile=` parameter
+    pub fn from_name(name: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|profile| profile.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(|profile| profile.name()).collect();
+                AppError::InvalidInput(format!(
+                    "Unknown transcoding profile '{}', expected one of: {}",
+                    name,
+                    names.join(", ")
+                ))
+            })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::Video1080p => "1080p",
+            Profile::Video720p => "720p",
+            Profile::AudioAac => "audio",
+        }
+    }
+
+    // Most bits per second the output takes, video and audio
+    pub fn bitrate(&self) -> i64 {
+        match self {
+            Profile::Video1080p => 8_000_000 + 192_000,
+            Profile::Video720p => 4_000_000 + 192_000,
+            Profile::AudioAac => 192_000,
+        }
+    }
+
+    pub fn has_video(&self) -> bool {
+        !matches!(self, Profile::AudioAac)
+    }
+
+    // ffmpeg stream selection and encoder arguments
+    pub fn args(&self) -> Vec<String> {
+        let mut args: Vec<&str> = Vec::new();
+
+        match self {
+            Profile::Video1080p | Profile::Video720p => {
+                let (scale, maxrate, bufsize) = match self {
+                    Profile::Video1080p => ("scale=-2:'min(1080,ih)'", "8M", "16M"),
+                    _ => ("scale=-2:'min(720,ih)'", "4M", "8M"),
+                };
+                args.extend([
+                    "-map", "0:v:0", "-map", "0:a:0?",
+                    "-c:v", "libx264", "-preset", "veryfast", "-profile:v", "high", "-pix_fmt", "yuv420p",
+                    "-crf", "21", "-maxrate", maxrate, "-bufsize", bufsize, "-vf", scale,
+                ]);
+            }
+            Profile::AudioAac => args.extend(["-map", "0:a:0", "-vn"]),
+        }
+
+        args.extend(["-c:a", "aac", "-b:a", "192k", "-ac", "2", "-sn", "-dn"]);
+        args.into_iter().map(String::from).collect()
+    }
+}
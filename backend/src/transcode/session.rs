@@ -0,0 +1,486 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::FromParam;
+use rocket::{Orbit, Rocket};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use uuid::Uuid;
+
+use crate::db;
+use crate::error::{AppError, Result};
+use crate::transcode::profile::Profile;
+
+// Length of the segments ffmpeg cuts. Video keyframes are forced onto this grid so
+// that a restarted encoder produces segments with the same boundaries.
+pub const SEGMENT_SECONDS: f64 = 6.0;
+
+// Requests this many segments past the encoder's progress restart it at the requested
+// segment instead of waiting for it to get there
+const SEEK_THRESHOLD: usize = 4;
+
+// Segments this far behind the last requested one are deleted
+const KEEP_BEHIND: usize = 10;
+
+// Sessions nobody fetched a segment from in this long are stopped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const SEGMENT_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+// Lines of ffmpeg's stderr kept for error messages
+const LOG_LINES: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct TranscodeConfig {
+    pub ffmpeg: PathBuf,
+    // Directory holding one subdirectory of segments per session
+    pub dir: PathBuf,
+    pub max_sessions: usize,
+}
+
+impl TranscodeConfig {
+    // FFMPEG_PATH, TRANSCODE_DIR and MAX_TRANSCODES, with defaults suitable for a
+    // single machine
+    pub fn from_env() -> Self {
+        let ffmpeg = env::var("FFMPEG_PATH").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("ffmpeg"));
+        let dir = env::var("TRANSCODE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir().join("mediarest-transcodes"));
+        let max_sessions = env::var("MAX_TRANSCODES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value| value > 0)
+            .unwrap_or(2);
+
+        Self { ffmpeg, dir, max_sessions }
+    }
+}
+
+// File name of transcoded segment `n`
+pub struct SegmentFile(pub usize);
+
+impl<'a> FromParam<'a> for SegmentFile {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> std::result::Result<Self, Self::Error> {
+        param
+            .strip_suffix(".ts")
+            .and_then(|number| number.parse().ok())
+            .map(SegmentFile)
+            .ok_or(param)
+    }
+}
+
+fn spawn_error(ffmpeg: &Path, e: std::io::Error) -> AppError {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        AppError::Server(format!(
+            "ffmpeg not found at '{}', set FFMPEG_PATH to enable transcoding",
+            ffmpeg.display()
+        ))
+    } else {
+        AppError::Server(format!("Failed to run ffmpeg: {}", e))
+    }
+}
+
+// Duration from the "Duration: 01:02:03.45, start: ..." line ffmpeg prints for an input
+fn parse_duration(output: &str) -> Option<f64> {
+    let value = output.split("Duration: ").nth(1)?.split(',').next()?.trim();
+    let mut seconds = 0.0;
+    for part in value.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+
+    (seconds > 0.0).then_some(seconds)
+}
+
+pub async fn probe_duration(ffmpeg: &Path, path: &Path) -> Result<f64> {
+    // Without an output ffmpeg only prints the input description and exits
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", "-nostdin", "-i"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| spawn_error(ffmpeg, e))?;
+
+    parse_duration(&String::from_utf8_lossy(&output.stderr))
+        .ok_or_else(|| AppError::InvalidInput(format!("Could not determine the duration of {}", path.display())))
+}
+
+// A running ffmpeg process producing segments from `start` onwards
+struct Encoder {
+    child: Child,
+    // Earliest segment of this run still on disk
+    start: usize,
+    // First segment of this run that isn't finished yet
+    frontier: usize,
+    log: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Encoder {
+    fn log_tail(&self) -> String {
+        let log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        log.iter().cloned().collect::<Vec<_>>().join("\n")
+    }
+}
+
+pub struct Session {
+    pub id: String,
+    pub path: PathBuf,
+    pub profile: Profile,
+    pub duration: f64,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    dir: PathBuf,
+    ffmpeg: PathBuf,
+    encoder: tokio::sync::Mutex<Option<Encoder>>,
+    last_access: Mutex<Instant>,
+}
+
+impl Session {
+    pub fn segment_count(&self) -> usize {
+        ((self.duration / SEGMENT_SECONDS).ceil() as usize).max(1)
+    }
+
+    fn segment_duration(&self, segment: usize) -> f64 {
+        (self.duration - segment as f64 * SEGMENT_SECONDS).clamp(0.0, SEGMENT_SECONDS)
+    }
+
+    fn segment_path(&self, segment: usize) -> PathBuf {
+        self.dir.join(format!("{}.ts", segment))
+    }
+
+    fn touch(&self) {
+        *self.last_access.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_access.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+    }
+
+    // VOD playlist of every segment of the session. Segments are listed up front so
+    // players can seek anywhere; `base` is the URL of the session's segment route and
+    // `query` is appended to every segment URI.
+    pub fn playlist(&self, base: &str, query: &str) -> String {
+        let mut playlist = String::new();
+        let _ = writeln!(playlist, "#EXTM3U");
+        let _ = writeln!(playlist, "#EXT-X-VERSION:3");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", SEGMENT_SECONDS.ceil() as u64);
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:0");
+        let _ = writeln!(playlist, "#EXT-X-PLAYLIST-TYPE:VOD");
+
+        for segment in 0..self.segment_count() {
+            let _ = writeln!(playlist, "#EXTINF:{:.6},", self.segment_duration(segment));
+            let _ = writeln!(playlist, "{}/{}/{}.ts{}", base, self.id, segment, query);
+        }
+
+        let _ = writeln!(playlist, "#EXT-X-ENDLIST");
+        playlist
+    }
+
+    // Path of a finished segment, waiting for the encoder to produce it. Requests
+    // behind or well ahead of the encoder restart it at the requested segment.
+    pub async fn segment(&self, segment: usize) -> Result<PathBuf> {
+        if segment >= self.segment_count() {
+            return Err(AppError::NotFound(format!("Segment not found: {}", segment)));
+        }
+        self.touch();
+
+        let deadline = Instant::now() + SEGMENT_TIMEOUT;
+        loop {
+            {
+                let mut encoder = self.encoder.lock().await;
+                let path = self.segment_path(segment);
+
+                if let Some(running) = encoder.as_mut() {
+                    while self.segment_path(running.frontier).exists() {
+                        running.frontier += 1;
+                    }
+
+                    if segment >= running.start && path.exists() {
+                        self.prune(running, segment).await;
+                        return Ok(path);
+                    }
+                }
+
+                let restart = match encoder.as_mut() {
+                    None => true,
+                    Some(running) if segment < running.start => true,
+                    Some(running) if segment > running.frontier + SEEK_THRESHOLD => true,
+                    Some(running) => match running.child.try_wait()? {
+                        None => false,
+                        Some(status) if status.success() => {
+                            return Err(AppError::NotFound(format!("Segment not found: {}", segment)));
+                        }
+                        Some(status) => {
+                            let message = format!("ffmpeg exited with {}: {}", status, running.log_tail());
+                            *encoder = None;
+                            return Err(AppError::Server(message));
+                        }
+                    },
+                };
+
+                if restart {
+                    if let Some(mut previous) = encoder.take() {
+                        let _ = previous.child.kill().await;
+                    }
+                    self.remove_segments().await;
+                    *encoder = Some(self.spawn(segment).await?);
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(AppError::Server(format!("Timed out waiting for segment {}", segment)));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    // Delete segments the player has moved well past
+    async fn prune(&self, encoder: &mut Encoder, requested: usize) {
+        let keep_from = requested.saturating_sub(KEEP_BEHIND);
+        while encoder.start < keep_from {
+            let _ = tokio::fs::remove_file(self.segment_path(encoder.start)).await;
+            encoder.start += 1;
+        }
+    }
+
+    async fn remove_segments(&self) {
+        let Ok(mut entries) = tokio::fs::read_dir(&self.dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let _ = tokio::fs::remove_file(entry.path()).await;
+        }
+    }
+
+    async fn spawn(&self, segment: usize) -> Result<Encoder> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let start = segment as f64 * SEGMENT_SECONDS;
+        let mut command = Command::new(&self.ffmpeg);
+        command
+            .args(["-hide_banner", "-loglevel", "error", "-nostdin"])
+            .arg("-ss")
+            .arg(format!("{:.3}", start))
+            .arg("-i")
+            .arg(&self.path)
+            // Keep the source timestamps so segments of a restarted encoder line up
+            // with the ones listed in the playlist
+            .args(["-copyts", "-start_at_zero"])
+            .args(self.profile.args());
+
+        if self.profile.has_video() {
+            command
+                .arg("-force_key_frames")
+                .arg(format!("expr:gte(t,{:.3}+n_forced*{})", start, SEGMENT_SECONDS));
+        }
+
+        command
+            .args(["-f", "hls", "-hls_time"])
+            .arg(SEGMENT_SECONDS.to_string())
+            .args(["-hls_list_size", "0", "-hls_segment_type", "mpegts", "-hls_flags", "temp_file"])
+            .arg("-start_number")
+            .arg(segment.to_string())
+            .arg("-hls_segment_filename")
+            .arg(self.dir.join("%d.ts"))
+            .arg(self.dir.join("ffmpeg.m3u8"))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let mut child = command.spawn().map_err(|e| spawn_error(&self.ffmpeg, e))?;
+        tracing::info!("Transcoding {} ({}) from {:.0}s", self.path.display(), self.profile.name(), start);
+
+        let log = Arc::new(Mutex::new(VecDeque::new()));
+        if let Some(stderr) = child.stderr.take() {
+            let log = log.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let mut log = log.lock().unwrap_or_else(|e| e.into_inner());
+                    if log.len() == LOG_LINES {
+                        log.pop_front();
+                    }
+                    log.push_back(line);
+                }
+            });
+        }
+
+        Ok(Encoder {
+            child,
+            start: segment,
+            frontier: segment,
+            log,
+        })
+    }
+
+    // Stop the encoder and delete the session's segments
+    async fn shutdown(&self) {
+        if let Some(mut encoder) = self.encoder.lock().await.take() {
+            let _ = encoder.child.kill().await;
+        }
+        if let Err(e) = tokio::fs::remove_dir_all(&self.dir).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove {}: {}", self.dir.display(), e);
+            }
+        }
+    }
+}
+
+// Transcoding sessions by id, capped at the configured number of concurrent sessions
+#[derive(Clone)]
+pub struct TranscodeManager {
+    config: Arc<TranscodeConfig>,
+    sessions: Arc<Mutex<HashMap<String, Arc<Session>>>>,
+}
+
+impl TranscodeManager {
+    pub fn new(config: TranscodeConfig) -> Self {
+        remove_stale_sessions(&config.dir);
+
+        Self {
+            config: Arc::new(config),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<Session>>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn slots_error(&self) -> AppError {
+        AppError::Unavailable(format!(
+            "All {} transcoding slots are in use, try again later",
+            self.config.max_sessions
+        ))
+    }
+
+    // Create a session for a file. The encoder starts with the first segment request.
+    pub async fn start(&self, path: PathBuf, profile: Profile) -> Result<Arc<Session>> {
+        self.reap_idle().await;
+        if self.sessions().len() >= self.config.max_sessions {
+            return Err(self.slots_error());
+        }
+
+        let duration = probe_duration(&self.config.ffmpeg, &path).await?;
+        let id = db::generate_id();
+        let session = Arc::new(Session {
+            dir: self.config.dir.join(&id),
+            id: id.clone(),
+            path,
+            profile,
+            duration,
+            created_at: chrono::Utc::now(),
+            ffmpeg: self.config.ffmpeg.clone(),
+            encoder: tokio::sync::Mutex::new(None),
+            last_access: Mutex::new(Instant::now()),
+        });
+
+        // Another request may have taken the last slot while probing
+        let mut sessions = self.sessions();
+        if sessions.len() >= self.config.max_sessions {
+            return Err(self.slots_error());
+        }
+        sessions.insert(id, session.clone());
+
+        Ok(session)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Arc<Session>> {
+        self.sessions()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| AppError::NotFound(format!("Transcoding session not found: {}", id)))
+    }
+
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        let mut sessions: Vec<Arc<Session>> = self.sessions().values().cloned().collect();
+        sessions.sort_by_key(|session| session.created_at);
+        sessions
+    }
+
+    pub async fn stop(&self, id: &str) -> Result<()> {
+        let session = self
+            .sessions()
+            .remove(id)
+            .ok_or_else(|| AppError::NotFound(format!("Transcoding session not found: {}", id)))?;
+        session.shutdown().await;
+        Ok(())
+    }
+
+    async fn reap_idle(&self) {
+        let idle: Vec<Arc<Session>> = {
+            let mut sessions = self.sessions();
+            let ids: Vec<String> = sessions
+                .values()
+                .filter(|session| session.idle_for() > IDLE_TIMEOUT)
+                .map(|session| session.id.clone())
+                .collect();
+            ids.iter().filter_map(|id| sessions.remove(id)).collect()
+        };
+
+        for session in idle {
+            tracing::info!("Stopping idle transcoding session {}", session.id);
+            session.shutdown().await;
+        }
+    }
+
+    async fn stop_all(&self) {
+        let sessions: Vec<Arc<Session>> = self.sessions().drain().map(|(_, session)| session).collect();
+        for session in sessions {
+            session.shutdown().await;
+        }
+    }
+}
+
+// Segment directories left behind by a previous run that didn't shut down cleanly
+fn remove_stale_sessions(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let is_session = entry.file_name().to_str().is_some_and(|name| Uuid::parse_str(name).is_ok());
+        if is_session {
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+// Stops idle sessions in the background and every session on shutdown
+pub struct TranscodeReaper;
+
+#[rocket::async_trait]
+impl Fairing for TranscodeReaper {
+    fn info(&self) -> Info {
+        Info {
+            name: "Transcode session reaper",
+            kind: Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(manager) = rocket.state::<TranscodeManager>().cloned() {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(REAP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    manager.reap_idle().await;
+                }
+            });
+        }
+    }
+
+    async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+        if let Some(manager) = rocket.state::<TranscodeManager>() {
+            manager.stop_all().await;
+        }
+    }
+}
@@ -0,0 +1,34 @@
+// Roku's Base Index Frames format: a 64 byte header, an index of where every image
+// starts, then the JPEG images one after another
+const MAGIC: [u8; 8] = [0x89, b'B', b'I', b'F', 0x0d, 0x0a, 0x1a, 0x0a];
+const HEADER_SIZE: usize = 64;
+const END_OF_INDEX: u32 = 0xffff_ffff;
+
+// BIF file of images taken every `interval_ms` milliseconds from the start
+pub fn write(images: &[Vec<u8>], interval_ms: u32) -> Vec<u8> {
+    let index_size = (images.len() + 1) * 8;
+    let data_size: usize = images.iter().map(Vec::len).sum();
+    let mut bif = Vec::with_capacity(HEADER_SIZE + index_size + data_size);
+
+    bif.extend_from_slice(&MAGIC);
+    bif.extend_from_slice(&0u32.to_le_bytes()); // version
+    bif.extend_from_slice(&(images.len() as u32).to_le_bytes());
+    // Index timestamps count in units of this many milliseconds
+    bif.extend_from_slice(&interval_ms.to_le_bytes());
+    bif.resize(HEADER_SIZE, 0);
+
+    let mut offset = (HEADER_SIZE + index_size) as u32;
+    for (number, image) in images.iter().enumerate() {
+        bif.extend_from_slice(&(number as u32).to_le_bytes());
+        bif.extend_from_slice(&offset.to_le_bytes());
+        offset += image.len() as u32;
+    }
+    bif.extend_from_slice(&END_OF_INDEX.to_le_bytes());
+    bif.extend_from_slice(&offset.to_le_bytes());
+
+    for image in images {
+        bif.extend_from_slice(image);
+    }
+
+    bif
+}
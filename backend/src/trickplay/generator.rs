@@ -0,0 +1,549 @@
+use chrono::Utc;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::request::FromParam;
+use rocket::{Orbit, Rocket};
+use sqlx::{Pool, Sqlite};
+use std::collections::VecDeque;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+use tokio::sync::Notify;
+
+use crate::db::generate_id;
+use crate::db::models::{Library, Trickplay};
+use crate::db::queries;
+use crate::error::{AppError, Result};
+use crate::media::missing;
+use crate::media::sandbox::PathSandbox;
+use crate::streaming::conditional::{self, Cached};
+use crate::streaming::file::validate_file;
+use crate::streaming::segment::derived_etag;
+use crate::subtitles::webvtt;
+use crate::transcode::session::probe_duration;
+use crate::trickplay::bif;
+
+pub const STATUS_QUEUED: &str = "queued";
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_READY: &str = "ready";
+pub const STATUS_FAILED: &str = "failed";
+
+// Thumbnails per sprite sheet, row by row
+const COLUMNS: u32 = 10;
+const ROWS: u32 = 10;
+
+// JPEG quality on ffmpeg's 2 (best) to 31 scale
+const QUALITY: &str = "5";
+
+#[derive(Debug, Clone)]
+pub struct TrickplayConfig {
+    pub ffmpeg: PathBuf,
+    // Directory holding one subdirectory of sprites per movie or episode
+    pub dir: PathBuf,
+    // Seconds between thumbnails
+    pub interval: f64,
+    // Width of a thumbnail, the height follows the video's aspect ratio
+    pub width: u32,
+    // Also write a Roku BIF file
+    pub bif: bool,
+}
+
+impl TrickplayConfig {
+    // FFMPEG_PATH, TRICKPLAY_DIR, TRICKPLAY_INTERVAL, TRICKPLAY_WIDTH and TRICKPLAY_BIF
+    pub fn from_env() -> Self {
+        let ffmpeg = env::var("FFMPEG_PATH").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("ffmpeg"));
+        let dir = env::var("TRICKPLAY_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("cache/trickplay"));
+        let interval = env::var("TRICKPLAY_INTERVAL")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value: &f64| value >= 1.0)
+            .unwrap_or(10.0);
+        let width = env::var("TRICKPLAY_WIDTH")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value| (64..=1280).contains(&value))
+            .unwrap_or(320);
+        let bif = env::var("TRICKPLAY_BIF").is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+
+        Self { ffmpeg, dir, interval, width, bif }
+    }
+}
+
+// File name of sprite sheet `n`, counting from 1
+pub struct SpriteFile(pub u32);
+
+impl<'a> FromParam<'a> for SpriteFile {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> std::result::Result<Self, Self::Error> {
+        param
+            .strip_prefix("sprite-")
+            .and_then(|rest| rest.strip_suffix(".jpg"))
+            .and_then(|number| number.parse().ok())
+            .filter(|&number| number > 0)
+            .map(SpriteFile)
+            .ok_or(param)
+    }
+}
+
+// A movie or episode waiting for its thumbnails
+#[derive(Debug, Clone)]
+struct Job {
+    media_id: String,
+    episode_id: Option<String>,
+    path: PathBuf,
+}
+
+// Makes seek preview thumbnails in the background, one video at a time. What was made
+// from which version of a file is kept in the trickplay table, so rescans and restarts
+// only redo videos that changed.
+#[derive(Clone)]
+pub struct TrickplayGenerator {
+    config: Arc<TrickplayConfig>,
+    queue: Arc<Mutex<VecDeque<Job>>>,
+    wake: Arc<Notify>,
+}
+
+// Directory name of a movie's or episode's thumbnails
+fn item_key<'a>(media_id: &'a str, episode_id: Option<&'a str>) -> &'a str {
+    episode_id.unwrap_or(media_id)
+}
+
+fn spawn_error(ffmpeg: &Path, e: std::io::Error) -> AppError {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        AppError::Server(format!(
+            "ffmpeg not found at '{}', set FFMPEG_PATH to enable seek previews",
+            ffmpeg.display()
+        ))
+    } else {
+        AppError::Server(format!("Failed to run ffmpeg: {}", e))
+    }
+}
+
+async fn set_status(db: &Pool<Sqlite>, id: &str, status: &str, error: Option<&str>) -> Result<()> {
+    sqlx::query("UPDATE trickplay SET status = ?, error = ?, updated_at = ? WHERE id = ?")
+        .bind(status)
+        .bind(error)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(db)
+        .await
+        .map_err(AppError::Database)?;
+    Ok(())
+}
+
+// Height of a thumbnail `width` wide, from the dimensions of the file's video stream
+// when it was probed, else 16:9. Kept even for the JPEG encoder's chroma subsampling.
+async fn thumbnail_height(db: &Pool<Sqlite>, path: &Path, width: u32) -> Result<u32> {
+    let dimensions: Option<(Option<i64>, Option<i64>)> = sqlx::query_as(
+        "SELECT s.width, s.height FROM media_streams s JOIN media_files f ON s.file_id = f.id
+         WHERE f.path = ? AND s.kind = 'video'
+         ORDER BY s.is_default DESC, s.stream_index LIMIT 1"
+    )
+    .bind(path.to_string_lossy().to_string())
+    .fetch_optional(db)
+    .await
+    .map_err(AppError::Database)?;
+
+    let aspect = match dimensions {
+        Some((Some(video_width), Some(video_height))) if video_width > 0 && video_height > 0 => {
+            video_height as f64 / video_width as f64
+        }
+        _ => 9.0 / 16.0,
+    };
+    Ok(((width as f64 * aspect / 2.0).round() as u32).max(1) * 2)
+}
+
+// Files in `dir` named <prefix><number>.jpg, in order
+async fn numbered_files(dir: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let number = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(".jpg"))
+            .and_then(|number| number.parse::<u32>().ok());
+        if let Some(number) = number {
+            files.push((number, entry.path()));
+        }
+    }
+
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+impl TrickplayGenerator {
+    pub fn new(config: TrickplayConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    fn queue(&self) -> std::sync::MutexGuard<'_, VecDeque<Job>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, job: Job) {
+        let mut queue = self.queue();
+        let queued = queue
+            .iter()
+            .any(|queued| queued.media_id == job.media_id && queued.episode_id == job.episode_id);
+        if !queued {
+            queue.push_back(job);
+            self.wake.notify_one();
+        }
+    }
+
+    fn item_dir(&self, trickplay: &Trickplay) -> PathBuf {
+        self.config
+            .dir
+            .join(item_key(&trickplay.media_id, trickplay.episode_id.as_deref()))
+    }
+
+    // Queue the thumbnails of a movie or episode, unless they are already made from the
+    // current version of its file with the current settings. A failure is only retried
+    // with `force`, or once the file changes.
+    pub async fn enqueue(
+        &self,
+        db: &Pool<Sqlite>,
+        media_id: &str,
+        episode_id: Option<&str>,
+        path: PathBuf,
+        force: bool,
+    ) -> Result<Trickplay> {
+        let metadata = validate_file(&path).await?;
+        let file_etag = conditional::file_etag(metadata.len(), metadata.modified().ok());
+        let existing = queries::get_trickplay(db, media_id, episode_id).await?;
+
+        let id = match existing {
+            Some(existing) => {
+                let current = existing.file_etag == file_etag
+                    && existing.interval == self.config.interval
+                    && existing.width == self.config.width as i32
+                    && (existing.has_bif || !self.config.bif || existing.status != STATUS_READY);
+                let pending = existing.status == STATUS_QUEUED || existing.status == STATUS_RUNNING;
+                if current && (pending || !force) {
+                    return Ok(existing);
+                }
+                existing.id
+            }
+            None => generate_id(),
+        };
+        sqlx::query(
+            "INSERT INTO trickplay (id, media_id, episode_id, status, file_etag, interval, width, height, columns, rows)
+             VALUES (?, ?, ?, ?, ?, ?, ?, 0, ?, ?)
+             ON CONFLICT (id) DO UPDATE SET status = excluded.status, file_etag = excluded.file_etag,
+                 interval = excluded.interval, width = excluded.width, error = NULL, updated_at = CURRENT_TIMESTAMP"
+        )
+        .bind(&id)
+        .bind(media_id)
+        .bind(episode_id)
+        .bind(STATUS_QUEUED)
+        .bind(&file_etag)
+        .bind(self.config.interval)
+        .bind(self.config.width)
+        .bind(COLUMNS)
+        .bind(ROWS)
+        .execute(db)
+        .await
+        .map_err(AppError::Database)?;
+
+        self.push(Job {
+            media_id: media_id.to_string(),
+            episode_id: episode_id.map(str::to_string),
+            path,
+        });
+
+        queries::get_trickplay(db, media_id, episode_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("Seek previews of {} not found", media_id)))
+    }
+
+    // Queue every movie and episode of a library whose thumbnails are missing or out
+    // of date. Returns how many were queued.
+    pub async fn enqueue_library(&self, db: &Pool<Sqlite>, library: &Library) -> Result<usize> {
+        let prefix = missing::path_prefix(library);
+        let items: Vec<(String, Option<String>, String)> = match library.media_type.as_str() {
+            "movie" => sqlx::query_as(
+                "SELECT id, NULL, path FROM media WHERE type = 'movie' AND substr(path, 1, length(?)) = ?"
+            ),
+            "tvshow" => sqlx::query_as(
+                "SELECT media_id, id, path FROM episodes WHERE substr(path, 1, length(?)) = ?"
+            ),
+            _ => return Ok(0),
+        }
+        .bind(&prefix)
+        .bind(&prefix)
+        .fetch_all(db)
+        .await
+        .map_err(AppError::Database)?;
+
+        let mut queued = 0;
+        for (media_id, episode_id, path) in items {
+            match self.enqueue(db, &media_id, episode_id.as_deref(), PathBuf::from(&path), false).await {
+                Ok(trickplay) if trickplay.status == STATUS_QUEUED => queued += 1,
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Not making seek previews of {}: {}", path, e),
+            }
+        }
+        Ok(queued)
+    }
+
+    // Queue again what a previous run didn't get to
+    async fn resume(&self, db: &Pool<Sqlite>) -> Result<()> {
+        let pending: Vec<(String, Option<String>)> =
+            sqlx::query_as("SELECT media_id, episode_id FROM trickplay WHERE status IN (?, ?) ORDER BY updated_at")
+                .bind(STATUS_QUEUED)
+                .bind(STATUS_RUNNING)
+                .fetch_all(db)
+                .await
+                .map_err(AppError::Database)?;
+
+        for (media_id, episode_id) in pending {
+            let path: Option<String> = match &episode_id {
+                Some(episode_id) => sqlx::query_scalar("SELECT path FROM episodes WHERE id = ?").bind(episode_id),
+                None => sqlx::query_scalar("SELECT path FROM media WHERE id = ?").bind(&media_id),
+            }
+            .fetch_optional(db)
+            .await
+            .map_err(AppError::Database)?;
+
+            if let Some(path) = path {
+                sqlx::query("UPDATE trickplay SET status = ? WHERE media_id = ? AND episode_id IS ?")
+                    .bind(STATUS_QUEUED)
+                    .bind(&media_id)
+                    .bind(&episode_id)
+                    .execute(db)
+                    .await
+                    .map_err(AppError::Database)?;
+                self.push(Job {
+                    media_id,
+                    episode_id,
+                    path: PathBuf::from(path),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn run(self, db: Pool<Sqlite>, sandbox: PathSandbox) {
+        if let Err(e) = self.resume(&db).await {
+            tracing::warn!("Failed to resume seek preview generation: {}", e);
+        }
+
+        loop {
+            let job = self.queue().pop_front();
+            match job {
+                Some(job) => self.process(&db, &sandbox, job).await,
+                None => self.wake.notified().await,
+            }
+        }
+    }
+
+    async fn process(&self, db: &Pool<Sqlite>, sandbox: &PathSandbox, job: Job) {
+        let Ok(Some(trickplay)) = queries::get_trickplay(db, &job.media_id, job.episode_id.as_deref()).await else {
+            return;
+        };
+
+        // Items queued before a library was removed, or whose files moved out of the
+        // libraries, are left failed
+        let result = async {
+            sandbox.check_file(db, &job.path).await?;
+            set_status(db, &trickplay.id, STATUS_RUNNING, None).await?;
+            self.generate(db, &trickplay, &job.path).await
+        }
+        .await;
+
+        match result {
+            Ok(()) => tracing::info!("Made seek previews of {}", job.path.display()),
+            Err(e) => {
+                tracing::warn!("Failed to make seek previews of {}: {}", job.path.display(), e);
+                if let Err(e) = set_status(db, &trickplay.id, STATUS_FAILED, Some(&e.to_string())).await {
+                    tracing::warn!("Failed to record seek preview failure: {}", e);
+                }
+            }
+        }
+    }
+
+    // Extract a thumbnail every interval and tile them into sprite sheets, in a
+    // temporary directory that replaces the previous thumbnails once complete
+    async fn generate(&self, db: &Pool<Sqlite>, trickplay: &Trickplay, path: &Path) -> Result<()> {
+        let config = &self.config;
+        let duration = probe_duration(&config.ffmpeg, path).await?;
+        let width = config.width;
+        let height = thumbnail_height(db, path, width).await?;
+
+        let dir = self.item_dir(trickplay);
+        let partial = dir.with_extension("tmp");
+        let frames = partial.join("frames");
+        let _ = tokio::fs::remove_dir_all(&partial).await;
+        tokio::fs::create_dir_all(if config.bif { &frames } else { &partial }).await?;
+
+        // Letterboxed to the exact size, so every tile lines up with its coordinates
+        let mut filter = format!(
+            "[0:v:0]fps=1/{interval},scale={width}:{height}:force_original_aspect_ratio=decrease,\
+             pad={width}:{height}:(ow-iw)/2:(oh-ih)/2,setsar=1",
+            interval = config.interval,
+        );
+        if config.bif {
+            filter.push_str(&format!(",split=2[frames][thumbnails];[thumbnails]tile={}x{}[sprites]", COLUMNS, ROWS));
+        } else {
+            filter.push_str(&format!(",tile={}x{}[sprites]", COLUMNS, ROWS));
+        }
+
+        // Only keyframes are decoded, previews don't need to be exact
+        let mut command = Command::new(&config.ffmpeg);
+        command
+            .args(["-hide_banner", "-nostdin", "-loglevel", "error", "-skip_frame", "nokey", "-i"])
+            .arg(path)
+            .args(["-filter_complex", &filter])
+            .args(["-map", "[sprites]", "-fps_mode", "vfr", "-q:v", QUALITY])
+            .arg(partial.join("sprite-%d.jpg"));
+        if config.bif {
+            command
+                .args(["-map", "[frames]", "-fps_mode", "vfr", "-q:v", QUALITY])
+                .arg(frames.join("%d.jpg"));
+        }
+
+        let output = command
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| spawn_error(&config.ffmpeg, e))?;
+        if !output.status.success() {
+            let _ = tokio::fs::remove_dir_all(&partial).await;
+            return Err(AppError::Server(format!(
+                "ffmpeg exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let sprites = numbered_files(&partial, "sprite-").await?.len() as u32;
+        let mut thumbnails = ((duration / config.interval).ceil() as u32).min(sprites * COLUMNS * ROWS);
+        if config.bif {
+            let mut images = Vec::new();
+            for frame in numbered_files(&frames, "").await? {
+                images.push(tokio::fs::read(frame).await?);
+            }
+            thumbnails = images.len() as u32;
+
+            let interval_ms = (config.interval * 1000.0).round() as u32;
+            tokio::fs::write(partial.join("thumbnails.bif"), bif::write(&images, interval_ms)).await?;
+            tokio::fs::remove_dir_all(&frames).await?;
+        }
+
+        if sprites == 0 {
+            let _ = tokio::fs::remove_dir_all(&partial).await;
+            return Err(AppError::Server(format!("No video frames in {}", path.display())));
+        }
+
+        match tokio::fs::remove_dir_all(&dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(AppError::Io(e)),
+            _ => {}
+        }
+        tokio::fs::rename(&partial, &dir).await?;
+
+        sqlx::query(
+            "UPDATE trickplay SET status = ?, width = ?, height = ?, columns = ?, rows = ?, thumbnails = ?,
+                 sprites = ?, has_bif = ?, error = NULL, updated_at = ? WHERE id = ?"
+        )
+        .bind(STATUS_READY)
+        .bind(width)
+        .bind(height)
+        .bind(COLUMNS)
+        .bind(ROWS)
+        .bind(thumbnails)
+        .bind(sprites)
+        .bind(config.bif)
+        .bind(Utc::now())
+        .bind(&trickplay.id)
+        .execute(db)
+        .await
+        .map_err(AppError::Database)?;
+
+        Ok(())
+    }
+
+    // Thumbnails of a movie or episode that are ready to be served
+    pub async fn ready(&self, db: &Pool<Sqlite>, media_id: &str, episode_id: Option<&str>) -> Result<Trickplay> {
+        queries::get_trickplay(db, media_id, episode_id)
+            .await?
+            .filter(|trickplay| trickplay.status == STATUS_READY)
+            .ok_or_else(|| AppError::NotFound(format!("No seek previews for {}", item_key(media_id, episode_id))))
+    }
+
+    pub fn sprite_path(&self, trickplay: &Trickplay, sprite: SpriteFile) -> Result<PathBuf> {
+        if sprite.0 as i32 > trickplay.sprites {
+            return Err(AppError::NotFound(format!("Sprite sheet {} not found", sprite.0)));
+        }
+        Ok(self.item_dir(trickplay).join(format!("sprite-{}.jpg", sprite.0)))
+    }
+
+    pub fn bif_path(&self, trickplay: &Trickplay) -> Result<PathBuf> {
+        if !trickplay.has_bif {
+            return Err(AppError::NotFound("No BIF file, set TRICKPLAY_BIF to make them".to_string()));
+        }
+        Ok(self.item_dir(trickplay).join("thumbnails.bif"))
+    }
+}
+
+// WebVTT thumbnails index: a cue per thumbnail pointing at its tile in a sprite sheet,
+// relative to the index itself. `query` is appended to the sprite URLs.
+pub fn thumbnails_vtt(trickplay: &Trickplay, query: &str) -> Cached<(ContentType, String)> {
+    let mut document = String::from("WEBVTT\n");
+    let per_sprite = trickplay.columns * trickplay.rows;
+
+    for number in 0..trickplay.thumbnails {
+        let sprite = number / per_sprite + 1;
+        let tile = number % per_sprite;
+        let x = tile % trickplay.columns * trickplay.width;
+        let y = tile / trickplay.columns * trickplay.height;
+
+        document.push_str(&format!(
+            "\n{} --> {}\nsprite-{}.jpg{}#xywh={},{},{},{}\n",
+            webvtt::format_timestamp(number as f64 * trickplay.interval),
+            webvtt::format_timestamp((number + 1) as f64 * trickplay.interval),
+            sprite,
+            query,
+            x,
+            y,
+            trickplay.width,
+            trickplay.height,
+        ));
+    }
+
+    let etag = derived_etag(&trickplay.file_etag, &format!("trickplay-{}", trickplay.updated_at.timestamp()));
+    Cached::new((ContentType::new("text", "vtt"), document), etag, None)
+        .with_cache_control(conditional::CACHE_CONTROL_METADATA)
+}
+
+// Runs the generator in the background once the server is up
+pub struct TrickplayWorker;
+
+#[rocket::async_trait]
+impl Fairing for TrickplayWorker {
+    fn info(&self) -> Info {
+        Info {
+            name: "Seek preview generator",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let (Some(generator), Some(db), Some(sandbox)) = (
+            rocket.state::<TrickplayGenerator>(),
+            rocket.state::<Pool<Sqlite>>(),
+            rocket.state::<PathSandbox>(),
+        ) {
+            tokio::spawn(generator.clone().run(db.clone(), sandbox.clone()));
+        }
+    }
+}
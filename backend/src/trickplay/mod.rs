@@ -0,0 +1,2 @@
+pub mod bif;
+pub mod generator;
@@ -1,9 +1,9 @@
 'use client';
 
-import { useState, useEffect } from 'react';
-import { PlusIcon, FolderIcon, TrashIcon, ArrowPathIcon } from '@heroicons/react/24/outline';
-import { getLibraries, deleteLibrary, scanLibrary } from '@/lib/api';
-import { Library } from '@/types';
+import { useState, useEffect, useRef } from 'react';
+import { PlusIcon, FolderIcon, TrashIcon, ArrowPathIcon, XMarkIcon } from '@heroicons/react/24/outline';
+import { getLibraries, deleteLibrary, scanLibrary, getJob, getJobs, cancelJob } from '@/lib/api';
+import { Job, Library } from '@/types';
 import LoadingSpinner from '@/components/ui/LoadingSpinner';
 import ErrorMessage from '@/components/ui/ErrorMessage';
 import AddLibraryForm from '@/components/libraries/AddLibraryForm';
@@ -11,15 +11,58 @@ import AddLibraryForm from '@/components/libraries/AddLibraryForm';
 export default function LibrariesPage() {
   const [libraries, setLibraries] = useState<Library[]>([]);
   const [loading, setLoading] = useState(true);
-  const [scanning, setScanning] = useState<string | null>(null);
   const [error, setError] = useState<string | null>(null);
   const [showAddForm, setShowAddForm] = useState(false);
-  const [scanResults, setScanResults] = useState<Record<string, any> | null>(null);
+  // Latest scan of each library, by library id
+  const [scans, setScans] = useState<Record<string, Job>>({});
+  const pollers = useRef<Record<string, ReturnType<typeof setInterval>>>({});
+
+  const isActive = (job?: Job) => job?.status === 'queued' || job?.status === 'running';
 
   useEffect(() => {
     fetchLibraries();
+    resumeScans();
+
+    const running = pollers.current;
+    return () => Object.values(running).forEach(clearInterval);
   }, []);
 
+  // Scans run in the background, so their job is polled until it ends
+  const followScan = (libraryId: string, job: Job) => {
+    setScans(prev => ({ ...prev, [libraryId]: job }));
+    if (!isActive(job) || pollers.current[libraryId]) {
+      return;
+    }
+
+    pollers.current[libraryId] = setInterval(async () => {
+      try {
+        const latest = await getJob(job.id);
+        setScans(prev => ({ ...prev, [libraryId]: latest }));
+        if (isActive(latest)) {
+          return;
+        }
+        // Refresh the library list to get updated counts
+        fetchLibraries();
+      } catch (err) {
+        console.error('Failed to get scan progress:', err);
+      }
+      clearInterval(pollers.current[libraryId]);
+      delete pollers.current[libraryId];
+    }, 1000);
+  };
+
+  // Pick up scans that were already running, e.g. started before a reload
+  const resumeScans = async () => {
+    try {
+      const jobs = await getJobs();
+      jobs
+        .filter(job => job.library_id && isActive(job))
+        .forEach(job => followScan(job.library_id!, job));
+    } catch (err) {
+      console.error('Failed to load scans:', err);
+    }
+  };
+
   const fetchLibraries = async () => {
     try {
       setLoading(true);
@@ -35,18 +78,26 @@ export default function LibrariesPage() {
 
   const handleScanLibrary = async (libraryId: string) => {
     try {
-      setScanning(libraryId);
-      setScanResults(null);
       const result = await scanLibrary(libraryId);
-      setScanResults(result.results);
-      setScanning(null);
-      
-      // Refresh the library list to get updated counts
-      fetchLibraries();
+      followScan(libraryId, result.job);
     } catch (err) {
       console.error('Failed to scan library:', err);
       setError('Failed to scan library. Please try again later.');
-      setScanning(null);
+    }
+  };
+
+  const handleCancelScan = async (libraryId: string) => {
+    const job = scans[libraryId];
+    if (!job) {
+      return;
+    }
+
+    try {
+      const cancelled = await cancelJob(job.id);
+      setScans(prev => ({ ...prev, [libraryId]: cancelled }));
+    } catch (err) {
+      console.error('Failed to cancel scan:', err);
+      setError('Failed to cancel the scan. Please try again later.');
     }
   };
 
@@ -153,38 +204,63 @@ export default function LibrariesPage() {
                   <TrashIcon className="w-5 h-5" />
                 </button>
                 
-                <button
-                  onClick={() => handleScanLibrary(library.id)}
-                  disabled={scanning === library.id}
-                  className="flex items-center gap-2 button-secondary"
-                >
-                  <ArrowPathIcon className={`w-5 h-5 ${scanning === library.id ? 'animate-spin' : ''}`} />
-                  {scanning === library.id ? 'Scanning...' : 'Scan Library'}
-                </button>
+                <div className="flex items-center gap-2">
+                  {isActive(scans[library.id]) && (
+                    <button
+                      onClick={() => handleCancelScan(library.id)}
+                      className="text-gray-400 hover:text-white p-2 rounded-full hover:bg-white/5"
+                      title="Cancel Scan"
+                    >
+                      <XMarkIcon className="w-5 h-5" />
+                    </button>
+                  )}
+                  <button
+                    onClick={() => handleScanLibrary(library.id)}
+                    disabled={isActive(scans[library.id])}
+                    className="flex items-center gap-2 button-secondary"
+                  >
+                    <ArrowPathIcon className={`w-5 h-5 ${isActive(scans[library.id]) ? 'animate-spin' : ''}`} />
+                    {isActive(scans[library.id]) ? 'Scanning...' : 'Scan Library'}
+                  </button>
+                </div>
               </div>
               
+              {/* Scan Progress */}
+              {isActive(scans[library.id]) && (
+                <div className="border-t border-gray-800 p-4">
+                  <p className="text-sm text-gray-400 mb-2">
+                    {scans[library.id].status === 'queued'
+                      ? 'Waiting for other scans to finish...'
+                      : `Scanned ${scans[library.id].processed} of ${scans[library.id].total} files`}
+                  </p>
+                  <div className="h-1.5 bg-gray-800 rounded">
+                    <div
+                      className="h-full bg-primary rounded"
+                      style={{ width: `${scans[library.id].total ? (scans[library.id].processed / scans[library.id].total) * 100 : 0}%` }}
+                    />
+                  </div>
+                </div>
+              )}
+
               {/* Scan Results */}
-              {scanResults && scanning === null && (
-                <div className="border-t border-gray-800 p-4 bg-green-900/20">
-                  <h3 className="font-semibold mb-2">Scan Results:</h3>
+              {scans[library.id] && !isActive(scans[library.id]) && (
+                <div className={`border-t border-gray-800 p-4 ${scans[library.id].status === 'completed' ? 'bg-green-900/20' : 'bg-red-900/20'}`}>
+                  <h3 className="font-semibold mb-2">
+                    {scans[library.id].status === 'completed' && 'Scan Results:'}
+                    {scans[library.id].status === 'cancelled' && 'Scan Cancelled:'}
+                    {scans[library.id].status === 'failed' && `Scan Failed: ${scans[library.id].error}`}
+                  </h3>
                   <ul className="text-sm space-y-1">
-                    {scanResults.addedShows && (
-                      <li>Added {scanResults.addedShows} TV shows</li>
-                    )}
-                    {scanResults.addedSeasons && (
-                      <li>Added {scanResults.addedSeasons} seasons</li>
-                    )}
-                    {scanResults.addedEpisodes && (
-                      <li>Added {scanResults.addedEpisodes} episodes</li>
-                    )}
-                    {scanResults.existingEpisodes && (
-                      <li>Found {scanResults.existingEpisodes} existing episodes</li>
+                    <li>Added {scans[library.id].added} new {library.media_type === 'tvshow' ? 'episodes' : 'items'}</li>
+                    <li>Found {scans[library.id].existing} existing</li>
+                    {scans[library.id].missing > 0 && (
+                      <li>{scans[library.id].missing} missing, kept for now in case they come back</li>
                     )}
-                    {scanResults.added && (
-                      <li>Added {scanResults.added} movies</li>
+                    {scans[library.id].removed > 0 && (
+                      <li>Removed {scans[library.id].removed} whose files are gone</li>
                     )}
-                    {scanResults.existing && (
-                      <li>Found {scanResults.existing} existing movies</li>
+                    {scans[library.id].errors > 0 && (
+                      <li>{scans[library.id].errors} files could not be read</li>
                     )}
                   </ul>
                 </div>
@@ -3,11 +3,11 @@
 import { useState, useEffect } from 'react';
 import Image from 'next/image';
 import Link from 'next/link';
-import { PlayIcon, ArrowPathIcon, HeartIcon } from '@heroicons/react/24/outline';
+import { PlayIcon, ArrowPathIcon, HeartIcon, ArrowDownTrayIcon } from '@heroicons/react/24/outline';
 import { HeartIcon as HeartIconSolid } from '@heroicons/react/24/solid';
 import axios from 'axios';
-import { getWatchProgress, refreshMetadata } from '@/lib/api';
-import { MediaDetails, Season, Episode, Person } from '@/types';
+import { getWatchProgress, refreshMetadata, signMediaDownload, signEpisodeDownload, signSeasonDownload, getDownloadUrl } from '@/lib/api';
+import { MediaDetails, Season, Episode, Person, SignedDownload } from '@/types';
 import LoadingSpinner from '@/components/ui/LoadingSpinner';
 import ErrorMessage from '@/components/ui/ErrorMessage';
 
@@ -23,6 +23,7 @@ export default function MediaDetailPage({ params }: MediaDetailProps) {
   const [expandedSeason, setExpandedSeason] = useState<string | null>(null);
   const [loading, setLoading] = useState(true);
   const [refreshing, setRefreshing] = useState(false);
+  const [downloading, setDownloading] = useState(false);
   const [error, setError] = useState<string | null>(null);
   const [isFavorite, setIsFavorite] = useState(false);
   const [watchProgress, setWatchProgress] = useState<number | null>(null);
@@ -97,6 +98,21 @@ export default function MediaDetailPage({ params }: MediaDetailProps) {
     }
   };
 
+  // The server names the file and sends it as an attachment, so navigating to the
+  // signed URL saves it without leaving the page
+  const handleDownload = async (sign: () => Promise<SignedDownload>) => {
+    try {
+      setDownloading(true);
+      const download = await sign();
+      window.location.href = getDownloadUrl(download);
+    } catch (err) {
+      console.error('Failed to start download:', err);
+      setError('Failed to start the download. You may not be allowed to download.');
+    } finally {
+      setDownloading(false);
+    }
+  };
+
   const toggleFavorite = () => {
     setIsFavorite(!isFavorite);
     // Here you would call an API to update favorites
@@ -183,6 +199,19 @@ export default function MediaDetailPage({ params }: MediaDetailProps) {
                       <p className="text-sm text-gray-400">{episodes.length} Episodes</p>
                     </div>
                   </div>
+                  {episodes.length > 0 && (
+                    <button
+                      onClick={(e) => {
+                        e.stopPropagation();
+                        handleDownload(() => signSeasonDownload(season.id));
+                      }}
+                      disabled={downloading}
+                      title="Download season"
+                      className="ml-auto mr-4 p-2 rounded-full hover:bg-white/10"
+                    >
+                      <ArrowDownTrayIcon className="w-5 h-5" />
+                    </button>
+                  )}
                   <svg 
                     xmlns="http://www.w3.org/2000/svg" 
                     fill="none" 
@@ -213,12 +242,22 @@ export default function MediaDetailPage({ params }: MediaDetailProps) {
                               <p className="text-sm text-gray-400 mt-1 line-clamp-2">{episode.overview}</p>
                             )}
                           </div>
-                          <Link 
-                            href={`/media/${id}/play?episode=${episode.id}`}
-                            className="p-2 rounded-full bg-primary/10 hover:bg-primary/20"
-                          >
-                            <PlayIcon className="w-5 h-5" />
-                          </Link>
+                          <div className="flex items-center gap-2">
+                            <button
+                              onClick={() => handleDownload(() => signEpisodeDownload(episode.id))}
+                              disabled={downloading}
+                              title="Download episode"
+                              className="p-2 rounded-full hover:bg-white/10"
+                            >
+                              <ArrowDownTrayIcon className="w-5 h-5" />
+                            </button>
+                            <Link 
+                              href={`/media/${id}/play?episode=${episode.id}`}
+                              className="p-2 rounded-full bg-primary/10 hover:bg-primary/20"
+                            >
+                              <PlayIcon className="w-5 h-5" />
+                            </Link>
+                          </div>
                         </div>
                       ))
                     )}
@@ -358,6 +397,15 @@ export default function MediaDetailPage({ params }: MediaDetailProps) {
                     )}
                   </button>
                   
+                  <button
+                    onClick={() => handleDownload(() => signMediaDownload(media.id))}
+                    disabled={downloading}
+                    className="button-secondary flex items-center gap-2"
+                  >
+                    <ArrowDownTrayIcon className="w-5 h-5" />
+                    {mediaType === 'tvshow' ? 'Download Show' : 'Download'}
+                  </button>
+                  
                   <button
                     onClick={handleRefreshMetadata}
                     disabled={refreshing}
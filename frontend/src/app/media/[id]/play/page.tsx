@@ -4,8 +4,8 @@ import { useState, useEffect, useRef } from 'react';
 import { useRouter, useSearchParams } from 'next/navigation';
 import ReactPlayer from 'react-player';
 import axios from 'axios';
-import { getMediaById, updateWatchProgress } from '@/lib/api';
-import { Media, Episode } from '@/types';
+import { getMediaById, updateWatchProgress, getPlaybackPlan, getPlaybackUrl, getSubtitles, getSubtitleUrl, getMarkers, getTrickplay, getTrickplaySpriteUrl, sendHeartbeat } from '@/lib/api';
+import { Media, Episode, PlaybackPlan, Subtitle, Marker, Trickplay } from '@/types';
 import LoadingSpinner from '@/components/ui/LoadingSpinner';
 import ErrorMessage from '@/components/ui/ErrorMessage';
 import { ArrowLeftIcon, 
@@ -34,6 +34,12 @@ const episodeId = useMemo(() => {
 
 const [media, setMedia] = useState<Media | null>(null);
 const [episode, setEpisode] = useState<Episode | null>(null);
+const [plan, setPlan] = useState<PlaybackPlan | null>(null);
+const [subtitles, setSubtitles] = useState<Subtitle[]>([]);
+const [markers, setMarkers] = useState<Marker[]>([]);
+const [trickplay, setTrickplay] = useState<Trickplay | null>(null);
+// Fraction of the progress bar under the mouse, for the seek preview
+const [hoverFraction, setHoverFraction] = useState<number | null>(null);
 const [loading, setLoading] = useState(true);
 const [error, setError] = useState<string | null>(null);
 const [isPlaying, setIsPlaying] = useState(true);
@@ -56,14 +62,9 @@ const fetchEpisode = async (id: string) => {
   return response.data;
 };
 
-// Function to get the stream URL for a media or episode
+// Function to get the stream URL for a media or episode, as planned by the server
 const getStreamUrl = () => {
-  const API_URL = process.env.NEXT_PUBLIC_API_URL || 'http://localhost:8000/api';
-  if (episodeId) {
-    return `${API_URL}/episodes/stream/${episodeId}`;
-  } else {
-    return `${API_URL}/media/info/${id}/stream`;
-  }
+  return plan ? getPlaybackUrl(plan) : '';
 };
 
 useEffect(() => {
@@ -96,10 +97,44 @@ useEffect(() => {
         return;
       }
       
+      // Ask the server whether this browser can play the file as is
+      const playbackPlan = await getPlaybackPlan(id, episodeId);
+      if (playbackPlan.reasons.length > 0) {
+        console.info(`Playback method ${playbackPlan.method}:`, playbackPlan.reasons.join('; '));
+      }
+      setPlan(playbackPlan);
+      
+      // Subtitles are optional, playback goes ahead without them
+      try {
+        setSubtitles(await getSubtitles(id, episodeId));
+      } catch (err) {
+        console.error('Failed to load subtitles:', err);
+      }
+      
+      // So are the intro and recap markers behind the skip button
+      try {
+        setMarkers(await getMarkers(id, episodeId));
+      } catch (err) {
+        console.error('Failed to load markers:', err);
+      }
+      
+      // Seek previews only exist once they have been generated
+      try {
+        const thumbnails = await getTrickplay(id, episodeId);
+        setTrickplay(thumbnails.status === 'ready' ? thumbnails : null);
+      } catch {
+        setTrickplay(null);
+      }
+      
       setLoading(false);
     } catch (err) {
       console.error('Failed to load media:', err);
-      setError('Failed to load media. Please try again later.');
+      // Users playing as many streams as they may are told so by the server
+      if (axios.isAxiosError(err) && err.response?.status === 429) {
+        setError(err.response.data.message);
+      } else {
+        setError('Failed to load media. Please try again later.');
+      }
       setLoading(false);
     }
   };
@@ -267,6 +302,31 @@ useEffect(() => {
   };
 }, [played, duration, seeking, seekLock]);
 
+// Tell the server the stream is still being watched, and stop if an admin ended it
+// Read from the interval, which would otherwise restart with every progress update
+const heartbeatRef = useRef({ position: 0, paused: false });
+heartbeatRef.current = { position: played * duration, paused: !isPlaying };
+
+useEffect(() => {
+  if (!plan) return;
+
+  const heartbeatInterval = setInterval(async () => {
+    const { position, paused } = heartbeatRef.current;
+    try {
+      await sendHeartbeat(plan.sessionId, position, paused);
+    } catch (err) {
+      if (axios.isAxiosError(err) && err.response?.status === 403) {
+        setIsPlaying(false);
+        setError('Playback was stopped by an administrator.');
+      }
+    }
+  }, 30000);
+
+  return () => {
+    clearInterval(heartbeatInterval);
+  };
+}, [plan]);
+
 const saveProgress = async (position: number) => {
   if (!media) return;
   
@@ -440,6 +500,26 @@ const handleProgressBarClick = (e: React.MouseEvent<HTMLDivElement>) => {
   }
 };
 
+// Thumbnail tile under the mouse on the progress bar, if there are seek previews
+const previewTile = (() => {
+  if (!trickplay || hoverFraction === null || duration <= 0) return null;
+  const index = Math.min(Math.floor(hoverFraction * duration / trickplay.interval), trickplay.thumbnails - 1);
+  const perSprite = trickplay.columns * trickplay.rows;
+  const tile = index % perSprite;
+  return {
+    url: getTrickplaySpriteUrl(id, episodeId, Math.floor(index / perSprite) + 1),
+    x: (tile % trickplay.columns) * trickplay.width,
+    y: Math.floor(tile / trickplay.columns) * trickplay.height,
+  };
+})();
+
+// Intro or recap at the current position, if any
+const currentSeconds = played * duration;
+const activeSkipMarker = markers.find((marker) =>
+  (marker.marker_type === 'intro' || marker.marker_type === 'recap') &&
+  currentSeconds >= marker.start_time && currentSeconds < marker.end_time - 1
+);
+
 return (
   <div className="-mx-6 -mt-6 h-screen bg-black relative" id="video-container">
     {/* Video Player */}
@@ -459,13 +539,37 @@ return (
       progressInterval={1000}
       config={{
         file: {
+          forceHLS: plan?.protocol === 'hls',
+          forceDASH: plan?.protocol === 'dash',
           attributes: {
-            controlsList: 'nodownload'
-          }
+            controlsList: 'nodownload',
+            crossOrigin: 'anonymous'
+          },
+          tracks: subtitles.map((subtitle) => ({
+            kind: 'subtitles',
+            src: getSubtitleUrl(subtitle.id),
+            srcLang: subtitle.language || 'und',
+            label: [
+              subtitle.title || subtitle.language?.toUpperCase() || 'Unknown',
+              subtitle.is_forced ? '(Forced)' : null,
+              subtitle.is_sdh ? '(SDH)' : null,
+            ].filter(Boolean).join(' '),
+            default: subtitle.is_forced || subtitle.is_default,
+          }))
         }
       }}
     />
     
+    {/* Skip button while an intro or recap is playing */}
+    {activeSkipMarker && (
+      <button
+        onClick={() => playerRef.current?.seekTo(activeSkipMarker.end_time, 'seconds')}
+        className="absolute right-8 bottom-28 z-10 bg-black/60 backdrop-blur-md text-white px-5 py-2.5 rounded-lg hover:bg-black/80"
+      >
+        {activeSkipMarker.marker_type === 'intro' ? 'Skip Intro' : 'Skip Recap'}
+      </button>
+    )}
+    
     {/* Back button (minimalist) */}
     <div className={`absolute top-4 left-4 z-10 transition-opacity duration-300 ${showControls ? 'opacity-100' : 'opacity-0 pointer-events-none'}`}>
       <button 
@@ -517,7 +621,30 @@ return (
           </span>
           
           {/* Progress bar */}
-          <div className="w-80 mx-2 relative">
+          <div
+            className="w-80 mx-2 relative"
+            onMouseMove={(e) => {
+              const rect = e.currentTarget.getBoundingClientRect();
+              setHoverFraction(Math.min(Math.max((e.clientX - rect.left) / rect.width, 0), 1));
+            }}
+            onMouseLeave={() => setHoverFraction(null)}
+          >
+            {previewTile && trickplay && hoverFraction !== null && (
+              <div
+                className="absolute bottom-6 -translate-x-1/2 rounded-md overflow-hidden shadow-lg pointer-events-none border border-white/20"
+                style={{
+                  left: `${hoverFraction * 100}%`,
+                  width: trickplay.width,
+                  height: trickplay.height,
+                  backgroundImage: `url(${previewTile.url})`,
+                  backgroundPosition: `-${previewTile.x}px -${previewTile.y}px`,
+                }}
+              >
+                <span className="absolute bottom-1 left-1/2 -translate-x-1/2 text-white text-xs bg-black/60 px-1.5 rounded">
+                  {formatTime(hoverFraction * duration)}
+                </span>
+              </div>
+            )}
             <div 
               className="w-full h-6 absolute -top-2 cursor-pointer"
               onClick={handleProgressBarClick}
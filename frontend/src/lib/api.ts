@@ -2,7 +2,8 @@
 import axios from 'axios';
 import { Media, MediaDetails, Season, Episode, Person, Genre, Library, 
   WatchProgress, CreateLibraryDto, UpdateProgressDto, SearchResponse, 
-  WatchHistoryItem } from '@/types';
+  WatchHistoryItem, DeviceProfile, PlaybackPlan, Subtitle, SubtitleUpload, SubtitleOffset, Marker, IntroDetection, Trickplay, SignedStreamUrls,
+  SignedDownload, DownloadPermission, PlaybackSession, Job } from '@/types';
 
 // Default API URL (change this to match your Rust backend)
 const API_URL = process.env.NEXT_PUBLIC_API_URL || 'http://localhost:8000/api';
@@ -39,10 +40,6 @@ export const getMediaDetails = async (id: string): Promise<MediaDetails> => {
   return response.data;
 };
 
-export const getMediaStreamUrl = (id: string): string => {
-  return `${API_URL}/media/${id}/stream`;
-};
-
 // Episode endpoints
 export const getEpisode = async (id: string): Promise<Episode> => {
   const response = await api.get(`/episodes/${id}`);
@@ -59,8 +56,238 @@ export const getEpisodesBySeason = async (seasonId: string): Promise<Episode[]>
   return response.data;
 };
 
-export const getEpisodeStreamUrl = (id: string): string => {
-  return `${API_URL}/episodes/stream/${id}`;
+// Playback endpoints
+const VIDEO_CODECS: Record<string, string> = {
+  h264: 'video/mp4; codecs="avc1.640028"',
+  hevc: 'video/mp4; codecs="hvc1.1.6.L120.90"',
+  av1: 'video/mp4; codecs="av01.0.08M.08"',
+  vp9: 'video/webm; codecs="vp9"',
+  vp8: 'video/webm; codecs="vp8"',
+};
+
+const AUDIO_CODECS: Record<string, string> = {
+  aac: 'audio/mp4; codecs="mp4a.40.2"',
+  mp3: 'audio/mpeg',
+  ac3: 'audio/mp4; codecs="ac-3"',
+  eac3: 'audio/mp4; codecs="ec-3"',
+  flac: 'audio/mp4; codecs="flac"',
+  opus: 'audio/webm; codecs="opus"',
+  vorbis: 'audio/webm; codecs="vorbis"',
+};
+
+const CONTAINERS: Record<string, string> = {
+  mp4: 'video/mp4',
+  webm: 'video/webm',
+  matroska: 'video/x-matroska',
+};
+
+// Ask the browser what it can decode. MediaSource is what HLS and DASH playback use,
+// so its answers count too.
+export const detectDeviceProfile = (): DeviceProfile => {
+  const video = document.createElement('video');
+  const canPlay = (type: string) =>
+    video.canPlayType(type) !== '' ||
+    (typeof MediaSource !== 'undefined' && MediaSource.isTypeSupported(type));
+  const supported = (types: Record<string, string>) =>
+    Object.keys(types).filter((name) => canPlay(types[name]));
+
+  const protocols: string[] = [];
+  if (video.canPlayType('application/vnd.apple.mpegurl') !== '' || typeof MediaSource !== 'undefined') {
+    protocols.push('hls');
+  }
+
+  return {
+    containers: supported(CONTAINERS),
+    video_codecs: supported(VIDEO_CODECS),
+    audio_codecs: supported(AUDIO_CODECS),
+    max_width: Math.round(window.screen.width * window.devicePixelRatio),
+    max_height: Math.round(window.screen.height * window.devicePixelRatio),
+    max_audio_channels: 2,
+    hdr_formats: window.matchMedia('(dynamic-range: high)').matches ? ['hdr10', 'hlg'] : [],
+    protocols,
+  };
+};
+
+// How to play a movie, or an episode of a show, on this browser
+export const getPlaybackPlan = async (mediaId: string, episodeId?: string | null, userId = DEFAULT_USER_ID): Promise<PlaybackPlan> => {
+  const device = detectDeviceProfile();
+  const params = { user_id: userId };
+  const response = episodeId
+    ? await api.post(`/episodes/stream/${episodeId}/playback`, device, { params })
+    : await api.post(`/media/info/${mediaId}/playback`, device, { params });
+  return response.data;
+};
+
+// Stream URLs that work without the API, e.g. in VLC, until they expire
+export const signStreamUrls = async (mediaId: string, episodeId?: string | null, userId = DEFAULT_USER_ID): Promise<SignedStreamUrls> => {
+  const response = episodeId
+    ? await api.post(`/episodes/stream/${episodeId}/sign`, null, { params: { user_id: userId } })
+    : await api.post(`/media/info/${mediaId}/sign`, null, { params: { user_id: userId } });
+  return response.data;
+};
+
+// Download URLs: movies, music, and whole shows or albums as ZIP archives
+export const signMediaDownload = async (mediaId: string, userId = DEFAULT_USER_ID): Promise<SignedDownload> => {
+  const response = await api.post(`/downloads/media/${mediaId}`, null, { params: { user_id: userId } });
+  return response.data;
+};
+
+export const signEpisodeDownload = async (episodeId: string, userId = DEFAULT_USER_ID): Promise<SignedDownload> => {
+  const response = await api.post(`/downloads/episodes/${episodeId}`, null, { params: { user_id: userId } });
+  return response.data;
+};
+
+export const signSeasonDownload = async (seasonId: string, userId = DEFAULT_USER_ID): Promise<SignedDownload> => {
+  const response = await api.post(`/downloads/seasons/${seasonId}`, null, { params: { user_id: userId } });
+  return response.data;
+};
+
+export const getDownloadPermission = async (userId = DEFAULT_USER_ID): Promise<DownloadPermission> => {
+  const response = await api.get(`/downloads/permissions/${encodeURIComponent(userId)}`);
+  return response.data;
+};
+
+export const setDownloadPermission = async (userId: string, canDownload: boolean): Promise<void> => {
+  await api.put(`/downloads/permissions/${encodeURIComponent(userId)}`, { can_download: canDownload });
+};
+
+// Download URLs start at the server root, like plan URLs
+export const getDownloadUrl = (download: SignedDownload): string => {
+  return new URL(download.url, new URL(API_URL, window.location.href)).toString();
+};
+
+// Keeps a playback session listed while the player plays from its buffer. Fails with
+// 403 once an admin stopped the session.
+export const sendHeartbeat = async (sessionId: string, position: number, paused: boolean): Promise<void> => {
+  await api.post(`/sessions/${sessionId}/heartbeat`, { position, paused });
+};
+
+export const getPlaybackSessions = async (): Promise<PlaybackSession[]> => {
+  const response = await api.get('/sessions');
+  return response.data;
+};
+
+export const stopPlaybackSession = async (sessionId: string): Promise<void> => {
+  await api.delete(`/sessions/${sessionId}`);
+};
+
+// Plan URLs start at the server root
+export const getPlaybackUrl = (plan: PlaybackPlan): string => {
+  return new URL(plan.url, new URL(API_URL, window.location.href)).toString();
+};
+
+// Seek preview endpoints, under the episode for episodes
+const trickplayBase = (mediaId: string, episodeId?: string | null): string =>
+  episodeId ? `/episodes/stream/${episodeId}/trickplay` : `/media/info/${mediaId}/trickplay`;
+
+export const getTrickplay = async (mediaId: string, episodeId?: string | null): Promise<Trickplay> => {
+  const response = await api.get(trickplayBase(mediaId, episodeId));
+  return response.data;
+};
+
+export const generateTrickplay = async (mediaId: string, episodeId?: string | null, force = false): Promise<Trickplay> => {
+  const response = await api.post(trickplayBase(mediaId, episodeId), null, {
+    params: force ? { force: true } : undefined,
+  });
+  return response.data;
+};
+
+// WebVTT thumbnails index, for players that read one
+export const getTrickplayVttUrl = (mediaId: string, episodeId?: string | null): string => {
+  return `${API_URL}${trickplayBase(mediaId, episodeId)}/thumbnails.vtt`;
+};
+
+// Sprite sheet `sprite`, counting from 1
+export const getTrickplaySpriteUrl = (mediaId: string, episodeId: string | null | undefined, sprite: number): string => {
+  return `${API_URL}${trickplayBase(mediaId, episodeId)}/sprite-${sprite}.jpg`;
+};
+
+// Subtitle endpoints
+export const getSubtitles = async (mediaId: string, episodeId?: string | null): Promise<Subtitle[]> => {
+  const response = await api.get(`/subtitles/media/${mediaId}`, {
+    params: episodeId ? { episode: episodeId } : undefined,
+  });
+  return response.data;
+};
+
+export const getSubtitleUrl = (id: string, userId?: string): string => {
+  const query = userId ? `?user_id=${encodeURIComponent(userId)}` : '';
+  return `${API_URL}/subtitles/${id}/subtitles.vtt${query}`;
+};
+
+// The file is sent as the request body; its name tells the backend the format
+export const uploadSubtitle = async (mediaId: string, file: File, details: SubtitleUpload = {}): Promise<Subtitle> => {
+  const response = await api.post(`/subtitles/media/${mediaId}`, file, {
+    params: { filename: file.name, ...details },
+    headers: { 'Content-Type': 'application/octet-stream' },
+  });
+  return response.data;
+};
+
+export const deleteSubtitle = async (id: string): Promise<void> => {
+  await api.delete(`/subtitles/${id}`);
+};
+
+export const getSubtitleOffset = async (id: string, userId: string): Promise<SubtitleOffset> => {
+  const response = await api.get(`/subtitles/${id}/offset`, { params: { user_id: userId } });
+  return response.data;
+};
+
+export const setSubtitleOffset = async (id: string, userId: string, offset: number): Promise<SubtitleOffset> => {
+  const response = await api.put(`/subtitles/${id}/offset`, { user_id: userId, offset });
+  return response.data;
+};
+
+export const shiftSubtitle = async (id: string, offset: number): Promise<Subtitle> => {
+  const response = await api.post(`/subtitles/${id}/shift`, { offset });
+  return response.data;
+};
+
+// Marker endpoints
+export const getMarkers = async (mediaId: string, episodeId?: string | null): Promise<Marker[]> => {
+  const response = await api.get(`/markers/media/${mediaId}`, {
+    params: episodeId ? { episode: episodeId } : undefined,
+  });
+  return response.data;
+};
+
+export const setMarker = async (
+  mediaId: string,
+  markerType: 'intro' | 'credits' | 'recap',
+  start: number,
+  end: number,
+  episodeId?: string | null,
+): Promise<Marker> => {
+  const response = await api.put(`/markers/media/${mediaId}/${markerType}`, { start, end }, {
+    params: episodeId ? { episode: episodeId } : undefined,
+  });
+  return response.data;
+};
+
+export const deleteMarker = async (id: string): Promise<void> => {
+  await api.delete(`/markers/${id}`);
+};
+
+export const detectIntros = async (seasonId: string): Promise<IntroDetection> => {
+  const response = await api.post(`/markers/seasons/${seasonId}/detect`);
+  return response.data;
+};
+
+export const getIntroDetection = async (seasonId: string): Promise<IntroDetection> => {
+  const response = await api.get(`/markers/seasons/${seasonId}/detect`);
+  return response.data;
+};
+
+export const getDetectedMarkers = async (seasonId?: string, maxConfidence?: number): Promise<Marker[]> => {
+  const response = await api.get('/markers/detected', {
+    params: { season: seasonId, max_confidence: maxConfidence },
+  });
+  return response.data;
+};
+
+export const acceptMarker = async (id: string): Promise<Marker> => {
+  const response = await api.post(`/markers/${id}/accept`);
+  return response.data;
 };
 
 // Library endpoints
@@ -74,11 +301,27 @@ export const createLibrary = async (library: CreateLibraryDto): Promise<Library>
   return response.data;
 };
 
-export const scanLibrary = async (id: string): Promise<{ success: boolean; message: string; results: any }> => {
+// Scans run in the background, follow the returned job with getJob
+export const scanLibrary = async (id: string): Promise<{ success: boolean; message: string; queued: boolean; job: Job }> => {
   const response = await api.post(`/libraries/${id}/scan`);
   return response.data;
 };
 
+export const getJob = async (id: string): Promise<Job> => {
+  const response = await api.get(`/jobs/${id}`);
+  return response.data;
+};
+
+export const getJobs = async (libraryId?: string): Promise<Job[]> => {
+  const response = await api.get('/jobs', { params: { library_id: libraryId } });
+  return response.data;
+};
+
+export const cancelJob = async (id: string): Promise<Job> => {
+  const response = await api.delete(`/jobs/${id}`);
+  return response.data;
+};
+
 export const deleteLibrary = async (id: string): Promise<{ success: boolean; message: string }> => {
   const response = await api.delete(`/libraries/${id}`);
   return response.data;
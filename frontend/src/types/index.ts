@@ -11,6 +11,7 @@ export interface Media {
     added_at: string;
     last_watched?: string;
     watch_count: number;
+    missing_since?: string; // set while its file is gone
   }
   
   export interface Season {
@@ -33,6 +34,7 @@ export interface Media {
     still_path?: string;
     air_date?: string;
     runtime?: number;
+    missing_since?: string;
   }
   
   export interface Person {
@@ -125,4 +127,166 @@ export interface Media {
     totalResults: number;
     totalPages: number;
     page: number;
-  }
\ No newline at end of file
+  }
+  // What this browser can play, sent when asking for a playback plan
+  export interface DeviceProfile {
+    containers: string[];
+    video_codecs: string[];
+    audio_codecs: string[];
+    max_width?: number;
+    max_height?: number;
+    max_bitrate?: number;
+    max_audio_channels?: number;
+    hdr_formats: string[];
+    protocols: string[];
+  }
+
+  export interface PlaybackPlan {
+    method: 'direct_play' | 'remux' | 'transcode';
+    protocol: 'http' | 'hls' | 'dash';
+    url: string; // signed, expires like SignedStreamUrls
+    profile?: string;
+    reasons: string[];
+    container?: string;
+    videoStream?: number;
+    audioStream?: number;
+    sessionId: string; // for heartbeats
+  }
+
+  // Short-lived stream URLs, relative to the server root
+  export interface SignedStreamUrls {
+    url: string;
+    hls: string;
+    dash: string;
+    userId: string;
+    expiresAt: string;
+    sessionId: string;
+  }
+
+  // A stream in progress, as listed for admins
+  export interface PlaybackSession {
+    id: string;
+    userId: string;
+    device?: string; // user agent
+    address?: string;
+    mediaId: string;
+    episodeId?: string;
+    title: string;
+    episode?: string; // e.g. "S01E02 - Title"
+    method: 'direct_play' | 'remux' | 'transcode';
+    profile?: string;
+    bitrate?: number; // bits per second
+    duration?: number;
+    position?: number;
+    paused: boolean;
+    transcodeSession?: string;
+    startedAt: string;
+    lastSeen: string;
+  }
+
+  // A short-lived download URL, for a file or a ZIP archive
+  export interface SignedDownload {
+    url: string;
+    filename: string;
+    size: number;
+    userId: string;
+    expiresAt: string;
+  }
+
+  export interface DownloadPermission {
+    userId: string;
+    canDownload: boolean;
+    isDefault: boolean; // no permission of their own, the server's default applies
+  }
+
+  export interface Subtitle {
+    id: string;
+    media_id: string;
+    episode_id?: string;
+    path: string;
+    stream_index?: number; // embedded track number
+    format: string;
+    language?: string;
+    title?: string;
+    is_forced: boolean;
+    is_sdh: boolean;
+    is_default: boolean;
+    is_uploaded: boolean;
+    time_offset: number; // seconds, applied for everyone
+    added_at: string;
+  }
+
+  export interface Marker {
+    id: string;
+    media_id: string;
+    episode_id?: string;
+    marker_type: 'chapter' | 'intro' | 'credits' | 'recap';
+    start_time: number; // seconds
+    end_time: number;
+    title?: string;
+    source: 'file' | 'manual' | 'detected';
+    confidence?: number; // 0 to 1, for detected markers
+    added_at: string;
+  }
+
+  export interface IntroDetection {
+    seasonId: string;
+    status: 'queued' | 'running' | 'finished' | 'failed';
+    episodes: number;
+    analyzed: number;
+    detected: number;
+    error?: string;
+    startedAt: string;
+    finishedAt?: string;
+  }
+
+  export interface Job {
+    id: string;
+    kind: 'scan';
+    library_id?: string;
+    paths?: string; // folders of a partial scan, one per line
+    status: 'queued' | 'running' | 'completed' | 'failed' | 'cancelled';
+    total: number; // files found
+    processed: number;
+    added: number;
+    existing: number;
+    missing: number; // items whose file is gone, removed after the grace period
+    removed: number;
+    errors: number; // files that couldn't be probed
+    error?: string;
+    created_at: string;
+    started_at?: string;
+    finished_at?: string;
+  }
+
+  export interface Trickplay {
+    id: string;
+    media_id: string;
+    episode_id?: string;
+    status: 'queued' | 'running' | 'ready' | 'failed';
+    interval: number; // seconds between thumbnails
+    width: number; // of one thumbnail
+    height: number;
+    columns: number; // thumbnails per sprite sheet row
+    rows: number;
+    thumbnails: number;
+    sprites: number;
+    has_bif: boolean;
+    error?: string;
+    updated_at: string;
+  }
+
+  export interface SubtitleUpload {
+    episode?: string;
+    language?: string;
+    title?: string;
+    forced?: boolean;
+    sdh?: boolean;
+  }
+
+  export interface SubtitleOffset {
+    subtitleId: string;
+    userId: string;
+    offset: number; // the user's own offset in seconds
+    subtitleOffset: number;
+  }